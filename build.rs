@@ -0,0 +1,12 @@
+// Generates the gRPC service code from proto/kv.proto for the `grpc` feature
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/kv.proto");
+        let descriptors = protox::compile(["kv.proto"], ["proto"]).expect("invalid proto/kv.proto");
+        tonic_prost_build::configure()
+            .bytes(".kv.v1")
+            .compile_fds(descriptors)
+            .expect("failed to generate the gRPC service");
+    }
+}
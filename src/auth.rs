@@ -0,0 +1,251 @@
+use crate::error::ApiError;
+use crate::keyspace::{self, Namespace};
+use axum::{
+    extract::FromRequestParts,
+    http::{request::Parts, Method},
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+
+// API keys accepted from clients. With none configured, every request is let
+// through, as before authentication existed.
+#[derive(Default)]
+pub struct ApiKeys {
+    keys: Vec<Vec<u8>>,
+}
+
+impl ApiKeys {
+    // Gather keys given directly and, if set, from a file holding one key per
+    // line. Blank lines and lines starting with # are skipped.
+    pub fn load(keys: &[String], file: Option<&Path>) -> Result<Self, String> {
+        let mut all: Vec<String> = keys.to_vec();
+        if let Some(path) = file {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+            all.extend(
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::to_string),
+            );
+        }
+        let keys: Vec<Vec<u8>> = all
+            .into_iter()
+            .map(|key| key.trim().to_string())
+            .filter(|key| !key.is_empty())
+            .map(String::into_bytes)
+            .collect();
+        Ok(Self { keys })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    // Whether `token` is one of the keys. Every key is compared in full, so the
+    // time taken doesn't reveal how much of a key a guess got right.
+    pub fn verify(&self, token: &str) -> bool {
+        self.keys.iter().fold(false, |found, key| {
+            found | constant_time_eq(key, token.as_bytes())
+        })
+    }
+}
+
+// The API key or ACL token a request authenticated with, attached to the
+// request by the authentication middleware
+#[derive(Clone)]
+pub struct Client(pub String);
+
+// What a grant lets a token do with the keys it covers. Write includes read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+}
+
+impl Permission {
+    // What a request to the key routes needs on its key. Copying only reads
+    // the source; the destination is checked separately.
+    pub fn required(method: &Method, sub_resource: Option<&str>) -> Self {
+        match (method, sub_resource) {
+            (&Method::GET | &Method::HEAD, _) | (_, Some("copy")) => Permission::Read,
+            _ => Permission::Write,
+        }
+    }
+}
+
+// A grant as written in the ACL file. Without a tenant or bucket it applies
+// to the default one.
+#[derive(Deserialize)]
+struct GrantSpec {
+    #[serde(default)]
+    prefix: String,
+    access: Permission,
+    tenant: Option<String>,
+    bucket: Option<String>,
+}
+
+// The ACL file: each token with the grants it holds, e.g.
+//   {"tokens": {"token-a": [{"prefix": "app1:", "access": "write"},
+//                           {"prefix": "shared:", "access": "read"}]}}
+#[derive(Deserialize)]
+struct AclFile {
+    tokens: HashMap<String, Vec<GrantSpec>>,
+}
+
+// Access to the keys starting with `prefix` in one namespace
+pub struct Grant {
+    namespace: Namespace,
+    prefix: String,
+    permission: Permission,
+}
+
+impl Grant {
+    fn covers(&self, stored: &str, permission: Permission) -> bool {
+        self.permission >= permission
+            && self
+                .namespace
+                .client_key(stored)
+                .is_some_and(|key| key.starts_with(&self.prefix))
+    }
+}
+
+// What the client of a request may do, attached to the request by the
+// authentication middleware. Requests without one have full access.
+#[derive(Clone)]
+pub enum Access {
+    // No authentication, or an API key
+    Full,
+    // A token from the ACL file
+    Scoped(Arc<[Grant]>),
+}
+
+impl Access {
+    // Whether the stored key, or every key under the stored prefix, may be
+    // read or written
+    pub fn allows(&self, stored: &str, permission: Permission) -> bool {
+        match self {
+            Access::Full => true,
+            Access::Scoped(grants) => grants.iter().any(|grant| grant.covers(stored, permission)),
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Access {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts.extensions.get().cloned().unwrap_or(Access::Full))
+    }
+}
+
+// The response to a request the client's grants don't allow. It is the same
+// whether or not the key exists.
+pub(crate) fn forbidden() -> ApiError {
+    ApiError::Forbidden("Forbidden")
+}
+
+// A token from the ACL file and its grants
+type ScopedToken = (Vec<u8>, Arc<[Grant]>);
+
+// Tokens scoped to key prefixes, loaded from an ACL file that can be re-read
+// while running. Without a file there are none.
+#[derive(Default)]
+pub struct Acl {
+    path: Option<PathBuf>,
+    tokens: RwLock<Vec<ScopedToken>>,
+}
+
+impl Acl {
+    pub fn load(path: Option<PathBuf>) -> Result<Self, String> {
+        let tokens = match &path {
+            Some(path) => read_acl(path)?,
+            None => Vec::new(),
+        };
+        Ok(Self {
+            path,
+            tokens: RwLock::new(tokens),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    // Re-read the ACL file, returning how many tokens it holds. On failure
+    // the tokens already loaded stay in effect.
+    pub fn reload(&self) -> Result<usize, String> {
+        let path = self.path.as_ref().ok_or("No ACL file is configured")?;
+        let tokens = read_acl(path)?;
+        let count = tokens.len();
+        *self.tokens.write().unwrap_or_else(PoisonError::into_inner) = tokens;
+        Ok(count)
+    }
+
+    // The grants of `token`, comparing it against every token in constant time
+    pub fn lookup(&self, token: &str) -> Option<Arc<[Grant]>> {
+        let tokens = self.tokens.read().unwrap_or_else(PoisonError::into_inner);
+        tokens.iter().fold(None, |found, (key, grants)| {
+            if constant_time_eq(key, token.as_bytes()) {
+                Some(grants.clone())
+            } else {
+                found
+            }
+        })
+    }
+}
+
+fn read_acl(path: &Path) -> Result<Vec<ScopedToken>, String> {
+    let contents =
+        std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let file: AclFile = serde_json::from_slice(&contents)
+        .map_err(|e| format!("invalid ACL file {}: {}", path.display(), e))?;
+    file.tokens
+        .into_iter()
+        .map(|(token, specs)| {
+            if token.trim().is_empty() {
+                return Err("ACL tokens must not be empty".to_string());
+            }
+            let grants = specs
+                .into_iter()
+                .map(|spec| {
+                    let namespace =
+                        Namespace::named(spec.tenant.as_deref(), spec.bucket.as_deref())
+                            .map_err(|e| format!("invalid grant for a token: {}", e))?;
+                    keyspace::validate(&spec.prefix)?;
+                    Ok(Grant {
+                        namespace,
+                        prefix: spec.prefix,
+                        permission: spec.access,
+                    })
+                })
+                .collect::<Result<Arc<[Grant]>, String>>()?;
+            Ok((token.into_bytes(), grants))
+        })
+        .collect()
+}
+
+// Byte-wise equality taking the same time wherever the first difference is.
+// Only the length can leak, through the early return.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,372 @@
+use axum::http::{HeaderName, HeaderValue, Method};
+use clap::{Parser, ValueEnum};
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+/// Which storage implementation backs the server
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// In-memory hash map, optionally persisted with snapshots and a write-ahead log
+    Memory,
+    /// Disk-backed sled database
+    Sled,
+}
+
+/// Startup configuration, parsed from the command line and environment. Build
+/// one in code with `Config::parse_from`, e.g.
+/// `Config::parse_from(["rust-kv", "--max-keys", "100"])`.
+#[derive(Parser, Clone, Debug)]
+#[command(version, about = "A simple in-memory key-value store over HTTP")]
+pub struct Config {
+    /// TOML file of settings, in [server], [storage], [metrics], [auth] and
+    /// [replication] tables keyed like the options below (`max_keys` for
+    /// --max-keys). RUSTKV_<KEY> environment variables override the file, and
+    /// flags override both
+    #[arg(long, env = "RUSTKV_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Refuse to start if the configuration file has unknown keys, instead of
+    /// warning about them
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Address to listen on
+    #[arg(long, default_value = "0.0.0.0", help_heading = "Server")]
+    pub bind: IpAddr,
+
+    /// Port to listen on; 0 requires --any-port
+    #[arg(long, default_value_t = 3000, help_heading = "Server")]
+    pub port: u16,
+
+    /// Let the OS pick a free port when --port is 0. The chosen port is logged
+    #[arg(long, help_heading = "Server")]
+    pub any_port: bool,
+
+    /// Unix domain socket to serve plain HTTP on as well, replacing a stale
+    /// socket left at the path. Unix only
+    #[arg(long, help_heading = "Server")]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Permissions of --unix-socket, in octal like 660; left to the umask if unset
+    #[arg(long, requires = "unix_socket", value_parser = parse_mode, help_heading = "Server")]
+    pub socket_mode: Option<u32>,
+
+    /// Serve only on --unix-socket, without listening on TCP
+    #[arg(
+        long,
+        requires = "unix_socket",
+        conflicts_with = "tls_cert",
+        help_heading = "Server"
+    )]
+    pub no_tcp: bool,
+
+    /// Port to serve the Redis protocol (RESP2) on as well, like 6379. Clients
+    /// see the default bucket of the default tenant, and must AUTH with an API
+    /// key or ACL token when authentication is enabled
+    #[arg(long, help_heading = "Server")]
+    pub resp_port: Option<u16>,
+
+    /// Port to serve the memcached text protocol on as well, like 11211.
+    /// Clients see the default bucket of the default tenant. The protocol has
+    /// no authentication, so this can't be combined with API keys or an ACL.
+    #[arg(
+        long,
+        conflicts_with_all = ["api_keys", "api_key_file", "acl_file"],
+        help_heading = "Server"
+    )]
+    pub memcache_port: Option<u16>,
+
+    /// Port to serve the gRPC service in proto/kv.proto on as well, like 50051.
+    /// Clients authenticate as over HTTP, with the token in metadata
+    #[cfg(feature = "grpc")]
+    #[arg(long, help_heading = "Server")]
+    pub grpc_port: Option<u16>,
+
+    /// Storage backend
+    #[arg(long, value_enum, default_value_t = Backend::Memory, help_heading = "Storage")]
+    pub backend: Backend,
+
+    /// Data directory for the sled backend
+    #[arg(long, default_value = "./data", help_heading = "Storage")]
+    pub data_dir: PathBuf,
+
+    /// Maximum number of keys; the least recently used key is evicted to make room
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), help_heading = "Storage")]
+    pub max_keys: Option<u64>,
+
+    /// Maximum total bytes of keys and values. Writes past the budget are refused
+    /// with 507, or make room by evicting when --max-keys is also set
+    #[arg(long, help_heading = "Storage")]
+    pub max_bytes: Option<u64>,
+
+    /// Number of independently locked shards the memory backend splits keys
+    /// across, so writes to different keys don't wait on each other. Defaults to
+    /// the number of CPUs. With --max-keys or --max-bytes set, writes lock every
+    /// shard, since the limits apply to all of them at once
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), help_heading = "Storage")]
+    pub shards: Option<u64>,
+
+    /// Interval between background sweeps of expired keys, in milliseconds
+    #[arg(long, default_value_t = 1000, help_heading = "Storage")]
+    pub sweep_interval_ms: u64,
+
+    /// File to persist snapshots to; persistence is disabled when unset
+    #[arg(long, help_heading = "Storage")]
+    pub snapshot_path: Option<PathBuf>,
+
+    /// File of newline-delimited JSON records to load into the store at
+    /// startup, before serving, as GET /admin/export writes them: `{"key":
+    /// "a", "value": "text", "ttl": 60}`, with `value_b64` in place of `value`
+    /// for binary values. Later lines for a key replace earlier ones, and the
+    /// records replace any recovered values of their keys. Malformed lines are
+    /// logged and skipped
+    #[arg(long, help_heading = "Storage")]
+    pub preload: Option<PathBuf>,
+
+    /// Refuse to start if any --preload line is malformed or doesn't fit,
+    /// rather than skipping it
+    #[arg(long, requires = "preload", help_heading = "Storage")]
+    pub preload_strict: bool,
+
+    /// Interval between periodic snapshots, in seconds
+    #[arg(long, default_value_t = 30, help_heading = "Storage")]
+    pub snapshot_interval_secs: u64,
+
+    /// Base path of the write-ahead log; logging is disabled when unset
+    #[arg(long, help_heading = "Storage")]
+    pub wal_path: Option<PathBuf>,
+
+    /// Fsync the write-ahead log before acknowledging each write
+    #[arg(long, help_heading = "Storage")]
+    pub wal_fsync: bool,
+
+    /// How long to wait for in-flight requests on shutdown, in seconds
+    #[arg(long, default_value_t = 30, help_heading = "Server")]
+    pub drain_timeout_secs: u64,
+
+    /// Start in read-only mode, refusing writes until POST /admin/readonly
+    /// turns it off
+    #[arg(long, help_heading = "Server")]
+    pub read_only: bool,
+
+    /// Number of replaced values kept per key for GET /{key}/history; 0 disables history
+    #[arg(long, default_value_t = 5, help_heading = "Storage")]
+    pub history_depth: usize,
+
+    /// Default maximum number of keys per tenant (X-Tenant). Overridable per tenant
+    /// through /admin/quota/{tenant}
+    #[arg(long, help_heading = "Storage")]
+    pub tenant_max_keys: Option<u64>,
+
+    /// Default maximum bytes of keys and values per tenant, history included
+    #[arg(long, help_heading = "Storage")]
+    pub tenant_max_bytes: Option<u64>,
+
+    /// Largest value accepted by PUT, and that PATCH may grow a value to, in bytes.
+    /// Larger bodies are refused with 413
+    #[arg(long, default_value_t = 2 * 1024 * 1024, help_heading = "Storage")]
+    pub max_value_bytes: usize,
+
+    /// Gzip-compress values of at least this many bytes written with PUT, when
+    /// that makes them smaller. Limits and quotas count the compressed size.
+    /// Values are stored as written when unset
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), help_heading = "Storage")]
+    pub compress_min_bytes: Option<u64>,
+
+    /// Largest request body accepted by /batch/get, /batch/put and /txn, in bytes
+    #[arg(long, default_value_t = 1024 * 1024, help_heading = "Storage")]
+    pub max_batch_bytes: usize,
+
+    /// Longest key accepted, in bytes after URL decoding
+    #[arg(
+        long,
+        default_value_t = 512,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help_heading = "Storage"
+    )]
+    pub max_key_bytes: u64,
+
+    /// Keep deleted keys restorable with POST /{key}/restore for this many seconds.
+    /// DELETE ?hard=true still deletes outright. Deleted keys are held in memory only
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), help_heading = "Storage")]
+    pub soft_delete_secs: Option<u64>,
+
+    /// Keep this many of the latest writes in memory, to be read from
+    /// GET /events; the change feed is off when unset
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), help_heading = "Storage")]
+    pub event_log_capacity: Option<u64>,
+
+    /// Include the values PUTs wrote in change feed events
+    #[arg(long, requires = "event_log_capacity", help_heading = "Storage")]
+    pub event_log_values: bool,
+
+    /// Length of the sliding window latency percentiles are computed over, in seconds
+    #[arg(
+        long,
+        default_value_t = 60,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help_heading = "Metrics"
+    )]
+    pub metrics_window_secs: u64,
+
+    /// Route template to leave out of the request metrics, like /stats; may be
+    /// repeated. /metrics, /healthz and /readyz are always left out
+    #[arg(
+        long = "metrics-exclude-route",
+        value_name = "ROUTE",
+        help_heading = "Metrics"
+    )]
+    pub metrics_exclude_routes: Vec<String>,
+
+    /// Log requests taking longer than this many milliseconds at WARN and keep
+    /// the latest ones for GET /metrics/slow
+    #[arg(long, help_heading = "Metrics")]
+    pub slow_ms: Option<u64>,
+
+    /// Seconds between metrics summaries in the log; 0 disables them
+    #[arg(long, default_value_t = 10, help_heading = "Metrics")]
+    pub metrics_log_interval: u64,
+
+    /// OTLP/HTTP endpoint to export request spans to, like
+    /// http://localhost:4318/v1/traces. Incoming W3C traceparent headers are honored
+    #[cfg(feature = "otlp")]
+    #[arg(long, help_heading = "Metrics")]
+    pub otlp_endpoint: Option<String>,
+
+    /// API key clients must send as `Authorization: Bearer <key>` or X-Api-Key;
+    /// may be repeated, or given comma-separated in KV_API_KEYS. Without any
+    /// keys, requests are not authenticated. /healthz and /readyz never are
+    #[arg(
+        long = "api-key",
+        value_name = "KEY",
+        env = "KV_API_KEYS",
+        value_delimiter = ',',
+        hide_env_values = true,
+        help_heading = "Auth"
+    )]
+    pub api_keys: Vec<String>,
+
+    /// File of API keys, one per line, accepted alongside --api-key
+    #[arg(long, help_heading = "Auth")]
+    pub api_key_file: Option<PathBuf>,
+
+    /// JSON file of tokens limited to reading or writing key prefixes, re-read
+    /// by POST /admin/acl/reload. Such tokens can only reach the data routes
+    #[arg(long, help_heading = "Auth")]
+    pub acl_file: Option<PathBuf>,
+
+    /// Token required by the /admin routes, sent as a Bearer token or in
+    /// X-Api-Key. May be repeated or comma-separated, and must differ from every
+    /// API key and ACL token. Without one the admin routes answer 403
+    #[arg(
+        long = "admin-token",
+        value_name = "TOKEN",
+        env = "KV_ADMIN_TOKENS",
+        value_delimiter = ',',
+        hide_env_values = true,
+        help_heading = "Auth"
+    )]
+    pub admin_tokens: Vec<String>,
+
+    /// Follower to push every write to, like http://follower:3000, running with
+    /// --follower and the same --replication-secret. The write-ahead log is
+    /// what's sent, so it takes the memory backend and --wal-path. How far the
+    /// follower has got is saved beside the log, to go on from after either
+    /// side restarts; a follower without that gets a full copy of the store
+    #[arg(
+        long,
+        value_parser = parse_url,
+        requires_all = ["wal_path", "replication_secret"],
+        help_heading = "Replication"
+    )]
+    pub replica_url: Option<String>,
+
+    /// Serve as a follower of a primary's --replica-url. Writes are refused
+    /// with 403, except the ones the primary pushes
+    #[arg(long, requires = "replication_secret", help_heading = "Replication")]
+    pub follower: bool,
+
+    /// Secret the primary authenticates to its follower with, as a Bearer token
+    #[arg(
+        long,
+        env = "RUSTKV_REPLICATION_SECRET",
+        hide_env_values = true,
+        help_heading = "Replication"
+    )]
+    pub replication_secret: Option<String>,
+
+    /// Requests per second allowed to each client, identified by its API key or
+    /// token, or else its IP address. Over the limit, requests get 429.
+    /// /healthz, /readyz and /metrics are never limited
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..), help_heading = "Server")]
+    pub rate_limit: Option<u32>,
+
+    /// Requests a client may make at once before --rate-limit applies; defaults
+    /// to one second's worth
+    #[arg(
+        long,
+        requires = "rate_limit",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help_heading = "Server"
+    )]
+    pub rate_limit_burst: Option<u32>,
+
+    /// PEM certificate chain to serve HTTPS with; requires --tls-key. On Unix,
+    /// SIGHUP reloads the certificate and key from the same files
+    #[arg(long, requires = "tls_key", help_heading = "Server")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of --tls-cert
+    #[arg(long, requires = "tls_cert", help_heading = "Server")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Longest a request may take before it is answered with 503, in seconds; 0
+    /// disables the limit. Imports take as long as their bodies do
+    #[arg(long, default_value_t = 5, help_heading = "Server")]
+    pub request_timeout_secs: u64,
+
+    /// Origin browsers may call the API from, like https://dash.example.com, or *
+    /// for any; may be repeated. CORS is disabled without one
+    #[arg(long = "cors-origin", value_name = "ORIGIN", help_heading = "Server")]
+    pub cors_origins: Vec<HeaderValue>,
+
+    /// Method allowed in cross-origin requests; may be repeated
+    #[arg(
+        long = "cors-method",
+        value_name = "METHOD",
+        default_values = ["GET", "HEAD", "PUT", "PATCH", "POST", "DELETE"],
+        help_heading = "Server"
+    )]
+    pub cors_methods: Vec<Method>,
+
+    /// Request header allowed in cross-origin requests; may be repeated
+    #[arg(
+        long = "cors-header",
+        value_name = "HEADER",
+        default_values = [
+            "authorization", "content-type", "if-match", "if-none-match", "x-api-key",
+            "x-confirm", "x-destination", "x-request-id", "x-return-old", "x-tenant",
+            "x-ttl-seconds",
+        ],
+        help_heading = "Server"
+    )]
+    pub cors_headers: Vec<HeaderName>,
+}
+
+// Parse an http:// or https:// URL, without the trailing slash
+fn parse_url(url: &str) -> Result<String, String> {
+    match url.split_once("://") {
+        Some(("http" | "https", rest)) if !rest.is_empty() => {
+            Ok(url.trim_end_matches('/').to_string())
+        }
+        _ => Err(format!("`{}` is not an http:// or https:// URL", url)),
+    }
+}
+
+// Parse file permissions given in octal, with or without a leading 0o
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|&mode| mode <= 0o7777)
+        .ok_or_else(|| format!("`{}` is not an octal file mode like 660", mode))
+}
@@ -0,0 +1,221 @@
+use clap::parser::ValueSource;
+use clap::{Arg, ArgMatches, Command};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::Path;
+
+// Prefix of the environment variables overriding file settings, like RUSTKV_PORT
+const ENV_PREFIX: &str = "RUSTKV_";
+
+// Options that select the configuration rather than being part of it
+const OWN_OPTIONS: [&str; 4] = ["config", "strict", "help", "version"];
+
+// Command-line arguments with the settings of the configuration file and the
+// RUSTKV_* environment variables filled in, plus warnings to log once logging
+// is up
+pub struct Resolved {
+    pub args: Vec<OsString>,
+    pub warnings: Vec<String>,
+}
+
+// Fill in every option not given on the command line (or through its own
+// environment variable) from RUSTKV_<OPTION>, or else from the configuration
+// file, so one clap parse applies the precedence: flags, then environment,
+// then file, then defaults. File settings live in tables named after each
+// option's help heading, like `port` under [server]. Unknown keys are
+// reported as warnings, or as an error when `strict`.
+pub fn resolve(
+    command: &Command,
+    matches: &ArgMatches,
+    args: Vec<OsString>,
+    file: Option<&Path>,
+    strict: bool,
+) -> Result<Resolved, String> {
+    let mut settings = match file {
+        Some(path) => read(command, path)?,
+        None => Settings::default(),
+    };
+    if strict && !settings.unknown.is_empty() {
+        return Err(settings.unknown.join("; "));
+    }
+
+    let mut extra = Vec::new();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if OWN_OPTIONS.contains(&id) || arg.get_long().is_none() {
+            continue;
+        }
+        if matches!(
+            matches.value_source(id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            continue;
+        }
+        let env = format!("{}{}", ENV_PREFIX, id.to_uppercase());
+        let values = match std::env::var(&env) {
+            Ok(value) => value.split(',').map(str::to_string).collect(),
+            Err(_) => match settings.values.remove(id) {
+                Some(values) => values,
+                None => continue,
+            },
+        };
+        push_arg(&mut extra, arg, values);
+    }
+
+    let mut args = args.into_iter();
+    Ok(Resolved {
+        args: args.next().into_iter().chain(extra).chain(args).collect(),
+        warnings: settings.unknown,
+    })
+}
+
+// Add `arg` set to `values` to the arguments; flags are only added if true
+fn push_arg(extra: &mut Vec<OsString>, arg: &Arg, values: Vec<String>) {
+    let long = arg.get_long().unwrap_or_default();
+    if !arg.get_action().takes_values() {
+        if values.iter().any(|value| value == "true" || value == "1") {
+            extra.push(format!("--{}", long).into());
+        }
+        return;
+    }
+    for value in values {
+        extra.push(format!("--{}={}", long, value).into());
+    }
+}
+
+// The settings of a configuration file, as option values in string form
+#[derive(Default)]
+struct Settings {
+    values: HashMap<String, Vec<String>>,
+    unknown: Vec<String>,
+}
+
+fn read(command: &Command, path: &Path) -> Result<Settings, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let table: toml::Table = contents
+        .parse()
+        .map_err(|e| format!("invalid configuration file {}: {}", path.display(), e))?;
+
+    let mut settings = Settings::default();
+    for (section, entries) in table {
+        let toml::Value::Table(entries) = entries else {
+            settings.unknown.push(format!(
+                "{}: `{}` must be inside a section like [server]",
+                path.display(),
+                section
+            ));
+            continue;
+        };
+        for (key, value) in entries {
+            let name = format!("{}.{}", section, key);
+            let arg = command
+                .get_arguments()
+                .find(|arg| arg.get_id().as_str() == key && !OWN_OPTIONS.contains(&key.as_str()));
+            let heading = arg.and_then(Arg::get_help_heading);
+            match heading {
+                Some(heading) if heading.eq_ignore_ascii_case(&section) => {
+                    let values = strings(&value).ok_or_else(|| {
+                        format!(
+                            "{}: `{}` must be a value or an array of values",
+                            path.display(),
+                            name
+                        )
+                    })?;
+                    settings.values.insert(key, values);
+                }
+                Some(heading) => settings.unknown.push(format!(
+                    "{}: unknown key `{}`; `{}` belongs in [{}]",
+                    path.display(),
+                    name,
+                    key,
+                    heading.to_lowercase()
+                )),
+                None => {
+                    settings
+                        .unknown
+                        .push(format!("{}: unknown key `{}`", path.display(), name))
+                }
+            }
+        }
+    }
+    Ok(settings)
+}
+
+// A TOML value as option values: a single value, or an array of them
+fn strings(value: &toml::Value) -> Option<Vec<String>> {
+    match value {
+        toml::Value::Array(items) => items.iter().map(scalar).collect(),
+        value => scalar(value).map(|value| vec![value]),
+    }
+}
+
+fn scalar(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(s) => Some(s.clone()),
+        toml::Value::Integer(n) => Some(n.to_string()),
+        toml::Value::Float(n) => Some(n.to_string()),
+        toml::Value::Boolean(b) => Some(b.to_string()),
+        toml::Value::Datetime(_) | toml::Value::Array(_) | toml::Value::Table(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, Parser};
+    use rust_kv::Config;
+
+    // Parse `args` as main does, with `settings` as the configuration file
+    fn parse(args: &[&str], settings: &str) -> Config {
+        let path = std::env::temp_dir().join(format!("rust-kv-config-{}.toml", std::process::id()));
+        std::fs::write(&path, settings).unwrap();
+        let args: Vec<OsString> = ["rust-kv"].iter().chain(args).map(OsString::from).collect();
+        let matches = Config::command().get_matches_from(&args);
+        let resolved = resolve(&Config::command(), &matches, args, Some(&path), true);
+        std::fs::remove_file(&path).unwrap();
+        Config::parse_from(resolved.unwrap().args)
+    }
+
+    // The only test here touching the environment, so nothing races it
+    #[test]
+    fn flags_beat_the_environment_which_beats_the_file() {
+        let settings = r#"
+            [server]
+            port = 4001
+            read_only = true
+            [storage]
+            history_depth = 7
+            max_value_bytes = 1234
+            [auth]
+            admin_tokens = ["from-file"]
+        "#;
+        std::env::set_var("RUSTKV_PORT", "4002");
+        std::env::set_var("RUSTKV_HISTORY_DEPTH", "8");
+        std::env::set_var("KV_ADMIN_TOKENS", "from-env");
+        let config = parse(&["--port", "4003"], settings);
+        let without_env = {
+            for env in ["RUSTKV_PORT", "RUSTKV_HISTORY_DEPTH", "KV_ADMIN_TOKENS"] {
+                std::env::remove_var(env);
+            }
+            parse(&[], settings)
+        };
+
+        // A flag wins over both
+        assert_eq!(config.port, 4003);
+        // The environment over the file, under either naming
+        assert_eq!(config.history_depth, 8);
+        assert_eq!(config.admin_tokens, ["from-env"]);
+        // The file over the defaults
+        assert_eq!(config.max_value_bytes, 1234);
+        assert!(config.read_only);
+        assert_eq!(
+            config.metrics_window_secs,
+            Config::parse_from(["rust-kv"]).metrics_window_secs
+        );
+
+        assert_eq!(without_env.port, 4001);
+        assert_eq!(without_env.history_depth, 7);
+        assert_eq!(without_env.admin_tokens, ["from-file"]);
+    }
+}
@@ -0,0 +1,418 @@
+use crate::handlers::accepts;
+use crate::keyspace;
+use crate::store::StorageError;
+use crate::{HEALTHZ_ROUTE, READYZ_ROUTE};
+use axum::{
+    body::{self, Body},
+    extract::{rejection::PathRejection, Request},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use std::any::Any;
+use std::fmt;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+// Longest body read from an error response made outside the handlers, to use
+// as its message
+const MAX_REJECTION_BYTES: usize = 64 * 1024;
+
+// Why a request failed. Responds with its status and a JSON body like
+// `{"error": "Key not found", "code": "key_not_found", "key": "greeting"}`,
+// where `code` is stable for clients to match on and `key` is only there for
+// errors about one key, as the client named it.
+#[derive(Debug)]
+pub enum ApiError {
+    // A malformed key, header, parameter or body
+    BadRequest(String),
+    // No valid token, with the Bearer challenge for WWW-Authenticate
+    Unauthorized {
+        challenge: String,
+        message: &'static str,
+    },
+    Forbidden(&'static str),
+    // A key without a live value, in its stored form
+    KeyNotFound(String),
+    // Anything else missing, like a feature that isn't configured
+    NotFound(&'static str),
+    // With the methods the route does serve for the Allow header, unless the
+    // router adds it; see `method_not_allowed`
+    MethodNotAllowed(Option<&'static str>),
+    Conflict {
+        key: String,
+        message: &'static str,
+    },
+    // Something kept only for a while, like change feed events, is no longer there
+    Gone(&'static str),
+    // A failed If-Match or If-None-Match
+    PreconditionFailed {
+        key: String,
+        message: &'static str,
+    },
+    // A request body over `limit` bytes
+    PayloadTooLarge(usize),
+    // A write that would leave a value over `limit` bytes
+    ValueTooLarge {
+        key: String,
+        limit: usize,
+    },
+    Unprocessable(String),
+    // A destructive request without its confirmation header
+    ConfirmationRequired(String),
+    RateLimited {
+        retry_after: u64,
+    },
+    // Logged where it happened; the client only learns that it did
+    Internal,
+    ReadOnly,
+    Timeout(Duration),
+    // Over the store's byte budget or a tenant's quota
+    InsufficientStorage(String),
+    // A Range no part of a value of `length` bytes satisfies
+    RangeNotSatisfiable {
+        length: usize,
+        message: &'static str,
+    },
+    // An error response made outside the handlers, like an extractor's rejection
+    Rejected(StatusCode, String),
+}
+
+impl ApiError {
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized { .. } => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::KeyNotFound(_) | ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::Conflict { .. } => StatusCode::CONFLICT,
+            ApiError::Gone(_) => StatusCode::GONE,
+            ApiError::PreconditionFailed { .. } => StatusCode::PRECONDITION_FAILED,
+            ApiError::PayloadTooLarge(_) | ApiError::ValueTooLarge { .. } => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            ApiError::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::ConfirmationRequired(_) => StatusCode::PRECONDITION_REQUIRED,
+            ApiError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::ReadOnly | ApiError::Timeout(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::InsufficientStorage(_) => StatusCode::INSUFFICIENT_STORAGE,
+            ApiError::RangeNotSatisfiable { .. } => StatusCode::RANGE_NOT_SATISFIABLE,
+            ApiError::Rejected(status, _) => *status,
+        }
+    }
+
+    // The machine-readable name of the error, in snake case
+    pub(crate) fn code(&self) -> String {
+        let code = match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unauthorized { .. } => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::KeyNotFound(_) => "key_not_found",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::MethodNotAllowed(_) => "method_not_allowed",
+            ApiError::Conflict { .. } => "conflict",
+            ApiError::Gone(_) => "gone",
+            ApiError::PreconditionFailed { .. } => "precondition_failed",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::ValueTooLarge { .. } => "value_too_large",
+            ApiError::Unprocessable(_) => "unprocessable",
+            ApiError::ConfirmationRequired(_) => "confirmation_required",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::Internal => "internal",
+            ApiError::ReadOnly => "read_only",
+            ApiError::Timeout(_) => "timeout",
+            ApiError::InsufficientStorage(_) => "insufficient_storage",
+            ApiError::RangeNotSatisfiable { .. } => "range_not_satisfiable",
+            // Named after the status, like `unsupported_media_type`
+            ApiError::Rejected(status, _) => {
+                return status
+                    .canonical_reason()
+                    .unwrap_or("error")
+                    .to_ascii_lowercase()
+                    .replace([' ', '-'], "_")
+            }
+        };
+        code.to_string()
+    }
+
+    // The key the error is about, as the client named it
+    fn key(&self) -> Option<&str> {
+        match self {
+            ApiError::KeyNotFound(key)
+            | ApiError::Conflict { key, .. }
+            | ApiError::PreconditionFailed { key, .. }
+            | ApiError::ValueTooLarge { key, .. } => Some(keyspace::client_key_of(key)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::BadRequest(msg)
+            | ApiError::Unprocessable(msg)
+            | ApiError::ConfirmationRequired(msg)
+            | ApiError::InsufficientStorage(msg)
+            | ApiError::Rejected(_, msg) => f.write_str(msg),
+            ApiError::Unauthorized { message, .. }
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::Gone(message)
+            | ApiError::Conflict { message, .. }
+            | ApiError::PreconditionFailed { message, .. }
+            | ApiError::RangeNotSatisfiable { message, .. } => f.write_str(message),
+            ApiError::KeyNotFound(_) => f.write_str("Key not found"),
+            ApiError::MethodNotAllowed(_) => {
+                f.write_str("Method not allowed; Allow lists the ones this resource serves")
+            }
+            ApiError::PayloadTooLarge(limit) => {
+                write!(f, "Request bodies are limited to {} bytes", limit)
+            }
+            ApiError::ValueTooLarge { limit, .. } => {
+                write!(f, "Values are limited to {} bytes", limit)
+            }
+            ApiError::RateLimited { retry_after } => {
+                write!(f, "Rate limit exceeded; retry in {} seconds", retry_after)
+            }
+            ApiError::Internal => f.write_str("Internal server error"),
+            ApiError::ReadOnly => f.write_str("The server is in read-only mode"),
+            ApiError::Timeout(timeout) => {
+                write!(f, "Request timed out after {} seconds", timeout.as_secs())
+            }
+        }
+    }
+}
+
+// Log a storage backend failure; the client gets a 500
+impl From<StorageError> for ApiError {
+    fn from(e: StorageError) -> Self {
+        tracing::error!("{}", e);
+        ApiError::Internal
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        ApiError::Rejected(rejection.status(), rejection.body_text())
+    }
+}
+
+// The body of an error response
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ErrorBody<'a> {
+    // What went wrong, for people
+    error: &'a str,
+    // What went wrong, for programs, like `key_not_found`
+    code: String,
+    // The key the error is about, if it is about one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<&'a str>,
+}
+
+// The message of an error response, for `error_middleware` to serve in the
+// terse form
+#[derive(Clone)]
+struct ErrorMessage(String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let message = self.to_string();
+        let body = ErrorBody {
+            error: &message,
+            code: self.code(),
+            key: self.key(),
+        };
+        let mut response = (self.status(), Json(body)).into_response();
+        let headers = response.headers_mut();
+        match &self {
+            ApiError::Unauthorized { challenge, .. } => {
+                if let Ok(value) = HeaderValue::from_str(challenge) {
+                    headers.insert(header::WWW_AUTHENTICATE, value);
+                }
+            }
+            ApiError::MethodNotAllowed(Some(allow)) => {
+                headers.insert(header::ALLOW, HeaderValue::from_static(allow));
+            }
+            ApiError::RateLimited { retry_after } => {
+                headers.insert(header::RETRY_AFTER, HeaderValue::from(*retry_after));
+            }
+            ApiError::RangeNotSatisfiable { length, .. } => {
+                let range = format!("bytes */{}", length);
+                headers.insert(
+                    header::CONTENT_RANGE,
+                    HeaderValue::from_str(&range).unwrap(),
+                );
+            }
+            _ => {}
+        }
+        response.extensions_mut().insert(ErrorMessage(message));
+        response
+    }
+}
+
+// Serve every 4xx and 5xx response in the same shape. Those made from an
+// `ApiError` are JSON already; other ones, like the router's 404 for an unknown
+// path or an extractor's rejection, are turned into one with their body as the
+// message. Bodies already in JSON, like a failed transaction's, are left alone.
+// Clients asking for text/plain but not JSON get just the message instead. The
+// probes keep their plain text answers for orchestrators.
+pub(crate) async fn error_middleware(request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    if path == HEALTHZ_ROUTE || path == READYZ_ROUTE {
+        return next.run(request).await;
+    }
+    let terse = request
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| {
+            accepts(accept, "text/plain") && !accepts(accept, "application/json")
+        });
+
+    let response = next.run(request).await;
+    let status = response.status();
+    if !(status.is_client_error() || status.is_server_error()) {
+        return response;
+    }
+    if let Some(ErrorMessage(message)) = response.extensions().get() {
+        if !terse {
+            return response;
+        }
+        let message = message.clone();
+        return with_body(response, "text/plain; charset=utf-8", Body::from(message));
+    }
+    if is_json(&response) {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let message = match body::to_bytes(body, MAX_REJECTION_BYTES).await {
+        Ok(bytes) if !bytes.trim_ascii().is_empty() => {
+            String::from_utf8_lossy(bytes.trim_ascii()).into_owned()
+        }
+        _ => status.canonical_reason().unwrap_or("Error").to_string(),
+    };
+    // Keeping the original headers, like Allow
+    let response = Response::from_parts(parts, Body::empty());
+    if terse {
+        return with_body(response, "text/plain; charset=utf-8", Body::from(message));
+    }
+    let body = ApiError::Rejected(status, message)
+        .into_response()
+        .into_body();
+    with_body(response, "application/json", body)
+}
+
+// The fallback of every method router, for a method its route doesn't serve.
+// The router adds the Allow header, listing the methods as `GET,HEAD`; the key
+// routes, which pick their handler themselves, list theirs the same way.
+pub(crate) async fn method_not_allowed() -> ApiError {
+    ApiError::MethodNotAllowed(None)
+}
+
+// Answer a request whose handler panicked with a 500, as for any internal
+// error, rather than dropping the connection. The store's locks can't be
+// poisoned and the other locks are taken so as to recover from poisoning, so
+// the requests after it are served as usual.
+pub(crate) fn panic_response(panic: Box<dyn Any + Send>) -> Response {
+    let cause = panic
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| panic.downcast_ref::<&str>().copied())
+        .unwrap_or("no message");
+    tracing::error!("A request handler panicked: {}", cause);
+    ApiError::Internal.into_response()
+}
+
+// `response` with its body replaced by `body` of `content_type`
+fn with_body(response: Response, content_type: &'static str, body: Body) -> Response {
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    Response::from_parts(parts, body)
+}
+
+// Whether a response's Content-Type is JSON
+fn is_json(response: &Response) -> bool {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| accepts(value, "application/json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn body(error: ApiError) -> (StatusCode, serde_json::Value) {
+        let response = error.into_response();
+        let status = response.status();
+        let bytes = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn server_errors_say_little() {
+        let (status, json) = body(ApiError::Internal).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            json,
+            serde_json::json!({ "error": "Internal server error", "code": "internal" })
+        );
+
+        let (status, json) = body(ApiError::Timeout(Duration::from_secs(30))).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(json["code"], "timeout");
+        assert_eq!(json["error"], "Request timed out after 30 seconds");
+    }
+
+    #[test]
+    fn rejections_are_coded_by_status() {
+        let rejected = ApiError::Rejected(StatusCode::UNSUPPORTED_MEDIA_TYPE, String::new());
+        assert_eq!(rejected.code(), "unsupported_media_type");
+        let rejected = ApiError::Rejected(StatusCode::NON_AUTHORITATIVE_INFORMATION, String::new());
+        assert_eq!(rejected.code(), "non_authoritative_information");
+    }
+
+    #[tokio::test]
+    async fn panicking_handlers_answer_500() {
+        use tower::ServiceExt;
+
+        async fn buggy() -> &'static str {
+            panic!("handler bug")
+        }
+        let app = axum::Router::new()
+            .route("/", axum::routing::get(buggy))
+            .layer(tower_http::catch_panic::CatchPanicLayer::custom(
+                panic_response,
+            ))
+            .layer(axum::middleware::from_fn(error_middleware));
+        for _ in 0..2 {
+            let request = Request::get("/").body(Body::empty()).unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            let bytes = body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(json["code"], "internal");
+        }
+    }
+
+    #[test]
+    fn keys_are_named_as_the_client_did() {
+        let stored = keyspace::Namespace::named(Some("acme"), Some("logs"))
+            .unwrap()
+            .storage_key("a/b");
+        assert_eq!(ApiError::KeyNotFound(stored).key(), Some("a/b"));
+    }
+}
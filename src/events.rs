@@ -0,0 +1,380 @@
+// The change feed: the most recent writes, numbered in the order they were
+// applied, kept in a bounded log for GET /events and pushed to watchers
+use crate::keyspace::{self, Namespace};
+use crate::persistence::{self, StoredValue};
+use crate::store::{Entry, StorageError};
+use bytes::Bytes;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Instant, SystemTime};
+use tokio::sync::{broadcast, watch};
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Op {
+    Put,
+    Delete,
+    // Removed once past its deadline
+    Expire,
+    // Given a new deadline, the value left as it was
+    Touch,
+}
+
+impl Op {
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Put => "put",
+            Op::Delete => "delete",
+            Op::Expire => "expire",
+            Op::Touch => "touch",
+        }
+    }
+}
+
+// One recorded write
+#[derive(Clone)]
+pub struct Event {
+    pub seq: u64,
+    op: Op,
+    // In its stored form
+    key: String,
+    at: SystemTime,
+    // The version a put wrote, or a touch kept
+    version: Option<u64>,
+    // The value a put wrote, as stored, when the log keeps values
+    value: Option<(Bytes, bool)>,
+    // When an expired key was due to expire, or a touched one now is
+    deadline: Option<SystemTime>,
+}
+
+// An event as served: the key named like in exports, by `tenant` and `bucket`
+// outside the default namespace
+#[derive(Serialize)]
+pub struct EventRecord<'a> {
+    seq: u64,
+    op: Op,
+    key: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bucket: Option<&'a str>,
+    at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    value: Option<StoredValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<String>,
+}
+
+impl EventRecord<'_> {
+    pub fn op(&self) -> &'static str {
+        self.op.name()
+    }
+}
+
+impl Event {
+    pub fn record(&self) -> Result<EventRecord<'_>, StorageError> {
+        let (tenant, bucket, key) = keyspace::split_stored(&self.key);
+        let value = match &self.value {
+            Some((value, compressed)) => {
+                let data = crate::store::stored_data(value, *compressed)?;
+                Some(StoredValue::encode(&data))
+            }
+            None => None,
+        };
+        Ok(EventRecord {
+            seq: self.seq,
+            op: self.op,
+            key,
+            tenant,
+            bucket,
+            at: persistence::system_time_to_rfc3339(self.at),
+            version: self.version,
+            value,
+            expires_at: self.deadline.map(persistence::system_time_to_rfc3339),
+        })
+    }
+}
+
+// The writes a watcher is told about
+pub enum Watched {
+    // Those to one stored key
+    Key(String),
+    // Those to the keys of a namespace starting with a prefix
+    Prefix(Namespace, String),
+}
+
+impl Watched {
+    pub fn matches(&self, event: &Event) -> bool {
+        match self {
+            Watched::Key(key) => event.key == *key,
+            Watched::Prefix(namespace, prefix) => namespace
+                .client_key(&event.key)
+                .is_some_and(|key| key.starts_with(prefix.as_str())),
+        }
+    }
+}
+
+// Why events can't be served after a sequence number: they've been dropped to
+// make room, or the number comes from before the server restarted
+pub struct Gone;
+
+// The events still kept, and the number of the last one ever recorded
+struct Ring {
+    events: VecDeque<Event>,
+    latest: u64,
+}
+
+// Events a watcher may fall behind by before it's told it missed some
+const WATCH_BUFFER: usize = 1024;
+
+// The GETs parked on one key, woken by writes to it
+struct Parking {
+    changes: watch::Sender<()>,
+    parked: usize,
+}
+
+// A GET waiting for a key to change, parked while this is held
+pub struct Parked<'a> {
+    events: &'a Events,
+    key: String,
+    changes: watch::Receiver<()>,
+}
+
+impl Parked<'_> {
+    // Resolves on the next write to the key since parking, or since this last
+    // resolved
+    pub async fn changed(&mut self) {
+        let _ = self.changes.changed().await;
+    }
+}
+
+impl Drop for Parked<'_> {
+    fn drop(&mut self) {
+        let mut parking = self
+            .events
+            .parking
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(on_key) = parking.get_mut(&self.key) {
+            on_key.parked -= 1;
+            if on_key.parked == 0 {
+                parking.remove(&self.key);
+            }
+        }
+        self.events.parked.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Where writes are announced: the log GET /events reads, when it's enabled,
+// the watchers subscribed at the time, and the GETs parked on the key
+pub struct Events {
+    // How many events the log keeps; None when it's off
+    capacity: Option<usize>,
+    // Whether the logged puts keep their values
+    values: bool,
+    ring: Mutex<Ring>,
+    watchers: broadcast::Sender<Event>,
+    // Set once watches are to end
+    closing: watch::Sender<bool>,
+    // By the stored form of the key
+    parking: Mutex<HashMap<String, Parking>>,
+    // How many GETs are parked on any key, so writes skip the lock when none are
+    parked: AtomicUsize,
+}
+
+impl Events {
+    pub fn new(capacity: Option<usize>, values: bool) -> Self {
+        Self {
+            capacity,
+            values,
+            ring: Mutex::new(Ring {
+                events: VecDeque::with_capacity(capacity.unwrap_or(0)),
+                latest: 0,
+            }),
+            watchers: broadcast::channel(WATCH_BUFFER).0,
+            closing: watch::channel(false).0,
+            parking: Mutex::new(HashMap::new()),
+            parked: AtomicUsize::new(0),
+        }
+    }
+
+    // Whether GET /events is served
+    pub fn is_logged(&self) -> bool {
+        self.capacity.is_some()
+    }
+
+    // Record a write to the stored key `key`: a put of `entry`, or a delete.
+    // Call while holding the store's lock on the key, so events are numbered
+    // in the order the writes were applied.
+    pub fn record(&self, key: &str, entry: Option<&Entry>) {
+        let op = if entry.is_some() { Op::Put } else { Op::Delete };
+        self.push(key, op, |event| {
+            if let Some(entry) = entry {
+                event.version = Some(entry.version);
+                event.value = Some((entry.value.clone(), entry.compressed));
+            }
+        });
+    }
+
+    // Record the removal of `entry`, stored under `key`, past its deadline,
+    // under the same lock as a write
+    pub fn expire(&self, key: &str, entry: &Entry) {
+        self.push(key, Op::Expire, |event| {
+            event.deadline = entry
+                .expires_at
+                .and_then(|deadline| wall_clock(deadline, event.at));
+        });
+    }
+
+    // Record the new deadline of `entry`, stored under `key`, set by a touch,
+    // under the same lock as a write
+    pub fn touch(&self, key: &str, entry: &Entry) {
+        self.push(key, Op::Touch, |event| {
+            event.version = Some(entry.version);
+            event.deadline = entry
+                .expires_at
+                .and_then(|deadline| wall_clock(deadline, event.at));
+        });
+    }
+
+    // Number an event of `op` on `key`, filled in by `fill`, and announce it;
+    // not even built when nothing would read it. A touch leaves the value as
+    // it was, so GETs waiting for a new one stay parked.
+    fn push(&self, key: &str, op: Op, fill: impl FnOnce(&mut Event)) {
+        if !matches!(op, Op::Touch) && self.parked.load(Ordering::SeqCst) > 0 {
+            let parking = self.parking.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(on_key) = parking.get(key) {
+                on_key.changes.send_replace(());
+            }
+        }
+        if self.capacity.is_none() && self.watchers.receiver_count() == 0 {
+            return;
+        }
+        let mut ring = self.ring.lock().unwrap_or_else(PoisonError::into_inner);
+        ring.latest += 1;
+        let mut event = Event {
+            seq: ring.latest,
+            op,
+            key: key.to_string(),
+            at: SystemTime::now(),
+            version: None,
+            value: None,
+            deadline: None,
+        };
+        fill(&mut event);
+        if let Some(capacity) = self.capacity {
+            if ring.events.len() == capacity {
+                ring.events.pop_front();
+            }
+            let mut logged = event.clone();
+            if !self.values {
+                logged.value = None;
+            }
+            ring.events.push_back(logged);
+        }
+        // Fails only when no one is watching
+        let _ = self.watchers.send(event);
+    }
+
+    // Every event from now on, until the receiver is dropped. One that falls
+    // more than WATCH_BUFFER events behind skips ahead, and is told how many
+    // it missed.
+    pub fn watch(&self) -> broadcast::Receiver<Event> {
+        self.watchers.subscribe()
+    }
+
+    // Park a GET until the stored key `key` is next written. Park before
+    // reading the key, so a write in between isn't missed.
+    pub fn park(&self, key: &str) -> Parked<'_> {
+        let mut parking = self.parking.lock().unwrap_or_else(PoisonError::into_inner);
+        let on_key = parking.entry(key.to_string()).or_insert_with(|| Parking {
+            changes: watch::channel(()).0,
+            parked: 0,
+        });
+        on_key.parked += 1;
+        self.parked.fetch_add(1, Ordering::SeqCst);
+        Parked {
+            events: self,
+            key: key.to_string(),
+            changes: on_key.changes.subscribe(),
+        }
+    }
+
+    // How many GETs are parked
+    pub fn parked(&self) -> usize {
+        self.parked.load(Ordering::SeqCst)
+    }
+
+    // End every watch, as the server shuts down
+    pub fn close(&self) {
+        self.closing.send_replace(true);
+    }
+
+    // Resolves once watches are to end
+    pub fn closed(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut closing = self.closing.subscribe();
+        async move {
+            let _ = closing.wait_for(|&closed| closed).await;
+        }
+    }
+
+    // Up to `limit` of the logged events after number `since`, with the
+    // number of the latest event
+    pub fn since(&self, since: u64, limit: usize) -> Result<(Vec<Event>, u64), Gone> {
+        let ring = self.ring.lock().unwrap_or_else(PoisonError::into_inner);
+        let first = ring.latest + 1 - ring.events.len() as u64;
+        if since > ring.latest || since + 1 < first {
+            return Err(Gone);
+        }
+        let skip = (since + 1 - first) as usize;
+        let events = ring.events.iter().skip(skip).take(limit).cloned().collect();
+        Ok((events, ring.latest))
+    }
+}
+
+// `deadline`, on the monotonic clock, as a wall clock time, with `at` for now
+fn wall_clock(deadline: Instant, at: SystemTime) -> Option<SystemTime> {
+    let now = Instant::now();
+    match deadline.checked_duration_since(now) {
+        Some(ahead) => at.checked_add(ahead),
+        None => at.checked_sub(now - deadline),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seqs(log: &Events, since: u64, limit: usize) -> Option<(Vec<u64>, u64)> {
+        let (events, latest) = log.since(since, limit).ok()?;
+        Some((events.iter().map(|event| event.seq).collect(), latest))
+    }
+
+    #[test]
+    fn old_events_wrap_around() {
+        let log = Events::new(Some(3), false);
+        assert_eq!(seqs(&log, 0, 10), Some((vec![], 0)));
+        for n in 0..5 {
+            log.record(&format!("k{n}"), None);
+        }
+        // 1 and 2 were dropped for 4 and 5, so only a reader that saw 2 can go on
+        assert_eq!(seqs(&log, 2, 10), Some((vec![3, 4, 5], 5)));
+        assert_eq!(seqs(&log, 3, 1), Some((vec![4], 5)));
+        assert!(log.since(1, 10).is_err());
+        assert!(log.since(0, 10).is_err());
+        // From a server that had recorded more, before a restart
+        assert!(log.since(6, 10).is_err());
+    }
+
+    #[test]
+    fn reading_from_the_latest_event_finds_nothing() {
+        let log = Events::new(Some(3), false);
+        log.record("k", Some(&Entry::new("v".into())));
+        assert_eq!(seqs(&log, 1, 10), Some((vec![], 1)));
+    }
+}
@@ -0,0 +1,398 @@
+//! The gRPC service described by `proto/kv.proto`, over the same store,
+//! credentials and metrics as the HTTP routes. Enabled by the `grpc` feature
+//! and served on `--grpc-port`.
+//!
+//! The generated client in [`proto::kv_client`] talks to it:
+//!
+//! ```no_run
+//! use rust_kv::grpc::proto::{kv_client::KvClient, GetRequest};
+//!
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut client = KvClient::connect("http://127.0.0.1:50051").await?;
+//! let response = client.get(GetRequest { key: "greeting".into() }).await?;
+//! println!("{:?}", response.into_inner().value);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::auth::{Access, Permission};
+use crate::handlers::{self, MAX_BATCH_KEYS};
+use crate::keyspace::{self, Namespace};
+use crate::middleware::{request_token, Authenticator};
+use crate::store::Entry;
+use crate::{wal, AppState, NoRoom};
+use axum::http::{Method, StatusCode};
+use proto::kv_server::{Kv, KvServer};
+use proto::{
+    BatchGetRequest, BatchGetResponse, DeleteRequest, DeleteResponse, GetRequest, GetResponse,
+    PutRequest, PutResponse,
+};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tonic::{metadata::MetadataMap, Code, Request, Response, Status};
+
+/// Messages, client and server generated from `proto/kv.proto`
+#[allow(missing_docs, clippy::all)]
+pub mod proto {
+    tonic::include_proto!("kv.v1");
+}
+
+// Metadata naming the tenant to operate on, as the X-Tenant header does
+const TENANT_METADATA: &str = "x-tenant";
+
+// Room for the rest of a message around the largest value accepted
+const MESSAGE_OVERHEAD: usize = 64 * 1024;
+
+// The HTTP status an RPC's outcome is counted under in the request metrics,
+// following the usual mapping between the two
+fn http_status(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        Code::InvalidArgument | Code::OutOfRange => StatusCode::BAD_REQUEST,
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+        Code::FailedPrecondition => StatusCode::PRECONDITION_FAILED,
+        Code::ResourceExhausted => StatusCode::INSUFFICIENT_STORAGE,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn storage_failure(e: impl std::fmt::Display) -> Status {
+    tracing::error!("{}", e);
+    Status::internal("Storage failure")
+}
+
+fn log_failure(e: impl std::fmt::Display) -> Status {
+    tracing::error!("Failed to log a gRPC write: {}", e);
+    Status::internal("Failed to log the write")
+}
+
+fn no_room(state: &AppState, reason: NoRoom) -> Status {
+    Status::resource_exhausted(match reason {
+        NoRoom::Budget => format!(
+            "The write would exceed the storage budget of {} bytes",
+            state.store.limits().max_bytes.unwrap_or_default()
+        ),
+        NoRoom::Quota(_) => "The write would exceed the tenant quota".to_string(),
+    })
+}
+
+fn get_response(entry: Entry) -> Result<GetResponse, Status> {
+    Ok(GetResponse {
+        value: entry.data().map_err(storage_failure)?,
+        version: entry.version,
+        content_type: entry.content_type,
+    })
+}
+
+// Everything the RPCs need, shared by all of them
+#[derive(Clone)]
+pub(crate) struct Grpc {
+    pub(crate) state: AppState,
+    pub(crate) authenticator: Authenticator,
+}
+
+impl Grpc {
+    // Serve on `listener` until `shutdown` is set, letting running RPCs finish
+    pub(crate) async fn serve(
+        self,
+        listener: tokio::net::TcpListener,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
+        let max_message = self.state.max_value_bytes.saturating_add(MESSAGE_OVERHEAD);
+        let service = KvServer::new(self).max_decoding_message_size(max_message);
+        let result = tonic::transport::Server::builder()
+            .add_service(service)
+            .serve_with_incoming_shutdown(
+                tonic::transport::server::TcpIncoming::from(listener),
+                async move {
+                    let _ = shutdown.wait_for(|&stop| stop).await;
+                },
+            )
+            .await;
+        if let Err(e) = result {
+            tracing::error!("gRPC server failed: {}", e);
+        }
+    }
+
+    // Run an RPC, counting it in the request metrics like an HTTP request to
+    // its path, POST /kv.v1.Kv/<rpc>
+    async fn observed<T>(
+        &self,
+        rpc: &str,
+        call: impl Future<Output = Result<T, Status>>,
+    ) -> Result<Response<T>, Status> {
+        let start = Instant::now();
+        let result = call.await;
+        let code = result.as_ref().map_or_else(Status::code, |_| Code::Ok);
+        let route = format!("/kv.v1.Kv/{}", rpc);
+        self.state
+            .metrics
+            .record(&Method::POST, &route, http_status(code), start.elapsed());
+        result.map(Response::new)
+    }
+
+    // What the caller may do, from its token when authentication is enabled
+    fn access(&self, metadata: &MetadataMap) -> Result<Access, Status> {
+        if !(self.authenticator.api_keys.is_enabled() || self.authenticator.acl.is_enabled()) {
+            return Ok(Access::Full);
+        }
+        let headers = metadata.clone().into_headers();
+        match request_token(&headers) {
+            Some(token) if self.authenticator.api_keys.verify(token) => Ok(Access::Full),
+            Some(token) => match self.authenticator.acl.lookup(token) {
+                Some(grants) => Ok(Access::Scoped(grants)),
+                None => Err(Status::unauthenticated("Invalid API key or ACL token")),
+            },
+            None => Err(Status::unauthenticated("Missing API key or ACL token")),
+        }
+    }
+
+    fn namespace(metadata: &MetadataMap) -> Result<Namespace, Status> {
+        let tenant = match metadata.get(TENANT_METADATA) {
+            Some(value) => Some(
+                value
+                    .to_str()
+                    .map_err(|_| Status::invalid_argument("x-tenant must be valid UTF-8"))?,
+            ),
+            None => None,
+        };
+        Namespace::named(tenant, None).map_err(Status::invalid_argument)
+    }
+
+    // The stored keys for `keys` from a request, if they are valid and the
+    // caller may use them as `permission` asks
+    fn keys<T>(
+        &self,
+        request: &Request<T>,
+        keys: &[String],
+        permission: Permission,
+    ) -> Result<(Namespace, Vec<String>), Status> {
+        let access = self.access(request.metadata())?;
+        let namespace = Self::namespace(request.metadata())?;
+        let mut stored = Vec::with_capacity(keys.len());
+        for key in keys {
+            keyspace::validate_key(key, self.state.max_key_bytes)
+                .map_err(Status::invalid_argument)?;
+            let key = namespace.storage_key(key);
+            if !access.allows(&key, permission) {
+                return Err(Status::permission_denied(
+                    "This token can't access that key",
+                ));
+            }
+            stored.push(key);
+        }
+        Ok((namespace, stored))
+    }
+
+    fn read_only(&self) -> Result<(), Status> {
+        if self.state.follower.is_some() {
+            return Err(Status::permission_denied(
+                "The server is a follower; write to its primary",
+            ));
+        }
+        if self.state.read_only.load(Ordering::Relaxed) {
+            return Err(Status::unavailable("The server is in read-only mode"));
+        }
+        Ok(())
+    }
+
+    async fn get_value(&self, request: Request<GetRequest>) -> Result<GetResponse, Status> {
+        let (namespace, keys) =
+            self.keys(&request, &[request.get_ref().key.clone()], Permission::Read)?;
+        let key = &keys[0];
+        let result = self
+            .state
+            .store
+            .with_key_read(key, |view| {
+                let now = Instant::now();
+                view.get(key)
+                    .map(|entry| (!entry.is_expired(now)).then_some(entry))
+            })
+            .await;
+        let found = match result.map_err(storage_failure)? {
+            Some(Some(entry)) => Some(entry),
+            Some(None) => {
+                handlers::remove_if_expired(&self.state, key)
+                    .await
+                    .map_err(storage_failure)?;
+                None
+            }
+            None => None,
+        };
+        self.state.ops.get(namespace.tenant(), found.is_some());
+        found
+            .map(get_response)
+            .ok_or_else(|| Status::not_found("No such key"))?
+    }
+
+    async fn put_value(&self, request: Request<PutRequest>) -> Result<PutResponse, Status> {
+        let (_, keys) = self.keys(
+            &request,
+            &[request.get_ref().key.clone()],
+            Permission::Write,
+        )?;
+        self.read_only()?;
+        let PutRequest {
+            value,
+            ttl_seconds,
+            content_type,
+            ..
+        } = request.into_inner();
+        if value.len() > self.state.max_value_bytes {
+            return Err(Status::invalid_argument(format!(
+                "Values are limited to {} bytes",
+                self.state.max_value_bytes
+            )));
+        }
+        let key = keys.into_iter().next().unwrap_or_default();
+
+        let mut entry = Entry {
+            expires_at: (ttl_seconds > 0)
+                .then(|| Instant::now() + Duration::from_secs(ttl_seconds)),
+            content_type,
+            ..Entry::new(value)
+        };
+        let state = &self.state;
+        let result = state
+            .store
+            .with_key_write(&key, |view| {
+                let now = Instant::now();
+                let current = view.get(&key).filter(|current| !current.is_expired(now));
+                if let Some(current) = &current {
+                    entry.replaces(current, state.history_depth);
+                }
+                state.check_room(view, &key, &entry)?;
+                entry.version = view.next_version();
+                let version = entry.version;
+                let ack = state.log(|| wal::WalRecord::put(&key, &entry));
+                state.record_event(&key, Some(&entry));
+                view.insert(key.clone(), entry);
+                Ok((current.is_none(), version, ack))
+            })
+            .await;
+        let (created, version, ack) = result
+            .map_err(storage_failure)?
+            .map_err(|reason| no_room(state, reason))?;
+        wal::wait(ack).await.map_err(log_failure)?;
+        state.ops.put(1);
+        Ok(PutResponse { version, created })
+    }
+
+    async fn delete_value(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<DeleteResponse, Status> {
+        let (_, keys) = self.keys(
+            &request,
+            &[request.get_ref().key.clone()],
+            Permission::Write,
+        )?;
+        self.read_only()?;
+        let key = keys.into_iter().next().unwrap_or_default();
+        let state = &self.state;
+        let result = state
+            .store
+            .with_key_write(&key, |view| {
+                let now = Instant::now();
+                // An expired entry is removed either way, but isn't found
+                match view.remove(&key) {
+                    Some(entry) if !entry.is_expired(now) => {
+                        let ack = state.log(|| wal::WalRecord::delete(&key));
+                        state.record_event(&key, None);
+                        if let Some(tombstones) = &state.tombstones {
+                            tombstones.bury(key.clone(), entry, now);
+                        }
+                        Some(ack)
+                    }
+                    Some(entry) => {
+                        state.record_expiry(&key, &entry);
+                        None
+                    }
+                    None => None,
+                }
+            })
+            .await;
+        let ack = result
+            .map_err(storage_failure)?
+            .ok_or_else(|| Status::not_found("No such key"))?;
+        wal::wait(ack).await.map_err(log_failure)?;
+        state.ops.delete();
+        Ok(DeleteResponse {})
+    }
+
+    async fn batch_get_values(
+        &self,
+        request: Request<BatchGetRequest>,
+    ) -> Result<BatchGetResponse, Status> {
+        let names = &request.get_ref().keys;
+        if names.len() > MAX_BATCH_KEYS {
+            return Err(Status::invalid_argument(format!(
+                "A batch may name at most {} keys",
+                MAX_BATCH_KEYS
+            )));
+        }
+        let (namespace, keys) = self.keys(&request, names, Permission::Read)?;
+
+        // One read transaction for the whole batch
+        let result = self
+            .state
+            .store
+            .with_read(|view| {
+                let now = Instant::now();
+                let mut found = HashMap::with_capacity(keys.len());
+                for (name, key) in names.iter().zip(&keys) {
+                    if found.contains_key(name) {
+                        continue;
+                    }
+                    let entry = view.get(key).filter(|entry| !entry.is_expired(now));
+                    found.insert(name.clone(), entry);
+                }
+                found
+            })
+            .await;
+        let found = result.map_err(storage_failure)?;
+        for entry in found.values() {
+            self.state.ops.get(namespace.tenant(), entry.is_some());
+        }
+        let values = found
+            .into_iter()
+            .filter_map(|(name, entry)| Some(get_response(entry?).map(|value| (name, value))))
+            .collect::<Result<_, Status>>()?;
+        Ok(BatchGetResponse { values })
+    }
+}
+
+#[tonic::async_trait]
+impl Kv for Grpc {
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        self.observed("Get", self.get_value(request)).await
+    }
+
+    async fn put(&self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
+        self.observed("Put", self.put_value(request)).await
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        self.observed("Delete", self.delete_value(request)).await
+    }
+
+    async fn batch_get(
+        &self,
+        request: Request<BatchGetRequest>,
+    ) -> Result<Response<BatchGetResponse>, Status> {
+        self.observed("BatchGet", self.batch_get_values(request))
+            .await
+    }
+}
@@ -0,0 +1,3344 @@
+use crate::auth::{self, Access, Permission};
+use crate::error::ApiError;
+use crate::keyspace::{self, Key, Namespace};
+use crate::metrics::{self, hit_ratio};
+use crate::store::{self, Entry, StorageError, Store, WriteView};
+use crate::{events, ndjson, persistence, quota, wal, AppState, NoRoom, Readiness};
+use axum::{
+    body::Bytes,
+    extract::{OriginalUri, Path, Query, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::sse::{Event as SseEvent, KeepAlive, Sse},
+    response::{AppendHeaders, IntoResponse, Response},
+    Json,
+};
+use http_body_util::channel::{Channel, Sender};
+use http_body_util::BodyExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::sync::atomic::Ordering;
+use std::sync::PoisonError;
+use std::time::{Duration, Instant, SystemTime};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+use utoipa::{IntoParams, ToSchema};
+
+// Content-Type served for values stored without one
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+// Page size for GET /keys when no limit is given, and the most a client may ask for
+const DEFAULT_LIST_LIMIT: usize = 100;
+
+const MAX_LIST_LIMIT: usize = 1000;
+
+// Most keys a single batch request may name
+pub(crate) const MAX_BATCH_KEYS: usize = 1000;
+
+// Header and value POST /admin/flush requires, so the store isn't wiped by accident
+const CONFIRM_HEADER: &str = "x-confirm";
+
+const FLUSH_CONFIRMATION: &str = "DELETE-EVERYTHING";
+
+// Header carrying an optional per-key TTL on PUT
+const TTL_HEADER: &str = "x-ttl-seconds";
+
+// Header asking PUT or DELETE to respond with the previous value
+const RETURN_OLD_HEADER: &str = "x-return-old";
+
+// Header naming the destination key of a rename or copy
+const DESTINATION_HEADER: &str = "x-destination";
+
+// Header reporting a value's total length after PATCH
+pub(crate) const VALUE_LENGTH_HEADER: &str = "x-value-length";
+
+// Parse the TTL header, if present. Zero and non-numeric values are rejected.
+fn parse_ttl(headers: &HeaderMap) -> Result<Option<Duration>, &'static str> {
+    let Some(raw) = headers.get(TTL_HEADER) else {
+        return Ok(None);
+    };
+
+    let secs: u64 = raw
+        .to_str()
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .ok_or("X-Ttl-Seconds must be a positive integer")?;
+
+    if secs == 0 {
+        return Err("X-Ttl-Seconds must be greater than zero");
+    }
+
+    Ok(Some(Duration::from_secs(secs)))
+}
+
+// 507 for a write that would exceed the byte budget or a tenant quota
+pub(crate) fn insufficient_storage(store: &Store, reason: NoRoom) -> ApiError {
+    let msg = match reason {
+        NoRoom::Budget => format!(
+            "Write would exceed the storage budget of {} bytes",
+            store.limits().max_bytes.unwrap_or_default()
+        ),
+        NoRoom::Quota(quota::Exceeded::Keys(max)) => {
+            format!("Write would exceed the tenant quota of {} keys", max)
+        }
+        NoRoom::Quota(quota::Exceeded::Bytes(max)) => {
+            format!("Write would exceed the tenant quota of {} bytes", max)
+        }
+    };
+    ApiError::InsufficientStorage(msg)
+}
+
+// A stored value served with its Content-Type. A compressed value goes out as
+// stored, with Content-Encoding: gzip, to clients accepting that, and is
+// decompressed for the rest.
+fn value_response(entry: Entry, headers: &HeaderMap) -> Result<Response, ApiError> {
+    let content_type = [(
+        header::CONTENT_TYPE,
+        entry
+            .content_type
+            .clone()
+            .unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_string()),
+    )];
+    if !entry.compressed {
+        return Ok((StatusCode::OK, content_type, entry.value).into_response());
+    }
+    let vary = (header::VARY, "accept-encoding");
+    if accepts_gzip(headers) {
+        let encoding = (header::CONTENT_ENCODING, "gzip");
+        return Ok((StatusCode::OK, content_type, [vary, encoding], entry.value).into_response());
+    }
+    Ok((StatusCode::OK, content_type, [vary], entry.data()?).into_response())
+}
+
+// The part of a value of `length` bytes a Range header asks for, as in
+// `bytes=0-99`, `bytes=100-` or `bytes=-100`. None if the header is to be
+// ignored, as one in another unit or malformed must be. Several ranges at once
+// aren't supported and are refused, like ranges starting past the end.
+fn requested_range(range: &str, length: usize) -> Option<Result<Range<usize>, &'static str>> {
+    let (unit, spec) = range.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    if spec.contains(',') {
+        return Some(Err("Only a single byte range is supported"));
+    }
+    let unsatisfiable = Some(Err("The range starts past the end of the value"));
+    let (first, last) = spec.trim().split_once('-')?;
+    if first.is_empty() {
+        let suffix: usize = last.parse().ok()?;
+        if suffix == 0 || length == 0 {
+            return unsatisfiable;
+        }
+        return Some(Ok(length.saturating_sub(suffix)..length));
+    }
+    let first: usize = first.parse().ok()?;
+    let last = match last {
+        "" => usize::MAX,
+        last => last.parse().ok().filter(|&last| last >= first)?,
+    };
+    if first >= length {
+        return unsatisfiable;
+    }
+    Some(Ok(first..last.min(length - 1) + 1))
+}
+
+// Whether Accept-Encoding allows gzip, by name or as `*`, with a nonzero weight
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    let codings = headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+    codings.into_iter().any(|coding| {
+        let mut parts = coding.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let refused = parts.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+    })
+}
+
+// The stored form of a PUT body: its gzip encoding when it is at least
+// --compress-min-bytes long and compression makes it smaller. Compressing runs
+// on a blocking thread so large values don't hold up other requests.
+async fn stored_value(state: &AppState, body: Bytes) -> (Bytes, bool) {
+    if state.compress_min_bytes.is_none_or(|min| body.len() < min) {
+        return (body, false);
+    }
+    let raw = body.clone();
+    match tokio::task::spawn_blocking(move || store::compress(&raw)).await {
+        Ok(Some(compressed)) => (compressed, true),
+        _ => (body, false),
+    }
+}
+
+// ETag served for a value version
+fn etag(version: u64) -> String {
+    format!("\"{}\"", version)
+}
+
+// Whether an If-Match or If-None-Match header matches the current entry: `*`
+// matches any existing entry, otherwise one of the comma-separated ETags must
+// be the entry's. Weak validators (`W/"..."`) are compared as if strong.
+fn etag_matches(tags: &HeaderValue, current: Option<&Entry>) -> bool {
+    current.is_some_and(|current| etag_listed(tags, current.version))
+}
+
+// Whether an If-Match or If-None-Match header matches an existing value's version
+fn etag_listed(tags: &HeaderValue, version: u64) -> bool {
+    let Ok(tags) = tags.to_str() else {
+        return false;
+    };
+    let current = etag(version);
+    tags.split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == current)
+}
+
+// 412 for a failed If-Match or If-None-Match on `key`
+fn precondition_failed(key: &str, message: &'static str) -> ApiError {
+    ApiError::PreconditionFailed {
+        key: key.to_string(),
+        message,
+    }
+}
+
+// The X-Tenant header, documented once for every route taking a `Namespace`
+// or `Key`. Only the OpenAPI document uses it; the extractors read the header.
+#[derive(IntoParams)]
+#[into_params(parameter_in = Header)]
+pub(crate) struct TenantHeader {
+    /// Tenant to operate on instead of the default one
+    #[param(rename = "X-Tenant")]
+    #[allow(dead_code)]
+    tenant: Option<String>,
+}
+
+// Query parameters accepted by PUT and DELETE on /{key}
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct WriteParams {
+    #[serde(rename = "return")]
+    return_: Option<String>,
+    // Delete outright even when soft delete is enabled
+    #[serde(default)]
+    hard: bool,
+}
+
+// Whether the client asked for the previous value back, via `?return=old` or
+// an `X-Return-Old: true` header
+fn wants_old(params: &WriteParams, headers: &HeaderMap) -> Result<bool, &'static str> {
+    let query = match params.return_.as_deref() {
+        None => false,
+        Some("old") => true,
+        Some(_) => return Err("The return parameter only accepts \"old\""),
+    };
+    let header = match headers.get(RETURN_OLD_HEADER).map(|value| value.to_str()) {
+        None => false,
+        Some(Ok(value)) if value.eq_ignore_ascii_case("true") => true,
+        Some(Ok(value)) if value.eq_ignore_ascii_case("false") => false,
+        Some(_) => return Err("X-Return-Old must be true or false"),
+    };
+    Ok(query || header)
+}
+
+// Why a PUT was refused
+enum PutError {
+    IfMatch,
+    IfNoneMatch,
+    NoRoom(NoRoom),
+}
+
+// PUT /{key} - Create or update a key-value pair, optionally with a TTL.
+// Responds 201 with a Location header if the key is new and 200 if it was
+// overwritten, as told by the same insert that stores the value. With
+// `?return=old` the replaced value is returned as the 200 body.
+// With `If-None-Match: *` the write only happens if the key doesn't exist,
+// and with `If-Match` only if the key's current ETag is listed; otherwise it is
+// refused with 412. Every successful write returns the new ETag.
+#[utoipa::path(
+    put, path = "/{key}", tag = "keys", operation_id = "put",
+    summary = "Create or replace a value",
+    params(
+        ("key" = String, Path, description = "The key, which may contain slashes"),
+        TenantHeader,
+        WriteParams,
+        ("X-Ttl-Seconds" = Option<u64>, Header, description = "Expire the key after this many seconds"),
+        ("X-Return-Old" = Option<bool>, Header, description = "Respond with the replaced value"),
+        ("If-Match" = Option<String>, Header, description = "Only write if the current ETag is listed"),
+        ("If-None-Match" = Option<String>, Header, description = "With `*`, only write if the key doesn't exist"),
+    ),
+    request_body(content = String, content_type = "application/octet-stream", description = "The value, stored with the request's Content-Type"),
+    responses(
+        (status = 200, description = "Replaced an existing value; with `?return=old` the body is the previous value", headers(("ETag" = String))),
+        (status = 201, description = "Created the key", headers(("ETag" = String), ("Location" = String))),
+        (status = 400, description = "Invalid key, TTL or parameters"),
+        (status = 412, description = "If-Match or If-None-Match did not hold"),
+        (status = 413, description = "Value over --max-value-bytes"),
+        (status = 507, description = "Over the store's byte budget or the tenant's quota"),
+    )
+)]
+pub(crate) async fn put_handler(
+    State(state): State<AppState>,
+    Key(key): Key,
+    OriginalUri(uri): OriginalUri,
+    Query(params): Query<WriteParams>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let ttl = match parse_ttl(&headers) {
+        Ok(ttl) => ttl,
+        Err(msg) => return Err(ApiError::BadRequest(msg.into())),
+    };
+    let return_old = match wants_old(&params, &headers) {
+        Ok(return_old) => return_old,
+        Err(msg) => return Err(ApiError::BadRequest(msg.into())),
+    };
+
+    // A PUT without the header replaces any previous TTL with no expiry,
+    // and likewise replaces any previous Content-Type with the default
+    let (value, compressed) = stored_value(&state, body).await;
+    let mut entry = Entry {
+        expires_at: ttl.map(|ttl| Instant::now() + ttl),
+        content_type: headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+        compressed,
+        ..Entry::new(value)
+    };
+
+    let if_match = headers.get(header::IF_MATCH);
+    let if_none_match = headers.get(header::IF_NONE_MATCH);
+
+    let result = state
+        .store
+        .with_key_write(&key, |view| {
+            let now = Instant::now();
+            let current = view.get(&key).filter(|current| !current.is_expired(now));
+            if if_match.is_some_and(|tags| !etag_matches(tags, current.as_ref())) {
+                return Err(PutError::IfMatch);
+            }
+            if if_none_match.is_some_and(|tags| etag_matches(tags, current.as_ref())) {
+                return Err(PutError::IfNoneMatch);
+            }
+            if let Some(current) = &current {
+                entry.replaces(current, state.history_depth);
+            }
+            state
+                .check_room(view, &key, &entry)
+                .map_err(PutError::NoRoom)?;
+
+            entry.version = view.next_version();
+            let version = entry.version;
+            let ack = state.log(|| wal::WalRecord::put(&key, &entry));
+            state.record_event(&key, Some(&entry));
+            let previous = view
+                .insert(key.clone(), entry)
+                .filter(|previous| !previous.is_expired(now));
+            Ok((previous, version, ack))
+        })
+        .await;
+    let (previous, version, ack) = match result? {
+        Ok(outcome) => outcome,
+        Err(PutError::IfMatch) => {
+            return Err(precondition_failed(
+                &key,
+                "ETag does not match the current value",
+            ))
+        }
+        Err(PutError::IfNoneMatch) => return Err(precondition_failed(&key, "Key already exists")),
+        Err(PutError::NoRoom(reason)) => return Err(insufficient_storage(&state.store, reason)),
+    };
+
+    // Only acknowledge once the write is durable in the log
+    if let Err(e) = wal::wait(ack).await {
+        tracing::error!("Failed to log PUT: {}", e);
+        return Err(ApiError::Internal);
+    }
+    state.ops.put(1);
+    let response = match previous {
+        Some(previous) if return_old => value_response(previous, &headers)?,
+        Some(_) => StatusCode::OK.into_response(),
+        None => (
+            StatusCode::CREATED,
+            [(header::LOCATION, uri.path().to_string())],
+        )
+            .into_response(),
+    };
+    Ok(([(header::ETAG, etag(version))], response).into_response())
+}
+
+// Why a PATCH was refused
+enum AppendError {
+    TooLarge,
+    NoRoom(NoRoom),
+    Storage(StorageError),
+}
+
+// PATCH /{key} - Append the body to the current value, creating the key if it
+// doesn't exist. An existing key keeps its expiry and Content-Type.
+#[utoipa::path(
+    patch, path = "/{key}", tag = "keys", operation_id = "append",
+    summary = "Append to a value, creating the key if missing",
+    params(
+        ("key" = String, Path, description = "The key, which may contain slashes"),
+        TenantHeader,
+    ),
+    request_body(content = String, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "Appended", headers(("ETag" = String), ("X-Value-Length" = u64, description = "Length of the whole value"))),
+        (status = 413, description = "The value would exceed --max-value-bytes"),
+        (status = 507, description = "Over the store's byte budget or the tenant's quota"),
+    )
+)]
+pub(crate) async fn append_handler(
+    State(state): State<AppState>,
+    Key(key): Key,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let result = state
+        .store
+        .with_key_write(&key, |view| {
+            let now = Instant::now();
+            let current = view.get(&key).filter(|current| !current.is_expired(now));
+            let mut entry = current.clone().unwrap_or_else(|| Entry {
+                content_type: headers
+                    .get(header::CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string),
+                ..Entry::new(Bytes::new())
+            });
+
+            // A compressed value is stored uncompressed once appended to
+            let length = entry.data_len() + body.len();
+            if length > state.max_value_bytes {
+                return Err(AppendError::TooLarge);
+            }
+            let mut value = Vec::with_capacity(length);
+            value.extend_from_slice(&entry.data().map_err(AppendError::Storage)?);
+            value.extend_from_slice(&body);
+            entry.value = Bytes::from(value);
+            entry.compressed = false;
+            if let Some(current) = &current {
+                entry.replaces(current, state.history_depth);
+            }
+
+            state
+                .check_room(view, &key, &entry)
+                .map_err(AppendError::NoRoom)?;
+            entry.version = view.next_version();
+            let version = entry.version;
+            let ack = state.log(|| wal::WalRecord::put(&key, &entry));
+            state.record_event(&key, Some(&entry));
+            view.insert(key.clone(), entry);
+            Ok((length, version, ack))
+        })
+        .await;
+    let (length, version, ack) = match result? {
+        Ok(outcome) => outcome,
+        Err(AppendError::TooLarge) => {
+            return Err(ApiError::ValueTooLarge {
+                key,
+                limit: state.max_value_bytes,
+            })
+        }
+        Err(AppendError::NoRoom(reason)) => return Err(insufficient_storage(&state.store, reason)),
+        Err(AppendError::Storage(e)) => return Err(e.into()),
+    };
+
+    if let Err(e) = wal::wait(ack).await {
+        tracing::error!("Failed to log PATCH: {}", e);
+        return Err(ApiError::Internal);
+    }
+    state.ops.put(1);
+    let headers = [
+        (header::ETAG, etag(version)),
+        (
+            HeaderName::from_static(VALUE_LENGTH_HEADER),
+            length.to_string(),
+        ),
+    ];
+    Ok((StatusCode::OK, headers).into_response())
+}
+
+// Query parameters for GET /{key}
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct GetParams {
+    // A version number, or the ETag naming it: `5`, `"5"` or `W/"5"`
+    #[serde(default, deserialize_with = "version_or_etag")]
+    version: Option<u64>,
+    // Seconds to wait for the key to move on from `version`
+    wait: Option<u64>,
+}
+
+// Parse `version` bare or as an ETag, so the tag of one response can be
+// handed straight back to wait on
+fn version_or_etag<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    let Some(version) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    let number = version
+        .strip_prefix("W/")
+        .unwrap_or(&version)
+        .strip_prefix('"')
+        .and_then(|tag| tag.strip_suffix('"'))
+        .unwrap_or(&version);
+    number
+        .parse()
+        .map(Some)
+        .map_err(|_| serde::de::Error::custom(format!("invalid version `{version}`")))
+}
+
+// The longest a GET may wait for a change
+const MAX_WAIT: Duration = Duration::from_secs(300);
+
+// How long a GET asking to wait `secs` seconds waits at most
+pub(crate) fn long_poll_wait(secs: u64) -> Duration {
+    Duration::from_secs(secs).min(MAX_WAIT)
+}
+
+// Wait up to `wait` seconds for the live version of the stored key `key` to
+// differ from `known`, None standing for no live value. Whether it did.
+async fn long_poll(
+    state: &AppState,
+    key: &str,
+    known: Option<u64>,
+    wait: u64,
+) -> Result<bool, StorageError> {
+    let deadline = tokio::time::Instant::now() + long_poll_wait(wait);
+    // Dropped, and so unparked, on returning or when the client goes away
+    let mut parked = state.events.park(key);
+    loop {
+        let current = state
+            .store
+            .with_key_read(key, |view| {
+                let now = Instant::now();
+                view.get(key)
+                    .filter(|entry| !entry.is_expired(now))
+                    .map(|entry| entry.version)
+            })
+            .await?;
+        if current != known {
+            return Ok(true);
+        }
+        if tokio::time::timeout_at(deadline, parked.changed())
+            .await
+            .is_err()
+        {
+            return Ok(false);
+        }
+    }
+}
+
+// GET /{key} - Retrieve a value by key, or with `?version=N` a specific version
+// from its history. If `If-None-Match` lists the served ETag, responds 304
+// without the body. A single byte range can be asked for with Range, and is
+// served with 206. With `?wait=S`, `version` is instead the one the client
+// has, or none if it has seen no value: the current value is served once it
+// differs, which may be at once, or 304 if it still doesn't after S seconds.
+// Waits are capped at five minutes, and come on top of the request timeout.
+#[utoipa::path(
+    get, path = "/{key}", tag = "keys", operation_id = "get",
+    summary = "Read a value, or a past version of it",
+    params(
+        ("key" = String, Path, description = "The key, which may contain slashes"),
+        TenantHeader,
+        GetParams,
+        ("If-None-Match" = Option<String>, Header, description = "Respond 304 if the current ETag is listed"),
+        ("Range" = Option<String>, Header, description = "A single byte range, like `bytes=0-99`, `bytes=100-` or `bytes=-100`"),
+        ("If-Range" = Option<String>, Header, description = "Only honor Range if this is the current ETag"),
+    ),
+    responses(
+        (status = 200, description = "The value, with its Content-Type", body = String, content_type = "application/octet-stream", headers(("ETag" = String), ("Accept-Ranges" = String))),
+        (status = 206, description = "The requested range of the value", body = String, content_type = "application/octet-stream", headers(("ETag" = String), ("Content-Range" = String))),
+        (status = 304, description = "The value has the listed ETag, or a wait ended without it changing"),
+        (status = 404, description = "No such key or version"),
+        (status = 416, description = "The range starts past the end of the value, or several ranges were asked for"),
+    )
+)]
+pub(crate) async fn get_handler(
+    State(state): State<AppState>,
+    Key(key): Key,
+    Query(mut params): Query<GetParams>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    if let Some(wait) = params.wait {
+        if !long_poll(&state, &key, params.version, wait).await? {
+            let tag = params.version.map(|version| (header::ETAG, etag(version)));
+            return Ok((StatusCode::NOT_MODIFIED, AppendHeaders(tag)).into_response());
+        }
+        params.version = None;
+    }
+    let result = state
+        .store
+        .with_key_read(&key, |view| {
+            // Expiry is judged against a single instant taken after the lock is held
+            let now = Instant::now();
+            view.get(&key).map(|entry| {
+                if entry.is_expired(now) {
+                    None
+                } else {
+                    Some(entry)
+                }
+            })
+        })
+        .await;
+
+    match result? {
+        Some(Some(entry)) => {
+            let entry = match params.version {
+                Some(version) if version != entry.version => {
+                    match entry.history.iter().find(|past| past.version == version) {
+                        Some(past) => Entry {
+                            content_type: past.content_type.clone(),
+                            version: past.version,
+                            compressed: past.compressed,
+                            ..Entry::new(past.value.clone())
+                        },
+                        None => {
+                            state.ops.get(keyspace::tenant_of(&key), false);
+                            return Err(ApiError::KeyNotFound(key));
+                        }
+                    }
+                }
+                _ => entry,
+            };
+            state.ops.get(keyspace::tenant_of(&key), true);
+            let tag = [(header::ETAG, etag(entry.version))];
+            let unchanged = headers
+                .get(header::IF_NONE_MATCH)
+                .is_some_and(|tags| etag_matches(tags, Some(&entry)));
+            if unchanged {
+                return Ok((StatusCode::NOT_MODIFIED, tag).into_response());
+            }
+
+            // If-Range only lets the range through while the ETag it names is current
+            let length = entry.data_len();
+            let range = headers
+                .get(header::RANGE)
+                .and_then(|range| range.to_str().ok())
+                .filter(|_| {
+                    headers
+                        .get(header::IF_RANGE)
+                        .is_none_or(|tag| tag.as_bytes() == etag(entry.version).as_bytes())
+                })
+                .and_then(|range| requested_range(range, length));
+            let accept_ranges = (header::ACCEPT_RANGES, "bytes");
+            let range = match range {
+                None => {
+                    let response = value_response(entry, &headers)?;
+                    return Ok((tag, [accept_ranges], response).into_response());
+                }
+                Some(Err(message)) => {
+                    return Err(ApiError::RangeNotSatisfiable { length, message })
+                }
+                Some(Ok(range)) => range,
+            };
+            let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, length);
+            let content_type = entry
+                .content_type
+                .clone()
+                .unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_string());
+            let headers = [
+                (header::CONTENT_TYPE, content_type),
+                (header::CONTENT_RANGE, content_range),
+            ];
+            // Ranges are of the value as written, so a compressed one is
+            // decompressed first
+            let value = entry.data()?.slice(range);
+            Ok((
+                StatusCode::PARTIAL_CONTENT,
+                tag,
+                [accept_ranges],
+                headers,
+                value,
+            )
+                .into_response())
+        }
+        Some(None) => {
+            state.ops.get(keyspace::tenant_of(&key), false);
+            // The entry has expired: upgrade to the write lock and remove it,
+            // unless it was rewritten in the meantime
+            remove_if_expired(&state, &key).await?;
+            Err(ApiError::KeyNotFound(key))
+        }
+        None => {
+            state.ops.get(keyspace::tenant_of(&key), false);
+            Err(ApiError::KeyNotFound(key))
+        }
+    }
+}
+
+// HEAD /{key} - The status and headers GET would send, including Content-Length,
+// without the value being copied into the response
+#[utoipa::path(
+    head, path = "/{key}", tag = "keys", operation_id = "head",
+    summary = "Headers of a value without the value",
+    params(
+        ("key" = String, Path, description = "The key, which may contain slashes"),
+        TenantHeader,
+        GetParams,
+        ("If-None-Match" = Option<String>, Header, description = "Respond 304 if the current ETag is listed"),
+    ),
+    responses(
+        (status = 200, description = "The key exists", headers(("ETag" = String), ("Content-Length" = u64))),
+        (status = 304, description = "The value has the listed ETag"),
+        (status = 404, description = "No such key or version"),
+    )
+)]
+pub(crate) async fn head_handler(
+    State(state): State<AppState>,
+    Key(key): Key,
+    Query(params): Query<GetParams>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let result = state
+        .store
+        .with_key_read(&key, |view| {
+            let now = Instant::now();
+            let entry = view.get(&key).filter(|entry| !entry.is_expired(now))?;
+            match params.version {
+                Some(version) if version != entry.version => {
+                    let past = entry.history.iter().find(|past| past.version == version)?;
+                    let length = (past.value.len(), past.data_len(), past.compressed);
+                    Some((length, past.version, past.content_type.clone()))
+                }
+                _ => {
+                    let length = (entry.value.len(), entry.data_len(), entry.compressed);
+                    Some((length, entry.version, entry.content_type))
+                }
+            }
+        })
+        .await;
+    let Some(((stored, length, compressed), version, content_type)) = result? else {
+        state.ops.get(keyspace::tenant_of(&key), false);
+        return Err(ApiError::KeyNotFound(key));
+    };
+    state.ops.get(keyspace::tenant_of(&key), true);
+
+    let tag = etag(version);
+    let unchanged = headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|tags| etag_listed(tags, version));
+    if unchanged {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, tag)]).into_response());
+    }
+    let content_type = content_type.unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_string());
+    let mut response = (
+        StatusCode::OK,
+        [
+            (header::ETAG, tag),
+            (header::CONTENT_TYPE, content_type),
+            (header::CONTENT_LENGTH, length.to_string()),
+            (header::ACCEPT_RANGES, "bytes".to_string()),
+        ],
+    )
+        .into_response();
+    // Mirror what GET would send for a compressed value
+    if compressed {
+        let response_headers = response.headers_mut();
+        response_headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+        if accepts_gzip(&headers) {
+            response_headers.insert(header::CONTENT_LENGTH, stored.into());
+            response_headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        }
+    }
+    Ok(response)
+}
+
+// Remove a key only if it is still expired once the write lock is held
+pub(crate) async fn remove_if_expired(state: &AppState, key: &str) -> Result<(), StorageError> {
+    state
+        .store
+        .with_key_write(key, |view| {
+            let now = Instant::now();
+            if let Some(entry) = view.get(key).filter(|entry| entry.is_expired(now)) {
+                view.remove(key);
+                state.record_expiry(key, &entry);
+            }
+        })
+        .await
+}
+
+// DELETE /{key} - Deletes a value by key. With `?return=old` the removed value
+// is returned (200 instead of 204), making this an atomic take. With `If-Match`
+// the key is only deleted if its current ETag is listed, and 412 otherwise.
+// With soft delete enabled the value is kept restorable, unless `?hard=true`.
+#[utoipa::path(
+    delete, path = "/{key}", tag = "keys", operation_id = "delete",
+    summary = "Delete a key",
+    params(
+        ("key" = String, Path, description = "The key, which may contain slashes"),
+        TenantHeader,
+        WriteParams,
+        ("X-Return-Old" = Option<bool>, Header, description = "Respond with the removed value"),
+        ("If-Match" = Option<String>, Header, description = "Only delete if the current ETag is listed"),
+    ),
+    responses(
+        (status = 200, description = "Deleted; the body is the removed value", body = String, content_type = "application/octet-stream"),
+        (status = 204, description = "Deleted"),
+        (status = 404, description = "No such key"),
+        (status = 412, description = "If-Match did not hold"),
+    )
+)]
+pub(crate) async fn delete_handler(
+    State(state): State<AppState>,
+    Key(key): Key,
+    Query(params): Query<WriteParams>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let return_old = match wants_old(&params, &headers) {
+        Ok(return_old) => return_old,
+        Err(msg) => return Err(ApiError::BadRequest(msg.into())),
+    };
+
+    let if_match = headers.get(header::IF_MATCH);
+
+    let result = state
+        .store
+        .with_key_write(&key, |view| {
+            let now = Instant::now();
+            if let Some(tags) = if_match {
+                let current = view.get(&key).filter(|current| !current.is_expired(now));
+                if !etag_matches(tags, current.as_ref()) {
+                    return None;
+                }
+            }
+
+            // An expired entry is removed either way, but reported as missing
+            Some(match view.remove(&key) {
+                Some(entry) if !entry.is_expired(now) => {
+                    let ack = state.log(|| wal::WalRecord::delete(&key));
+                    state.record_event(&key, None);
+                    // A hard delete also drops any earlier soft-deleted value
+                    match &state.tombstones {
+                        Some(tombstones) if params.hard => drop(tombstones.take(&key, now)),
+                        Some(tombstones) => tombstones.bury(key.clone(), entry.clone(), now),
+                        None => {}
+                    }
+                    (Some(entry), ack)
+                }
+                Some(entry) => {
+                    state.record_expiry(&key, &entry);
+                    (None, None)
+                }
+                None => (None, None),
+            })
+        })
+        .await;
+    let Some((removed, ack)) = result? else {
+        return Err(precondition_failed(
+            &key,
+            "ETag does not match the current value",
+        ));
+    };
+
+    if let Err(e) = wal::wait(ack).await {
+        tracing::error!("Failed to log DELETE: {}", e);
+        return Err(ApiError::Internal);
+    }
+    if removed.is_some() {
+        state.ops.delete();
+    }
+    match removed {
+        Some(entry) if return_old => value_response(entry, &headers),
+        Some(_) => Ok(StatusCode::NO_CONTENT.into_response()),
+        None => Err(ApiError::KeyNotFound(key)),
+    }
+}
+
+// Why a restore was refused
+enum RestoreError {
+    Missing,
+    Exists,
+    NoRoom(NoRoom),
+}
+
+// POST /{key}/restore - Bring back a soft-deleted key with its value, expiry,
+// Content-Type and history, as a new version. Refused with 409 if the key has
+// been written again since, and 404 if there is nothing to restore.
+#[utoipa::path(
+    post, path = "/{key}/restore", tag = "keys", operation_id = "restore",
+    summary = "Restore a soft-deleted key",
+    params(
+        ("key" = String, Path, description = "The key, which may contain slashes"),
+        TenantHeader,
+    ),
+    responses(
+        (status = 200, description = "Restored as a new version"),
+        (status = 404, description = "Nothing to restore"),
+        (status = 409, description = "The key was written again since it was deleted"),
+        (status = 507, description = "Over the store's byte budget or the tenant's quota"),
+    )
+)]
+pub(crate) async fn restore_handler(
+    State(state): State<AppState>,
+    Key(key): Key,
+) -> Result<Response, ApiError> {
+    let Some(tombstones) = &state.tombstones else {
+        return Err(ApiError::NotFound("Soft delete is not enabled"));
+    };
+
+    let result = state
+        .store
+        .with_key_write(&key, |view| {
+            let now = Instant::now();
+            if view
+                .get(&key)
+                .is_some_and(|current| !current.is_expired(now))
+            {
+                return Err(RestoreError::Exists);
+            }
+            // An entry whose own TTL ran out meanwhile would be gone anyway
+            let (mut entry, deleted_at) = tombstones
+                .take(&key, now)
+                .filter(|(entry, _)| !entry.is_expired(now))
+                .ok_or(RestoreError::Missing)?;
+            if let Err(reason) = state.check_room(view, &key, &entry) {
+                tombstones.bury(key.clone(), entry, deleted_at);
+                return Err(RestoreError::NoRoom(reason));
+            }
+            entry.version = view.next_version();
+            let version = entry.version;
+            let ack = state.log(|| wal::WalRecord::put(&key, &entry));
+            state.record_event(&key, Some(&entry));
+            view.insert(key.clone(), entry);
+            Ok((version, ack))
+        })
+        .await;
+    let (version, ack) = match result? {
+        Ok(outcome) => outcome,
+        Err(RestoreError::Missing) => return Err(ApiError::KeyNotFound(key)),
+        Err(RestoreError::Exists) => {
+            return Err(ApiError::Conflict {
+                key,
+                message: "Key has been written since it was deleted",
+            })
+        }
+        Err(RestoreError::NoRoom(reason)) => {
+            return Err(insufficient_storage(&state.store, reason))
+        }
+    };
+
+    if let Err(e) = wal::wait(ack).await {
+        tracing::error!("Failed to log restore: {}", e);
+        return Err(ApiError::Internal);
+    }
+    Ok(([(header::ETAG, etag(version))], StatusCode::OK).into_response())
+}
+
+// GET /{key}/meta - When a key was created and last written, with its size,
+// version and remaining TTL, without the value
+#[utoipa::path(
+    get, path = "/{key}/meta", tag = "keys", operation_id = "meta",
+    summary = "Metadata of a key without its value",
+    params(
+        ("key" = String, Path, description = "The key, which may contain slashes"),
+        TenantHeader,
+    ),
+    responses(
+        (status = 200, description = "Size, version, Content-Type, creation and update times, and remaining TTL", content_type = "application/json"),
+        (status = 404, description = "No such key"),
+    )
+)]
+pub(crate) async fn meta_handler(
+    State(state): State<AppState>,
+    Key(key): Key,
+) -> Result<Response, ApiError> {
+    let result = state
+        .store
+        .with_key_read(&key, |view| {
+            let now = Instant::now();
+            view.get(&key)
+                .filter(|entry| !entry.is_expired(now))
+                .map(|entry| {
+                    let ttl = entry
+                        .expires_at
+                        .map(|deadline| (deadline - now).as_secs_f64());
+                    serde_json::json!({
+                        "size": entry.data_len(),
+                        "stored_size": entry.value.len(),
+                        "version": entry.version,
+                        "content_type": entry.content_type,
+                        "created_at": persistence::system_time_to_rfc3339(entry.created_at),
+                        "updated_at": persistence::system_time_to_rfc3339(entry.updated_at),
+                        "ttl_seconds": ttl,
+                    })
+                })
+        })
+        .await;
+
+    match result? {
+        Some(meta) => Ok(Json(meta).into_response()),
+        None => Err(ApiError::KeyNotFound(key)),
+    }
+}
+
+// A replaced value as listed by GET /{key}/history
+#[derive(Serialize, ToSchema)]
+pub(crate) struct HistoryItem {
+    version: u64,
+    replaced_at_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(flatten)]
+    value: persistence::StoredValue,
+}
+
+// GET /{key}/history - Values the current one replaced, newest first, as
+// `{"current_version": N, "versions": [...]}`. History goes with the key, so a
+// deleted or expired key has none.
+#[utoipa::path(
+    get, path = "/{key}/history", tag = "keys", operation_id = "history",
+    summary = "Past versions of a value, newest first",
+    params(
+        ("key" = String, Path, description = "The key, which may contain slashes"),
+        TenantHeader,
+    ),
+    responses(
+        (status = 200, description = "`current_version` and the `versions` it replaced, each a HistoryItem", content_type = "application/json"),
+        (status = 404, description = "No such key"),
+    )
+)]
+pub(crate) async fn history_handler(
+    State(state): State<AppState>,
+    Key(key): Key,
+) -> Result<Response, ApiError> {
+    let result = state
+        .store
+        .with_key_read(&key, |view| {
+            let now = Instant::now();
+            view.get(&key).filter(|entry| !entry.is_expired(now))
+        })
+        .await;
+    let Some(entry) = result? else {
+        return Err(ApiError::KeyNotFound(key));
+    };
+
+    let versions = entry
+        .history
+        .iter()
+        .map(|past| {
+            Ok(HistoryItem {
+                version: past.version,
+                replaced_at_ms: persistence::system_time_to_unix_ms(past.replaced_at),
+                content_type: past.content_type.clone(),
+                value: persistence::StoredValue::encode(&past.data()?),
+            })
+        })
+        .collect::<Result<Vec<_>, StorageError>>()?;
+    Ok(
+        Json(serde_json::json!({ "current_version": entry.version, "versions": versions }))
+            .into_response(),
+    )
+}
+
+// GET /{key}/ttl - Remaining lifetime in seconds, or -1 for keys without expiry
+#[utoipa::path(
+    get, path = "/{key}/ttl", tag = "keys", operation_id = "ttl",
+    summary = "Remaining lifetime of a key",
+    params(
+        ("key" = String, Path, description = "The key, which may contain slashes"),
+        TenantHeader,
+    ),
+    responses(
+        (status = 200, description = "Seconds left, or -1 for keys without expiry", body = i64, content_type = "text/plain"),
+        (status = 404, description = "No such key"),
+    )
+)]
+pub(crate) async fn ttl_handler(
+    State(state): State<AppState>,
+    Key(key): Key,
+) -> Result<Response, ApiError> {
+    let result = state
+        .store
+        .with_key_read(&key, |view| {
+            let now = Instant::now();
+            match view.get(&key) {
+                Some(entry) if !entry.is_expired(now) => Some(match entry.expires_at {
+                    Some(deadline) => format!("{:.3}", (deadline - now).as_secs_f64()),
+                    None => "-1".to_string(),
+                }),
+                _ => None,
+            }
+        })
+        .await;
+
+    match result? {
+        Some(remaining) => Ok((StatusCode::OK, remaining).into_response()),
+        None => Err(ApiError::KeyNotFound(key)),
+    }
+}
+
+// POST /{key}/touch - Reset a key's expiry without rewriting its value
+#[utoipa::path(
+    post, path = "/{key}/touch", tag = "keys", operation_id = "touch",
+    summary = "Reset the expiry of a key",
+    params(
+        ("key" = String, Path, description = "The key, which may contain slashes"),
+        TenantHeader,
+        ("X-Ttl-Seconds" = Option<u64>, Header, description = "New lifetime; without it the key no longer expires"),
+    ),
+    responses(
+        (status = 200, description = "Expiry updated"),
+        (status = 400, description = "Invalid TTL"),
+        (status = 404, description = "No such key"),
+    )
+)]
+pub(crate) async fn touch_handler(
+    State(state): State<AppState>,
+    Key(key): Key,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let ttl = match parse_ttl(&headers) {
+        Ok(Some(ttl)) => ttl,
+        Ok(None) => {
+            return Err(ApiError::BadRequest(
+                "X-Ttl-Seconds header is required".into(),
+            ))
+        }
+        Err(msg) => return Err(ApiError::BadRequest(msg.into())),
+    };
+
+    let result = state
+        .store
+        .with_key_write(&key, |view| {
+            let now = Instant::now();
+            match view.get(&key) {
+                Some(mut entry) if !entry.is_expired(now) => {
+                    entry.expires_at = Some(now + ttl);
+                    let ack = state.log(|| wal::WalRecord::put(&key, &entry));
+                    state.record_touch(&key, &entry);
+                    view.insert(key.clone(), entry);
+                    Some(ack)
+                }
+                _ => None,
+            }
+        })
+        .await;
+    let Some(ack) = result? else {
+        return Err(ApiError::KeyNotFound(key));
+    };
+
+    if let Err(e) = wal::wait(ack).await {
+        tracing::error!("Failed to log touch: {}", e);
+        return Err(ApiError::Internal);
+    }
+    Ok(StatusCode::OK.into_response())
+}
+
+// Query parameters for POST /{key}/rename and /copy
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct MoveParams {
+    // Whether an existing destination may be replaced; defaults to true
+    overwrite: Option<bool>,
+}
+
+// The destination key of a rename or copy, from the X-Destination header or the body
+fn destination<'a>(
+    headers: &'a HeaderMap,
+    body: &'a [u8],
+    max_key_bytes: keyspace::MaxKeyBytes,
+) -> Result<&'a str, String> {
+    let body = std::str::from_utf8(body)
+        .map_err(|_| "Destination must be valid UTF-8".to_string())?
+        .trim_end_matches(['\r', '\n']);
+    let destination = match (headers.get(DESTINATION_HEADER), body) {
+        (Some(value), "") => value
+            .to_str()
+            .map_err(|_| "X-Destination must be valid UTF-8".to_string())?,
+        (None, "") => {
+            return Err(
+                "Give the destination key in the body or an X-Destination header".to_string(),
+            )
+        }
+        (None, body) => body,
+        (Some(_), _) => {
+            return Err(
+                "Give the destination either in X-Destination or the body, not both".to_string(),
+            )
+        }
+    };
+    keyspace::validate_key(destination, max_key_bytes)?;
+    Ok(destination)
+}
+
+// POST /{key}/rename - Move a key's value, expiry and Content-Type to another
+// key of the same namespace
+#[utoipa::path(
+    post, path = "/{key}/rename", tag = "keys", operation_id = "rename",
+    summary = "Move a key within its namespace",
+    params(
+        ("key" = String, Path, description = "The key, which may contain slashes"),
+        TenantHeader,
+        MoveParams,
+        ("X-Destination" = Option<String>, Header, description = "Destination key, unless given as the body"),
+    ),
+    request_body(content = String, content_type = "text/plain", description = "Destination key, unless given in X-Destination"),
+    responses(
+        (status = 200, description = "Renamed"),
+        (status = 400, description = "Missing or invalid destination"),
+        (status = 403, description = "The client may not write the destination"),
+        (status = 404, description = "No such key"),
+        (status = 409, description = "The destination exists and `overwrite=false`"),
+        (status = 507, description = "Over the store's byte budget or the tenant's quota"),
+    )
+)]
+pub(crate) async fn rename_handler(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    access: Access,
+    Key(key): Key,
+    Query(params): Query<MoveParams>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let destination = match destination(&headers, &body, state.max_key_bytes) {
+        Ok(destination) => namespace.storage_key(destination),
+        Err(msg) => return Err(ApiError::BadRequest(msg)),
+    };
+    if !access.allows(&destination, Permission::Write) {
+        return Err(auth::forbidden());
+    }
+    move_key(state, key, destination, params, true).await
+}
+
+// POST /{key}/copy - Duplicate a key's value, expiry and Content-Type under
+// another key of the same namespace
+#[utoipa::path(
+    post, path = "/{key}/copy", tag = "keys", operation_id = "copy",
+    summary = "Copy a key within its namespace",
+    params(
+        ("key" = String, Path, description = "The key, which may contain slashes"),
+        TenantHeader,
+        MoveParams,
+        ("X-Destination" = Option<String>, Header, description = "Destination key, unless given as the body"),
+    ),
+    request_body(content = String, content_type = "text/plain", description = "Destination key, unless given in X-Destination"),
+    responses(
+        (status = 200, description = "Copied"),
+        (status = 400, description = "Missing or invalid destination"),
+        (status = 403, description = "The client may not write the destination"),
+        (status = 404, description = "No such key"),
+        (status = 409, description = "The destination exists and `overwrite=false`"),
+        (status = 507, description = "Over the store's byte budget or the tenant's quota"),
+    )
+)]
+pub(crate) async fn copy_handler(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    access: Access,
+    Key(key): Key,
+    Query(params): Query<MoveParams>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let destination = match destination(&headers, &body, state.max_key_bytes) {
+        Ok(destination) => namespace.storage_key(destination),
+        Err(msg) => return Err(ApiError::BadRequest(msg)),
+    };
+    if !access.allows(&destination, Permission::Write) {
+        return Err(auth::forbidden());
+    }
+    move_key(state, key, destination, params, false).await
+}
+
+// Why a rename or copy was refused
+enum MoveError {
+    Missing,
+    Exists,
+    NoRoom(NoRoom),
+}
+
+// Shared body of rename and copy. The destination is written as a new version
+// with its own history, and a rename deletes the source in the same
+// transaction. Naming the source as the destination changes nothing.
+async fn move_key(
+    state: AppState,
+    source: String,
+    destination: String,
+    params: MoveParams,
+    rename: bool,
+) -> Result<Response, ApiError> {
+    let overwrite = params.overwrite.unwrap_or(true);
+
+    let result = state
+        .store
+        .with_write(|view| {
+            let now = Instant::now();
+            let current = view
+                .get(&source)
+                .filter(|current| !current.is_expired(now))
+                .ok_or(MoveError::Missing)?;
+            if destination == source {
+                return Ok((current.version, Vec::new()));
+            }
+            if !overwrite
+                && view
+                    .get(&destination)
+                    .is_some_and(|existing| !existing.is_expired(now))
+            {
+                return Err(MoveError::Exists);
+            }
+
+            let entry = Entry {
+                expires_at: current.expires_at,
+                content_type: current.content_type,
+                flags: current.flags,
+                compressed: current.compressed,
+                ..Entry::new(current.value)
+            };
+            // Delete first so a rename's source doesn't count against the budget twice
+            let mut changes = Vec::with_capacity(2);
+            if rename {
+                changes.push((source.clone(), None));
+            }
+            changes.push((destination.clone(), Some(entry)));
+            let (_, acks) = apply_changes(&state, view, &changes).map_err(MoveError::NoRoom)?;
+            let version = view.get(&destination).map_or(0, |entry| entry.version);
+            Ok((version, acks))
+        })
+        .await;
+    let (version, acks) = match result? {
+        Ok(outcome) => outcome,
+        Err(MoveError::Missing) => return Err(ApiError::KeyNotFound(source)),
+        Err(MoveError::Exists) => {
+            return Err(ApiError::Conflict {
+                key: destination,
+                message: "Destination key already exists",
+            })
+        }
+        Err(MoveError::NoRoom(reason)) => return Err(insufficient_storage(&state.store, reason)),
+    };
+
+    if let Err(e) = wal::wait_all(acks).await {
+        tracing::error!(
+            "Failed to log {}: {}",
+            if rename { "rename" } else { "copy" },
+            e
+        );
+        return Err(ApiError::Internal);
+    }
+    Ok(([(header::ETAG, etag(version))], StatusCode::OK).into_response())
+}
+
+// Query parameters for POST /{key}/incr and /decr
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct CounterParams {
+    by: Option<i64>,
+}
+
+// POST /{key}/incr - Atomically add to a counter, by 1 or by `?by=N` / the body
+#[utoipa::path(
+    post, path = "/{key}/incr", tag = "keys", operation_id = "incr",
+    summary = "Add to a counter",
+    params(
+        ("key" = String, Path, description = "The key, which may contain slashes"),
+        TenantHeader,
+        CounterParams,
+    ),
+    request_body(content = i64, content_type = "text/plain", description = "Amount, unless given as `by`"),
+    responses(
+        (status = 200, description = "The new value", body = i64, content_type = "text/plain"),
+        (status = 400, description = "Invalid amount"),
+        (status = 409, description = "The current value is not an integer"),
+        (status = 422, description = "The result would overflow"),
+        (status = 507, description = "Over the store's byte budget or the tenant's quota"),
+    )
+)]
+pub(crate) async fn incr_handler(
+    State(state): State<AppState>,
+    Key(key): Key,
+    Query(params): Query<CounterParams>,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    adjust_counter(state, key, params, body, false).await
+}
+
+// POST /{key}/decr - Atomically subtract from a counter
+#[utoipa::path(
+    post, path = "/{key}/decr", tag = "keys", operation_id = "decr",
+    summary = "Subtract from a counter",
+    params(
+        ("key" = String, Path, description = "The key, which may contain slashes"),
+        TenantHeader,
+        CounterParams,
+    ),
+    request_body(content = i64, content_type = "text/plain", description = "Amount, unless given as `by`"),
+    responses(
+        (status = 200, description = "The new value", body = i64, content_type = "text/plain"),
+        (status = 400, description = "Invalid amount"),
+        (status = 409, description = "The current value is not an integer"),
+        (status = 422, description = "The result would overflow"),
+        (status = 507, description = "Over the store's byte budget or the tenant's quota"),
+    )
+)]
+pub(crate) async fn decr_handler(
+    State(state): State<AppState>,
+    Key(key): Key,
+    Query(params): Query<CounterParams>,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    adjust_counter(state, key, params, body, true).await
+}
+
+// Why a counter could not be adjusted
+enum CounterError {
+    NotANumber,
+    Overflow,
+    NoRoom(NoRoom),
+}
+
+// Shared body of incr/decr. The stored value is parsed as a signed 64-bit
+// integer, with a missing or expired key counting as 0; the result is stored
+// back as text, keeping any expiry and Content-Type the key already had.
+async fn adjust_counter(
+    state: AppState,
+    key: String,
+    params: CounterParams,
+    body: Bytes,
+    negate: bool,
+) -> Result<Response, ApiError> {
+    let body_delta = std::str::from_utf8(&body).map(str::trim).unwrap_or("?");
+    let delta = match (params.by, body_delta) {
+        (Some(by), "") => by,
+        (None, "") => 1,
+        (None, text) => match text.parse::<i64>() {
+            Ok(by) => by,
+            Err(_) => {
+                return Err(ApiError::BadRequest(
+                    "Body must be a signed 64-bit integer".into(),
+                ))
+            }
+        },
+        (Some(_), _) => {
+            return Err(ApiError::BadRequest(
+                "Give the amount either as ?by= or in the body, not both".into(),
+            ))
+        }
+    };
+    let delta = if negate {
+        delta.checked_neg()
+    } else {
+        Some(delta)
+    };
+
+    let result = state
+        .store
+        .with_key_write(&key, |view| {
+            let now = Instant::now();
+            let current = view.get(&key).filter(|current| !current.is_expired(now));
+            let mut entry = current
+                .clone()
+                .unwrap_or_else(|| Entry::new(Bytes::from_static(b"0")));
+
+            // Compressed values are all longer than any integer
+            let count: i64 = std::str::from_utf8(&entry.value)
+                .ok()
+                .filter(|_| !entry.compressed)
+                .and_then(|text| text.parse().ok())
+                .ok_or(CounterError::NotANumber)?;
+            let next = delta
+                .and_then(|delta| count.checked_add(delta))
+                .ok_or(CounterError::Overflow)?;
+
+            entry.value = Bytes::from(next.to_string());
+            entry.compressed = false;
+            if let Some(current) = &current {
+                entry.replaces(current, state.history_depth);
+            }
+            state
+                .check_room(view, &key, &entry)
+                .map_err(CounterError::NoRoom)?;
+            entry.version = view.next_version();
+            let version = entry.version;
+            let ack = state.log(|| wal::WalRecord::put(&key, &entry));
+            state.record_event(&key, Some(&entry));
+            view.insert(key.clone(), entry);
+            Ok((next, version, ack))
+        })
+        .await;
+    let (next, version, ack) = match result? {
+        Ok(outcome) => outcome,
+        Err(CounterError::NotANumber) => {
+            return Err(ApiError::Conflict {
+                key,
+                message: "Stored value is not a signed 64-bit integer",
+            })
+        }
+        Err(CounterError::Overflow) => {
+            return Err(ApiError::Unprocessable(
+                "Result would overflow a signed 64-bit integer".into(),
+            ))
+        }
+        Err(CounterError::NoRoom(reason)) => {
+            return Err(insufficient_storage(&state.store, reason))
+        }
+    };
+
+    if let Err(e) = wal::wait(ack).await {
+        tracing::error!("Failed to log counter update: {}", e);
+        return Err(ApiError::Internal);
+    }
+    Ok((
+        StatusCode::OK,
+        [(header::ETAG, etag(version))],
+        next.to_string(),
+    )
+        .into_response())
+}
+
+// Query parameters for GET /keys
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct ListParams {
+    limit: Option<usize>,
+    after: Option<String>,
+    #[serde(default)]
+    prefix: String,
+    #[serde(default)]
+    include_values: bool,
+}
+
+// A listed entry when values are requested
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ListedEntry {
+    key: String,
+    #[serde(flatten)]
+    value: persistence::StoredValue,
+}
+
+// GET /keys - List keys in sorted order, one page at a time, optionally only
+// those under `prefix`. Pass the returned `next` value as `after` to fetch the
+// following page. With `include_values=true` the page is returned as
+// `entries`, each holding the value as text or, if binary, as `value_b64`.
+#[utoipa::path(
+    get, path = "/keys", tag = "keys", operation_id = "list",
+    summary = "List keys in sorted order, a page at a time",
+    params(TenantHeader, ListParams),
+    responses(
+        (status = 200, description = "`keys`, or with `include_values` `entries` of ListedEntry, and the `next` cursor", content_type = "application/json"),
+        (status = 400, description = "Invalid parameters"),
+    )
+)]
+pub(crate) async fn list_keys_handler(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    access: Access,
+    Query(params): Query<ListParams>,
+) -> Result<Response, ApiError> {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+    if let Err(msg) = keyspace::validate(&params.prefix) {
+        return Err(ApiError::BadRequest(msg.into()));
+    }
+    let prefix = namespace.storage_key(&params.prefix);
+    let after = params.after.map(|after| namespace.storage_key(&after));
+
+    // Only the page is collected under the lock; encoding happens after release
+    let result = state
+        .store
+        .with_read(|view| view.scan(&prefix, after.as_deref(), limit, Instant::now()))
+        .await;
+    let scanned = result?;
+
+    // Other namespaces sort after the default one, so dropping their keys only
+    // ever shortens the final page
+    let full = scanned.len() == limit;
+    let in_namespace: Vec<(String, Entry)> = scanned
+        .into_iter()
+        .filter_map(|(key, entry)| Some((namespace.client_key(&key)?.to_string(), entry)))
+        .collect();
+    let next = if full && in_namespace.len() == limit {
+        in_namespace.last().map(|(key, _)| key.clone())
+    } else {
+        None
+    };
+
+    // Keys the client may not read are dropped after paging, so a page can
+    // come back short, or empty, and still have a `next`
+    let page: Vec<(String, Entry)> = in_namespace
+        .into_iter()
+        .filter(|(key, _)| access.allows(&namespace.storage_key(key), Permission::Read))
+        .collect();
+
+    if params.include_values {
+        let entries = page
+            .into_iter()
+            .map(|(key, entry)| {
+                Ok(ListedEntry {
+                    key,
+                    value: persistence::StoredValue::encode(&entry.data()?),
+                })
+            })
+            .collect::<Result<Vec<_>, StorageError>>()?;
+        Ok(Json(serde_json::json!({ "entries": entries, "next": next })).into_response())
+    } else {
+        let keys: Vec<String> = page.into_iter().map(|(key, _)| key).collect();
+        Ok(Json(serde_json::json!({ "keys": keys, "next": next })).into_response())
+    }
+}
+
+// Query parameters for DELETE /keys
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct DeletePrefixParams {
+    prefix: Option<String>,
+}
+
+// DELETE /keys?prefix=... - Remove every key under a prefix, returning the count.
+// A prefix is mandatory so a typo can't wipe the whole store.
+#[utoipa::path(
+    delete, path = "/keys", tag = "keys", operation_id = "delete_prefix",
+    summary = "Delete every key under a prefix",
+    params(TenantHeader, DeletePrefixParams),
+    responses(
+        (status = 200, description = "How many keys were removed", body = u64, content_type = "text/plain"),
+        (status = 400, description = "Missing or empty prefix"),
+        (status = 403, description = "The client may not write every key under the prefix"),
+    )
+)]
+pub(crate) async fn delete_prefix_handler(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    access: Access,
+    Query(params): Query<DeletePrefixParams>,
+) -> Result<Response, ApiError> {
+    let prefix = match params.prefix {
+        Some(prefix) if !prefix.is_empty() => prefix,
+        _ => {
+            return Err(ApiError::BadRequest(
+                "A non-empty prefix query parameter is required".into(),
+            ))
+        }
+    };
+    if let Err(msg) = keyspace::validate(&prefix) {
+        return Err(ApiError::BadRequest(msg.into()));
+    }
+    let prefix = namespace.storage_key(&prefix);
+    if !access.allows(&prefix, Permission::Write) {
+        return Err(auth::forbidden());
+    }
+    delete_matching(&state, &prefix).await
+}
+
+// DELETE /b/{bucket} - Drop a bucket and everything in it, returning the count
+#[utoipa::path(
+    delete, path = "/b/{bucket}", tag = "keys", operation_id = "drop_bucket",
+    summary = "Drop a bucket and everything in it",
+    params(("bucket" = String, Path), TenantHeader),
+    responses(
+        (status = 200, description = "How many keys were removed", body = u64, content_type = "text/plain"),
+        (status = 403, description = "The client may not write the whole bucket"),
+    )
+)]
+pub(crate) async fn drop_bucket_handler(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    access: Access,
+) -> Result<Response, ApiError> {
+    let prefix = namespace.prefix();
+    if !access.allows(&prefix, Permission::Write) {
+        return Err(auth::forbidden());
+    }
+    delete_matching(&state, &prefix).await
+}
+
+// Remove every stored key starting with `prefix`, responding with the number
+// of live keys removed
+async fn delete_matching(state: &AppState, prefix: &str) -> Result<Response, ApiError> {
+    let result = state
+        .store
+        .with_write(|view| {
+            let now = Instant::now();
+            let mut deleted = 0;
+            let mut acks = Vec::new();
+
+            // Collect the matching keys first so only they are cloned, not the map
+            for key in view.keys_with_prefix(prefix) {
+                if let Some(entry) = view.remove(&key) {
+                    acks.extend(state.log(|| wal::WalRecord::delete(&key)));
+                    // Expired entries are cleaned up too, but weren't visible
+                    if entry.is_expired(now) {
+                        state.record_expiry(&key, &entry);
+                    } else {
+                        state.record_event(&key, None);
+                        deleted += 1;
+                    }
+                }
+            }
+            (deleted, acks)
+        })
+        .await;
+    let (deleted, acks) = result?;
+
+    if let Err(e) = wal::wait_all(acks).await {
+        tracing::error!("Failed to log prefix DELETE: {}", e);
+        return Err(ApiError::Internal);
+    }
+    Ok((StatusCode::OK, deleted.to_string()).into_response())
+}
+
+// POST /batch/get - Fetch several keys at once from a JSON array of names.
+// Responds with an object mapping every requested key to `{"value": ...}`
+// (or `{"value_b64": ...}` for binary values), or to null if it is missing.
+#[utoipa::path(
+    post, path = "/batch/get", tag = "batch", operation_id = "batch_get",
+    summary = "Read several keys at once",
+    params(TenantHeader),
+    request_body(content = Vec<String>, description = "Keys to read"),
+    responses(
+        (status = 200, description = "Each key mapped to `{\"value\": ...}`, `{\"value_b64\": ...}`, or null if missing", body = HashMap<String, persistence::StoredValue>),
+        (status = 400, description = "Invalid or too many keys"),
+        (status = 403, description = "The client may not read one of the keys"),
+    )
+)]
+pub(crate) async fn batch_get_handler(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    access: Access,
+    Json(keys): Json<Vec<String>>,
+) -> Result<Response, ApiError> {
+    if keys.len() > MAX_BATCH_KEYS {
+        return Err(ApiError::BadRequest(format!(
+            "A batch may name at most {} keys",
+            MAX_BATCH_KEYS
+        )));
+    }
+    let max_key_bytes = state.max_key_bytes;
+    keys.iter()
+        .try_for_each(|key| keyspace::validate_key(key, max_key_bytes))
+        .map_err(ApiError::BadRequest)?;
+    if !keys
+        .iter()
+        .all(|key| access.allows(&namespace.storage_key(key), Permission::Read))
+    {
+        return Err(auth::forbidden());
+    }
+
+    // One read transaction for the whole batch; encoding happens after release
+    let result = state
+        .store
+        .with_read(|view| {
+            let now = Instant::now();
+            let mut found = HashMap::with_capacity(keys.len());
+            for key in &keys {
+                if found.contains_key(key) {
+                    continue;
+                }
+                let entry = view
+                    .get(&namespace.storage_key(key))
+                    .filter(|entry| !entry.is_expired(now));
+                found.insert(key.clone(), entry);
+            }
+            found
+        })
+        .await;
+    let found = result?;
+    for entry in found.values() {
+        state.ops.get(namespace.tenant(), entry.is_some());
+    }
+
+    let values = found
+        .into_iter()
+        .map(|(key, entry)| {
+            let value = match entry {
+                Some(entry) => Some(persistence::StoredValue::encode(&entry.data()?)),
+                None => None,
+            };
+            Ok((key, value))
+        })
+        .collect::<Result<BTreeMap<_, _>, StorageError>>()?;
+    Ok(Json(values).into_response())
+}
+
+// Apply puts (Some) and deletes (None) in order within one write transaction,
+// giving each put a fresh version and logging each change. If a put doesn't fit
+// the byte budget or its tenant's quota, every change already made is undone
+// and the reason returned; otherwise returns each key's previous entry.
+fn apply_changes(
+    state: &AppState,
+    view: &mut dyn WriteView,
+    changes: &[(String, Option<Entry>)],
+) -> Result<(Vec<Option<Entry>>, Vec<wal::WalAck>), NoRoom> {
+    let mut previous = Vec::with_capacity(changes.len());
+    // What was written for each change, for the log
+    let mut written = Vec::with_capacity(changes.len());
+    for (key, change) in changes {
+        let old = match change {
+            Some(entry) => {
+                let mut entry = entry.clone();
+                let now = Instant::now();
+                if let Some(current) = view.get(key).filter(|current| !current.is_expired(now)) {
+                    entry.replaces(&current, state.history_depth);
+                }
+                if let Err(reason) = state.check_room(view, key, &entry) {
+                    // Put back everything replaced so far, newest change first
+                    for ((key, _), old) in changes.iter().zip(previous).rev() {
+                        match old {
+                            Some(old) => view.insert(key.clone(), old),
+                            None => view.remove(key),
+                        };
+                    }
+                    return Err(reason);
+                }
+                entry.version = view.next_version();
+                written.push(Some(entry.clone()));
+                view.insert(key.clone(), entry)
+            }
+            None => {
+                written.push(None);
+                view.remove(key)
+            }
+        };
+        previous.push(old);
+    }
+
+    let acks = changes
+        .iter()
+        .zip(written)
+        .filter_map(|((key, _), entry)| {
+            state.record_event(key, entry.as_ref());
+            state.log(|| match entry {
+                Some(entry) => wal::WalRecord::put(key, &entry),
+                None => wal::WalRecord::delete(key),
+            })
+        })
+        .collect();
+    Ok((previous, acks))
+}
+
+// POST /batch/put - Write several keys at once from a JSON object of string
+// values. The whole batch is applied in one write transaction, so readers see
+// all of it or none of it; if it doesn't fit the byte budget nothing is written.
+#[utoipa::path(
+    post, path = "/batch/put", tag = "batch", operation_id = "batch_put",
+    summary = "Write several keys in one transaction",
+    params(TenantHeader),
+    request_body(content = HashMap<String, String>, description = "Keys mapped to their new values"),
+    responses(
+        (status = 200, description = "How many keys were `created` and `updated`", content_type = "application/json"),
+        (status = 400, description = "Invalid body, keys or values"),
+        (status = 403, description = "The client may not write one of the keys"),
+        (status = 507, description = "Over the store's byte budget or a tenant's quota; nothing was written"),
+    )
+)]
+pub(crate) async fn batch_put_handler(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    access: Access,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let object: serde_json::Map<String, serde_json::Value> = match serde_json::from_slice(&body) {
+        Ok(object) => object,
+        Err(e) => {
+            return Err(ApiError::BadRequest(format!(
+                "Body must be a JSON object of keys to string values: {}",
+                e
+            )))
+        }
+    };
+    if object.len() > MAX_BATCH_KEYS {
+        return Err(ApiError::BadRequest(format!(
+            "A batch may name at most {} keys",
+            MAX_BATCH_KEYS
+        )));
+    }
+
+    let mut changes = Vec::with_capacity(object.len());
+    for (key, value) in object {
+        let serde_json::Value::String(value) = value else {
+            return Err(ApiError::BadRequest(format!(
+                "Value for key {:?} must be a string",
+                key
+            )));
+        };
+        keyspace::validate_key(&key, state.max_key_bytes).map_err(ApiError::BadRequest)?;
+        let key = namespace.storage_key(&key);
+        if !access.allows(&key, Permission::Write) {
+            return Err(auth::forbidden());
+        }
+        changes.push((key, Some(Entry::new(Bytes::from(value)))));
+    }
+
+    let result = state
+        .store
+        .with_write(|view| {
+            let now = Instant::now();
+            let (previous, acks) = apply_changes(&state, view, &changes)?;
+            let created = previous
+                .iter()
+                .filter(|old| old.as_ref().is_none_or(|old| old.is_expired(now)))
+                .count();
+            Ok((created, acks))
+        })
+        .await;
+    let (created, acks) = result?.map_err(|reason| insufficient_storage(&state.store, reason))?;
+
+    if let Err(e) = wal::wait_all(acks).await {
+        tracing::error!("Failed to log batch PUT: {}", e);
+        return Err(ApiError::Internal);
+    }
+    state.ops.put(changes.len() as u64);
+    let updated = changes.len() - created;
+    Ok(Json(serde_json::json!({ "created": created, "updated": updated })).into_response())
+}
+
+// A precondition checked by POST /txn
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(tag = "check", rename_all = "lowercase")]
+enum TxnCondition {
+    // The key holds exactly this value
+    Equals { key: String, value: String },
+    // The key is missing or expired
+    Absent { key: String },
+}
+
+// A write applied by POST /txn once every condition holds
+#[derive(Deserialize, ToSchema)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum TxnOperation {
+    Put { key: String, value: String },
+    Delete { key: String },
+}
+
+// Why POST /txn applied nothing
+enum TxnError {
+    // Index of the first condition that doesn't hold
+    Condition(usize),
+    NoRoom(NoRoom),
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct Txn {
+    #[serde(default)]
+    conditions: Vec<TxnCondition>,
+    #[serde(default)]
+    operations: Vec<TxnOperation>,
+}
+
+// POST /txn - Apply operations only if all conditions hold, e.g.
+//   {"conditions": [{"check": "equals", "key": "a", "value": "1"},
+//                   {"check": "absent", "key": "b"}],
+//    "operations": [{"op": "put", "key": "b", "value": "2"},
+//                   {"op": "delete", "key": "a"}]}
+// Conditions are checked and operations applied in one write transaction.
+// Responds 200 with {"succeeded": true}, or 409 naming the first failed condition.
+#[utoipa::path(
+    post, path = "/txn", tag = "batch", operation_id = "txn",
+    summary = "Apply writes if every condition holds",
+    params(TenantHeader),
+    request_body(content = Txn),
+    responses(
+        (status = 200, description = "`succeeded`: the operations were applied", content_type = "application/json"),
+        (status = 400, description = "Invalid body or keys"),
+        (status = 403, description = "The client may not read a condition's key or write an operation's"),
+        (status = 409, description = "A condition failed, named as `failed`; nothing was written", content_type = "application/json"),
+    )
+)]
+pub(crate) async fn txn_handler(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    access: Access,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let txn: Txn = match serde_json::from_slice(&body) {
+        Ok(txn) => txn,
+        Err(e) => return Err(ApiError::BadRequest(format!("Invalid transaction: {}", e))),
+    };
+    if txn.conditions.len() + txn.operations.len() > MAX_BATCH_KEYS {
+        return Err(ApiError::BadRequest(format!(
+            "A transaction may hold at most {} conditions and operations",
+            MAX_BATCH_KEYS
+        )));
+    }
+    let mut condition_keys = txn.conditions.iter().map(|condition| match condition {
+        TxnCondition::Equals { key, .. } | TxnCondition::Absent { key } => key,
+    });
+    let mut operation_keys = txn.operations.iter().map(|operation| match operation {
+        TxnOperation::Put { key, .. } | TxnOperation::Delete { key } => key,
+    });
+    let mut keys = condition_keys.clone().chain(operation_keys.clone());
+    keys.try_for_each(|key| keyspace::validate_key(key, state.max_key_bytes))
+        .map_err(ApiError::BadRequest)?;
+    // Conditions only read their keys; operations write theirs
+    let allowed = |key: &String, permission| access.allows(&namespace.storage_key(key), permission);
+    if !(condition_keys.all(|key| allowed(key, Permission::Read))
+        && operation_keys.all(|key| allowed(key, Permission::Write)))
+    {
+        return Err(auth::forbidden());
+    }
+
+    let changes: Vec<(String, Option<Entry>)> = txn
+        .operations
+        .into_iter()
+        .map(|operation| match operation {
+            TxnOperation::Put { key, value } => (
+                namespace.storage_key(&key),
+                Some(Entry::new(Bytes::from(value))),
+            ),
+            TxnOperation::Delete { key } => (namespace.storage_key(&key), None),
+        })
+        .collect();
+
+    let result = state
+        .store
+        .with_write(|view| {
+            let now = Instant::now();
+            let failed = txn.conditions.iter().position(|condition| match condition {
+                TxnCondition::Equals { key, value } => {
+                    !view.get(&namespace.storage_key(key)).is_some_and(|entry| {
+                        !entry.is_expired(now)
+                            && entry.data().is_ok_and(|data| data == value.as_bytes())
+                    })
+                }
+                TxnCondition::Absent { key } => view
+                    .get(&namespace.storage_key(key))
+                    .is_some_and(|entry| !entry.is_expired(now)),
+            });
+            if let Some(index) = failed {
+                return Err(TxnError::Condition(index));
+            }
+            apply_changes(&state, view, &changes)
+                .map(|(_, acks)| acks)
+                .map_err(TxnError::NoRoom)
+        })
+        .await;
+    let acks = match result? {
+        Ok(acks) => acks,
+        // In the shape of any other error, with what failed alongside
+        Err(TxnError::Condition(index)) => {
+            let body = serde_json::json!({
+                "error": "A condition does not hold",
+                "code": "condition_failed",
+                "succeeded": false,
+                "failed": { "index": index, "condition": &txn.conditions[index] },
+            });
+            return Ok((StatusCode::CONFLICT, Json(body)).into_response());
+        }
+        Err(TxnError::NoRoom(reason)) => return Err(insufficient_storage(&state.store, reason)),
+    };
+
+    if let Err(e) = wal::wait_all(acks).await {
+        tracing::error!("Failed to log transaction: {}", e);
+        return Err(ApiError::Internal);
+    }
+    Ok(Json(serde_json::json!({ "succeeded": true })).into_response())
+}
+
+// Query parameters for GET /metrics
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct MetricsParams {
+    format: Option<String>,
+    // Comma-separated quantiles between 0 and 1 to report, like 0.5,0.9,0.999
+    quantiles: Option<String>,
+    // Also break lookups down by tenant
+    #[serde(default)]
+    by_tenant: bool,
+}
+
+// Quantiles the text and JSON metrics report unless others are asked for
+const DEFAULT_QUANTILES: [f64; 3] = [0.5, 0.95, 0.99];
+
+// Parse the `quantiles` parameter
+fn parse_quantiles(list: &str) -> Result<Vec<f64>, String> {
+    list.split(',')
+        .map(|q| match q.trim().parse::<f64>() {
+            Ok(q) if (0.0..=1.0).contains(&q) => Ok(q),
+            _ => Err(format!(
+                "Quantiles must be numbers from 0 to 1, got {:?}",
+                q
+            )),
+        })
+        .collect()
+}
+
+// Name of a quantile as a percentile, like P99.9 for 0.999
+fn percentile_name(q: f64) -> String {
+    format!("P{}", (q * 100.0 * 1e6).round() / 1e6)
+}
+
+// GET /healthz - Liveness probe. Answers without touching the store or the
+// metrics, so it stays fast while either is busy.
+#[utoipa::path(
+    get, path = "/healthz", tag = "probes", operation_id = "healthz",
+    summary = "Liveness probe",
+    security(()),
+    responses((status = 200, description = "The process is up", body = String, content_type = "text/plain"))
+)]
+pub(crate) async fn healthz_handler() -> &'static str {
+    "ok"
+}
+
+// GET /readyz - Readiness probe: 200 once the store is loaded and serving,
+// 503 while starting up or draining for shutdown. The body names the state.
+#[utoipa::path(
+    get, path = "/readyz", tag = "probes", operation_id = "readyz",
+    summary = "Readiness probe",
+    security(()),
+    responses(
+        (status = 200, description = "Serving: `ready` or `ready (read-only)`", body = String, content_type = "text/plain"),
+        (status = 503, description = "`starting` or `draining`", body = String, content_type = "text/plain"),
+    )
+)]
+pub(crate) async fn readyz_handler(State(state): State<AppState>) -> Response {
+    let readiness = Readiness::load(&state.readiness);
+    let status = match readiness {
+        Readiness::Ready => StatusCode::OK,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    if readiness == Readiness::Ready && state.read_only.load(Ordering::Relaxed) {
+        return (status, "ready (read-only)").into_response();
+    }
+    (status, readiness.name()).into_response()
+}
+
+// Content-Type of the Prometheus text exposition format
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+// GET /metrics - Request and store metrics in the Prometheus text format, as
+// JSON with `Accept: application/json`, or with `?format=text` as a short
+// human-readable summary. An explicit `format` wins over the Accept header.
+#[utoipa::path(
+    get, path = "/metrics", tag = "metrics", operation_id = "metrics",
+    summary = "Request and store metrics",
+    params(MetricsParams, ("Accept" = Option<String>, Header, description = "`application/json` for JSON")),
+    responses(
+        (status = 200, description = "Prometheus text format, JSON, or a text summary", body = String, content_type = "text/plain"),
+        (status = 400, description = "Invalid format or quantiles"),
+    )
+)]
+pub(crate) async fn metrics_handler(
+    State(state): State<AppState>,
+    Query(params): Query<MetricsParams>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let format = params.format.as_deref().or_else(|| {
+        headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .filter(|accept| accepts(accept, "application/json"))
+            .map(|_| "json")
+    });
+    let quantiles = match params.quantiles.as_deref().map(parse_quantiles) {
+        None => DEFAULT_QUANTILES.to_vec(),
+        Some(Ok(quantiles)) => quantiles,
+        Some(Err(msg)) => return Err(ApiError::BadRequest(msg)),
+    };
+    match format {
+        None | Some("prometheus") => prometheus_metrics(&state, params.by_tenant).await,
+        Some("json") => Ok(json_metrics(&state, &quantiles, params.by_tenant)),
+        Some("text") => Ok(text_metrics(&state, &quantiles, params.by_tenant).into_response()),
+        Some(_) => Err(ApiError::BadRequest(
+            "The format parameter accepts \"prometheus\", \"json\" or \"text\"".into(),
+        )),
+    }
+}
+
+// Whether an Accept or Content-Type header lists `media`, ignoring parameters
+// like q
+pub(crate) fn accepts(accept: &str, media: &str) -> bool {
+    accept.split(',').any(|listed| {
+        listed
+            .split(';')
+            .next()
+            .is_some_and(|listed| listed.trim().eq_ignore_ascii_case(media))
+    })
+}
+
+// GET /metrics/slow - The latest requests slower than --slow-ms, most recent
+// first
+#[utoipa::path(
+    get, path = "/metrics/slow", tag = "metrics", operation_id = "slow_requests",
+    summary = "The latest requests slower than --slow-ms",
+    responses((status = 200, description = "Slow requests, most recent first", content_type = "application/json"))
+)]
+pub(crate) async fn slow_requests_handler(State(state): State<AppState>) -> Response {
+    let threshold_ms = state
+        .metrics
+        .slow_threshold()
+        .map(|threshold| threshold.as_millis() as u64);
+    Json(serde_json::json!({
+        "threshold_ms": threshold_ms,
+        "requests": state.metrics.slow_requests(),
+    }))
+    .into_response()
+}
+
+// POST /admin/metrics/reset - Clear the latency percentiles and the request and
+// response counters, e.g. between load test runs. Responds with what was
+// cleared. The store's own counters, like evictions, are left alone.
+#[utoipa::path(
+    post, path = "/admin/metrics/reset", tag = "admin", operation_id = "reset_metrics",
+    summary = "Clear the request metrics",
+    security(("admin_token" = [])),
+    responses((status = 200, description = "What was cleared", content_type = "application/json"))
+)]
+pub(crate) async fn reset_metrics_handler(State(state): State<AppState>) -> Response {
+    let discarded = state.metrics.reset();
+    tracing::info!(
+        "Reset metrics, discarding {} windowed requests",
+        discarded.percentiles.3
+    );
+    Json(latency_json(
+        &state,
+        discarded.percentiles,
+        &discarded.statuses,
+    ))
+    .into_response()
+}
+
+// The windowed percentiles and the status counts as a JSON object
+fn latency_json(
+    state: &AppState,
+    (p50, p95, p99, count): (f64, f64, f64, usize),
+    statuses: &metrics::StatusCounts,
+) -> serde_json::Value {
+    let classes: serde_json::Map<String, serde_json::Value> = statuses
+        .classes
+        .iter()
+        .map(|(class, count)| (format!("{}xx", class), (*count).into()))
+        .collect();
+    let codes: serde_json::Map<String, serde_json::Value> = statuses
+        .codes
+        .iter()
+        .map(|(code, count)| (code.to_string(), (*count).into()))
+        .collect();
+
+    serde_json::json!({
+        "window_secs": state.metrics.window().as_secs(),
+        "count": count,
+        "p50_ms": p50,
+        "p95_ms": p95,
+        "p99_ms": p99,
+        "responses": classes,
+        "status_codes": codes,
+    })
+}
+
+// Hits, misses and hit ratio as a JSON object
+fn lookups_json(lookups: (u64, u64)) -> serde_json::Value {
+    serde_json::json!({
+        "hits": lookups.0,
+        "misses": lookups.1,
+        "hit_ratio": hit_ratio(lookups),
+    })
+}
+
+fn json_metrics(state: &AppState, quantiles: &[f64], by_tenant: bool) -> Response {
+    let mut metrics = latency_json(
+        state,
+        state.metrics.get_percentiles(),
+        &state.metrics.status_counts(),
+    );
+    metrics["quantiles_ms"] = quantiles
+        .iter()
+        .zip(state.metrics.quantiles(quantiles))
+        .map(|(q, ms)| (q.to_string(), ms.into()))
+        .collect::<serde_json::Map<_, _>>()
+        .into();
+    let per_route: serde_json::Map<String, serde_json::Value> = state
+        .metrics
+        .route_percentiles()
+        .into_iter()
+        .map(|route| {
+            let key = format!("{} {}", route.method, route.route);
+            let value = serde_json::json!({
+                "method": route.method,
+                "route": route.route,
+                "count": route.count,
+                "p50_ms": route.p50,
+                "p95_ms": route.p95,
+                "p99_ms": route.p99,
+            });
+            (key, value)
+        })
+        .collect();
+    metrics["per_route"] = per_route.into();
+    let throughput = state.metrics.throughput();
+    let rate_json = |rate: metrics::Rate| serde_json::json!({ "current_rps": rate.current, "avg_1m_rps": rate.minute });
+    let mut throughput_json = rate_json(throughput.total);
+    throughput_json["by_method"] = throughput
+        .by_method
+        .into_iter()
+        .map(|(method, rate)| (method, rate_json(rate)))
+        .collect::<serde_json::Map<_, _>>()
+        .into();
+    metrics["throughput"] = throughput_json;
+    metrics["evictions"] = state.store.evictions().into();
+    metrics["stored_bytes"] = state.store.bytes().into();
+    metrics["value_sizes"] = value_sizes_json(&state.store);
+    if let Some(backlog) = &state.backlog {
+        let (entries, age) = backlog.lag();
+        metrics["replication"] = serde_json::json!({
+            "lag_entries": entries,
+            "lag_seconds": age.as_secs_f64(),
+        });
+    }
+    metrics["lookups"] = lookups_json(state.ops.lookups.load());
+    if by_tenant {
+        metrics["lookups"]["by_tenant"] = state
+            .ops
+            .tenant_lookups()
+            .into_iter()
+            .map(|(tenant, lookups)| (tenant, lookups_json(lookups)))
+            .collect::<serde_json::Map<_, _>>()
+            .into();
+    }
+    metrics["byte_budget"] = state.store.limits().max_bytes.into();
+    Json(metrics).into_response()
+}
+
+// Stored values per size bucket, keyed by bucket name
+fn value_sizes_json(store: &Store) -> serde_json::Value {
+    store::VALUE_SIZE_LABELS
+        .iter()
+        .zip(store.value_sizes())
+        .map(|(label, count)| (label.to_string(), count.into()))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+async fn prometheus_metrics(state: &AppState, by_tenant: bool) -> Result<Response, ApiError> {
+    let keys = state.store.with_read(|view| view.len()).await?;
+    let mut out = String::new();
+    state.metrics.write_prometheus(&mut out);
+    let gauges = [
+        (
+            "kv_keys",
+            "Keys currently stored, including expired ones not yet swept.",
+            keys as u64,
+        ),
+        (
+            "kv_bytes",
+            "Bytes of keys and values currently stored.",
+            state.store.bytes(),
+        ),
+        (
+            "kv_long_polls",
+            "GETs waiting for a key to change.",
+            state.events.parked() as u64,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        out.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n"
+        ));
+    }
+    if let Some(backlog) = &state.backlog {
+        let (entries, age) = backlog.lag();
+        out.push_str(&format!(
+            "# HELP kv_replication_lag_entries Writes the follower has yet to acknowledge.\n\
+             # TYPE kv_replication_lag_entries gauge\n\
+             kv_replication_lag_entries {}\n\
+             # HELP kv_replication_lag_seconds Age of the oldest write the follower has yet to acknowledge.\n\
+             # TYPE kv_replication_lag_seconds gauge\n\
+             kv_replication_lag_seconds {}\n",
+            entries,
+            age.as_secs_f64()
+        ));
+    }
+    out.push_str(
+        "# HELP kv_values_by_size Values currently stored, by size in bytes.\n\
+         # TYPE kv_values_by_size gauge\n",
+    );
+    for (label, count) in store::VALUE_SIZE_LABELS
+        .iter()
+        .zip(state.store.value_sizes())
+    {
+        out.push_str(&format!("kv_values_by_size{{size=\"{label}\"}} {count}\n"));
+    }
+    let lookups = state.ops.lookups.load();
+    out.push_str(&format!(
+        "# HELP kv_lookups_total Key lookups, by whether they found a live value.\n\
+         # TYPE kv_lookups_total counter\n\
+         kv_lookups_total{{result=\"hit\"}} {}\n\
+         kv_lookups_total{{result=\"miss\"}} {}\n\
+         # HELP kv_lookup_hit_ratio Share of key lookups that found a live value.\n\
+         # TYPE kv_lookup_hit_ratio gauge\n\
+         kv_lookup_hit_ratio {}\n",
+        lookups.0,
+        lookups.1,
+        hit_ratio(lookups).unwrap_or(f64::NAN)
+    ));
+    if by_tenant {
+        out.push_str(
+            "# HELP kv_tenant_lookups_total Key lookups per tenant, by whether they found a live value.\n\
+             # TYPE kv_tenant_lookups_total counter\n",
+        );
+        for (tenant, (hits, misses)) in state.ops.tenant_lookups() {
+            let tenant = metrics::escape_label(&tenant);
+            out.push_str(&format!(
+                "kv_tenant_lookups_total{{tenant=\"{tenant}\",result=\"hit\"}} {hits}\n\
+                 kv_tenant_lookups_total{{tenant=\"{tenant}\",result=\"miss\"}} {misses}\n"
+            ));
+        }
+    }
+    out.push_str(&format!(
+        "# HELP kv_evictions_total Keys evicted to stay within a capacity limit.\n\
+         # TYPE kv_evictions_total counter\n\
+         kv_evictions_total {}\n",
+        state.store.evictions()
+    ));
+    Ok(([(header::CONTENT_TYPE, PROMETHEUS_CONTENT_TYPE)], out).into_response())
+}
+
+fn text_metrics(state: &AppState, quantiles: &[f64], by_tenant: bool) -> impl IntoResponse {
+    let count = state.metrics.get_percentiles().3;
+
+    let mut response = format!(
+        "Latency Metrics ({} requests in the last {}s)\n",
+        count,
+        state.metrics.window().as_secs()
+    );
+    for (q, ms) in quantiles.iter().zip(state.metrics.quantiles(quantiles)) {
+        response.push_str(&format!("{}: {:.2}ms\n", percentile_name(*q), ms));
+    }
+    response.push_str(&format!(
+        "Evictions: {}\n\
+         Stored bytes: {}\n\
+         Byte budget: {}\n",
+        state.store.evictions(),
+        state.store.bytes(),
+        state
+            .store
+            .limits()
+            .max_bytes
+            .map_or("unlimited".to_string(), |max| max.to_string())
+    ));
+    let sizes: Vec<String> = store::VALUE_SIZE_LABELS
+        .iter()
+        .zip(state.store.value_sizes())
+        .map(|(label, count)| format!("{}: {}", label, count))
+        .collect();
+    response.push_str(&format!("Value sizes: {}\n", sizes.join(", ")));
+
+    response.push_str(&format!(
+        "Throughput: {}\n",
+        state.metrics.throughput().summary()
+    ));
+    response.push_str(&format!(
+        "Responses: {}\n",
+        state.metrics.status_counts().summary()
+    ));
+
+    response.push_str(&format!("Lookups: {}\n", state.ops.lookup_summary()));
+    if by_tenant {
+        for (tenant, (hits, misses)) in state.ops.tenant_lookups() {
+            response.push_str(&format!("  {}: {} hits, {} misses\n", tenant, hits, misses));
+        }
+    }
+
+    response.push_str("\nPer route:\n");
+    for route in state.metrics.route_percentiles() {
+        response.push_str(&format!(
+            "{} {}: P50 {:.2}ms, P95 {:.2}ms, P99 {:.2}ms ({} requests)\n",
+            route.method, route.route, route.p50, route.p95, route.p99, route.count
+        ));
+    }
+
+    (StatusCode::OK, response)
+}
+
+// GET /stats - Store usage statistics and key operations served since startup,
+// or with X-Tenant that tenant's usage only
+#[utoipa::path(
+    get, path = "/stats", tag = "metrics", operation_id = "stats",
+    summary = "Store usage and operation counts",
+    params(TenantHeader),
+    responses((status = 200, description = "Usage of the store, or with X-Tenant of that tenant", content_type = "application/json"))
+)]
+pub(crate) async fn stats_handler(
+    State(state): State<AppState>,
+    namespace: Namespace,
+) -> Result<Response, ApiError> {
+    if let Some(tenant) = namespace.tenant() {
+        let usage = state.store.tenant_usage(tenant);
+        return Ok(
+            Json(serde_json::json!({ "keys": usage.keys, "bytes": usage.bytes })).into_response(),
+        );
+    }
+
+    // The largest sizes take a pass over the store, but only under the read lock
+    let result = state
+        .store
+        .with_read(|view| {
+            let (mut largest_key, mut largest_value) = (0, 0);
+            view.for_each(&mut |key, entry| {
+                largest_key = largest_key.max(key.len());
+                largest_value = largest_value.max(entry.value.len());
+            });
+            (view.len(), largest_key, largest_value)
+        })
+        .await;
+    let (keys, largest_key, largest_value) = result?;
+    let limits = state.store.limits();
+
+    Ok(Json(serde_json::json!({
+        "keys": keys,
+        "bytes": state.store.bytes(),
+        "largest_key_bytes": largest_key,
+        "largest_value_bytes": largest_value,
+        "max_bytes": limits.max_bytes,
+        "max_keys": limits.max_keys,
+        "evictions": state.store.evictions(),
+        "value_sizes": value_sizes_json(&state.store),
+        "operations": state.ops.to_json(),
+        "read_only": state.read_only.load(Ordering::Relaxed),
+        "last_compaction": state
+            .last_compaction
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .map(persistence::system_time_to_rfc3339),
+    }))
+    .into_response())
+}
+
+// POST /admin/flush - Delete every key of every tenant and bucket. Refused with
+// 428 unless the request carries `X-Confirm: DELETE-EVERYTHING`. The flush is
+// logged as a single record and followed by a snapshot, so the keys don't come
+// back on restart, and soft-deleted keys can no longer be restored. Responds
+// with the number of keys deleted.
+#[utoipa::path(
+    post, path = "/admin/flush", tag = "admin", operation_id = "flush",
+    summary = "Delete every key of every tenant and bucket",
+    security(("admin_token" = [])),
+    params(("X-Confirm" = String, Header, description = "Must be `DELETE-EVERYTHING`")),
+    responses(
+        (status = 200, description = "`deleted`: how many keys were removed", content_type = "application/json"),
+        (status = 428, description = "X-Confirm is missing or wrong"),
+    )
+)]
+pub(crate) async fn flush_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    if headers
+        .get(CONFIRM_HEADER)
+        .is_none_or(|value| value != FLUSH_CONFIRMATION)
+    {
+        return Err(ApiError::ConfirmationRequired(format!(
+            "Flushing deletes every key; confirm with X-Confirm: {}",
+            FLUSH_CONFIRMATION
+        )));
+    }
+
+    let result = state
+        .store
+        .with_write(|view| {
+            let now = Instant::now();
+            let mut deleted = 0;
+            for key in view.keys_with_prefix("") {
+                match view.remove(&key) {
+                    Some(entry) if entry.is_expired(now) => state.record_expiry(&key, &entry),
+                    Some(_) => {
+                        state.record_event(&key, None);
+                        deleted += 1;
+                    }
+                    None => {}
+                }
+            }
+            // Under the same lock, so no delete after the flush is lost
+            if let Some(tombstones) = &state.tombstones {
+                tombstones.clear();
+            }
+            (deleted, state.log(|| wal::WalRecord::Clear))
+        })
+        .await;
+    let (deleted, ack) = result?;
+
+    if let Err(e) = wal::wait(ack).await {
+        tracing::error!("Failed to log flush: {}", e);
+        return Err(ApiError::Internal);
+    }
+    state.snapshot_requests.notify_one();
+    tracing::warn!("Flushed the store, deleting {} keys", deleted);
+    Ok(Json(serde_json::json!({ "deleted": deleted })).into_response())
+}
+
+// POST /admin/snapshot - Ask for a snapshot now rather than at the next
+// interval. It is written in the background, so this answers 202 right away.
+#[utoipa::path(
+    post, path = "/admin/snapshot", tag = "admin", operation_id = "snapshot",
+    summary = "Write a snapshot now",
+    security(("admin_token" = [])),
+    responses(
+        (status = 202, description = "The snapshot task was asked for a snapshot"),
+        (status = 404, description = "No --snapshot-path is configured"),
+    )
+)]
+pub(crate) async fn snapshot_handler(State(state): State<AppState>) -> Result<Response, ApiError> {
+    if !state.snapshots_enabled {
+        return Err(ApiError::NotFound("No --snapshot-path is configured"));
+    }
+    state.snapshot_requests.notify_one();
+    Ok(StatusCode::ACCEPTED.into_response())
+}
+
+// POST /admin/compact - Remove expired keys and stale tombstones now and give
+// back the memory deleted keys left allocated. The store is compacted a shard
+// at a time, so requests keep being served meanwhile. Responds with the rough
+// bytes held before and after.
+#[utoipa::path(
+    post, path = "/admin/compact", tag = "admin", operation_id = "compact",
+    summary = "Reclaim memory left over from deleted and expired keys",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "`bytes_before` and `bytes_after`: estimated memory held for entries; `expired` and `tombstones_purged`: how many were removed", content_type = "application/json"),
+    )
+)]
+pub(crate) async fn compact_handler(State(state): State<AppState>) -> Result<Response, ApiError> {
+    // Expired keys are swept first, and any expiring since are removed by the
+    // compaction itself, each announced like the sweeper's
+    let removed = |key: &str, entry: &Entry| state.record_expiry(key, entry);
+    let swept = store::sweep_expired(&state.store, &removed).await?;
+    let now = Instant::now();
+    let mut compaction = state.store.compact(now, &removed).await?;
+    compaction.expired += swept;
+    let (purged, buried_before, buried_after) = state
+        .tombstones
+        .as_ref()
+        .map_or((0, 0, 0), |tombstones| tombstones.compact(now));
+    *state
+        .last_compaction
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner) = Some(SystemTime::now());
+
+    let (before, after) = (
+        compaction.bytes_before + buried_before,
+        compaction.bytes_after + buried_after,
+    );
+    tracing::info!(
+        "Compacted the store from {} to {} bytes, removing {} expired keys and {} tombstones",
+        before,
+        after,
+        compaction.expired,
+        purged
+    );
+    Ok(Json(serde_json::json!({
+        "bytes_before": before,
+        "bytes_after": after,
+        "expired": compaction.expired,
+        "tombstones_purged": purged,
+    }))
+    .into_response())
+}
+
+// Entries read from the store per page of an export
+const EXPORT_PAGE: usize = 1000;
+
+// Query parameters for GET /admin/export
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct ExportParams {
+    // Only export keys starting with this, within their namespace
+    #[serde(default)]
+    prefix: String,
+}
+
+// GET /admin/export - Every unexpired entry of every namespace as
+// newline-delimited JSON, one record per line in the format --preload reads.
+// The body is streamed as the store is read, a part at a time: each shard of
+// the memory backend, and each page of keys for sled, is read in one
+// consistent view, but the export as a whole is not a point-in-time snapshot.
+// A write made while it runs may or may not be included, and one spanning
+// several keys may be included only in part.
+#[utoipa::path(
+    get, path = "/admin/export", tag = "admin", operation_id = "export",
+    summary = "Stream every entry as newline-delimited JSON",
+    security(("admin_token" = [])),
+    params(ExportParams),
+    responses(
+        (status = 200, description = "One JSON record per line: `key`, `value` or `value_b64` for binary values, `tenant` and `bucket` outside the default namespace, `ttl` in seconds, `content_type`, `version`, `created_at` and `updated_at`. Each part of the store is read consistently, the whole export isn't.", content_type = "application/x-ndjson"),
+    )
+)]
+pub(crate) async fn export_handler(
+    State(state): State<AppState>,
+    Query(params): Query<ExportParams>,
+) -> Response {
+    // A few pages in flight at most, so a slow client holds up the export
+    // rather than having the store buffered for it
+    let (mut sender, body) = Channel::<Bytes, StorageError>::new(4);
+    tokio::spawn(async move {
+        if let Err(e) = export(&state, &params.prefix, &mut sender).await {
+            tracing::error!("Export failed: {}", e);
+            sender.abort(e);
+        }
+    });
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        axum::body::Body::new(body),
+    )
+        .into_response()
+}
+
+// Send the store's entries to `sender` page by page, until it's done or the
+// client goes away
+async fn export(
+    state: &AppState,
+    prefix: &str,
+    sender: &mut Sender<Bytes, StorageError>,
+) -> Result<(), StorageError> {
+    for part in 0..state.store.parts() {
+        let mut after = None;
+        loop {
+            let now = Instant::now();
+            let mut page = state
+                .store
+                .scan_part(part, after.as_deref(), EXPORT_PAGE, now)
+                .await?;
+            let done = page.len() < EXPORT_PAGE;
+            for chunk in page.chunks(EXPORT_PAGE) {
+                let mut lines = Vec::new();
+                for (key, entry) in chunk {
+                    if !keyspace::split_stored(key).2.starts_with(prefix) {
+                        continue;
+                    }
+                    let record = ndjson::Record::encode(key, entry, now)?;
+                    serde_json::to_writer(&mut lines, &record).map_err(StorageError::new)?;
+                    lines.push(b'\n');
+                }
+                if !lines.is_empty() && sender.send_data(lines.into()).await.is_err() {
+                    return Ok(());
+                }
+            }
+            if done {
+                break;
+            }
+            after = page.pop().map(|(key, _)| key);
+        }
+    }
+    Ok(())
+}
+
+// Query parameters for GET /events
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct EventsParams {
+    #[serde(default)]
+    since: u64,
+    limit: Option<usize>,
+}
+
+// GET /events - The change feed: up to `limit` of the writes applied
+// after event number `since`, oldest first, with the number of the latest
+// event. Pass the last event's number, or the latest if there were none, as
+// the next `since`. Only the last --event-log-capacity events are kept, in
+// memory, so a reader that has fallen further behind, or one whose `since`
+// comes from before a restart, gets 410 and has to start over from a full
+// export.
+#[utoipa::path(
+    get, path = "/events", tag = "keys", operation_id = "events",
+    summary = "Writes applied since an event",
+    params(EventsParams),
+    responses(
+        (status = 200, description = "`events`: each with its `seq`, `op` (`put`, `delete`, `expire` for keys removed past their deadline, or `touch` for a new deadline), `key` with its `tenant` and `bucket` outside the default namespace, `at`, for puts and touches the `version` and, for puts with --event-log-values, the `value` or `value_b64`, for expiries the `expires_at` the key had, and for touches the one it now has; `latest`: the number of the latest event", content_type = "application/json"),
+        (status = 404, description = "The change feed is not enabled"),
+        (status = 410, description = "Events after `since` are no longer kept; resync from GET /admin/export"),
+    )
+)]
+pub(crate) async fn events_handler(
+    State(state): State<AppState>,
+    Query(params): Query<EventsParams>,
+) -> Result<Response, ApiError> {
+    let events = &state.events;
+    if !events.is_logged() {
+        return Err(ApiError::NotFound("The change feed is not enabled"));
+    }
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+    let Ok((events, latest)) = events.since(params.since, limit) else {
+        return Err(ApiError::Gone(
+            "Events after `since` are no longer kept; resync from a full export",
+        ));
+    };
+    let records = events
+        .iter()
+        .map(|event| event.record())
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Json(serde_json::json!({
+        "events": records,
+        "latest": latest,
+    }))
+    .into_response())
+}
+
+// Query parameters for GET /watch
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct WatchParams {
+    #[serde(default)]
+    prefix: String,
+}
+
+// Stream the writes `watched` picks out as Server-Sent Events, from now until
+// the client disconnects. Each event is named after its op and carries the
+// change feed's record of the write, with the value for puts and the event
+// number as its id. A client that reads too slowly to keep up loses events
+// rather than holding up writers, and gets a `lagged` event with how many.
+fn watch(state: &AppState, watched: events::Watched) -> Response {
+    let stream = BroadcastStream::new(state.events.watch()).filter_map(move |event| match event {
+        Ok(event) if watched.matches(&event) => {
+            Some(event.record().map_err(axum::Error::new).and_then(|record| {
+                SseEvent::default()
+                    .id(event.seq.to_string())
+                    .event(record.op())
+                    .json_data(record)
+            }))
+        }
+        Ok(_) => None,
+        Err(BroadcastStreamRecvError::Lagged(missed)) => Some(Ok(SseEvent::default()
+            .event("lagged")
+            .data(missed.to_string()))),
+    });
+    let stream = futures_util::StreamExt::take_until(stream, state.events.closed());
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+// GET /watch/{key} - Stream every write to a key as it happens, as
+// Server-Sent Events: a `put` with the new value on creates and updates, a
+// `delete` when the key is deleted, an `expire` with the deadline it had
+// when it's removed for having expired, and a `touch` with the new deadline
+// when POST /{key}/touch resets it
+#[utoipa::path(
+    get, path = "/watch/{key}", tag = "keys", operation_id = "watch_key",
+    summary = "Stream a key's changes",
+    params(
+        ("key" = String, Path, description = "Key to watch"),
+        TenantHeader,
+    ),
+    responses(
+        (status = 200, description = "An event stream: `put`, `delete`, `expire` and `touch` events with their number as the id and the record GET /events would list as data, with the value; `lagged` with the number of events missed by reading too slowly", content_type = "text/event-stream"),
+        (status = 400, description = "Invalid key"),
+    )
+)]
+pub(crate) async fn watch_key_handler(State(state): State<AppState>, Key(key): Key) -> Response {
+    watch(&state, events::Watched::Key(key))
+}
+
+// GET /watch - Stream every write to keys starting with `prefix`, like
+// GET /watch/{key}
+#[utoipa::path(
+    get, path = "/watch", tag = "keys", operation_id = "watch_prefix",
+    summary = "Stream the changes to keys with a prefix",
+    params(
+        WatchParams,
+        TenantHeader,
+    ),
+    responses(
+        (status = 200, description = "An event stream, as for GET /watch/{key}", content_type = "text/event-stream"),
+        (status = 400, description = "Invalid prefix"),
+    )
+)]
+pub(crate) async fn watch_prefix_handler(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    Query(params): Query<WatchParams>,
+) -> Result<Response, ApiError> {
+    if let Err(msg) = keyspace::validate(&params.prefix) {
+        return Err(ApiError::BadRequest(msg.into()));
+    }
+    Ok(watch(
+        &state,
+        events::Watched::Prefix(namespace, params.prefix),
+    ))
+}
+
+// Line errors listed in the response to an import, at most
+const IMPORT_ERRORS: usize = 100;
+
+// Query parameters for POST /admin/import
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct ImportParams {
+    #[serde(default)]
+    mode: ndjson::Mode,
+    // Without it, text/csv bodies are CSV and the rest NDJSON
+    format: Option<ImportFormat>,
+    // The CSV columns holding keys and values
+    key_col: Option<String>,
+    value_col: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ImportFormat {
+    Ndjson,
+    Csv,
+}
+
+// What an import did, line by line
+#[derive(Default, Serialize)]
+struct ImportReport {
+    processed: usize,
+    inserted: usize,
+    updated: usize,
+    skipped: usize,
+    errored: usize,
+    // The first IMPORT_ERRORS of them
+    errors: Vec<LineError>,
+}
+
+#[derive(Serialize)]
+struct LineError {
+    line: usize,
+    error: String,
+}
+
+impl ImportReport {
+    fn error(&mut self, line: usize, error: String) {
+        self.errored += 1;
+        if self.errors.len() < IMPORT_ERRORS {
+            self.errors.push(LineError { line, error });
+        }
+    }
+
+    // Parse line number `line`, adding its entry to `batch`
+    fn parse(
+        &mut self,
+        state: &AppState,
+        line: usize,
+        text: &[u8],
+        batch: &mut Vec<(usize, String, Entry)>,
+    ) {
+        if text.trim_ascii().is_empty() {
+            return;
+        }
+        self.processed += 1;
+        let record = serde_json::from_slice::<ndjson::Record>(text).map_err(|e| e.to_string());
+        match record.and_then(|record| record.decode(state)) {
+            Ok((key, entry)) => batch.push((line, key, entry)),
+            Err(e) => self.error(line, e),
+        }
+    }
+
+    // Write `batch`, once it's durable. A FailOnConflict import stops at the
+    // first key with a value, keeping what it wrote before.
+    async fn load(
+        &mut self,
+        state: &AppState,
+        batch: Vec<(usize, String, Entry)>,
+        mode: ndjson::Mode,
+    ) -> Result<(), ApiError> {
+        let loaded = ndjson::load(state, batch, mode, true).await?;
+        let mut written = 0;
+        for (line, outcome) in loaded.outcomes {
+            match outcome {
+                ndjson::Outcome::Inserted => self.inserted += 1,
+                ndjson::Outcome::Updated => self.updated += 1,
+                ndjson::Outcome::Skipped => self.skipped += 1,
+                ndjson::Outcome::NoRoom(reason) => {
+                    let error = insufficient_storage(&state.store, reason);
+                    self.error(line, error.to_string());
+                    continue;
+                }
+            }
+            written += 1;
+        }
+        if let Err(e) = wal::wait_all(loaded.acks).await {
+            tracing::error!("Failed to log import: {}", e);
+            return Err(ApiError::Internal);
+        }
+        state.ops.put(written);
+        match loaded.conflict {
+            Some(key) => Err(ApiError::Conflict {
+                key,
+                message: "Key already exists",
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+// POST /admin/import - Load newline-delimited JSON records, as GET
+// /admin/export writes them, or with `?format=csv` or a text/csv body the rows
+// of a CSV with a header row, taking keys and values from the `key_col` and
+// `value_col` columns (`key` and `value` by default). Records are written a
+// chunk at a time as they arrive. With `?mode=upsert`, the default, they
+// replace existing values; with `skip-existing` they leave them be; with
+// `fail-on-conflict` the import stops with 409 at the first key that has one,
+// keeping the records before it. Malformed records and ones that don't fit are
+// reported by line number, and the rest of the body is still imported.
+// Responds with what it did.
+#[utoipa::path(
+    post, path = "/admin/import", tag = "admin", operation_id = "import",
+    summary = "Load records in the export format, or rows of a CSV",
+    security(("admin_token" = [])),
+    params(ImportParams),
+    request_body(content = String, content_type = "application/x-ndjson", description = "One JSON record per line, or CSV with a header row"),
+    responses(
+        (status = 200, description = "How many records were `processed`, `inserted`, `updated`, `skipped` and `errored`, with the first errors and their line numbers, or for a CSV their row numbers counting the header as the first", content_type = "application/json"),
+        (status = 400, description = "The CSV lacks the key or value column, or can't be read"),
+        (status = 409, description = "With `?mode=fail-on-conflict`, a key already had a value; earlier records are imported"),
+        (status = 413, description = "A line was longer than any record can be"),
+    )
+)]
+pub(crate) async fn import_handler(
+    State(state): State<AppState>,
+    Query(params): Query<ImportParams>,
+    headers: HeaderMap,
+    body: axum::body::Body,
+) -> Result<Response, ApiError> {
+    let csv = match params.format {
+        Some(format) => format == ImportFormat::Csv,
+        None => headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("text/csv")),
+    };
+    let report = if csv {
+        import_csv(&state, params, body).await?
+    } else {
+        import_ndjson(&state, params.mode, body).await?
+    };
+    tracing::info!(
+        "Imported {} records: {} inserted, {} updated, {} skipped, {} errored",
+        report.processed,
+        report.inserted,
+        report.updated,
+        report.skipped,
+        report.errored
+    );
+    Ok(Json(report).into_response())
+}
+
+async fn import_ndjson(
+    state: &AppState,
+    mode: ndjson::Mode,
+    mut body: axum::body::Body,
+) -> Result<ImportReport, ApiError> {
+    // A record holding the largest value, escaped, with room for the rest of it
+    let max_line = state
+        .max_value_bytes
+        .saturating_mul(6)
+        .saturating_add(64 * 1024);
+    let mut report = ImportReport::default();
+    // Lines so far, and the start of one not yet ended
+    let (mut lines, mut pending) = (0, Vec::new());
+    loop {
+        let chunk = match body.frame().await {
+            Some(frame) => {
+                let frame = frame.map_err(|e| {
+                    ApiError::BadRequest(format!("Failed to read request body: {}", e))
+                })?;
+                let Ok(chunk) = frame.into_data() else {
+                    continue;
+                };
+                Some(chunk)
+            }
+            None => None,
+        };
+        let mut batch = Vec::new();
+        let mut start = 0;
+        if let Some(chunk) = &chunk {
+            pending.extend_from_slice(chunk);
+            while let Some(end) = pending[start..].iter().position(|&b| b == b'\n') {
+                lines += 1;
+                report.parse(state, lines, &pending[start..start + end], &mut batch);
+                start += end + 1;
+            }
+            pending.drain(..start);
+            if pending.len() > max_line {
+                return Err(ApiError::PayloadTooLarge(max_line));
+            }
+        } else if !pending.is_empty() {
+            // The last line, without a newline
+            report.parse(state, lines + 1, &pending, &mut batch);
+        }
+        if !batch.is_empty() {
+            report.load(state, batch, mode).await?;
+        }
+        if chunk.is_none() {
+            break;
+        }
+    }
+    Ok(report)
+}
+
+// Rows of a CSV import parsed before they're written, at most
+const CSV_BATCH: usize = 1000;
+
+// The chunks of a request body as a blocking reader, for the CSV parser
+struct ChunkReader {
+    chunks: tokio::sync::mpsc::Receiver<std::io::Result<Bytes>>,
+    current: Bytes,
+}
+
+impl std::io::Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.current.is_empty() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => self.current = chunk?,
+                None => return Ok(0),
+            }
+        }
+        let read = buf.len().min(self.current.len());
+        buf[..read].copy_from_slice(&self.current.split_to(read));
+        Ok(read)
+    }
+}
+
+// Parse the CSV on a blocking thread as the body arrives, since the csv
+// crate only reads synchronously
+async fn import_csv(
+    state: &AppState,
+    params: ImportParams,
+    mut body: axum::body::Body,
+) -> Result<ImportReport, ApiError> {
+    let (sender, chunks) = tokio::sync::mpsc::channel(4);
+    let parser = {
+        let state = state.clone();
+        tokio::task::spawn_blocking(move || {
+            let reader = ChunkReader {
+                chunks,
+                current: Bytes::new(),
+            };
+            parse_csv(&state, &params, reader)
+        })
+    };
+    while let Some(frame) = body.frame().await {
+        let chunk = match frame {
+            Ok(frame) => match frame.into_data() {
+                Ok(chunk) => Ok(chunk),
+                Err(_) => continue,
+            },
+            Err(e) => Err(std::io::Error::other(e)),
+        };
+        let failed = chunk.is_err();
+        // The parser stops early on a conflict or unreadable CSV
+        if sender.send(chunk).await.is_err() || failed {
+            break;
+        }
+    }
+    drop(sender);
+    parser.await.map_err(|e| {
+        tracing::error!("CSV import failed: {}", e);
+        ApiError::Internal
+    })?
+}
+
+// Where a CSV row is reported: its row in a spreadsheet, counting the header
+// as the first. That's its line unless quoted fields span lines.
+fn row_number(position: &csv::Position) -> u64 {
+    position.record() + 1
+}
+
+fn parse_csv(
+    state: &AppState,
+    params: &ImportParams,
+    reader: ChunkReader,
+) -> Result<ImportReport, ApiError> {
+    let unreadable = |e: csv::Error| ApiError::BadRequest(format!("Failed to read CSV: {}", e));
+    let mut csv = csv::Reader::from_reader(reader);
+    let columns = csv.byte_headers().map_err(unreadable)?.clone();
+    let column = |name: &str| {
+        columns
+            .iter()
+            .position(|column| column == name.as_bytes())
+            .ok_or_else(|| ApiError::BadRequest(format!("The CSV has no `{}` column", name)))
+    };
+    let key_at = column(params.key_col.as_deref().unwrap_or("key"))?;
+    let value_at = column(params.value_col.as_deref().unwrap_or("value"))?;
+
+    let runtime = tokio::runtime::Handle::current();
+    let mut report = ImportReport::default();
+    let mut batch = Vec::new();
+    let mut row = csv::ByteRecord::new();
+    loop {
+        match csv.read_byte_record(&mut row) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) if e.is_io_error() => return Err(unreadable(e)),
+            // Like a row with the wrong number of fields; the next is read as usual
+            Err(e) => {
+                let line = e.position().map_or(0, row_number);
+                report.processed += 1;
+                report.error(line as usize, e.to_string());
+                continue;
+            }
+        }
+        let line = row.position().map_or(0, row_number) as usize;
+        report.processed += 1;
+        let entry = std::str::from_utf8(&row[key_at])
+            .map_err(|_| "key is not valid UTF-8".to_string())
+            .and_then(|key| {
+                let value = Bytes::copy_from_slice(&row[value_at]);
+                Ok((key.to_string(), ndjson::entry(state, key, value, None)?))
+            });
+        match entry {
+            Ok((key, entry)) => batch.push((line, key, entry)),
+            Err(e) => report.error(line, e),
+        }
+        if batch.len() == CSV_BATCH {
+            runtime.block_on(report.load(state, std::mem::take(&mut batch), params.mode))?;
+        }
+    }
+    if !batch.is_empty() {
+        runtime.block_on(report.load(state, batch, params.mode))?;
+    }
+    Ok(report)
+}
+
+// POST /admin/acl/reload - Re-read the ACL file, responding with the number of
+// tokens now loaded. If the file can't be read or parsed, the previous tokens
+// stay in effect.
+#[utoipa::path(
+    post, path = "/admin/acl/reload", tag = "admin", operation_id = "reload_acl",
+    summary = "Re-read the ACL file",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "`tokens`: how many tokens are loaded", content_type = "application/json"),
+        (status = 404, description = "No ACL file is configured"),
+        (status = 422, description = "The file is invalid; the previous tokens stay in effect"),
+    )
+)]
+pub(crate) async fn reload_acl_handler(
+    State(state): State<AppState>,
+) -> Result<Response, ApiError> {
+    if !state.acl.is_enabled() {
+        return Err(ApiError::NotFound("No ACL file is configured"));
+    }
+    match state.acl.reload() {
+        Ok(tokens) => {
+            tracing::info!("Reloaded {} scoped tokens from the ACL", tokens);
+            Ok(Json(serde_json::json!({ "tokens": tokens })).into_response())
+        }
+        Err(e) => {
+            tracing::error!("Failed to reload the ACL: {}", e);
+            Err(ApiError::Unprocessable(e))
+        }
+    }
+}
+
+// POST /admin/readonly - Turn read-only mode on with a body of `true`, or off
+// with `false`. Requests already running finish either way. Responds with the
+// mode now in effect.
+#[utoipa::path(
+    post, path = "/admin/readonly", tag = "admin", operation_id = "set_read_only",
+    summary = "Turn read-only mode on or off",
+    security(("admin_token" = [])),
+    request_body(content = bool, content_type = "text/plain"),
+    responses(
+        (status = 200, description = "`read_only`: the mode now in effect", content_type = "application/json"),
+        (status = 400, description = "The body is neither `true` nor `false`"),
+    )
+)]
+pub(crate) async fn read_only_handler(
+    State(state): State<AppState>,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let read_only = match body.trim_ascii() {
+        b"true" => true,
+        b"false" => false,
+        _ => {
+            return Err(ApiError::BadRequest(
+                "Body must be `true` or `false`".into(),
+            ))
+        }
+    };
+    if state.read_only.swap(read_only, Ordering::Relaxed) != read_only {
+        tracing::warn!(
+            "Read-only mode turned {}",
+            if read_only { "on" } else { "off" }
+        );
+    }
+    Ok(Json(serde_json::json!({ "read_only": read_only })).into_response())
+}
+
+// The quota and usage of a tenant, as served by /admin/quota/{tenant}
+fn quota_response(state: &AppState, tenant: &str) -> Response {
+    let (quota, overridden) = state.quotas.effective(tenant);
+    let usage = state.store.tenant_usage(tenant);
+    Json(serde_json::json!({
+        "tenant": tenant,
+        "max_keys": quota.max_keys,
+        "max_bytes": quota.max_bytes,
+        "override": overridden,
+        "keys": usage.keys,
+        "bytes": usage.bytes,
+    }))
+    .into_response()
+}
+
+// GET /admin/quota/{tenant} - A tenant's quota and current usage
+#[utoipa::path(
+    get, path = "/admin/quota/{tenant}", tag = "admin", operation_id = "get_quota",
+    summary = "A tenant's quota and usage",
+    security(("admin_token" = [])),
+    params(("tenant" = String, Path)),
+    responses((status = 200, description = "The effective quota and current usage", content_type = "application/json"))
+)]
+pub(crate) async fn get_quota_handler(
+    State(state): State<AppState>,
+    Path(tenant): Path<String>,
+) -> Result<Response, ApiError> {
+    keyspace::validate(&tenant).map_err(|msg| ApiError::BadRequest(msg.into()))?;
+    Ok(quota_response(&state, &tenant))
+}
+
+// PUT /admin/quota/{tenant} - Override the default quota for one tenant, e.g.
+// {"max_keys": 1000, "max_bytes": null}. Omitted or null limits are unlimited.
+// Overrides only last until restart, and never remove data already stored.
+#[utoipa::path(
+    put, path = "/admin/quota/{tenant}", tag = "admin", operation_id = "put_quota",
+    summary = "Override a tenant's quota until restart",
+    security(("admin_token" = [])),
+    params(("tenant" = String, Path)),
+    request_body(content = quota::Quota),
+    responses(
+        (status = 200, description = "The effective quota and current usage", content_type = "application/json"),
+        (status = 400, description = "Invalid tenant or quota"),
+    )
+)]
+pub(crate) async fn put_quota_handler(
+    State(state): State<AppState>,
+    Path(tenant): Path<String>,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    keyspace::validate(&tenant).map_err(|msg| ApiError::BadRequest(msg.into()))?;
+    let quota: quota::Quota = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::BadRequest(format!("Invalid quota: {}", e)))?;
+    state.quotas.set(&tenant, quota);
+    Ok(quota_response(&state, &tenant))
+}
+
+// DELETE /admin/quota/{tenant} - Drop a tenant's override, restoring the default
+#[utoipa::path(
+    delete, path = "/admin/quota/{tenant}", tag = "admin", operation_id = "delete_quota",
+    summary = "Restore a tenant's default quota",
+    security(("admin_token" = [])),
+    params(("tenant" = String, Path)),
+    responses(
+        (status = 204, description = "The override was dropped"),
+        (status = 404, description = "The tenant has no override"),
+    )
+)]
+pub(crate) async fn delete_quota_handler(
+    State(state): State<AppState>,
+    Path(tenant): Path<String>,
+) -> Result<Response, ApiError> {
+    if state.quotas.clear(&tenant) {
+        Ok(StatusCode::NO_CONTENT.into_response())
+    } else {
+        Err(ApiError::NotFound("The tenant has no quota override"))
+    }
+}
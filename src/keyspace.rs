@@ -0,0 +1,299 @@
+use crate::auth::{self, Access, Permission};
+use crate::error::ApiError;
+use axum::{
+    extract::{FromRef, FromRequestParts, Path},
+    http::{request::Parts, HeaderMap},
+};
+use std::collections::HashMap;
+
+// Separates a namespace from the key inside a stored key. It can't appear in
+// client-supplied names, and as the highest code point it sorts namespaced keys
+// after every key of the default namespace.
+const MARKER: char = '\u{10FFFF}';
+
+// Header selecting the tenant a request operates on
+const TENANT_HEADER: &str = "x-tenant";
+
+// An independent keyspace: a bucket within a tenant. Keys of the default bucket
+// of the default tenant are stored as-is, so data written before namespaces
+// existed stays where it was. Other buckets of the default tenant are stored as
+// `<M><bucket><M><key>`, and everything of another tenant as
+// `<M><M><tenant><M><bucket><M><key>`, so no namespace's prefix is a prefix of
+// another's.
+#[derive(Clone, Debug, Default)]
+pub struct Namespace {
+    // From the X-Tenant header; empty for the default tenant
+    tenant: String,
+    // Set by the `/b/{bucket}/...` routes; empty for the default bucket
+    bucket: String,
+}
+
+impl Namespace {
+    // Resolve the tenant from the headers and the optional bucket
+    fn resolve(headers: &HeaderMap, bucket: Option<&str>) -> Result<Self, &'static str> {
+        let tenant = match headers.get(TENANT_HEADER) {
+            Some(value) => {
+                let tenant = value.to_str().map_err(|_| "X-Tenant must be valid UTF-8")?;
+                if tenant.is_empty() {
+                    return Err("X-Tenant must not be empty");
+                }
+                validate(tenant)?;
+                tenant.to_string()
+            }
+            None => String::new(),
+        };
+        let bucket = match bucket {
+            Some("") => return Err("Bucket name must not be empty"),
+            Some(bucket) => {
+                validate(bucket)?;
+                bucket.to_string()
+            }
+            None => String::new(),
+        };
+        Ok(Self { tenant, bucket })
+    }
+
+    // A namespace named outside a request, like in the ACL file
+    pub fn named(tenant: Option<&str>, bucket: Option<&str>) -> Result<Self, &'static str> {
+        for name in tenant.iter().chain(bucket.iter()) {
+            if name.is_empty() {
+                return Err("Tenant and bucket names must not be empty");
+            }
+            validate(name)?;
+        }
+        Ok(Self {
+            tenant: tenant.unwrap_or_default().to_string(),
+            bucket: bucket.unwrap_or_default().to_string(),
+        })
+    }
+
+    fn is_default(&self) -> bool {
+        self.tenant.is_empty() && self.bucket.is_empty()
+    }
+
+    // The tenant id, or None for the default tenant
+    pub fn tenant(&self) -> Option<&str> {
+        (!self.tenant.is_empty()).then_some(self.tenant.as_str())
+    }
+
+    // What every stored key of this namespace starts with
+    pub fn prefix(&self) -> String {
+        if self.is_default() {
+            String::new()
+        } else {
+            match self.tenant_prefix() {
+                Some(tenant) => format!("{tenant}{}{MARKER}", self.bucket),
+                None => format!("{MARKER}{}{MARKER}", self.bucket),
+            }
+        }
+    }
+
+    // What every stored key of this namespace's tenant starts with, across all
+    // its buckets, or None for the default tenant whose keys share no prefix
+    fn tenant_prefix(&self) -> Option<String> {
+        self.tenant()
+            .map(|tenant| format!("{MARKER}{MARKER}{tenant}{MARKER}"))
+    }
+
+    // The stored form of a key in this namespace
+    pub fn storage_key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix(), key)
+    }
+
+    // The client-facing key for a stored key, or None if it belongs elsewhere
+    pub fn client_key<'a>(&self, stored: &'a str) -> Option<&'a str> {
+        if self.is_default() {
+            (!stored.starts_with(MARKER)).then_some(stored)
+        } else {
+            stored.strip_prefix(&self.prefix())
+        }
+    }
+}
+
+// The tenant a stored key belongs to, or None for the default tenant
+pub fn tenant_of(stored: &str) -> Option<&str> {
+    let rest = stored.strip_prefix(MARKER)?.strip_prefix(MARKER)?;
+    rest.split_once(MARKER).map(|(tenant, _)| tenant)
+}
+
+// The key a stored key was named by, without its namespace
+pub fn client_key_of(stored: &str) -> &str {
+    match stored.rsplit_once(MARKER) {
+        Some((_, key)) => key,
+        None => stored,
+    }
+}
+
+// The tenant and bucket a stored key belongs to, None for the defaults, and
+// the key it was named by within them
+pub fn split_stored(stored: &str) -> (Option<&str>, Option<&str>, &str) {
+    let Some(rest) = stored.strip_prefix(MARKER) else {
+        return (None, None, stored);
+    };
+    let (tenant, rest) = match rest
+        .strip_prefix(MARKER)
+        .and_then(|rest| rest.split_once(MARKER))
+    {
+        Some((tenant, rest)) => (Some(tenant), rest),
+        None => (None, rest),
+    };
+    match rest.split_once(MARKER) {
+        Some((bucket, key)) => (tenant, (!bucket.is_empty()).then_some(bucket), key),
+        None => (tenant, None, rest),
+    }
+}
+
+// Reject names that could be confused with the namespace encoding
+pub fn validate(name: &str) -> Result<(), &'static str> {
+    if name.contains(MARKER) {
+        return Err("Names must not contain U+10FFFF");
+    }
+    Ok(())
+}
+
+// Per-key resources addressed as `/{key}/<name>`. Since keys may contain
+// slashes, a path ending in one of these names always means the resource, so a
+// key whose last segment is one of them can't be reached through the key routes.
+const SUB_RESOURCES: [&str; 9] = [
+    "ttl", "history", "meta", "touch", "rename", "copy", "restore", "incr", "decr",
+];
+
+// Split the key path captured by the key routes into the key and the
+// sub-resource it addresses, if any
+pub fn split_sub_resource(path: &str) -> (&str, Option<&'static str>) {
+    if let Some((key, last)) = path.rsplit_once('/') {
+        if let Some(name) = SUB_RESOURCES.iter().find(|&&name| name == last) {
+            return (key, Some(name));
+        }
+    }
+    (path, None)
+}
+
+// Longest key accepted, in bytes after URL decoding
+#[derive(Clone, Copy, Debug)]
+pub struct MaxKeyBytes(pub usize);
+
+// Check a client-supplied key. Every handler taking keys goes through this, so
+// reads, writes and deletes agree on which keys exist: a key must not be empty
+// or only whitespace, must fit the length limit, and must pass `validate`.
+// Keys may contain slashes, but every `/`-separated segment must be non-empty
+// and neither `.` nor `..`, so no two spellings name the same path.
+pub fn validate_key(key: &str, max: MaxKeyBytes) -> Result<(), String> {
+    if key.trim().is_empty() {
+        return Err("Key must not be empty or only whitespace".to_string());
+    }
+    if key
+        .split('/')
+        .any(|segment| segment.is_empty() || segment == "." || segment == "..")
+    {
+        return Err(
+            "Key segments must not be empty, \".\" or \"..\" (no leading, trailing or doubled slashes)"
+                .to_string(),
+        );
+    }
+    if key.len() > max.0 {
+        return Err(format!(
+            "Key is {} bytes, over the limit of {} bytes",
+            key.len(),
+            max.0
+        ));
+    }
+    validate(key).map_err(str::to_string)
+}
+
+async fn path_params<S: Send + Sync>(
+    parts: &mut Parts,
+    state: &S,
+) -> Result<HashMap<String, String>, ApiError> {
+    let Path(params) = Path::<HashMap<String, String>>::from_request_parts(parts, state).await?;
+    Ok(params)
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Namespace {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let params = path_params(parts, state).await?;
+        let bucket = params.get("bucket").map(String::as_str);
+        Namespace::resolve(&parts.headers, bucket).map_err(|msg| ApiError::BadRequest(msg.into()))
+    }
+}
+
+// The key captured by the key routes, without any sub-resource name, resolved
+// to its stored form within the request's namespace
+pub struct Key(pub String);
+
+impl<S: Send + Sync> FromRequestParts<S> for Key
+where
+    MaxKeyBytes: FromRef<S>,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let namespace = Namespace::from_request_parts(parts, state).await?;
+        let params = path_params(parts, state).await?;
+        let Some(path) = params.get("key") else {
+            return Err(ApiError::Internal);
+        };
+        let (key, sub_resource) = split_sub_resource(path);
+        validate_key(key, MaxKeyBytes::from_ref(state)).map_err(ApiError::BadRequest)?;
+        let key = namespace.storage_key(key);
+
+        // Checked before the store is touched, so a refusal says nothing about the key
+        let access = parts.extensions.get().cloned().unwrap_or(Access::Full);
+        if !access.allows(&key, Permission::required(&parts.method, sub_resource)) {
+            return Err(auth::forbidden());
+        }
+        Ok(Key(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: MaxKeyBytes = MaxKeyBytes(16);
+
+    #[test]
+    fn keys_must_not_be_empty_or_only_whitespace() {
+        // `/%20` and `/%09%0A` arrive decoded
+        for key in ["", " ", "\t\n", "\u{3000}"] {
+            assert!(validate_key(key, MAX).is_err(), "{:?}", key);
+        }
+        for key in [" a", "a b", "%20"] {
+            assert_eq!(validate_key(key, MAX), Ok(()), "{:?}", key);
+        }
+    }
+
+    #[test]
+    fn unicode_keys_are_limited_in_bytes() {
+        assert_eq!(validate_key("日本語", MAX), Ok(()));
+        assert_eq!(validate_key("café/métro", MAX), Ok(()));
+        // 5 characters, 20 bytes
+        assert!(validate_key("🔑🔑🔑🔑🔑", MAX).is_err());
+        let marker = format!("a{MARKER}b");
+        assert!(validate_key(&marker, MAX).is_err());
+    }
+
+    #[test]
+    fn the_length_limit_is_inclusive() {
+        assert_eq!(validate_key(&"k".repeat(16), MAX), Ok(()));
+        assert_eq!(
+            validate_key(&"k".repeat(17), MAX),
+            Err("Key is 17 bytes, over the limit of 16 bytes".to_string())
+        );
+        // Multi-byte characters are counted whole
+        assert_eq!(validate_key(&format!("{}é", "k".repeat(14)), MAX), Ok(()));
+        assert!(validate_key(&format!("{}é", "k".repeat(15)), MAX).is_err());
+    }
+
+    #[test]
+    fn segments_must_not_be_empty_or_relative() {
+        for key in ["/a", "a/", "a//b", "./a", "a/..", "a/./b"] {
+            assert!(validate_key(key, MAX).is_err(), "{:?}", key);
+        }
+        for key in ["a/b/c", "a.b/..c", "...", "%2F"] {
+            assert_eq!(validate_key(key, MAX), Ok(()), "{:?}", key);
+        }
+    }
+}
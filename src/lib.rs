@@ -0,0 +1,846 @@
+//! An HTTP key-value store.
+//!
+//! The `rust-kv` binary parses a [`Config`] and serves the router built here.
+//! The same router can be driven in-process, for tests or to embed the store
+//! in another program:
+//!
+//! ```
+//! use clap::Parser;
+//!
+//! let router = rust_kv::app(rust_kv::Config::parse_from(["rust-kv"]));
+//! # let _ = router;
+//! ```
+//!
+//! [`app`] serves requests but runs none of the background work, such as
+//! removing expired keys and writing snapshots; a [`Server`] does both.
+
+use axum::{
+    extract::{DefaultBodyLimit, FromRef, Path, Request, State},
+    handler::Handler,
+    http::{header, HeaderName, Method},
+    middleware::{from_fn, from_fn_with_state},
+    response::{IntoResponse, Response},
+    routing::{any, delete, get, post},
+    RequestExt, Router,
+};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinHandle;
+use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+mod auth;
+mod config;
+mod error;
+mod events;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod handlers;
+mod keyspace;
+mod memcache;
+mod metrics;
+mod middleware;
+mod ndjson;
+mod openapi;
+mod persistence;
+mod preload;
+mod quota;
+mod ratelimit;
+mod replication;
+mod resp;
+pub mod store;
+#[cfg(feature = "otlp")]
+pub mod telemetry;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+mod tombstones;
+mod wal;
+mod ws;
+
+pub use config::{Backend, Config};
+pub use store::Store;
+
+use error::ApiError;
+use handlers::*;
+use metrics::{Metrics, OpCounts};
+use middleware::{
+    admin_middleware, auth_middleware, follower_middleware, limit_body, metrics_middleware,
+    rate_limit_middleware, read_only_middleware, refused_origin_middleware, request_id_middleware,
+    timeout_middleware, Authenticator, RateLimitClient, RequestMetrics, WriteGate,
+    REQUEST_ID_HEADER,
+};
+use store::{entry_size, Entry, WriteView};
+
+// State shared by all handlers
+#[derive(Clone)]
+struct AppState {
+    store: Store,
+    metrics: Metrics,
+    wal: Option<wal::Wal>,
+    history_depth: usize,
+    quotas: Arc<quota::Quotas>,
+    // Asks the snapshot task for an immediate snapshot
+    snapshot_requests: Arc<Notify>,
+    // Whether there is a snapshot task, i.e. --snapshot-path is set
+    snapshots_enabled: bool,
+    // Set when soft delete is enabled
+    tombstones: Option<Arc<tombstones::Tombstones>>,
+    // Where writes are announced, for the change feed and watchers
+    events: Arc<events::Events>,
+    max_key_bytes: keyspace::MaxKeyBytes,
+    max_value_bytes: usize,
+    // Set when values written with PUT are compressed
+    compress_min_bytes: Option<usize>,
+    ops: Arc<OpCounts>,
+    readiness: Arc<AtomicU8>,
+    acl: Arc<auth::Acl>,
+    // Set while writes are refused; see `read_only_middleware`
+    read_only: Arc<AtomicBool>,
+    // Set on a follower: the secret its primary pushes writes with. Other
+    // writes are refused.
+    follower: Option<Arc<auth::ApiKeys>>,
+    // Set on a primary: the writes its follower has yet to acknowledge
+    backlog: Option<Arc<replication::Backlog>>,
+    // When POST /admin/compact last finished
+    last_compaction: Arc<Mutex<Option<SystemTime>>>,
+}
+
+impl AppState {
+    // Announce a put of `entry`, or a delete, to the change feed and watchers;
+    // call while holding the store write lock
+    fn record_event(&self, key: &str, entry: Option<&Entry>) {
+        self.events.record(key, entry);
+    }
+
+    // Announce the removal of `entry`, past its deadline, like a write
+    fn record_expiry(&self, key: &str, entry: &Entry) {
+        self.events.expire(key, entry);
+    }
+
+    // Announce the new deadline of a touched `entry`
+    fn record_touch(&self, key: &str, entry: &Entry) {
+        self.events.touch(key, entry);
+    }
+
+    // Queue a log record for a mutation; call while holding the store write lock
+    fn log(&self, record: impl FnOnce() -> wal::WalRecord) -> Option<wal::WalAck> {
+        let ack = self.wal.as_ref().map(|wal| wal.append(record()));
+        if let Some(backlog) = &self.backlog {
+            backlog.logged();
+        }
+        ack
+    }
+
+    // Whether `entry` may be stored under `key`: it must fit the store's byte
+    // budget and the quota of the key's tenant. Call before inserting, with
+    // `entry` already carrying its history.
+    fn check_room(&self, view: &dyn WriteView, key: &str, entry: &Entry) -> Result<(), NoRoom> {
+        let size = entry_size(key, entry);
+        if !view.has_room(key, size) {
+            return Err(NoRoom::Budget);
+        }
+        let Some(tenant) = keyspace::tenant_of(key) else {
+            return Ok(());
+        };
+        let Some(quota) = self.quotas.get(tenant) else {
+            return Ok(());
+        };
+        // Expired entries still count until they are swept
+        let previous = view.get(key).map(|entry| entry_size(key, &entry));
+        quota
+            .check(view.tenant_usage(tenant), previous, size)
+            .map_err(NoRoom::Quota)
+    }
+}
+
+impl FromRef<AppState> for keyspace::MaxKeyBytes {
+    fn from_ref(state: &AppState) -> Self {
+        state.max_key_bytes
+    }
+}
+
+// Why a value could not be stored
+enum NoRoom {
+    // Over the store-wide byte budget
+    Budget,
+    // Over its tenant's quota
+    Quota(quota::Exceeded),
+}
+
+// The application router, with every middleware, serving `state` as `config`
+// says. Background tasks are not started here.
+fn build_router(
+    config: &Config,
+    state: AppState,
+    authenticator: Authenticator,
+    rate_limiter: Option<Arc<ratelimit::RateLimiter<RateLimitClient>>>,
+) -> Router {
+    // Requests to these routes pass through without being counted, so scrapers
+    // and health checks don't skew the statistics
+    let mut excluded_routes = config.metrics_exclude_routes.clone();
+    excluded_routes.push(METRICS_ROUTE.to_string());
+    excluded_routes.push(HEALTHZ_ROUTE.to_string());
+    excluded_routes.push(READYZ_ROUTE.to_string());
+    let request_metrics = RequestMetrics {
+        metrics: state.metrics.clone(),
+        excluded_routes: Arc::new(excluded_routes.into_iter().collect()),
+    };
+
+    // Build the router. The fixed paths (/keys, /b/..., /batch/..., /txn, /admin/...,
+    // /events, /watch/..., /ws, /replication/apply, /metrics, /stats, /healthz,
+    // /readyz, /openapi.json, /docs) take precedence over the wildcard key route, so
+    // keys with exactly those names, or starting with `admin/`, can't be addressed as
+    // written; nested keys like `x/metrics` can. Routes are matched before
+    // percent-decoding, so encoding a character is the escape: `/%61dmin/flush` is the
+    // key `admin/flush`. Every key route is also served within a bucket.
+    let app = Router::new()
+        .merge(key_routes(config.max_value_bytes))
+        .nest("/b/{bucket}", key_routes(config.max_value_bytes))
+        .route("/b/{bucket}", delete(drop_bucket_handler))
+        .merge(batch_routes(config.max_batch_bytes))
+        .nest("/admin", admin_routes(authenticator.admin_tokens.clone()))
+        .merge(replication_routes(state.follower.clone()))
+        .route(METRICS_ROUTE, get(metrics_handler))
+        .route("/metrics/slow", get(slow_requests_handler))
+        .route("/stats", get(stats_handler))
+        .route("/events", get(events_handler))
+        .route("/watch", get(watch_prefix_handler))
+        .route("/watch/{*key}", get(watch_key_handler))
+        .route("/ws", get(ws::ws_handler))
+        .route(HEALTHZ_ROUTE, get(healthz_handler))
+        .route(READYZ_ROUTE, get(readyz_handler))
+        .route(OPENAPI_ROUTE, get(openapi::openapi_handler))
+        .route(DOCS_ROUTE, get(openapi::docs_handler))
+        .method_not_allowed_fallback(error::method_not_allowed)
+        .layer(from_fn_with_state(
+            WriteGate {
+                read_only: state.read_only.clone(),
+                follower: state.follower.is_some(),
+            },
+            read_only_middleware,
+        ))
+        .layer(from_fn_with_state(rate_limiter, rate_limit_middleware))
+        .layer(from_fn_with_state(authenticator, auth_middleware))
+        .layer(from_fn_with_state(
+            Duration::from_secs(config.request_timeout_secs),
+            timeout_middleware,
+        ))
+        .layer(CatchPanicLayer::custom(error::panic_response))
+        .layer(from_fn(error::error_middleware))
+        .layer(from_fn_with_state(request_metrics, metrics_middleware))
+        .layer(from_fn(request_id_middleware))
+        .with_state(state);
+
+    // Outermost, so preflights are answered before authentication and metrics
+    match cors_layer(config) {
+        Some(cors) => app.layer(cors).layer(from_fn(refused_origin_middleware)),
+        None => app,
+    }
+}
+
+// Operational routes, mounted at /admin. They take admin tokens rather than the
+// data credentials; see `admin_middleware`.
+fn admin_routes(admin_tokens: Arc<auth::ApiKeys>) -> Router<AppState> {
+    Router::new()
+        .route("/flush", post(flush_handler))
+        .route("/snapshot", post(snapshot_handler))
+        .route("/compact", post(compact_handler))
+        .route("/export", get(export_handler))
+        .route("/import", post(import_handler))
+        .route("/readonly", post(read_only_handler))
+        .route("/acl/reload", post(reload_acl_handler))
+        .route(
+            "/quota/{tenant}",
+            get(get_quota_handler)
+                .put(put_quota_handler)
+                .delete(delete_quota_handler),
+        )
+        .route("/metrics/reset", post(reset_metrics_handler))
+        .layer(from_fn_with_state(admin_tokens, admin_middleware))
+}
+
+// The route a primary pushes writes to, taking the replication secret rather
+// than the data credentials; see `follower_middleware`
+fn replication_routes(secret: Option<Arc<auth::ApiKeys>>) -> Router<AppState> {
+    Router::new()
+        .route(replication::APPLY_ROUTE, post(replication::apply_handler))
+        // The primary is trusted, and bounds its batches. Route layers, so
+        // requests no route matches are left to the fallback.
+        .route_layer(DefaultBodyLimit::disable())
+        .route_layer(from_fn_with_state(secret, follower_middleware))
+}
+
+// Routes addressing keys of one namespace, mounted at the root and under /b/{bucket}
+fn key_routes(max_value_bytes: usize) -> Router<AppState> {
+    Router::new()
+        .route(
+            "/keys",
+            get(list_keys_handler).delete(delete_prefix_handler),
+        )
+        .route("/{*key}", any(key_dispatch))
+        .layer(from_fn(move |req, next| {
+            limit_body(max_value_bytes, req, next)
+        }))
+        .layer(DefaultBodyLimit::disable())
+}
+
+// All requests for /{key} and /{key}/<sub-resource>. Keys may contain slashes,
+// so the key routes are a single wildcard, and the handler is chosen here from
+// the method and the path's last segment; see `keyspace::split_sub_resource`.
+// Other methods get 405 with the ones the resource does serve in Allow.
+async fn key_dispatch(State(state): State<AppState>, mut request: Request) -> Response {
+    let sub_resource = match request
+        .extract_parts::<Path<HashMap<String, String>>>()
+        .await
+    {
+        Ok(Path(params)) => params
+            .get("key")
+            .and_then(|path| keyspace::split_sub_resource(path).1),
+        Err(rejection) => return ApiError::from(rejection).into_response(),
+    };
+    let method = request.method().clone();
+
+    // Name the operation on the request span, since every one shares a route
+    macro_rules! dispatch {
+        ($operation:literal, $handler:expr) => {{
+            tracing::Span::current().record("kv.operation", $operation);
+            $handler.call(request, state).await
+        }};
+    }
+    match (sub_resource, method) {
+        (None, Method::GET) => dispatch!("get", get_handler),
+        (None, Method::HEAD) => dispatch!("head", head_handler),
+        (None, Method::PUT) => dispatch!("put", put_handler),
+        (None, Method::PATCH) => dispatch!("append", append_handler),
+        (None, Method::DELETE) => dispatch!("delete", delete_handler),
+        (Some("ttl"), Method::GET) => dispatch!("ttl", ttl_handler),
+        (Some("history"), Method::GET) => dispatch!("history", history_handler),
+        (Some("meta"), Method::GET) => dispatch!("meta", meta_handler),
+        (Some("touch"), Method::POST) => dispatch!("touch", touch_handler),
+        (Some("rename"), Method::POST) => dispatch!("rename", rename_handler),
+        (Some("copy"), Method::POST) => dispatch!("copy", copy_handler),
+        (Some("restore"), Method::POST) => dispatch!("restore", restore_handler),
+        (Some("incr"), Method::POST) => dispatch!("incr", incr_handler),
+        (Some("decr"), Method::POST) => dispatch!("decr", decr_handler),
+        (None, _) => ApiError::MethodNotAllowed(Some("GET,HEAD,PUT,PATCH,DELETE")).into_response(),
+        (Some("ttl" | "history" | "meta"), _) => {
+            ApiError::MethodNotAllowed(Some("GET")).into_response()
+        }
+        (Some(_), _) => ApiError::MethodNotAllowed(Some("POST")).into_response(),
+    }
+}
+
+// Routes reading or writing several keys of one namespace at once
+fn batch_routes(max_batch_bytes: usize) -> Router<AppState> {
+    Router::new()
+        .route("/batch/get", post(batch_get_handler))
+        .route("/batch/put", post(batch_put_handler))
+        .route("/txn", post(txn_handler))
+        .layer(from_fn(move |req, next| {
+            limit_body(max_batch_bytes, req, next)
+        }))
+        .layer(DefaultBodyLimit::disable())
+}
+
+// Response headers browsers let cross-origin callers read
+const CORS_EXPOSED_HEADERS: [HeaderName; 6] = [
+    header::ETAG,
+    header::LOCATION,
+    header::RETRY_AFTER,
+    header::WWW_AUTHENTICATE,
+    HeaderName::from_static(REQUEST_ID_HEADER),
+    HeaderName::from_static(VALUE_LENGTH_HEADER),
+];
+
+// The CORS policy from the command line, or None if no origin is allowed
+fn cors_layer(config: &Config) -> Option<CorsLayer> {
+    if config.cors_origins.is_empty() {
+        return None;
+    }
+    let origins = if config.cors_origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(config.cors_origins.iter().cloned())
+    };
+    tracing::info!(
+        "Allowing cross-origin requests from {:?}",
+        config.cors_origins
+    );
+    Some(
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(config.cors_methods.clone())
+            .allow_headers(config.cors_headers.clone())
+            .expose_headers(CORS_EXPOSED_HEADERS),
+    )
+}
+
+// How often clients idle past their rate limit are forgotten
+const RATE_LIMIT_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Route of GET /metrics, which is never counted in the metrics it serves
+const METRICS_ROUTE: &str = "/metrics";
+
+// Route of the liveness probe, also never counted
+const HEALTHZ_ROUTE: &str = "/healthz";
+
+// Route of the readiness probe, also never counted
+const READYZ_ROUTE: &str = "/readyz";
+
+// Where the admin routes are mounted
+const ADMIN_PREFIX: &str = "/admin/";
+
+// Routes of the OpenAPI document and the page rendering it
+const OPENAPI_ROUTE: &str = "/openapi.json";
+
+const DOCS_ROUTE: &str = "/docs";
+
+// Where the server is in its lifecycle, for GET /readyz. Stored in an AtomicU8
+// shared between main and the handlers.
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+enum Readiness {
+    // Recovering the store from disk
+    Starting,
+    Ready,
+    // Shutdown has begun; in-flight requests are finishing
+    Draining,
+}
+
+impl Readiness {
+    fn load(state: &AtomicU8) -> Self {
+        match state.load(Ordering::Relaxed) {
+            0 => Readiness::Starting,
+            1 => Readiness::Ready,
+            _ => Readiness::Draining,
+        }
+    }
+
+    fn store(self, state: &AtomicU8) {
+        state.store(self as u8, Ordering::Relaxed);
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Readiness::Starting => "starting",
+            Readiness::Ready => "ready",
+            Readiness::Draining => "draining",
+        }
+    }
+}
+
+/// A configured server: its store, opened and recovered, the router serving
+/// it, and the background tasks that maintain it.
+///
+/// The binary builds one from the command line; tests and embedders can build
+/// one from any [`Config`]:
+///
+/// ```no_run
+/// # async fn run() -> Result<(), String> {
+/// use clap::Parser;
+///
+/// let server = rust_kv::Server::new(rust_kv::Config::parse_from(["rust-kv"]))?;
+/// let (shutdown, stop) = tokio::sync::watch::channel(false);
+/// let tasks = server.spawn_tasks(stop);
+/// server.mark_ready();
+/// let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+/// axum::serve(listener, server.router()).await.unwrap();
+/// # let _ = (shutdown, tasks);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Server {
+    config: Config,
+    state: AppState,
+    authenticator: Authenticator,
+    rate_limiter: Option<Arc<ratelimit::RateLimiter<RateLimitClient>>>,
+    // Set with --replica-url
+    replication: Option<replication::Primary>,
+}
+
+impl Server {
+    /// Load the credentials and open the store `config` describes, recovering
+    /// its contents from disk. Fails if a credentials file or the store can't
+    /// be read, or if an admin token is also a data credential.
+    pub fn new(config: Config) -> Result<Self, String> {
+        let api_keys = auth::ApiKeys::load(&config.api_keys, config.api_key_file.as_deref())
+            .map_err(|e| format!("failed to load API keys: {}", e))?;
+        if api_keys.is_enabled() {
+            tracing::info!("Authenticating requests with {} API keys", api_keys.len());
+        }
+        let acl = auth::Acl::load(config.acl_file.clone())
+            .map_err(|e| format!("failed to load the ACL: {}", e))?;
+        if acl.is_enabled() {
+            tracing::info!("Loaded {} scoped tokens from the ACL", acl.len());
+        }
+        // Distinct from the data credentials, so a leaked data token can't reach them
+        let admin_tokens = auth::ApiKeys::load(&config.admin_tokens, None).unwrap_or_default();
+        if config
+            .admin_tokens
+            .iter()
+            .any(|token| api_keys.verify(token.trim()) || acl.lookup(token.trim()).is_some())
+        {
+            return Err("admin tokens must differ from every API key and ACL token".to_string());
+        }
+        if admin_tokens.is_enabled() {
+            tracing::info!("Admin routes accept {} admin tokens", admin_tokens.len());
+        } else {
+            tracing::info!("Admin routes are disabled; set --admin-token to enable them");
+        }
+        let acl = Arc::new(acl);
+        let authenticator = Authenticator {
+            api_keys: Arc::new(api_keys),
+            acl: acl.clone(),
+            admin_tokens: Arc::new(admin_tokens),
+        };
+        let rate_limiter = config.rate_limit.map(|rate| {
+            let burst = config.rate_limit_burst.unwrap_or(rate);
+            tracing::info!(
+                "Limiting each client to {} requests per second, bursts of {}",
+                rate,
+                burst
+            );
+            Arc::new(ratelimit::RateLimiter::new(f64::from(rate), burst))
+        });
+
+        // Open the configured storage backend, recovering its contents
+        let (store, wal) = store::open(&config)?;
+        let secret = config.replication_secret.as_deref().unwrap_or_default();
+        let replication = match &config.replica_url {
+            Some(url) => Some(replication::Primary::new(url, secret, wal.as_ref())?),
+            None => None,
+        };
+        let follower = config.follower.then(|| {
+            tracing::info!("Serving as a follower; writes are refused but for the primary's");
+            Arc::new(auth::ApiKeys::load(&[secret.to_string()], None).unwrap_or_default())
+        });
+
+        let state = AppState {
+            store,
+            metrics: Metrics::new(
+                Duration::from_secs(config.metrics_window_secs),
+                config.slow_ms.map(Duration::from_millis),
+            ),
+            wal,
+            history_depth: config.history_depth,
+            snapshot_requests: Arc::new(Notify::new()),
+            snapshots_enabled: config.snapshot_path.is_some(),
+            tombstones: config
+                .soft_delete_secs
+                .map(|secs| Arc::new(tombstones::Tombstones::new(Duration::from_secs(secs)))),
+            events: Arc::new(events::Events::new(
+                config.event_log_capacity.map(|capacity| capacity as usize),
+                config.event_log_values,
+            )),
+            max_key_bytes: keyspace::MaxKeyBytes(config.max_key_bytes as usize),
+            max_value_bytes: config.max_value_bytes,
+            compress_min_bytes: config.compress_min_bytes.map(|min| min as usize),
+            ops: Arc::new(OpCounts::default()),
+            readiness: Arc::new(AtomicU8::new(Readiness::Starting as u8)),
+            acl,
+            read_only: Arc::new(AtomicBool::new(config.read_only)),
+            follower,
+            backlog: replication.as_ref().map(|primary| primary.backlog.clone()),
+            last_compaction: Arc::default(),
+            quotas: Arc::new(quota::Quotas::new(quota::Quota {
+                max_keys: config.tenant_max_keys,
+                max_bytes: config.tenant_max_bytes,
+            })),
+        };
+        Ok(Self {
+            config,
+            state,
+            authenticator,
+            rate_limiter,
+            replication,
+        })
+    }
+
+    /// Load the --preload file into the store, if there is one, returning the
+    /// number of records loaded. Run it before serving, so requests never see
+    /// the store part-loaded. Like a [`TestServer`](test_util::TestServer)
+    /// fixture, the records bypass the write-ahead log: they're loaded again on
+    /// every start, and only saved by snapshots.
+    pub async fn preload(&self) -> Result<usize, String> {
+        match &self.config.preload {
+            Some(path) => preload::load(&self.state, path, self.config.preload_strict).await,
+            None => Ok(0),
+        }
+    }
+
+    /// The configuration the server was built from
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The store requests are served from
+    pub fn store(&self) -> &Store {
+        &self.state.store
+    }
+
+    /// The router serving every route, with all of its middleware. Routers
+    /// from one server share its store and metrics.
+    pub fn router(&self) -> Router {
+        build_router(
+            &self.config,
+            self.state.clone(),
+            self.authenticator.clone(),
+            self.rate_limiter.clone(),
+        )
+    }
+
+    /// Start the background tasks: the metrics log, removal of expired and
+    /// soft-deleted keys, forgetting idle rate-limited clients, snapshots, and
+    /// pushing writes to the --replica-url follower.
+    /// They stop once `shutdown` is set to true; the snapshot task writes a
+    /// final snapshot first, so await the handles before exiting.
+    pub fn spawn_tasks(&self, shutdown: watch::Receiver<bool>) -> Vec<JoinHandle<()>> {
+        let config = &self.config;
+        let mut tasks = Vec::new();
+
+        // Log a metrics summary periodically, unless disabled. Intervals
+        // without requests are skipped.
+        if config.metrics_log_interval > 0 {
+            let metrics = self.state.metrics.clone();
+            let ops = self.state.ops.clone();
+            let mut shutdown = shutdown.clone();
+            let period = Duration::from_secs(config.metrics_log_interval);
+            tasks.push(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(period);
+                let mut last_total = 0;
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = shutdown.changed() => break,
+                    }
+                    let statuses = metrics.status_counts();
+                    let total: u64 = statuses.codes.values().sum();
+                    if total == last_total {
+                        continue;
+                    }
+                    last_total = total;
+
+                    let (p50, p95, p99, count) = metrics.get_percentiles();
+                    let throughput = metrics.throughput();
+                    tracing::info!(
+                        requests = count,
+                        window_secs = metrics.window().as_secs(),
+                        p50_ms = p50,
+                        p95_ms = p95,
+                        p99_ms = p99,
+                        rps = throughput.total.current,
+                        rps_1m = throughput.total.minute,
+                        responses = %statuses.summary(),
+                        lookups = %ops.lookup_summary(),
+                        "metrics summary"
+                    );
+                    for route in metrics.route_percentiles() {
+                        tracing::info!(
+                            method = %route.method,
+                            route = %route.route,
+                            requests = route.count,
+                            p50_ms = route.p50,
+                            p95_ms = route.p95,
+                            p99_ms = route.p99,
+                            "route latency"
+                        );
+                    }
+                }
+            }));
+        }
+
+        // Remove expired keys
+        let state = self.state.clone();
+        let sweep_interval = Duration::from_millis(config.sweep_interval_ms.max(1));
+        let mut sweeper_shutdown = shutdown.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(sweep_interval);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = sweeper_shutdown.changed() => break,
+                }
+                let removed = |key: &str, entry: &Entry| state.record_expiry(key, entry);
+                match store::sweep_expired(&state.store, &removed).await {
+                    Ok(0) => {}
+                    Ok(evicted) => tracing::debug!("Sweeper evicted {} expired keys", evicted),
+                    Err(e) => tracing::error!("Sweeper failed: {}", e),
+                }
+            }
+        }));
+
+        // Forget clients idle long enough for their rate limit to have reset
+        if let Some(limiter) = self.rate_limiter.clone() {
+            let mut shutdown = shutdown.clone();
+            tasks.push(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(RATE_LIMIT_PURGE_INTERVAL);
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = shutdown.changed() => break,
+                    }
+                    let purged = limiter.purge(Instant::now());
+                    if purged > 0 {
+                        tracing::debug!("Dropped rate limits of {} idle clients", purged);
+                    }
+                }
+            }));
+        }
+
+        // Drop soft-deleted keys past their window
+        if let Some(tombstones) = self.state.tombstones.clone() {
+            let mut shutdown = shutdown.clone();
+            tasks.push(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(sweep_interval);
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = shutdown.changed() => break,
+                    }
+                    let purged = tombstones.purge(Instant::now());
+                    if purged > 0 {
+                        tracing::debug!("Purged {} soft-deleted keys", purged);
+                    }
+                }
+            }));
+        }
+
+        // Push writes to the follower as they're logged
+        if let Some(primary) = self.replication.clone() {
+            let store = self.state.store.clone();
+            tasks.push(tokio::spawn(primary.run(store, shutdown.clone())));
+        }
+
+        // Persist snapshots periodically, on request, and once more on
+        // shutdown. All snapshot jobs run through this one task so they never
+        // overlap.
+        if let Some(path) = config.snapshot_path.clone() {
+            let store = self.state.store.clone();
+            let wal = self.state.wal.clone();
+            let requests = self.state.snapshot_requests.clone();
+            let snapshot_interval = Duration::from_secs(config.snapshot_interval_secs.max(1));
+            let mut shutdown = shutdown.clone();
+            tasks.push(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(snapshot_interval);
+                // The first tick completes immediately; there's nothing new to save yet
+                interval.tick().await;
+                loop {
+                    let on_demand = tokio::select! {
+                        _ = interval.tick() => false,
+                        _ = requests.notified() => true,
+                        _ = shutdown.changed() => break,
+                    };
+                    let result =
+                        persistence::snapshot_blocking(store.clone(), path.clone(), wal.clone())
+                            .await;
+                    match result {
+                        Ok((keys, bytes)) if on_demand => {
+                            tracing::info!(
+                                "Wrote on-demand snapshot: {} keys, {} bytes",
+                                keys,
+                                bytes
+                            )
+                        }
+                        Ok((keys, bytes)) => {
+                            tracing::debug!("Wrote snapshot: {} keys, {} bytes", keys, bytes)
+                        }
+                        Err(e) => tracing::error!("Failed to write snapshot: {}", e),
+                    }
+                }
+
+                match persistence::snapshot_blocking(store, path, wal).await {
+                    Ok((keys, bytes)) => {
+                        tracing::info!("Wrote final snapshot: {} keys, {} bytes", keys, bytes)
+                    }
+                    Err(e) => tracing::error!("Failed to write final snapshot: {}", e),
+                }
+            }));
+        }
+
+        tasks
+    }
+
+    /// Serve the Redis protocol on `listener` until `shutdown` is set to
+    /// true, over the same store and credentials as the router. Connections
+    /// close at shutdown once their current command has been answered.
+    pub async fn serve_resp(
+        &self,
+        listener: tokio::net::TcpListener,
+        shutdown: watch::Receiver<bool>,
+    ) {
+        let resp = resp::Resp {
+            state: self.state.clone(),
+            authenticator: self.authenticator.clone(),
+        };
+        resp.serve(listener, shutdown).await
+    }
+
+    /// Serve the memcached text protocol on `listener` until `shutdown` is
+    /// set to true, over the same store as the router. Connections close at
+    /// shutdown once their current command has been answered.
+    pub async fn serve_memcache(
+        &self,
+        listener: tokio::net::TcpListener,
+        shutdown: watch::Receiver<bool>,
+    ) {
+        let memcache = memcache::Memcache {
+            state: self.state.clone(),
+        };
+        memcache.serve(listener, shutdown).await
+    }
+
+    /// Serve the gRPC service on `listener` until `shutdown` is set to true,
+    /// over the same store, credentials and metrics as the router. Running
+    /// RPCs are let finish at shutdown.
+    #[cfg(feature = "grpc")]
+    pub async fn serve_grpc(
+        &self,
+        listener: tokio::net::TcpListener,
+        shutdown: watch::Receiver<bool>,
+    ) {
+        let grpc = grpc::Grpc {
+            state: self.state.clone(),
+            authenticator: self.authenticator.clone(),
+        };
+        grpc.serve(listener, shutdown).await
+    }
+
+    /// Ask the snapshot task for a snapshot now. Returns false, doing
+    /// nothing, if snapshots aren't configured.
+    pub fn request_snapshot(&self) -> bool {
+        if self.state.snapshots_enabled {
+            self.state.snapshot_requests.notify_one();
+        }
+        self.state.snapshots_enabled
+    }
+
+    /// Report the server ready on GET /readyz. It reports "starting" until
+    /// this is called.
+    pub fn mark_ready(&self) {
+        Readiness::Ready.store(&self.state.readiness);
+    }
+
+    /// Report the server draining on GET /readyz, once shutdown has begun,
+    /// and end the streams of watchers and WebSocket sessions
+    pub fn mark_draining(&self) {
+        Readiness::Draining.store(&self.state.readiness);
+        self.state.events.close();
+    }
+}
+
+/// The router for `config`, ready to serve, without background tasks: keys
+/// still expire, but are only removed when next accessed, and no snapshots
+/// are written. Use a [`Server`] to run those too.
+///
+/// # Panics
+///
+/// If [`Server::new`] fails, for example when the store can't be opened.
+pub fn app(config: Config) -> Router {
+    let server = Server::new(config).unwrap_or_else(|e| panic!("{}", e));
+    server.mark_ready();
+    server.router()
+}
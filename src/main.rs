@@ -1,178 +1,429 @@
-use axum::{
-    extract::{Path, Request, State},
-    http::StatusCode,
-    middleware::{self, Next},
-    response::{IntoResponse, Response},
-    routing::{get, put},
-    Router,
-};
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
-use std::time::{Duration, Instant};
-
-type Store = Arc<RwLock<HashMap<String, String>>>;
-
-// Latency metrics storage
-#[derive(Clone)]
-struct Metrics {
-    latencies: Arc<RwLock<Vec<Duration>>>,
-}
+use clap::{CommandFactory, Parser};
+use rust_kv::{Backend, Config, Server};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
 
-impl Metrics {
-    fn new() -> Self {
-        Self {
-            latencies: Arc::new(RwLock::new(Vec::new())),
-        }
-    }
+mod config_file;
 
-    fn record(&self, duration: Duration) {
-        let mut latencies = self.latencies.write().unwrap();
-        latencies.push(duration);
+#[tokio::main]
+async fn main() {
+    // Parse the configuration from the command line, environment and file
+    let args: Vec<std::ffi::OsString> = std::env::args_os().collect();
+    let matches = Config::command().get_matches_from(&args);
+    let resolved = config_file::resolve(
+        &Config::command(),
+        &matches,
+        args,
+        matches.get_one::<PathBuf>("config").map(PathBuf::as_path),
+        matches.get_flag("strict"),
+    )
+    .unwrap_or_else(|e| {
+        Config::command()
+            .error(clap::error::ErrorKind::InvalidValue, e)
+            .exit()
+    });
+    let config = Config::parse_from(resolved.args);
+    #[cfg(not(unix))]
+    if config.unix_socket.is_some() {
+        Config::command()
+            .error(
+                clap::error::ErrorKind::InvalidValue,
+                "--unix-socket is only supported on Unix",
+            )
+            .exit();
     }
-
-    fn get_percentiles(&self) -> (f64, f64, f64, usize) {
-        let mut latencies = self.latencies.write().unwrap();
-
-        if latencies.is_empty() {
-            return (0.0, 0.0, 0.0, 0);
-        }
-
-        latencies.sort();
-
-        let len = latencies.len();
-        let p50_idx = (len as f64 * 0.50) as usize;
-        let p95_idx = (len as f64 * 0.95) as usize;
-        let p99_idx = (len as f64 * 0.99) as usize;
-
-        let p50 = latencies[p50_idx.min(len - 1)].as_micros() as f64 / 1000.0;
-        let p95 = latencies[p95_idx.min(len - 1)].as_micros() as f64 / 1000.0;
-        let p99 = latencies[p99_idx.min(len - 1)].as_micros() as f64 / 1000.0;
-
-        (p50, p95, p99, len)
+    if config.backend == Backend::Sled
+        && (config.snapshot_path.is_some()
+            || config.wal_path.is_some()
+            || config.max_keys.is_some())
+    {
+        Config::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--snapshot-path, --wal-path and --max-keys only apply to the memory backend",
+            )
+            .exit();
+    }
+    if config.port == 0 && !config.any_port {
+        Config::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                "--port 0 picks a random port; pass --any-port as well if that is intended",
+            )
+            .exit();
     }
-}
 
-#[tokio::main]
-async fn main() {
-    // Initialize tracing for logging
+    // Initialize tracing for logging, exporting spans too if configured
+    #[cfg(feature = "otlp")]
+    let telemetry = match config.otlp_endpoint.as_deref() {
+        Some(endpoint) => match rust_kv::telemetry::init(endpoint) {
+            Ok(telemetry) => Some(telemetry),
+            Err(e) => {
+                eprintln!("Failed to set up OTLP export to {}: {}", endpoint, e);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            tracing_subscriber::fmt::init();
+            None
+        }
+    };
+    #[cfg(not(feature = "otlp"))]
     tracing_subscriber::fmt::init();
+    for warning in &resolved.warnings {
+        tracing::warn!("{}", warning);
+    }
 
-    // Initialize the in-memory store
-    let store: Store = Arc::new(RwLock::new(HashMap::new()));
-
-    // Initialize metrics
-    let metrics = Metrics::new();
-
-    // Clone metrics for the background task BEFORE using it in the router
-    let metrics_clone = metrics.clone();
-
-    // Create a clone for the middleware
-    let middleware_metrics = metrics.clone();
-
-    // Build the router
-    let app = Router::new()
-        .route(
-            "/{key}",
-            put(put_handler).get(get_handler).delete(delete_handler),
-        )
-        .route("/metrics", get(metrics_handler))
-        .layer(middleware::from_fn(move |req, next| {
-            let metrics_clone = middleware_metrics.clone();
-            async move {
-                let start = Instant::now();
-                let response = latency_middleware(req, next).await;
-                let duration = start.elapsed();
-                metrics_clone.record(duration);
-                response
+    let server = match Server::new(config.clone()) {
+        Ok(server) => server,
+        Err(e) => {
+            tracing::error!("Failed to start: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = server.preload().await {
+        tracing::error!("Failed to preload: {}", e);
+        std::process::exit(1);
+    }
+    let store = server.store().clone();
+    let app = server.router();
+
+    // Background tasks watch this channel and stop once shutdown begins
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut tasks = server.spawn_tasks(shutdown_rx.clone());
+
+    // Take snapshots on demand via SIGUSR1, and keep handling the signal
+    // without --snapshot-path so it doesn't terminate the process
+    let mut trigger = SnapshotTrigger::new();
+    let mut trigger_shutdown = shutdown_rx.clone();
+    let trigger_server = server.clone();
+    tasks.push(tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = trigger.recv() => {
+                    if !trigger_server.request_snapshot() {
+                        tracing::warn!("Snapshot requested but no --snapshot-path is configured")
+                    }
+                }
+                _ = trigger_shutdown.changed() => break,
             }
-        }))
-        .with_state((store, metrics));
+        }
+    }));
+
+    // Load the TLS certificate before binding, so a bad one fails startup
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(load_tls(cert, key).await),
+        _ => None,
+    };
+    #[cfg(unix)]
+    if let (Some(tls), Some(cert), Some(key)) = (&tls, &config.tls_cert, &config.tls_key) {
+        tasks.push(tokio::spawn(reload_tls_on_hangup(
+            tls.clone(),
+            cert.clone(),
+            key.clone(),
+            shutdown_rx.clone(),
+        )));
+    }
+    let scheme = if tls.is_some() { "https" } else { "http" };
 
-    // Spawn a background task to print metrics every 10 seconds
+    // Run the server
+    let listener = if config.no_tcp {
+        None
+    } else {
+        let address = SocketAddr::new(config.bind, config.port);
+        let listener = match tokio::net::TcpListener::bind(address).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                tracing::error!("Failed to listen on {}: {}", address, e);
+                std::process::exit(1);
+            }
+        };
+        let address = listener.local_addr().unwrap_or(address);
+        tracing::info!("Server running on {}://{}", scheme, address);
+        tracing::info!("Metrics available at {}://{}/metrics", scheme, address);
+        Some(listener)
+    };
+    #[cfg(unix)]
+    let unix_listener = config.unix_socket.as_deref().map(|path| {
+        let listener = bind_unix_socket(path, config.socket_mode).unwrap_or_else(|e| {
+            tracing::error!("Failed to listen on {}: {}", path.display(), e);
+            std::process::exit(1);
+        });
+        tracing::info!("Server running on unix:{}", path.display());
+        listener
+    });
+    let resp_listener = match config.resp_port {
+        Some(port) => Some(bind_protocol(config.bind, port, "Redis protocol").await),
+        None => None,
+    };
+    let memcache_listener = match config.memcache_port {
+        Some(port) => Some(bind_protocol(config.bind, port, "Memcached protocol").await),
+        None => None,
+    };
+    #[cfg(feature = "grpc")]
+    let grpc_listener = match config.grpc_port {
+        Some(port) => Some(bind_protocol(config.bind, port, "gRPC service").await),
+        None => None,
+    };
+
+    // Stop accepting connections on SIGTERM/Ctrl-C, then give in-flight
+    // requests up to the drain timeout to finish
+    let draining = Arc::new(Notify::new());
+    let signal_draining = draining.clone();
+    let signal_server = server.clone();
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let shutdown = shutdown_signal();
+    server.mark_ready();
     tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(10));
-        loop {
-            interval.tick().await;
-            let (p50, p95, p99, count) = metrics_clone.get_percentiles();
-            println!("\n📊 Latency Metrics (last {} requests):", count);
-            println!("   P50: {:.2}ms", p50);
-            println!("   P95: {:.2}ms", p95);
-            println!("   P99: {:.2}ms", p99);
-        }
+        shutdown.await;
+        tracing::info!("Shutdown requested, draining in-flight requests");
+        signal_server.mark_draining();
+        signal_draining.notify_one();
+        let _ = stop_tx.send(true);
     });
+    if let Some(listener) = resp_listener {
+        let resp_server = server.clone();
+        let stop = stop_rx.clone();
+        tasks.push(tokio::spawn(async move {
+            resp_server.serve_resp(listener, stop).await
+        }));
+    }
+    if let Some(listener) = memcache_listener {
+        let memcache_server = server.clone();
+        let stop = stop_rx.clone();
+        tasks.push(tokio::spawn(async move {
+            memcache_server.serve_memcache(listener, stop).await
+        }));
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(listener) = grpc_listener {
+        let grpc_server = server.clone();
+        let stop = stop_rx.clone();
+        tasks.push(tokio::spawn(async move {
+            grpc_server.serve_grpc(listener, stop).await
+        }));
+    }
 
-    // Run the server
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    // Unix socket clients have no address, so they share one rate limit
+    #[cfg(unix)]
+    let unix_server = {
+        let app = app.clone().into_make_service();
+        let stop = stopped(stop_rx.clone());
+        async move {
+            match unix_listener {
+                Some(listener) => {
+                    axum::serve(listener, app)
+                        .with_graceful_shutdown(stop)
+                        .await
+                }
+                None => Ok(()),
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let unix_server = std::future::ready(Ok(()));
+
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    let tcp_server = async move {
+        match (listener, tls) {
+            (None, _) => Ok(()),
+            (Some(listener), Some(tls)) => {
+                let handle = axum_server::Handle::new();
+                let shutdown_handle = handle.clone();
+                tokio::spawn(async move {
+                    stopped(stop_rx).await;
+                    shutdown_handle.graceful_shutdown(None);
+                });
+                axum_server::from_tcp_rustls(listener.into_std()?, tls)?
+                    .handle(handle)
+                    .serve(app)
+                    .await
+            }
+            (Some(listener), None) => {
+                axum::serve(listener, app)
+                    .with_graceful_shutdown(stopped(stop_rx))
+                    .await
+            }
+        }
+    };
 
-    println!("Server running on http://127.0.0.1:3000");
-    println!("Metrics available at http://127.0.0.1:3000/metrics");
+    let drain_timeout = Duration::from_secs(config.drain_timeout_secs);
+    tokio::select! {
+        result = async { tokio::try_join!(tcp_server, unix_server) } => {
+            if let Err(e) = result {
+                tracing::error!("Server failed: {}", e);
+            }
+        }
+        _ = async {
+            draining.notified().await;
+            tokio::time::sleep(drain_timeout).await;
+        } => tracing::warn!("Drain timeout elapsed, dropping remaining connections"),
+    }
+    #[cfg(unix)]
+    if let Some(path) = &config.unix_socket {
+        let _ = std::fs::remove_file(path);
+    }
 
-    axum::serve(listener, app).await.unwrap();
+    // Stop the background tasks and wait for the final snapshot
+    let _ = shutdown_tx.send(true);
+    for task in tasks {
+        let _ = task.await;
+    }
+    if let Err(e) = store.flush() {
+        tracing::error!("Failed to flush store: {}", e);
+    }
+    tracing::info!("Shutdown complete");
+    #[cfg(feature = "otlp")]
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown();
+    }
 }
 
-// Middleware to track latency
-async fn latency_middleware(request: Request, next: Next) -> Response {
-    let start = Instant::now();
-
-    // Call the next handler
-    let response = next.run(request).await;
-
-    let duration = start.elapsed();
-
-    // Log each request
-    tracing::info!("Request took {:.2}ms", duration.as_micros() as f64 / 1000.0);
+// Listen on a Unix domain socket at `path`, first removing a socket left
+// there by an earlier run. Anything else at the path is left alone and the
+// bind fails.
+#[cfg(unix)]
+fn bind_unix_socket(
+    path: &std::path::Path,
+    mode: Option<u32>,
+) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
 
-    response
+// Listen on `port` of `bind` to serve `what` on, like "Redis protocol", exiting
+// if it can't
+async fn bind_protocol(bind: IpAddr, port: u16, what: &str) -> tokio::net::TcpListener {
+    let address = SocketAddr::new(bind, port);
+    match tokio::net::TcpListener::bind(address).await {
+        Ok(listener) => {
+            let address = listener.local_addr().unwrap_or(address);
+            tracing::info!("{} available on {}", what, address);
+            listener
+        }
+        Err(e) => {
+            tracing::error!("Failed to listen on {}: {}", address, e);
+            std::process::exit(1);
+        }
+    }
 }
 
-// PUT /{key} - Create or update a key-value pair
-async fn put_handler(
-    State((store, _metrics)): State<(Store, Metrics)>,
-    Path(key): Path<String>,
-    body: String,
-) -> impl IntoResponse {
-    let mut map = store.write().unwrap();
-    map.insert(key, body);
-    StatusCode::OK
+// Resolves once `stop` is set
+async fn stopped(mut stop: watch::Receiver<bool>) {
+    let _ = stop.wait_for(|&stop| stop).await;
 }
 
-// GET /{key} - Retrieve a value by key
-async fn get_handler(
-    State((store, _metrics)): State<(Store, Metrics)>,
-    Path(key): Path<String>,
-) -> impl IntoResponse {
-    let map = store.read().unwrap();
+// Read a PEM certificate chain and private key for serving HTTPS, exiting if
+// either can't be read or they don't belong together
+async fn load_tls(
+    cert: &std::path::Path,
+    key: &std::path::Path,
+) -> axum_server::tls_rustls::RustlsConfig {
+    // Only ring is compiled in, so this can't clash with another provider
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    match axum_server::tls_rustls::RustlsConfig::from_pem_file(cert, key).await {
+        Ok(tls) => {
+            tracing::info!("Serving HTTPS with the certificate in {}", cert.display());
+            tls
+        }
+        Err(e) => {
+            tracing::error!(
+                "Failed to load TLS certificate {} and key {}: {}",
+                cert.display(),
+                key.display(),
+                e
+            );
+            std::process::exit(1);
+        }
+    }
+}
 
-    match map.get(&key) {
-        Some(value) => (StatusCode::OK, value.clone()),
-        None => (StatusCode::NOT_FOUND, String::new()),
+// Reload the TLS certificate and key from their files on every SIGHUP until
+// shutdown. New connections use the reloaded certificate; if it fails to load,
+// the previous one stays in use.
+#[cfg(unix)]
+async fn reload_tls_on_hangup(
+    tls: axum_server::tls_rustls::RustlsConfig,
+    cert: PathBuf,
+    key: PathBuf,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("failed to install SIGHUP handler");
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {}
+            _ = shutdown.changed() => break,
+        }
+        match tls.reload_from_pem_file(&cert, &key).await {
+            Ok(()) => tracing::info!("Reloaded TLS certificate from {}", cert.display()),
+            Err(e) => tracing::error!(
+                "Failed to reload TLS certificate, keeping the old one: {}",
+                e
+            ),
+        }
     }
 }
 
-// DELETE /{key} - Deletes a value by key
-async fn delete_handler(
-    State((store, _metrics)): State<(Store, Metrics)>,
-    Path(key): Path<String>,
-) -> impl IntoResponse {
-    let mut map = store.write().unwrap();
+// Source of on-demand snapshot requests: SIGUSR1 on Unix, never elsewhere
+struct SnapshotTrigger {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
 
-    match map.remove(&key) {
-        Some(_) => StatusCode::NO_CONTENT,
-        None => StatusCode::NOT_FOUND,
+impl SnapshotTrigger {
+    fn new() -> Self {
+        Self {
+            #[cfg(unix)]
+            signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())
+                .expect("failed to install SIGUSR1 handler"),
+        }
     }
-}
 
-// GET /metrics - Get current latency metrics
-async fn metrics_handler(State((_store, metrics)): State<(Store, Metrics)>) -> impl IntoResponse {
-    let (p50, p95, p99, count) = metrics.get_percentiles();
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        self.signal.recv().await;
 
-    let response = format!(
-        "Latency Metrics (last {} requests)\n\
-         P50: {:.2}ms\n\
-         P95: {:.2}ms\n\
-         P99: {:.2}ms\n",
-        count, p50, p95, p99
-    );
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    }
+}
 
-    (StatusCode::OK, response)
+// Resolves when the process receives Ctrl-C or, on Unix, SIGTERM. The SIGTERM
+// handler is installed before this returns, so a signal sent as soon as the
+// server reports ready isn't left to kill the process outright.
+fn shutdown_signal() -> impl std::future::Future<Output = ()> {
+    #[cfg(unix)]
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("failed to install SIGTERM handler");
+
+    async move {
+        let ctrl_c = async {
+            tokio::signal::ctrl_c()
+                .await
+                .expect("failed to install Ctrl-C handler");
+        };
+
+        #[cfg(unix)]
+        let terminate = terminate.recv();
+
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = ctrl_c => {}
+            _ = terminate => {}
+        }
+    }
 }
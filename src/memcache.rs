@@ -0,0 +1,681 @@
+// A memcached text protocol listener over the same store as the HTTP routes,
+// so memcached clients can read and write the keys of the default namespace.
+// Supports set, add, replace, get with any number of keys, delete, flush_all,
+// version and quit. The protocol has no authentication, so the listener can't
+// be enabled along with API keys or an ACL.
+
+use crate::keyspace::{self, Namespace};
+use crate::store::Entry;
+use crate::{handlers, wal, AppState, NoRoom};
+use bytes::{Bytes, BytesMut};
+use std::ops::Range;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+// Longest command line accepted, as in memcached
+const MAX_LINE: usize = 2048;
+
+// Longest key memcached accepts; the store's own limit applies too
+const MAX_KEY: usize = 250;
+
+// Exptimes up to 30 days are seconds from now, and larger ones Unix times
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+const BAD_FORMAT: &str = "CLIENT_ERROR bad command line format";
+
+// A request the connection can't recover from, like a value too large to
+// buffer. The connection is closed after replying with the message.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ProtocolError(&'static str);
+
+// Which storage command a value came with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Mode {
+    Set,
+    // Only stores the value if the key doesn't exist
+    Add,
+    // Only stores the value if the key exists
+    Replace,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Command {
+    Store {
+        mode: Mode,
+        key: String,
+        flags: u32,
+        exptime: i64,
+        // The value, as a range of the buffer it came from
+        value: Range<usize>,
+        noreply: bool,
+    },
+    Get(Vec<String>),
+    Delete {
+        key: String,
+        noreply: bool,
+    },
+    FlushAll {
+        noreply: bool,
+    },
+    Version,
+    Quit,
+}
+
+// A request, or the error line answering one that couldn't be understood.
+// Either way the connection carries on with whatever follows it.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Parsed {
+    Command(Command),
+    Invalid(&'static str),
+}
+
+// Parse the request at the start of `buf`, and how many bytes of it the request
+// took up. Returns None until the whole request, value included, has arrived.
+pub(crate) fn parse(
+    buf: &[u8],
+    max_value: usize,
+) -> Result<Option<(Parsed, usize)>, ProtocolError> {
+    let Some(end) = buf.iter().position(|&byte| byte == b'\n') else {
+        if buf.len() > MAX_LINE {
+            return Err(ProtocolError("CLIENT_ERROR line too long"));
+        }
+        return Ok(None);
+    };
+    let used = end + 1;
+    let line = buf[..end].strip_suffix(b"\r").unwrap_or(&buf[..end]);
+    let words: Vec<&[u8]> = line
+        .split(|&byte| byte == b' ')
+        .filter(|word| !word.is_empty())
+        .collect();
+    let Some((&name, args)) = words.split_first() else {
+        return Ok(Some((Parsed::Invalid("ERROR"), used)));
+    };
+
+    let command = match name {
+        b"set" | b"add" | b"replace" => {
+            let mode = match name {
+                b"set" => Mode::Set,
+                b"add" => Mode::Add,
+                _ => Mode::Replace,
+            };
+            let (args, noreply) = noreply(args);
+            let [key, flags, exptime, bytes] = args else {
+                return Ok(Some((Parsed::Invalid("ERROR"), used)));
+            };
+            let (Ok(key), Some(flags), Some(exptime), Some(bytes)) = (
+                parse_key(key),
+                number::<u32>(flags),
+                number::<i64>(exptime),
+                number::<usize>(bytes),
+            ) else {
+                return Ok(Some((Parsed::Invalid(BAD_FORMAT), used)));
+            };
+            if bytes > max_value {
+                return Err(ProtocolError("SERVER_ERROR object too large for cache"));
+            }
+            let Some(rest) = buf.get(used + bytes..).filter(|rest| rest.len() >= 2) else {
+                return Ok(None);
+            };
+            if !rest.starts_with(b"\r\n") {
+                // The value ran past its length, so skip the rest of its line
+                let error = Parsed::Invalid("CLIENT_ERROR bad data chunk");
+                return match rest.iter().position(|&byte| byte == b'\n') {
+                    Some(end) => Ok(Some((error, used + bytes + end + 1))),
+                    None if rest.len() > MAX_LINE => {
+                        Err(ProtocolError("CLIENT_ERROR line too long"))
+                    }
+                    None => Ok(None),
+                };
+            }
+            return Ok(Some((
+                Parsed::Command(Command::Store {
+                    mode,
+                    key,
+                    flags,
+                    exptime,
+                    value: used..used + bytes,
+                    noreply,
+                }),
+                used + bytes + 2,
+            )));
+        }
+        b"get" if !args.is_empty() => match args.iter().map(|key| parse_key(key)).collect() {
+            Ok(keys) => Command::Get(keys),
+            Err(error) => return Ok(Some((Parsed::Invalid(error), used))),
+        },
+        b"delete" => {
+            // A time of 0 was once allowed here, and still is
+            let (args, noreply) = noreply(args);
+            match args {
+                [key] | [key, b"0"] => match parse_key(key) {
+                    Ok(key) => Command::Delete { key, noreply },
+                    Err(error) => return Ok(Some((Parsed::Invalid(error), used))),
+                },
+                _ => return Ok(Some((Parsed::Invalid(BAD_FORMAT), used))),
+            }
+        }
+        b"flush_all" => {
+            let (args, noreply) = noreply(args);
+            match args {
+                [] | [b"0"] => Command::FlushAll { noreply },
+                [delay] if number::<u32>(delay).is_some() => {
+                    let error = "CLIENT_ERROR delayed flush_all is not supported";
+                    return Ok(Some((Parsed::Invalid(error), used)));
+                }
+                _ => return Ok(Some((Parsed::Invalid(BAD_FORMAT), used))),
+            }
+        }
+        b"version" if args.is_empty() => Command::Version,
+        b"quit" if args.is_empty() => Command::Quit,
+        _ => return Ok(Some((Parsed::Invalid("ERROR"), used))),
+    };
+    Ok(Some((Parsed::Command(command), used)))
+}
+
+// The arguments without a trailing `noreply`, and whether it was there
+fn noreply<'a, 'b>(args: &'a [&'b [u8]]) -> (&'a [&'b [u8]], bool) {
+    match args.split_last() {
+        Some((&b"noreply", rest)) => (rest, true),
+        _ => (args, false),
+    }
+}
+
+fn number<T: std::str::FromStr>(word: &[u8]) -> Option<T> {
+    std::str::from_utf8(word).ok()?.parse().ok()
+}
+
+// Keys can't contain control characters, as in memcached, and must be UTF-8
+// to be stored
+fn parse_key(word: &[u8]) -> Result<String, &'static str> {
+    if word.len() > MAX_KEY || word.iter().any(u8::is_ascii_control) {
+        return Err(BAD_FORMAT);
+    }
+    String::from_utf8(word.to_vec()).map_err(|_| "CLIENT_ERROR keys must be UTF-8")
+}
+
+// When a value stored with `exptime` expires: never for 0, that many seconds
+// from now up to 30 days, and at that Unix time beyond. A negative exptime or a
+// Unix time gone by has the value expire straight away.
+fn expiry(exptime: i64, now: Instant) -> Option<Instant> {
+    match exptime {
+        0 => None,
+        ..0 => Some(now),
+        1..=MAX_RELATIVE_EXPTIME => Some(now + Duration::from_secs(exptime as u64)),
+        _ => {
+            let at = UNIX_EPOCH + Duration::from_secs(exptime as u64);
+            let left = at.duration_since(SystemTime::now()).unwrap_or_default();
+            Some(now + left)
+        }
+    }
+}
+
+fn storage_failure(e: impl std::fmt::Display) -> String {
+    tracing::error!("{}", e);
+    "SERVER_ERROR storage failure".to_string()
+}
+
+fn log_failure(e: impl std::fmt::Display) -> String {
+    tracing::error!("Failed to log a memcached protocol write: {}", e);
+    "SERVER_ERROR failed to log the write".to_string()
+}
+
+// Everything a connection needs, shared by all of them
+#[derive(Clone)]
+pub(crate) struct Memcache {
+    pub(crate) state: AppState,
+}
+
+impl Memcache {
+    // Accept connections until `shutdown` is set. Connections also close then,
+    // once the command they are running, if any, has been answered.
+    pub(crate) async fn serve(self, listener: TcpListener, mut shutdown: watch::Receiver<bool>) {
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        tracing::warn!("Failed to accept a memcached protocol connection: {}", e);
+                        continue;
+                    }
+                },
+                _ = shutdown.wait_for(|&stop| stop) => break,
+            };
+            let memcache = self.clone();
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                if let Err(e) = memcache.connection(stream, shutdown).await {
+                    tracing::debug!("Memcached protocol connection failed: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn connection(
+        &self,
+        mut stream: TcpStream,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> std::io::Result<()> {
+        let mut buf = BytesMut::with_capacity(4096);
+        let mut out = Vec::new();
+        loop {
+            // Answer every request that has fully arrived, then send the
+            // replies together, so pipelined requests take one write
+            loop {
+                let (parsed, used) = match parse(&buf, self.state.max_value_bytes) {
+                    Ok(Some(parsed)) => parsed,
+                    Ok(None) => break,
+                    Err(ProtocolError(message)) => {
+                        line(&mut out, message);
+                        return stream.write_all(&out).await;
+                    }
+                };
+                let frame = buf.split_to(used).freeze();
+                match parsed {
+                    Parsed::Invalid(message) => line(&mut out, message),
+                    Parsed::Command(Command::Quit) => return stream.write_all(&out).await,
+                    Parsed::Command(command) => self.execute(command, &frame, &mut out).await,
+                }
+            }
+            if !out.is_empty() {
+                stream.write_all(&out).await?;
+                out.clear();
+            }
+            tokio::select! {
+                read = stream.read_buf(&mut buf) => {
+                    if read? == 0 {
+                        return Ok(());
+                    }
+                }
+                _ = shutdown.wait_for(|&stop| stop) => return Ok(()),
+            }
+        }
+    }
+
+    // Append the reply to `command` to `out`; nothing for noreply commands
+    async fn execute(&self, command: Command, frame: &Bytes, out: &mut Vec<u8>) {
+        let (reply, noreply) = match command {
+            Command::Store {
+                mode,
+                key,
+                flags,
+                exptime,
+                value,
+                noreply,
+            } => {
+                let entry = Entry {
+                    expires_at: expiry(exptime, Instant::now()),
+                    flags,
+                    ..Entry::new(frame.slice(value))
+                };
+                (self.store(mode, &key, entry).await, noreply)
+            }
+            Command::Get(keys) => return self.get(&keys, out).await,
+            Command::Delete { key, noreply } => (self.delete(&key).await, noreply),
+            Command::FlushAll { noreply } => (self.flush_all().await, noreply),
+            Command::Version => (format!("VERSION {}", env!("CARGO_PKG_VERSION")), false),
+            Command::Quit => return,
+        };
+        if !noreply {
+            line(out, &reply);
+        }
+    }
+
+    // The stored form of a client's key, if the store accepts it
+    fn key(&self, key: &str) -> Result<String, String> {
+        keyspace::validate_key(key, self.state.max_key_bytes)
+            .map_err(|msg| format!("CLIENT_ERROR {}", msg))?;
+        Ok(Namespace::default().storage_key(key))
+    }
+
+    fn read_only(&self) -> Result<(), String> {
+        if self.state.follower.is_some() {
+            return Err("SERVER_ERROR the server is a follower; write to its primary".to_string());
+        }
+        if self.state.read_only.load(Ordering::Relaxed) {
+            return Err("SERVER_ERROR the server is in read-only mode".to_string());
+        }
+        Ok(())
+    }
+
+    async fn store(&self, mode: Mode, key: &str, mut entry: Entry) -> String {
+        let key = match self.key(key).and_then(|key| self.read_only().map(|()| key)) {
+            Ok(key) => key,
+            Err(reply) => return reply,
+        };
+        let state = &self.state;
+        let result = state
+            .store
+            .with_key_write(&key, |view| {
+                let now = Instant::now();
+                let current = view.get(&key).filter(|current| !current.is_expired(now));
+                let refused = match mode {
+                    Mode::Set => false,
+                    Mode::Add => current.is_some(),
+                    Mode::Replace => current.is_none(),
+                };
+                if refused {
+                    return Ok(None);
+                }
+                if let Some(current) = &current {
+                    entry.replaces(current, state.history_depth);
+                }
+                state.check_room(view, &key, &entry)?;
+                entry.version = view.next_version();
+                let ack = state.log(|| wal::WalRecord::put(&key, &entry));
+                state.record_event(&key, Some(&entry));
+                view.insert(key.clone(), entry);
+                Ok(Some(ack))
+            })
+            .await;
+        let ack = match result {
+            Ok(Ok(Some(ack))) => ack,
+            Ok(Ok(None)) => return "NOT_STORED".to_string(),
+            Ok(Err(NoRoom::Budget | NoRoom::Quota(_))) => {
+                return "SERVER_ERROR out of memory storing object".to_string()
+            }
+            Err(e) => return storage_failure(e),
+        };
+        if let Err(e) = wal::wait(ack).await {
+            return log_failure(e);
+        }
+        state.ops.put(1);
+        "STORED".to_string()
+    }
+
+    // A VALUE block for each key found, then END. Repeated keys are returned
+    // each time, as memcached does.
+    async fn get(&self, keys: &[String], out: &mut Vec<u8>) {
+        let keys = match keys
+            .iter()
+            .map(|key| self.key(key))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(keys) => keys,
+            Err(reply) => return line(out, &reply),
+        };
+        let result = self
+            .state
+            .store
+            .with_read(|view| {
+                let now = Instant::now();
+                keys.iter()
+                    .map(|key| {
+                        view.get(key)
+                            .map(|entry| (!entry.is_expired(now)).then_some(entry))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .await;
+        let found = match result {
+            Ok(found) => found,
+            Err(e) => return line(out, &storage_failure(e)),
+        };
+        for (key, entry) in keys.iter().zip(found) {
+            match entry {
+                Some(Some(entry)) => {
+                    self.state.ops.get(None, true);
+                    let value = match entry.data() {
+                        Ok(value) => value,
+                        Err(e) => return line(out, &storage_failure(e)),
+                    };
+                    let header = format!("VALUE {} {} {}", key, entry.flags, value.len());
+                    line(out, &header);
+                    out.extend_from_slice(&value);
+                    out.extend_from_slice(b"\r\n");
+                }
+                Some(None) => {
+                    self.state.ops.get(None, false);
+                    if let Err(e) = handlers::remove_if_expired(&self.state, key).await {
+                        tracing::error!("{}", e);
+                    }
+                }
+                None => self.state.ops.get(None, false),
+            }
+        }
+        line(out, "END");
+    }
+
+    async fn delete(&self, key: &str) -> String {
+        let key = match self.key(key).and_then(|key| self.read_only().map(|()| key)) {
+            Ok(key) => key,
+            Err(reply) => return reply,
+        };
+        let state = &self.state;
+        let result = state
+            .store
+            .with_key_write(&key, |view| {
+                let now = Instant::now();
+                // An expired entry is removed either way, but isn't found
+                match view.remove(&key) {
+                    Some(entry) if !entry.is_expired(now) => {
+                        let ack = state.log(|| wal::WalRecord::delete(&key));
+                        state.record_event(&key, None);
+                        if let Some(tombstones) = &state.tombstones {
+                            tombstones.bury(key.clone(), entry, now);
+                        }
+                        Some(ack)
+                    }
+                    Some(entry) => {
+                        state.record_expiry(&key, &entry);
+                        None
+                    }
+                    None => None,
+                }
+            })
+            .await;
+        let ack = match result {
+            Ok(Some(ack)) => ack,
+            Ok(None) => return "NOT_FOUND".to_string(),
+            Err(e) => return storage_failure(e),
+        };
+        if let Err(e) = wal::wait(ack).await {
+            return log_failure(e);
+        }
+        state.ops.delete();
+        "DELETED".to_string()
+    }
+
+    // Deletes every key of the default namespace; other tenants and buckets
+    // aren't visible to memcached clients, so they are left alone
+    async fn flush_all(&self) -> String {
+        if let Err(reply) = self.read_only() {
+            return reply;
+        }
+        let state = &self.state;
+        let namespace = Namespace::default();
+        let result = state
+            .store
+            .with_write(|view| {
+                let now = Instant::now();
+                let mut deleted = 0;
+                let mut acks = Vec::new();
+                for key in view.keys_with_prefix(&namespace.prefix()) {
+                    if namespace.client_key(&key).is_none() {
+                        continue;
+                    }
+                    if let Some(entry) = view.remove(&key) {
+                        acks.extend(state.log(|| wal::WalRecord::delete(&key)));
+                        if entry.is_expired(now) {
+                            state.record_expiry(&key, &entry);
+                        } else {
+                            state.record_event(&key, None);
+                            deleted += 1;
+                        }
+                    }
+                }
+                (deleted, acks)
+            })
+            .await;
+        let (deleted, acks) = match result {
+            Ok(outcome) => outcome,
+            Err(e) => return storage_failure(e),
+        };
+        if let Err(e) = wal::wait_all(acks).await {
+            return log_failure(e);
+        }
+        tracing::warn!(
+            "Flushed the default namespace over memcached, deleting {} keys",
+            deleted
+        );
+        "OK".to_string()
+    }
+}
+
+fn line(out: &mut Vec<u8>, text: &str) {
+    out.extend_from_slice(text.as_bytes());
+    out.extend_from_slice(b"\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(buf: &[u8]) -> Option<(Parsed, usize)> {
+        parse(buf, 1024).unwrap()
+    }
+
+    fn command(buf: &[u8]) -> Command {
+        match parsed(buf) {
+            Some((Parsed::Command(command), _)) => command,
+            other => panic!("not a command: {:?}", other),
+        }
+    }
+
+    fn invalid(buf: &[u8]) -> &'static str {
+        match parsed(buf) {
+            Some((Parsed::Invalid(error), _)) => error,
+            other => panic!("not invalid: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_storage_commands() {
+        let buf = b"set k 5 0 7\r\nv\r\nalue\r\nget k\r\n";
+        let (parsed, used) = parsed(buf).unwrap();
+        assert_eq!(used, 22);
+        assert_eq!(
+            parsed,
+            Parsed::Command(Command::Store {
+                mode: Mode::Set,
+                key: "k".to_string(),
+                flags: 5,
+                exptime: 0,
+                value: 13..20,
+                noreply: false,
+            })
+        );
+        assert!(matches!(
+            command(b"add k 0 -1 0 noreply\r\n\r\n"),
+            Command::Store {
+                mode: Mode::Add,
+                exptime: -1,
+                noreply: true,
+                ..
+            }
+        ));
+        assert!(matches!(
+            command(b"replace k 0 0 1\r\nx\r\n"),
+            Command::Store {
+                mode: Mode::Replace,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn waits_for_the_value() {
+        let buf = b"set k 0 0 3\r\nabc\r\n";
+        for end in 0..buf.len() {
+            assert_eq!(parsed(&buf[..end]), None, "parsed {} bytes", end);
+        }
+        assert!(parsed(buf).is_some());
+    }
+
+    #[test]
+    fn parses_retrieval_and_other_commands() {
+        assert_eq!(
+            command(b"get a  b\n"),
+            Command::Get(vec!["a".to_string(), "b".to_string()])
+        );
+        assert_eq!(
+            command(b"delete k noreply\r\n"),
+            Command::Delete {
+                key: "k".to_string(),
+                noreply: true
+            }
+        );
+        assert_eq!(
+            command(b"delete k 0\r\n"),
+            Command::Delete {
+                key: "k".to_string(),
+                noreply: false
+            }
+        );
+        assert_eq!(
+            command(b"flush_all\r\n"),
+            Command::FlushAll { noreply: false }
+        );
+        assert_eq!(
+            command(b"flush_all 0 noreply\r\n"),
+            Command::FlushAll { noreply: true }
+        );
+        assert_eq!(command(b"version\r\n"), Command::Version);
+        assert_eq!(command(b"quit\r\n"), Command::Quit);
+    }
+
+    #[test]
+    fn answers_malformed_lines() {
+        assert_eq!(invalid(b"\r\n"), "ERROR");
+        assert_eq!(invalid(b"bogus\r\n"), "ERROR");
+        assert_eq!(invalid(b"get\r\n"), "ERROR");
+        assert_eq!(invalid(b"SET k 0 0 1\r\n"), "ERROR");
+        assert_eq!(invalid(b"set k 0 0\r\n"), "ERROR");
+        assert_eq!(invalid(b"set k x 0 1\r\nv\r\n"), BAD_FORMAT);
+        assert_eq!(invalid(b"set k 0 0 -1\r\n"), BAD_FORMAT);
+        assert_eq!(
+            parsed(b"set k 0 0 1\r\nxy z\r\n"),
+            Some((Parsed::Invalid("CLIENT_ERROR bad data chunk"), 19))
+        );
+        assert_eq!(invalid(b"delete a b\r\n"), BAD_FORMAT);
+        assert_eq!(
+            invalid(b"flush_all 10\r\n"),
+            "CLIENT_ERROR delayed flush_all is not supported"
+        );
+        let long_key = format!("get {}\r\n", "k".repeat(MAX_KEY + 1));
+        assert_eq!(invalid(long_key.as_bytes()), BAD_FORMAT);
+        assert_eq!(invalid(b"get \xff\r\n"), "CLIENT_ERROR keys must be UTF-8");
+    }
+
+    #[test]
+    fn rejects_what_it_cant_recover_from() {
+        assert_eq!(
+            parse(&vec![b'x'; MAX_LINE + 1], 1024),
+            Err(ProtocolError("CLIENT_ERROR line too long"))
+        );
+        assert_eq!(
+            parse(b"set k 0 0 2000\r\n", 1024),
+            Err(ProtocolError("SERVER_ERROR object too large for cache"))
+        );
+    }
+
+    #[test]
+    fn maps_exptimes() {
+        let now = Instant::now();
+        assert_eq!(expiry(0, now), None);
+        assert_eq!(expiry(-1, now), Some(now));
+        assert_eq!(expiry(60, now), Some(now + Duration::from_secs(60)));
+        assert_eq!(expiry(1, now), Some(now + Duration::from_secs(1)));
+        // A Unix time in the past
+        assert_eq!(expiry(MAX_RELATIVE_EXPTIME + 1, now), Some(now));
+        let in_an_hour = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 3600;
+        let at = expiry(in_an_hour as i64, now).unwrap();
+        assert!(at > now + Duration::from_secs(3590) && at <= now + Duration::from_secs(3600));
+    }
+}
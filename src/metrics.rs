@@ -0,0 +1,917 @@
+use axum::http::{Method, StatusCode};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+// Route label for requests that matched no route, so unknown paths can't
+// create unbounded label values
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+// Upper bounds of the latency histogram buckets, in seconds. Prometheus buckets
+// are cumulative, so each one also counts every faster request.
+const LATENCY_BUCKETS: [f64; 15] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+    10.0,
+];
+
+// The percentiles come from finer buckets: 20 per decade from 0.1ms to 10s, so
+// each bucket's upper bound is about 12% above its lower one, plus one for
+// slower requests. Quantiles interpolate between order statistics (type 7),
+// each estimated by spreading its bucket's requests evenly across the bucket,
+// so they are accurate to about one bucket width. The fastest and slowest
+// requests are tracked exactly.
+const PERCENTILE_BUCKETS_PER_DECADE: usize = 20;
+const PERCENTILE_BUCKETS: usize = 5 * PERCENTILE_BUCKETS_PER_DECADE + 1;
+const PERCENTILE_MIN_SECS: f64 = 0.0001;
+
+// Upper bound of a percentile bucket, in seconds
+fn percentile_bound(bucket: usize) -> f64 {
+    PERCENTILE_MIN_SECS * 10f64.powf(bucket as f64 / PERCENTILE_BUCKETS_PER_DECADE as f64)
+}
+
+// Labels of one time series
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Labels {
+    method: String,
+    route: String,
+    status: u16,
+}
+
+// Latency distribution of the requests sharing one set of labels
+#[derive(Default)]
+struct Histogram {
+    // Requests per bucket, not cumulative; the last slot counts those slower
+    // than every bound
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    count: u64,
+    sum_secs: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum_secs += secs;
+    }
+}
+
+// Latency counts of one group of requests, for the percentiles
+#[derive(Clone)]
+struct LatencyCounts {
+    // Requests per percentile bucket; the last slot counts those slower than
+    // every bound
+    buckets: [u64; PERCENTILE_BUCKETS + 1],
+    count: u64,
+    min_secs: f64,
+    max_secs: f64,
+}
+
+impl Default for LatencyCounts {
+    fn default() -> Self {
+        Self {
+            buckets: [0; PERCENTILE_BUCKETS + 1],
+            count: 0,
+            min_secs: f64::INFINITY,
+            max_secs: 0.0,
+        }
+    }
+}
+
+impl LatencyCounts {
+    fn observe(&mut self, secs: f64) {
+        // Bucket i holds latencies up to percentile_bound(i); compute it
+        // directly rather than searching, nudging for floating point error
+        let bucket = if secs <= PERCENTILE_MIN_SECS {
+            0
+        } else {
+            let exact = (secs / PERCENTILE_MIN_SECS).log10() * PERCENTILE_BUCKETS_PER_DECADE as f64;
+            let mut bucket = exact.ceil() as usize;
+            if bucket > 0 && secs <= percentile_bound(bucket - 1) {
+                bucket -= 1;
+            }
+            bucket.min(PERCENTILE_BUCKETS)
+        };
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.min_secs = self.min_secs.min(secs);
+        self.max_secs = self.max_secs.max(secs);
+    }
+
+    fn merge(&mut self, other: &LatencyCounts) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += count;
+        }
+        self.count += other.count;
+        self.min_secs = self.min_secs.min(other.min_secs);
+        self.max_secs = self.max_secs.max(other.max_secs);
+    }
+
+    // Estimate the `rank`th fastest request (from 0), in seconds
+    fn order_statistic(&self, rank: u64) -> f64 {
+        if rank == 0 {
+            return self.min_secs;
+        }
+        if rank + 1 >= self.count {
+            return self.max_secs;
+        }
+        let mut before = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            if rank < before + count {
+                let lower = match bucket {
+                    0 => 0.0,
+                    _ => percentile_bound(bucket - 1),
+                };
+                let upper = match bucket {
+                    PERCENTILE_BUCKETS => self.max_secs,
+                    _ => percentile_bound(bucket),
+                };
+                let (lower, upper) = (lower.max(self.min_secs), upper.min(self.max_secs));
+                let position = ((rank - before) as f64 + 0.5) / count as f64;
+                return lower + (upper - lower) * position;
+            }
+            before += count;
+        }
+        self.max_secs
+    }
+
+    // The latencies at each of `quantiles` (between 0 and 1), in milliseconds,
+    // interpolating linearly between the order statistics around (n - 1) * q
+    fn quantiles(&self, quantiles: &[f64]) -> Vec<f64> {
+        quantiles
+            .iter()
+            .map(|&q| {
+                if self.count == 0 {
+                    return 0.0;
+                }
+                let h = (self.count - 1) as f64 * q.clamp(0.0, 1.0);
+                let below = self.order_statistic(h.floor() as u64);
+                let above = self.order_statistic(h.ceil() as u64);
+                (below + (above - below) * h.fract()) * 1000.0
+            })
+            .collect()
+    }
+
+    // P50, P95 and P99 in milliseconds, and the sample count
+    fn percentiles(&self) -> (f64, f64, f64, usize) {
+        let quantiles = self.quantiles(&[0.50, 0.95, 0.99]);
+        (
+            quantiles[0],
+            quantiles[1],
+            quantiles[2],
+            self.count as usize,
+        )
+    }
+}
+
+// What a reset discarded: the windowed percentiles and the status counts
+pub struct Discarded {
+    pub percentiles: (f64, f64, f64, usize),
+    pub statuses: StatusCounts,
+}
+
+// Method and matched route a latency sample is grouped under
+type RouteKey = (String, String);
+
+// Percentiles of one group of requests
+pub struct RouteLatency {
+    pub method: String,
+    pub route: String,
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub count: usize,
+}
+
+// Responses served, per status class (2 for 2xx, ...) and per exact code
+pub struct StatusCounts {
+    pub classes: BTreeMap<u16, u64>,
+    pub codes: BTreeMap<u16, u64>,
+}
+
+impl StatusCounts {
+    // Render as "2xx: 10, 4xx: 2 (200: 9, 201: 1, 404: 2)"
+    pub fn summary(&self) -> String {
+        let classes: Vec<String> = self
+            .classes
+            .iter()
+            .map(|(class, count)| format!("{}xx: {}", class, count))
+            .collect();
+        let codes: Vec<String> = self
+            .codes
+            .iter()
+            .map(|(code, count)| format!("{}: {}", code, count))
+            .collect();
+        format!("{} ({})", classes.join(", "), codes.join(", "))
+    }
+}
+
+// Latency counts over a sliding window: a ring with one slot per second, each
+// holding the counts of the requests finished during that second. A slot is
+// reset when the ring comes back around to it, so requests older than the
+// window drop out.
+struct Window {
+    started: Instant,
+    slots: Vec<Second>,
+}
+
+#[derive(Default)]
+struct Second {
+    // Seconds since `started` that this slot holds, or None if never used
+    second: Option<u64>,
+    groups: BTreeMap<RouteKey, LatencyCounts>,
+}
+
+impl Window {
+    fn new(started: Instant, window: Duration) -> Self {
+        let len = window.as_secs().max(1) as usize;
+        Self {
+            started,
+            slots: (0..len).map(|_| Second::default()).collect(),
+        }
+    }
+
+    fn second(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.started).as_secs()
+    }
+
+    fn observe(&mut self, key: RouteKey, secs: f64, now: Instant) {
+        let second = self.second(now);
+        let len = self.slots.len() as u64;
+        let slot = &mut self.slots[(second % len) as usize];
+        if slot.second != Some(second) {
+            slot.second = Some(second);
+            slot.groups.clear();
+        }
+        slot.groups.entry(key).or_default().observe(secs);
+    }
+
+    // Counts per method and route over the window ending at `now`
+    fn groups(&self, now: Instant) -> BTreeMap<RouteKey, LatencyCounts> {
+        let current = self.second(now);
+        let len = self.slots.len() as u64;
+        let mut groups: BTreeMap<RouteKey, LatencyCounts> = BTreeMap::new();
+        let live = self.slots.iter().filter(|slot| {
+            slot.second
+                .is_some_and(|second| second <= current && current - second < len)
+        });
+        for slot in live {
+            for (key, counts) in &slot.groups {
+                groups.entry(key.clone()).or_default().merge(counts);
+            }
+        }
+        groups
+    }
+}
+
+// Seconds of request counts kept for the throughput average
+const RATE_SECS: u64 = 60;
+
+// Request rates, in requests per second: over the last full second, and the
+// average over the last minute (or the uptime, if shorter)
+#[derive(Clone, Copy, Default)]
+pub struct Rate {
+    pub current: f64,
+    pub minute: f64,
+}
+
+// Request rates overall and per method
+pub struct Throughput {
+    pub total: Rate,
+    pub by_method: BTreeMap<String, Rate>,
+}
+
+impl Throughput {
+    // Render as "12.0 req/s now, 8.3 req/s over 1m (GET: 10.0 now, 6.1 over 1m, ...)"
+    pub fn summary(&self) -> String {
+        let methods: Vec<String> = self
+            .by_method
+            .iter()
+            .map(|(method, rate)| {
+                format!(
+                    "{}: {:.1} now, {:.1} over 1m",
+                    method, rate.current, rate.minute
+                )
+            })
+            .collect();
+        format!(
+            "{:.1} req/s now, {:.1} req/s over 1m ({})",
+            self.total.current,
+            self.total.minute,
+            methods.join(", ")
+        )
+    }
+}
+
+// Requests per method finished in each of the last RATE_SECS seconds, as a ring
+// of per-second slots like `Window`
+struct Rates {
+    started: Instant,
+    slots: Vec<(Option<u64>, BTreeMap<String, u64>)>,
+}
+
+impl Rates {
+    fn new(started: Instant) -> Self {
+        Self {
+            started,
+            slots: (0..RATE_SECS).map(|_| (None, BTreeMap::new())).collect(),
+        }
+    }
+
+    fn second(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.started).as_secs()
+    }
+
+    fn count(&mut self, method: &str, now: Instant) {
+        let second = self.second(now);
+        let (slot_second, methods) = &mut self.slots[(second % RATE_SECS) as usize];
+        if *slot_second != Some(second) {
+            *slot_second = Some(second);
+            methods.clear();
+        }
+        *methods.entry(method.to_string()).or_default() += 1;
+    }
+
+    fn throughput(&self, now: Instant) -> Throughput {
+        let current = self.second(now);
+        // Average over full seconds only, so a second in progress doesn't drag
+        // the rate down
+        let seconds = current.clamp(1, RATE_SECS) as f64;
+        let mut throughput = Throughput {
+            total: Rate::default(),
+            by_method: BTreeMap::new(),
+        };
+        for (second, methods) in &self.slots {
+            let Some(second) = second.filter(|&s| s < current && current - s <= RATE_SECS) else {
+                continue;
+            };
+            for (method, &count) in methods {
+                let rate = throughput.by_method.entry(method.clone()).or_default();
+                for rate in [rate, &mut throughput.total] {
+                    rate.minute += count as f64 / seconds;
+                    if second + 1 == current {
+                        rate.current += count as f64;
+                    }
+                }
+            }
+        }
+        throughput
+    }
+}
+
+// How many slow requests the slow log keeps; older ones are dropped
+const SLOW_LOG_CAPACITY: usize = 100;
+
+// One request that took longer than the slow threshold
+#[derive(Clone, Serialize)]
+pub struct SlowRequest {
+    pub method: String,
+    pub path: String,
+    pub route: String,
+    pub status: u16,
+    pub duration_ms: f64,
+    // From the request's Content-Length, if it had one
+    pub request_bytes: Option<u64>,
+    // None when the response is streamed without a known length
+    pub response_bytes: Option<u64>,
+    // RFC 3339 time the request finished
+    pub finished_at: String,
+}
+
+// The most recent slow requests, oldest first
+struct SlowLog {
+    threshold: Option<Duration>,
+    requests: Mutex<VecDeque<SlowRequest>>,
+}
+
+// Request latency metrics: bucket counts per method and route over a sliding
+// window for the percentiles, and a histogram per method, route and status
+// since startup for Prometheus. Both take constant memory per label set however
+// many requests are recorded. The locks stay synchronous: each guards a
+// bounded amount of work, never a sort or an await.
+#[derive(Clone)]
+pub struct Metrics {
+    window: Duration,
+    latencies: Arc<Mutex<Window>>,
+    rates: Arc<Mutex<Rates>>,
+    slow: Arc<SlowLog>,
+    series: Arc<Mutex<BTreeMap<Labels, Histogram>>>,
+}
+
+impl Metrics {
+    // `slow_threshold` enables the slow log for requests taking longer
+    pub fn new(window: Duration, slow_threshold: Option<Duration>) -> Self {
+        Self {
+            window,
+            slow: Arc::new(SlowLog {
+                threshold: slow_threshold,
+                requests: Mutex::new(VecDeque::with_capacity(SLOW_LOG_CAPACITY)),
+            }),
+            latencies: Arc::new(Mutex::new(Window::new(Instant::now(), window))),
+            rates: Arc::new(Mutex::new(Rates::new(Instant::now()))),
+            series: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    // Length of the window the percentiles cover
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    // Requests taking longer than this go to the slow log, if it is enabled
+    pub fn slow_threshold(&self) -> Option<Duration> {
+        self.slow.threshold
+    }
+
+    // Add a request to the slow log, dropping the oldest one if it is full
+    pub fn record_slow(&self, request: SlowRequest) {
+        let mut requests = self
+            .slow
+            .requests
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if requests.len() == SLOW_LOG_CAPACITY {
+            requests.pop_front();
+        }
+        requests.push_back(request);
+    }
+
+    // The slow log, most recent first
+    pub fn slow_requests(&self) -> Vec<SlowRequest> {
+        let requests = self
+            .slow
+            .requests
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        requests.iter().rev().cloned().collect()
+    }
+
+    // Record one finished request. `route` is the matched route template, not
+    // the raw path, so label values stay bounded.
+    pub fn record(&self, method: &Method, route: &str, status: StatusCode, duration: Duration) {
+        let now = Instant::now();
+        self.latencies
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .observe(
+                (method.to_string(), route.to_string()),
+                duration.as_secs_f64(),
+                now,
+            );
+        self.rates
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .count(method.as_str(), now);
+
+        let labels = Labels {
+            method: method.to_string(),
+            route: route.to_string(),
+            status: status.as_u16(),
+        };
+        self.series
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(labels)
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+
+    // Percentiles over all requests in the window
+    pub fn get_percentiles(&self) -> (f64, f64, f64, usize) {
+        overall(
+            &self
+                .latencies
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+            Instant::now(),
+        )
+        .percentiles()
+    }
+
+    // Latencies at arbitrary quantiles over all requests in the window, in
+    // milliseconds
+    pub fn quantiles(&self, quantiles: &[f64]) -> Vec<f64> {
+        overall(
+            &self
+                .latencies
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+            Instant::now(),
+        )
+        .quantiles(quantiles)
+    }
+
+    // Request rates overall and per method
+    pub fn throughput(&self) -> Throughput {
+        self.rates
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .throughput(Instant::now())
+    }
+
+    // Start over from no requests, returning what was recorded until now.
+    // Requests finishing meanwhile land on one side or the other, never lost.
+    pub fn reset(&self) -> Discarded {
+        let now = Instant::now();
+        let window = std::mem::replace(
+            &mut *self
+                .latencies
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+            Window::new(now, self.window),
+        );
+        *self.rates.lock().unwrap_or_else(PoisonError::into_inner) = Rates::new(now);
+        let series =
+            std::mem::take(&mut *self.series.lock().unwrap_or_else(PoisonError::into_inner));
+        Discarded {
+            percentiles: overall(&window, now).percentiles(),
+            statuses: count_statuses(&series),
+        }
+    }
+
+    // Percentiles of each method and route in the window, in that order
+    pub fn route_percentiles(&self) -> Vec<RouteLatency> {
+        let groups = self
+            .latencies
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .groups(Instant::now());
+        groups
+            .iter()
+            .map(|((method, route), counts)| {
+                let (p50, p95, p99, count) = counts.percentiles();
+                RouteLatency {
+                    method: method.clone(),
+                    route: route.clone(),
+                    p50,
+                    p95,
+                    p99,
+                    count,
+                }
+            })
+            .collect()
+    }
+
+    // Responses served so far by status. The 2xx, 4xx and 5xx classes are
+    // always present, even at zero.
+    pub fn status_counts(&self) -> StatusCounts {
+        count_statuses(&self.series.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    // Append the request series in the Prometheus text exposition format
+    pub fn write_prometheus(&self, out: &mut String) {
+        let series = self.series.lock().unwrap_or_else(PoisonError::into_inner);
+
+        out.push_str("# HELP kv_http_requests_total HTTP requests served.\n");
+        out.push_str("# TYPE kv_http_requests_total counter\n");
+        for (labels, histogram) in series.iter() {
+            let _ = writeln!(
+                out,
+                "kv_http_requests_total{{{}}} {}",
+                labels.render(),
+                histogram.count
+            );
+        }
+
+        out.push_str("# HELP kv_http_errors_total HTTP requests answered with a 5xx status.\n");
+        out.push_str("# TYPE kv_http_errors_total counter\n");
+        for (labels, histogram) in series.iter().filter(|(labels, _)| labels.status >= 500) {
+            let _ = writeln!(
+                out,
+                "kv_http_errors_total{{{}}} {}",
+                labels.render(),
+                histogram.count
+            );
+        }
+
+        out.push_str("# HELP kv_http_responses_total HTTP responses by status class.\n");
+        out.push_str("# TYPE kv_http_responses_total counter\n");
+        for (class, count) in count_statuses(&series).classes {
+            let _ = writeln!(
+                out,
+                "kv_http_responses_total{{class=\"{}xx\"}} {}",
+                class, count
+            );
+        }
+
+        out.push_str("# HELP kv_http_request_duration_seconds HTTP request latency.\n");
+        out.push_str("# TYPE kv_http_request_duration_seconds histogram\n");
+        for (labels, histogram) in series.iter() {
+            let labels = labels.render();
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "kv_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "kv_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "kv_http_request_duration_seconds_sum{{{}}} {}",
+                labels, histogram.sum_secs
+            );
+            let _ = writeln!(
+                out,
+                "kv_http_request_duration_seconds_count{{{}}} {}",
+                labels, histogram.count
+            );
+        }
+    }
+}
+
+// Counts of every group in the window ending at `now`, merged
+fn overall(window: &Window, now: Instant) -> LatencyCounts {
+    let mut all = LatencyCounts::default();
+    for counts in window.groups(now).values() {
+        all.merge(counts);
+    }
+    all
+}
+
+fn count_statuses(series: &BTreeMap<Labels, Histogram>) -> StatusCounts {
+    let mut counts = StatusCounts {
+        classes: [2, 4, 5].into_iter().map(|class| (class, 0)).collect(),
+        codes: BTreeMap::new(),
+    };
+    for (labels, histogram) in series {
+        *counts.classes.entry(labels.status / 100).or_default() += histogram.count;
+        *counts.codes.entry(labels.status).or_default() += histogram.count;
+    }
+    counts
+}
+
+impl Labels {
+    fn render(&self) -> String {
+        format!(
+            "method=\"{}\",route=\"{}\",status=\"{}\"",
+            escape_label(&self.method),
+            escape_label(&self.route),
+            self.status
+        )
+    }
+}
+
+// Escape a label value as the exposition format requires
+pub fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Key operations served since startup, for GET /stats and /metrics. Plain
+// atomics, so counting never takes a lock, apart from a read lock to find a
+// tenant's lookup counts.
+#[derive(Default)]
+pub(crate) struct OpCounts {
+    pub(crate) puts: AtomicU64,
+    pub(crate) gets: AtomicU64,
+    pub(crate) deletes: AtomicU64,
+    pub(crate) lookups: Lookups,
+    // Lookups by tenant, for every tenant other than the default one
+    tenants: RwLock<HashMap<String, Lookups>>,
+}
+
+// Lookups that found (hits) or didn't find (misses) a live value
+#[derive(Default)]
+pub(crate) struct Lookups {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Lookups {
+    pub(crate) fn count(&self, hit: bool) {
+        let outcome = if hit { &self.hits } else { &self.misses };
+        outcome.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn load(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+// Share of lookups that were hits, or None before the first lookup
+pub(crate) fn hit_ratio((hits, misses): (u64, u64)) -> Option<f64> {
+    (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64)
+}
+
+impl OpCounts {
+    pub(crate) fn put(&self, count: u64) {
+        self.puts.fetch_add(count, Ordering::Relaxed);
+    }
+
+    // Count a lookup in `tenant`'s namespace (None for the default one) that
+    // found (hit) or didn't find (miss) a live value
+    pub(crate) fn get(&self, tenant: Option<&str>, hit: bool) {
+        self.gets.fetch_add(1, Ordering::Relaxed);
+        self.lookups.count(hit);
+        let Some(tenant) = tenant else {
+            return;
+        };
+        if let Some(lookups) = self
+            .tenants
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(tenant)
+        {
+            lookups.count(hit);
+            return;
+        }
+        let mut tenants = self.tenants.write().unwrap_or_else(PoisonError::into_inner);
+        tenants.entry(tenant.to_string()).or_default().count(hit);
+    }
+
+    pub(crate) fn delete(&self) {
+        self.deletes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Hits and misses of each tenant other than the default one
+    pub(crate) fn tenant_lookups(&self) -> BTreeMap<String, (u64, u64)> {
+        let tenants = self.tenants.read().unwrap_or_else(PoisonError::into_inner);
+        tenants
+            .iter()
+            .map(|(tenant, lookups)| (tenant.clone(), lookups.load()))
+            .collect()
+    }
+
+    // Lookup counts and hit ratio, as "12 hits, 3 misses (80.0% hit ratio)"
+    pub(crate) fn lookup_summary(&self) -> String {
+        let lookups = self.lookups.load();
+        let ratio = hit_ratio(lookups).map_or("no lookups yet".to_string(), |ratio| {
+            format!("{:.1}% hit ratio", ratio * 100.0)
+        });
+        format!("{} hits, {} misses ({})", lookups.0, lookups.1, ratio)
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let (hits, misses) = self.lookups.load();
+        serde_json::json!({
+            "puts": self.puts.load(Ordering::Relaxed),
+            "gets": self.gets.load(Ordering::Relaxed),
+            "deletes": self.deletes.load(Ordering::Relaxed),
+            "hits": hits,
+            "misses": misses,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    // A read costs the same however many requests the window holds: nothing is
+    // sorted, only bucket counts are summed
+    #[test]
+    fn reads_with_many_samples_dont_starve_recording() {
+        let metrics = Metrics::new(Duration::from_secs(60), None);
+        for n in 0..200_000u64 {
+            let duration = Duration::from_micros(100 + n % 50_000);
+            metrics.record(&Method::GET, "/{key}", StatusCode::OK, duration);
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let metrics = metrics.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let mut slowest = Duration::ZERO;
+                while !stop.load(Ordering::Relaxed) {
+                    let started = Instant::now();
+                    assert!(metrics.get_percentiles().3 >= 200_000);
+                    slowest = slowest.max(started.elapsed());
+                }
+                slowest
+            })
+        };
+        let mut slowest = Duration::ZERO;
+        for _ in 0..10_000 {
+            let started = Instant::now();
+            metrics.record(
+                &Method::PUT,
+                "/{key}",
+                StatusCode::OK,
+                Duration::from_millis(1),
+            );
+            slowest = slowest.max(started.elapsed());
+        }
+        stop.store(true, Ordering::Relaxed);
+        let slowest_read = reader.join().unwrap();
+
+        assert!(slowest < Duration::from_millis(50), "{:?}", slowest);
+        assert!(
+            slowest_read < Duration::from_millis(50),
+            "{:?}",
+            slowest_read
+        );
+        assert_eq!(metrics.get_percentiles().3, 210_000);
+    }
+
+    // Counts of requests taking each of `ms` milliseconds
+    fn counts(ms: &[f64]) -> LatencyCounts {
+        let mut counts = LatencyCounts::default();
+        for &ms in ms {
+            counts.observe(ms / 1000.0);
+        }
+        counts
+    }
+
+    fn assert_quantiles(counts: &LatencyCounts, expected: &[(f64, f64)]) {
+        let quantiles: Vec<f64> = expected.iter().map(|&(q, _)| q).collect();
+        for (&(q, ms), actual) in expected.iter().zip(counts.quantiles(&quantiles)) {
+            assert!((actual - ms).abs() < 1e-9, "q{}: {} != {}", q, actual, ms);
+        }
+    }
+
+    #[test]
+    fn quantiles_interpolate_between_order_statistics() {
+        assert_quantiles(&counts(&[]), &[(0.5, 0.0), (0.99, 0.0)]);
+        assert_quantiles(&counts(&[7.0]), &[(0.0, 7.0), (0.5, 7.0), (1.0, 7.0)]);
+        assert_quantiles(&counts(&[4.0; 5]), &[(0.1, 4.0), (0.5, 4.0), (0.99, 4.0)]);
+
+        // Type 7: h = (n - 1) * q, between the fastest and slowest of two
+        let two = counts(&[3.0, 1.0]);
+        assert_quantiles(
+            &two,
+            &[(0.0, 1.0), (0.25, 1.5), (0.5, 2.0), (0.9, 2.8), (1.0, 3.0)],
+        );
+        // Out of range quantiles are clamped
+        assert_quantiles(&two, &[(-1.0, 1.0), (2.0, 3.0)]);
+
+        // Not the largest sample, as indexing the sorted samples would give
+        let three = counts(&[1.0, 2.0, 50.0]);
+        let p95 = three.quantiles(&[0.95])[0];
+        assert!(p95 < 50.0, "{}", p95);
+        // Sharing a bucket (1ms to about 1.12ms), the middle one is estimated
+        // halfway between the others
+        let bucket = counts(&[1.01, 1.05, 1.1]);
+        assert_quantiles(
+            &bucket,
+            &[
+                (0.0, 1.01),
+                (0.25, 1.0325),
+                (0.5, 1.055),
+                (0.75, 1.0775),
+                (1.0, 1.1),
+            ],
+        );
+    }
+
+    #[test]
+    fn old_samples_age_out_of_the_window() {
+        let started = Instant::now();
+        let at = |secs| started + Duration::from_secs(secs);
+        let route = || ("GET".to_string(), "/{key}".to_string());
+        let mut window = Window::new(started, Duration::from_secs(10));
+        window.observe(route(), 0.001, at(0));
+        window.observe(route(), 0.002, at(3));
+        window.observe(route(), 0.003, at(9));
+        let count = |window: &Window, secs| overall(window, at(secs)).count;
+        assert_eq!(count(&window, 9), 3);
+        assert_eq!(count(&window, 10), 2);
+        assert_eq!(count(&window, 13), 1);
+        assert_eq!(count(&window, 19), 0);
+
+        // A second that reuses a slot replaces what it held
+        window.observe(route(), 0.004, at(13));
+        assert_eq!(count(&window, 13), 2);
+        assert_eq!(overall(&window, at(19)).min_secs, 0.004);
+        assert_eq!(count(&window, 23), 0);
+    }
+
+    #[test]
+    fn a_poisoned_lock_keeps_recording() {
+        let metrics = Metrics::new(Duration::from_secs(60), None);
+        let latencies = metrics.latencies.clone();
+        let poisoner = std::thread::spawn(move || {
+            let _guard = latencies.lock().unwrap();
+            panic!("poisoning the lock");
+        });
+        assert!(poisoner.join().is_err());
+        assert!(metrics.latencies.is_poisoned());
+
+        metrics.record(
+            &Method::GET,
+            "/{key}",
+            StatusCode::OK,
+            Duration::from_millis(1),
+        );
+        assert_eq!(metrics.get_percentiles().3, 1);
+    }
+}
@@ -0,0 +1,515 @@
+use crate::error::ApiError;
+use crate::metrics::{self, Metrics};
+#[cfg(feature = "otlp")]
+use crate::telemetry;
+use crate::{auth, handlers, persistence, ratelimit, replication};
+use crate::{ADMIN_PREFIX, DOCS_ROUTE, HEALTHZ_ROUTE, METRICS_ROUTE, OPENAPI_ROUTE, READYZ_ROUTE};
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, MatchedPath, Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use bytes::BytesMut;
+use http_body_util::BodyExt;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::Instrument;
+
+// Drop the CORS headers of a response whose origin wasn't allowed. The CORS
+// layer adds the allowed methods and headers to every preflight, and the
+// exposed headers to every response, whether or not it allowed the origin;
+// without Access-Control-Allow-Origin they grant nothing, so don't advertise
+// the policy to origins outside it. Runs outside the CORS layer.
+pub(crate) async fn refused_origin_middleware(request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    if !headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN) {
+        for name in [
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        ] {
+            headers.remove(name);
+        }
+    }
+    response
+}
+
+// Refuse request bodies over `limit` bytes with 413. A declared Content-Length
+// is checked before anything is read; otherwise reading stops as soon as the
+// limit is passed, so an oversized body is never buffered whole.
+pub(crate) async fn limit_body(limit: usize, request: Request, next: Next) -> Response {
+    let declared = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if declared.is_some_and(|length| length > limit as u64) {
+        return ApiError::PayloadTooLarge(limit).into_response();
+    }
+
+    let (parts, body) = request.into_parts();
+    match read_body(body, limit, declared.unwrap_or_default() as usize).await {
+        Ok(body) => next.run(Request::from_parts(parts, Body::from(body))).await,
+        Err(e) => e.into_response(),
+    }
+}
+
+// Read a body of at most `limit` bytes into one buffer as its chunks arrive,
+// so each chunk is freed once copied and the body is held about once. A body
+// sent in a single chunk is kept as it is, without a copy.
+async fn read_body(mut body: Body, limit: usize, expected: usize) -> Result<Bytes, ApiError> {
+    let mut first: Option<Bytes> = None;
+    let mut buffer = BytesMut::new();
+    let mut length = 0;
+    while let Some(frame) = body.frame().await {
+        let frame = frame
+            .map_err(|e| ApiError::BadRequest(format!("Failed to read request body: {}", e)))?;
+        let Ok(chunk) = frame.into_data() else {
+            continue;
+        };
+        length += chunk.len();
+        if length > limit {
+            return Err(ApiError::PayloadTooLarge(limit));
+        }
+        if buffer.is_empty() {
+            match first.take() {
+                None => {
+                    first = Some(chunk);
+                    continue;
+                }
+                Some(first) => {
+                    buffer.reserve(expected.max(length));
+                    buffer.extend_from_slice(&first);
+                }
+            }
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+    Ok(first.unwrap_or_else(|| buffer.freeze()))
+}
+
+// Header carrying a request's ID, both ways
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Longest X-Request-Id accepted from a client; longer ones are replaced
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// Tag each request with the client's X-Request-Id, or a fresh UUID if it sent
+// none (or an unusable one). Everything logged while serving the request is in
+// a span carrying the ID, and the response echoes it back, errors included.
+// When spans are exported, the span also gets fields named for OpenTelemetry;
+// recording those is a no-op otherwise.
+pub(crate) async fn request_id_middleware(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map_or_else(|| uuid::Uuid::new_v4().to_string(), str::to_string);
+    #[cfg(feature = "otlp")]
+    let method = request.method().clone();
+    #[cfg(feature = "otlp")]
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(metrics::UNMATCHED_ROUTE, |matched| matched.as_str());
+
+    #[cfg(feature = "otlp")]
+    let span = if telemetry::enabled() {
+        let span = tracing::info_span!(
+            "request",
+            request_id = %id,
+            otel.name = %format_args!("{} {}", method, route),
+            otel.kind = "server",
+            http.request.method = %method,
+            http.route = %route,
+            http.response.status_code = tracing::field::Empty,
+            kv.operation = tracing::field::Empty,
+        );
+        telemetry::set_parent(&span, request.headers());
+        span
+    } else {
+        tracing::info_span!("request", request_id = %id)
+    };
+    #[cfg(not(feature = "otlp"))]
+    let span = tracing::info_span!("request", request_id = %id);
+
+    let mut response = next.run(request).instrument(span.clone()).await;
+    span.record("http.response.status_code", response.status().as_u16());
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+// Header an API key may be sent in, as an alternative to Authorization: Bearer
+pub(crate) const API_KEY_HEADER: &str = "x-api-key";
+
+// Routes open to ACL tokens. Their handlers check the keys they touch against
+// the token's grants; every other route needs full access.
+pub(crate) const SCOPED_ROUTES: [&str; 8] = [
+    "/{*key}",
+    "/keys",
+    "/b/{bucket}",
+    "/b/{bucket}/{*key}",
+    "/b/{bucket}/keys",
+    "/batch/get",
+    "/batch/put",
+    "/txn",
+];
+
+// Who may make requests: API keys with full access, and ACL tokens
+#[derive(Clone)]
+pub(crate) struct Authenticator {
+    pub(crate) api_keys: Arc<auth::ApiKeys>,
+    pub(crate) acl: Arc<auth::Acl>,
+    pub(crate) admin_tokens: Arc<auth::ApiKeys>,
+}
+
+// The token a request carries, as a Bearer token or in X-Api-Key
+pub(crate) fn request_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| headers.get(API_KEY_HEADER)?.to_str().ok())
+        .map(str::trim)
+}
+
+// 401 with a Bearer challenge. Per RFC 6750, only a rejected token gets an
+// error code in the challenge.
+fn unauthorized(realm: &str, rejected: bool, message: &'static str) -> Response {
+    let mut challenge = format!("Bearer realm=\"{}\"", realm);
+    if rejected {
+        challenge.push_str(", error=\"invalid_token\"");
+    }
+    ApiError::Unauthorized { challenge, message }.into_response()
+}
+
+// Refuse requests without a valid API key or ACL token with 401, when either
+// is configured, and attach what the client may do for the handlers to check.
+// The probes and the API description stay open so orchestrators and browsers
+// don't need a key, and the admin and replication routes check their own
+// tokens.
+pub(crate) async fn auth_middleware(
+    State(authenticator): State<Authenticator>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+    if !(authenticator.api_keys.is_enabled() || authenticator.acl.is_enabled())
+        || path == HEALTHZ_ROUTE
+        || path == READYZ_ROUTE
+        || path == OPENAPI_ROUTE
+        || path == DOCS_ROUTE
+        || path.starts_with(ADMIN_PREFIX)
+        || path == replication::APPLY_ROUTE
+    {
+        return next.run(request).await;
+    }
+
+    match request_token(request.headers()) {
+        Some(token) if authenticator.api_keys.verify(token) => {
+            let client = auth::Client(token.to_string());
+            request.extensions_mut().insert(client);
+            next.run(request).await
+        }
+        Some(token) if let Some(grants) = authenticator.acl.lookup(token) => {
+            let client = auth::Client(token.to_string());
+            let route = request
+                .extensions()
+                .get::<MatchedPath>()
+                .map(|matched| matched.as_str());
+            if !route.is_some_and(|route| SCOPED_ROUTES.contains(&route)) {
+                return auth::forbidden().into_response();
+            }
+            request
+                .extensions_mut()
+                .insert(auth::Access::Scoped(grants));
+            request.extensions_mut().insert(client);
+            next.run(request).await
+        }
+        Some(_) => unauthorized(env!("CARGO_PKG_NAME"), true, "Invalid API key"),
+        None => unauthorized(env!("CARGO_PKG_NAME"), false, "Missing API key"),
+    }
+}
+
+// Refuse admin requests without a valid admin token with 401, or all of them
+// with 403 when no admin token is configured. API keys and ACL tokens are
+// never accepted here.
+pub(crate) async fn admin_middleware(
+    State(admin_tokens): State<Arc<auth::ApiKeys>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if !admin_tokens.is_enabled() {
+        return ApiError::Forbidden(
+            "Admin routes are disabled; configure --admin-token to enable them",
+        )
+        .into_response();
+    }
+    let realm = concat!(env!("CARGO_PKG_NAME"), " admin");
+    match request_token(request.headers()) {
+        Some(token) if admin_tokens.verify(token) => {
+            let client = auth::Client(token.to_string());
+            request.extensions_mut().insert(client);
+            next.run(request).await
+        }
+        Some(_) => unauthorized(realm, true, "Invalid admin token"),
+        None => unauthorized(realm, false, "Missing admin token"),
+    }
+}
+
+// Refuse requests to the replication route without the replication secret
+// with 401, or all of them with 403 unless this server is a follower
+pub(crate) async fn follower_middleware(
+    State(secret): State<Option<Arc<auth::ApiKeys>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(secret) = secret else {
+        return ApiError::Forbidden("This server isn't a follower; start it with --follower")
+            .into_response();
+    };
+    let realm = concat!(env!("CARGO_PKG_NAME"), " replication");
+    match request_token(request.headers()) {
+        Some(token) if secret.verify(token) => next.run(request).await,
+        Some(_) => unauthorized(realm, true, "Invalid replication secret"),
+        None => unauthorized(realm, false, "Missing replication secret"),
+    }
+}
+
+// Routes that take as long as their request bodies do: an import streams
+// however much data the client has
+const UNTIMED_ROUTES: [&str; 1] = ["/admin/import"];
+
+// Routes whose GETs may wait for a change with `?wait=`, and get that long on
+// top of the timeout
+const LONG_POLL_ROUTES: [&str; 2] = ["/{*key}", "/b/{bucket}/{*key}"];
+
+#[derive(Deserialize)]
+struct LongPoll {
+    wait: Option<u64>,
+}
+
+// Answer requests still running after `timeout` with 503, unless it is zero
+// or the route is untimed; long polls have as long again as they wait. Inside
+// the metrics middleware, so timeouts are counted like any other 503.
+// The handler is dropped at its next await point: store changes are made in
+// synchronous transactions, so they are either applied whole or not at all,
+// but one applied just before the timeout stays applied. A handler blocked
+// inside a transaction can't be interrupted, only answered once it yields.
+pub(crate) async fn timeout_middleware(
+    State(timeout): State<Duration>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|matched| matched.as_str());
+    let untimed = route.is_some_and(|route| UNTIMED_ROUTES.contains(&route));
+    if timeout.is_zero() || untimed {
+        return next.run(request).await;
+    }
+    let long_poll = route.is_some_and(|route| LONG_POLL_ROUTES.contains(&route))
+        && request.method() == Method::GET;
+    let timeout = match Query::<LongPoll>::try_from_uri(request.uri()) {
+        Ok(Query(LongPoll { wait: Some(wait) })) if long_poll => {
+            timeout + handlers::long_poll_wait(wait)
+        }
+        _ => timeout,
+    };
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            tracing::warn!("Request timed out after {:?}", timeout);
+            ApiError::Timeout(timeout).into_response()
+        }
+    }
+}
+
+// What refuses writes: read-only mode, which can be switched, and serving as
+// a follower
+#[derive(Clone)]
+pub(crate) struct WriteGate {
+    pub(crate) read_only: Arc<AtomicBool>,
+    pub(crate) follower: bool,
+}
+
+// Refuse requests that would change stored data with 503 while in read-only
+// mode, or 403 on a follower. These are the requests to the data routes other
+// than GET, HEAD and POST /batch/get, plus flushes and imports; copies count,
+// since they write their destination. Other administration, metrics, the
+// probes and the writes a follower's primary pushes keep working.
+pub(crate) async fn read_only_middleware(
+    State(gate): State<WriteGate>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let read_only = gate.read_only.load(Ordering::Relaxed);
+    if !(read_only || gate.follower) || matches!(*request.method(), Method::GET | Method::HEAD) {
+        return next.run(request).await;
+    }
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|matched| matched.as_str());
+    let writes = route.is_some_and(|route| {
+        route != "/batch/get"
+            && (SCOPED_ROUTES.contains(&route)
+                || ["/admin/flush", "/admin/import"].contains(&route))
+    });
+    if writes && gate.follower {
+        return ApiError::Forbidden("This server is a follower; write to its primary")
+            .into_response();
+    }
+    if writes {
+        return ApiError::ReadOnly.into_response();
+    }
+    next.run(request).await
+}
+
+// Who a rate limit applies to: the API key or token a request authenticated
+// with, or else the address it came from
+#[derive(PartialEq, Eq, Hash)]
+pub(crate) enum RateLimitClient {
+    Token(String),
+    Address(Option<IpAddr>),
+}
+
+// Refuse requests over their client's rate limit with 429 and a Retry-After in
+// whole seconds, when a limit is configured. The probes and /metrics aren't
+// limited, so monitoring keeps working while a client is throttled, and
+// neither are the writes a follower's primary pushes.
+pub(crate) async fn rate_limit_middleware(
+    State(limiter): State<Option<Arc<ratelimit::RateLimiter<RateLimitClient>>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(limiter) = limiter else {
+        return next.run(request).await;
+    };
+    let path = request.uri().path();
+    if path == HEALTHZ_ROUTE
+        || path == READYZ_ROUTE
+        || path == METRICS_ROUTE
+        || path == replication::APPLY_ROUTE
+    {
+        return next.run(request).await;
+    }
+
+    let client = match request.extensions().get::<auth::Client>() {
+        Some(auth::Client(token)) => RateLimitClient::Token(token.clone()),
+        None => RateLimitClient::Address(
+            request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(address)| address.ip()),
+        ),
+    };
+    match limiter.check(client, Instant::now()) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            ApiError::RateLimited { retry_after }.into_response()
+        }
+    }
+}
+
+// What the metrics middleware needs: where to record, and which routes to skip
+#[derive(Clone)]
+pub(crate) struct RequestMetrics {
+    pub(crate) metrics: Metrics,
+    pub(crate) excluded_routes: Arc<HashSet<String>>,
+}
+
+// Time each request once, then record that duration in the metrics and log it
+// in a single event. Requests to excluded routes are only logged, at trace level.
+pub(crate) async fn metrics_middleware(
+    State(request_metrics): State<RequestMetrics>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let request_bytes = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<u64>().ok());
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(metrics::UNMATCHED_ROUTE.to_string(), |matched| {
+            matched.as_str().to_string()
+        });
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let duration = start.elapsed();
+
+    let status = response.status();
+    let duration_ms = duration.as_micros() as f64 / 1000.0;
+    // None when the response is streamed without a known length
+    let response_bytes = axum::body::HttpBody::size_hint(response.body()).exact();
+    if request_metrics.excluded_routes.contains(&route) {
+        tracing::trace!(
+            %method,
+            %path,
+            %route,
+            status = status.as_u16(),
+            duration_ms,
+            response_bytes,
+            "request"
+        );
+    } else {
+        request_metrics
+            .metrics
+            .record(&method, &route, status, duration);
+        tracing::info!(
+            %method,
+            %path,
+            %route,
+            status = status.as_u16(),
+            duration_ms,
+            response_bytes,
+            "request"
+        );
+
+        let slow = request_metrics
+            .metrics
+            .slow_threshold()
+            .is_some_and(|threshold| duration > threshold);
+        if slow {
+            tracing::warn!(
+                %method,
+                %path,
+                %route,
+                status = status.as_u16(),
+                duration_ms,
+                request_bytes,
+                response_bytes,
+                "slow request"
+            );
+            request_metrics.metrics.record_slow(metrics::SlowRequest {
+                method: method.to_string(),
+                path,
+                route,
+                status: status.as_u16(),
+                duration_ms,
+                request_bytes,
+                response_bytes,
+                finished_at: persistence::system_time_to_rfc3339(SystemTime::now()),
+            });
+        }
+    }
+
+    response
+}
@@ -0,0 +1,183 @@
+// Entries as lines of newline-delimited JSON, the format GET /admin/export
+// writes and POST /admin/import and --preload load back
+use crate::keyspace::{self, Namespace};
+use crate::persistence::{self, StoredValue};
+use crate::store::{self, Entry, StorageError};
+use crate::wal::{self, WalAck};
+use crate::{AppState, NoRoom};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+// One entry: `{"key": "a", "value": "text", "ttl": 60}`, with `value_b64` in
+// place of `value` for binary values. Keys outside the default namespace name
+// their `tenant` and `bucket`. The version and timestamps are written for
+// reference; loading a record writes it anew.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Record {
+    key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bucket: Option<String>,
+    #[serde(flatten)]
+    value: StoredValue,
+    // Seconds until the key expires, rounded up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    updated_at: Option<String>,
+}
+
+impl Record {
+    // The record of an entry stored under `stored`, as of `now`
+    pub(crate) fn encode(stored: &str, entry: &Entry, now: Instant) -> Result<Self, StorageError> {
+        let (tenant, bucket, key) = keyspace::split_stored(stored);
+        let ttl = entry.expires_at.map(|deadline| {
+            let left = deadline.saturating_duration_since(now);
+            (left.as_secs() + u64::from(left.subsec_nanos() > 0)).max(1)
+        });
+        Ok(Self {
+            key: key.to_string(),
+            tenant: tenant.map(str::to_string),
+            bucket: bucket.map(str::to_string),
+            value: StoredValue::encode(&entry.data()?),
+            ttl,
+            content_type: entry.content_type.clone(),
+            version: Some(entry.version),
+            created_at: Some(persistence::system_time_to_rfc3339(entry.created_at)),
+            updated_at: Some(persistence::system_time_to_rfc3339(entry.updated_at)),
+        })
+    }
+
+    // The stored key and a new entry for the record, or why it can't be stored
+    pub(crate) fn decode(self, state: &AppState) -> Result<(String, Entry), String> {
+        let namespace = Namespace::named(self.tenant.as_deref(), self.bucket.as_deref())?;
+        let mut entry = entry(state, &self.key, self.value.decode()?, self.ttl)?;
+        entry.content_type = self.content_type;
+        Ok((namespace.storage_key(&self.key), entry))
+    }
+}
+
+// A new entry for `value` under `key`, expiring in `ttl` seconds, or why it
+// can't be stored
+pub(crate) fn entry(
+    state: &AppState,
+    key: &str,
+    value: Bytes,
+    ttl: Option<u64>,
+) -> Result<Entry, String> {
+    keyspace::validate_key(key, state.max_key_bytes)?;
+    if value.len() > state.max_value_bytes {
+        return Err(format!(
+            "value of {} bytes exceeds the {} byte limit",
+            value.len(),
+            state.max_value_bytes
+        ));
+    }
+    let ttl = match ttl {
+        Some(0) => return Err("ttl must be greater than zero".to_string()),
+        ttl => ttl.map(Duration::from_secs),
+    };
+    let compressed = state
+        .compress_min_bytes
+        .filter(|&min| value.len() >= min)
+        .and_then(|_| store::compress(&value));
+    Ok(Entry {
+        expires_at: ttl.map(|ttl| Instant::now() + ttl),
+        compressed: compressed.is_some(),
+        ..Entry::new(compressed.unwrap_or(value))
+    })
+}
+
+// How a load treats keys that already have a live value
+#[derive(Clone, Copy, Default, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Mode {
+    // Replace the value
+    #[default]
+    Upsert,
+    // Keep the value and move on
+    SkipExisting,
+    // Stop loading
+    FailOnConflict,
+}
+
+// What loading one record did
+pub(crate) enum Outcome {
+    Inserted,
+    Updated,
+    Skipped,
+    NoRoom(NoRoom),
+}
+
+// The result of loading a batch: the outcome for each line, up to the key
+// that stopped a FailOnConflict load if one did, with acknowledgements to wait
+// for before reporting the writes done
+pub(crate) struct Loaded {
+    pub outcomes: Vec<(usize, Outcome)>,
+    pub conflict: Option<String>,
+    pub acks: Vec<WalAck>,
+}
+
+// Write a batch of (line, stored key, entry) in order in one transaction, as
+// PUTs would, logging and announcing each write when `log`
+pub(crate) async fn load(
+    state: &AppState,
+    batch: Vec<(usize, String, Entry)>,
+    mode: Mode,
+    log: bool,
+) -> Result<Loaded, StorageError> {
+    state
+        .store
+        .with_write(|view| {
+            let now = Instant::now();
+            let mut loaded = Loaded {
+                outcomes: Vec::with_capacity(batch.len()),
+                conflict: None,
+                acks: Vec::new(),
+            };
+            for (line, key, mut entry) in batch {
+                let current = view.get(&key).filter(|current| !current.is_expired(now));
+                if let Some(current) = &current {
+                    match mode {
+                        Mode::Upsert => entry.replaces(current, state.history_depth),
+                        Mode::SkipExisting => {
+                            loaded.outcomes.push((line, Outcome::Skipped));
+                            continue;
+                        }
+                        Mode::FailOnConflict => {
+                            loaded.conflict = Some(key);
+                            break;
+                        }
+                    }
+                }
+                if let Err(reason) = state.check_room(view, &key, &entry) {
+                    loaded.outcomes.push((line, Outcome::NoRoom(reason)));
+                    continue;
+                }
+                entry.version = view.next_version();
+                if log {
+                    loaded
+                        .acks
+                        .extend(state.log(|| wal::WalRecord::put(&key, &entry)));
+                    state.record_event(&key, Some(&entry));
+                }
+                view.insert(key, entry);
+                let outcome = match current {
+                    Some(_) => Outcome::Updated,
+                    None => Outcome::Inserted,
+                };
+                loaded.outcomes.push((line, outcome));
+            }
+            loaded
+        })
+        .await
+}
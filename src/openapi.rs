@@ -0,0 +1,209 @@
+use axum::{
+    http::header,
+    response::{Html, IntoResponse, Response},
+};
+use std::sync::OnceLock;
+use utoipa::openapi::path::{ParameterBuilder, ParameterIn};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::openapi::{Content, ObjectBuilder, Ref, RefOr, Required, Type};
+use utoipa::{Modify, OpenApi};
+
+// The API description, gathered from the `#[utoipa::path]` attributes on the
+// handlers. Routes served within a bucket as well are added by `document`.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "rust-kv", description = "An HTTP key-value store"),
+    paths(
+        crate::handlers::put_handler,
+        crate::handlers::append_handler,
+        crate::handlers::get_handler,
+        crate::handlers::head_handler,
+        crate::handlers::delete_handler,
+        crate::handlers::restore_handler,
+        crate::handlers::meta_handler,
+        crate::handlers::history_handler,
+        crate::handlers::ttl_handler,
+        crate::handlers::touch_handler,
+        crate::handlers::rename_handler,
+        crate::handlers::copy_handler,
+        crate::handlers::incr_handler,
+        crate::handlers::decr_handler,
+        crate::handlers::list_keys_handler,
+        crate::handlers::delete_prefix_handler,
+        crate::handlers::drop_bucket_handler,
+        crate::handlers::batch_get_handler,
+        crate::handlers::batch_put_handler,
+        crate::handlers::txn_handler,
+        crate::handlers::healthz_handler,
+        crate::handlers::readyz_handler,
+        crate::handlers::metrics_handler,
+        crate::handlers::slow_requests_handler,
+        crate::handlers::stats_handler,
+        crate::handlers::reset_metrics_handler,
+        crate::handlers::flush_handler,
+        crate::handlers::snapshot_handler,
+        crate::handlers::compact_handler,
+        crate::handlers::export_handler,
+        crate::handlers::events_handler,
+        crate::handlers::watch_key_handler,
+        crate::handlers::watch_prefix_handler,
+        crate::ws::ws_handler,
+        crate::replication::apply_handler,
+        crate::handlers::import_handler,
+        crate::handlers::reload_acl_handler,
+        crate::handlers::read_only_handler,
+        crate::handlers::get_quota_handler,
+        crate::handlers::put_quota_handler,
+        crate::handlers::delete_quota_handler,
+        openapi_handler,
+        docs_handler,
+    ),
+    components(schemas(
+        crate::handlers::HistoryItem,
+        crate::handlers::ListedEntry,
+        crate::error::ErrorBody
+    )),
+    modifiers(&Credentials, &ErrorResponses),
+    security(("bearer" = []), ("api_key" = [])),
+    tags(
+        (name = "keys", description = "Keys of the default bucket; each is also served under /b/{bucket}. Keys may contain slashes, so `{key}` can span several path segments"),
+        (name = "batch", description = "Several keys at once"),
+        (name = "admin", description = "Operational routes, taking --admin-token rather than the data credentials"),
+        (name = "replication", description = "Writes a primary pushes to its follower, taking --replication-secret"),
+        (name = "metrics"),
+        (name = "probes"),
+        (name = "docs"),
+    )
+)]
+struct ApiDoc;
+
+// Describe how clients authenticate: API keys and ACL tokens as Bearer tokens
+// or in X-Api-Key, admin tokens the same way on the admin routes, and the
+// replication secret on the route a primary pushes writes to
+struct Credentials;
+
+impl Modify for Credentials {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(
+                crate::middleware::API_KEY_HEADER,
+            ))),
+        );
+        components.add_security_scheme(
+            "admin_token",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+        components.add_security_scheme(
+            "replication_secret",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+// Describe the body of every error response the handlers don't describe
+// themselves as an ErrorBody
+struct ErrorResponses;
+
+impl Modify for ErrorResponses {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let body = Content::new(Some(Ref::from_schema_name("ErrorBody")));
+        for item in openapi.paths.paths.values_mut() {
+            let operations = [
+                &mut item.get,
+                &mut item.put,
+                &mut item.post,
+                &mut item.delete,
+                &mut item.head,
+                &mut item.patch,
+            ];
+            for operation in operations.into_iter().flatten() {
+                for (status, response) in &mut operation.responses.responses {
+                    let RefOr::T(response) = response else {
+                        continue;
+                    };
+                    if status.starts_with(['4', '5']) && response.content.is_empty() {
+                        response
+                            .content
+                            .insert("application/json".to_string(), body.clone().into());
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The full document, with every key route repeated under /b/{bucket}
+fn document() -> utoipa::openapi::OpenApi {
+    let mut openapi = ApiDoc::openapi();
+    let bucket = ParameterBuilder::new()
+        .name("bucket")
+        .parameter_in(ParameterIn::Path)
+        .required(Required::True)
+        .schema(Some(ObjectBuilder::new().schema_type(Type::String)))
+        .build();
+    let in_buckets: Vec<_> = openapi
+        .paths
+        .paths
+        .iter()
+        .filter(|(path, _)| path.starts_with("/{key}") || path.as_str() == "/keys")
+        .map(|(path, item)| {
+            let mut item = item.clone();
+            item.parameters
+                .get_or_insert_with(Vec::new)
+                .push(bucket.clone().into());
+            (format!("/b/{{bucket}}{}", path), item)
+        })
+        .collect();
+    openapi.paths.paths.extend(in_buckets);
+    openapi
+}
+
+// GET /openapi.json - The OpenAPI document describing every route
+#[utoipa::path(
+    get, path = "/openapi.json", tag = "docs", operation_id = "openapi",
+    summary = "This document",
+    security(()),
+    responses((status = 200, description = "The OpenAPI document", content_type = "application/json"))
+)]
+pub async fn openapi_handler() -> Response {
+    // Built once; the routes can't change while running
+    static JSON: OnceLock<String> = OnceLock::new();
+    let json = JSON.get_or_init(|| {
+        document()
+            .to_json()
+            .expect("the OpenAPI document serializes")
+    });
+    ([(header::CONTENT_TYPE, "application/json")], json.as_str()).into_response()
+}
+
+// Redoc, loaded from its CDN, rendering /openapi.json
+const DOCS_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+  <head>
+    <title>rust-kv API</title>
+    <meta charset="utf-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1">
+  </head>
+  <body>
+    <redoc spec-url="openapi.json"></redoc>
+    <script src="https://cdn.redoc.ly/redoc/latest/bundles/redoc.standalone.js"></script>
+  </body>
+</html>
+"#;
+
+// GET /docs - The API documentation as a web page
+#[utoipa::path(
+    get, path = "/docs", tag = "docs", operation_id = "docs",
+    summary = "API documentation page",
+    security(()),
+    responses((status = 200, description = "Redoc rendering of /openapi.json", content_type = "text/html"))
+)]
+pub async fn docs_handler() -> Html<&'static str> {
+    Html(DOCS_PAGE)
+}
@@ -0,0 +1,382 @@
+use crate::store::{Entry, PastVersion};
+use crate::wal::Wal;
+use crate::Store;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
+
+// Bumped whenever the on-disk layout changes. Version 1 stored values as
+// plain strings only; version 2 added `value_b64` for binary values; version 3
+// added per-entry write versions; version 4 added version history; version 5
+// added creation and update times; version 6 added memcached flags.
+const SNAPSHOT_FORMAT_VERSION: u32 = 6;
+
+// On-disk snapshot layout
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    entries: Vec<SnapshotEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    key: String,
+    #[serde(flatten)]
+    entry: StoredEntry,
+}
+
+// An entry as written to disk, shared by snapshots, the write-ahead log and the
+// sled backend. Expiry is stored as wall-clock milliseconds since the Unix epoch
+// because an `Instant` doesn't survive a restart.
+#[derive(Serialize, Deserialize)]
+pub struct StoredEntry {
+    #[serde(flatten)]
+    value: StoredValue,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(default)]
+    version: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    history: Vec<StoredPastVersion>,
+    // Missing for entries written before timestamps were tracked
+    #[serde(default)]
+    created_at_ms: Option<u64>,
+    #[serde(default)]
+    updated_at_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    flags: u32,
+    // Whether the value is stored gzip-compressed
+    #[serde(default, skip_serializing_if = "is_false")]
+    compressed: bool,
+}
+
+fn is_zero(flags: &u32) -> bool {
+    *flags == 0
+}
+
+fn is_false(flag: &bool) -> bool {
+    !*flag
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredPastVersion {
+    #[serde(flatten)]
+    value: StoredValue,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    version: u64,
+    replaced_at_ms: u64,
+    #[serde(default, skip_serializing_if = "is_false")]
+    compressed: bool,
+}
+
+impl StoredEntry {
+    pub fn encode(entry: &Entry) -> Self {
+        let now = Instant::now();
+        let now_sys = SystemTime::now();
+        Self {
+            value: StoredValue::encode(&entry.value),
+            expires_at_ms: entry
+                .expires_at
+                .map(|deadline| instant_to_unix_ms(deadline, now, now_sys)),
+            content_type: entry.content_type.clone(),
+            version: entry.version,
+            history: entry
+                .history
+                .iter()
+                .map(|past| StoredPastVersion {
+                    value: StoredValue::encode(&past.value),
+                    content_type: past.content_type.clone(),
+                    version: past.version,
+                    replaced_at_ms: system_time_to_unix_ms(past.replaced_at),
+                    compressed: past.compressed,
+                })
+                .collect(),
+            created_at_ms: Some(system_time_to_unix_ms(entry.created_at)),
+            updated_at_ms: Some(system_time_to_unix_ms(entry.updated_at)),
+            flags: entry.flags,
+            compressed: entry.compressed,
+        }
+    }
+
+    // An expiry that passed while the entry was on disk maps to an instant
+    // in the past, so the decoded entry reads as expired. Entries without
+    // timestamps are taken to have been written when they are loaded.
+    pub fn decode(self) -> Result<Entry, String> {
+        let now = Instant::now();
+        let now_sys = SystemTime::now();
+        let history = self
+            .history
+            .into_iter()
+            .map(|past| {
+                Ok(PastVersion {
+                    value: past.value.decode()?,
+                    content_type: past.content_type,
+                    version: past.version,
+                    replaced_at: unix_ms_to_system_time(past.replaced_at_ms),
+                    compressed: past.compressed,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Entry {
+            value: self.value.decode()?,
+            expires_at: self
+                .expires_at_ms
+                .map(|ms| unix_ms_to_instant(ms, now, now_sys)),
+            content_type: self.content_type,
+            version: self.version,
+            history,
+            created_at: self.created_at_ms.map_or(now_sys, unix_ms_to_system_time),
+            updated_at: self.updated_at_ms.map_or(now_sys, unix_ms_to_system_time),
+            flags: self.flags,
+            compressed: self.compressed,
+        })
+    }
+}
+
+// A value as written to disk. UTF-8 values are kept readable in `value`;
+// anything else is base64-encoded in `value_b64`.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct StoredValue {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value_b64: Option<String>,
+}
+
+impl StoredValue {
+    pub fn encode(value: &Bytes) -> Self {
+        match std::str::from_utf8(value) {
+            Ok(text) => Self {
+                value: Some(text.to_string()),
+                value_b64: None,
+            },
+            Err(_) => Self {
+                value: None,
+                value_b64: Some(BASE64.encode(value)),
+            },
+        }
+    }
+
+    pub(crate) fn decode(self) -> Result<Bytes, String> {
+        match (self.value, self.value_b64) {
+            (Some(text), None) => Ok(Bytes::from(text)),
+            (None, Some(encoded)) => BASE64
+                .decode(encoded)
+                .map(Bytes::from)
+                .map_err(|e| format!("invalid base64 value: {}", e)),
+            _ => Err("expected exactly one of `value` or `value_b64`".to_string()),
+        }
+    }
+}
+
+// Convert a monotonic deadline to wall-clock milliseconds since the epoch
+fn instant_to_unix_ms(deadline: Instant, now: Instant, now_sys: SystemTime) -> u64 {
+    system_time_to_unix_ms(now_sys + deadline.saturating_duration_since(now))
+}
+
+pub fn system_time_to_unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn unix_ms_to_system_time(ms: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(ms)
+}
+
+// Format a wall-clock time as RFC 3339 in UTC with millisecond precision,
+// e.g. `2024-05-01T12:30:00.250Z`
+pub fn system_time_to_rfc3339(time: SystemTime) -> String {
+    let ms = system_time_to_unix_ms(time);
+    let secs = ms / 1000;
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since the epoch, after Howard Hinnant's `civil_from_days`
+    let z = days as i64 + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        ms % 1000
+    )
+}
+
+// Convert wall-clock milliseconds back to a deadline. A deadline that has passed
+// stays in the past even when compared against an instant taken before `now`.
+fn unix_ms_to_instant(ms: u64, now: Instant, now_sys: SystemTime) -> Instant {
+    let wall = UNIX_EPOCH + Duration::from_millis(ms);
+    match wall.duration_since(now_sys) {
+        Ok(left) => now + left,
+        Err(e) => now
+            .checked_sub(e.duration().max(Duration::from_millis(1)))
+            .unwrap_or(now),
+    }
+}
+
+// Load a snapshot from disk. A missing file yields an empty store; a corrupt or
+// truncated file is logged and also yields an empty store rather than aborting.
+pub fn load_snapshot(path: &Path) -> HashMap<String, Entry> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            tracing::info!("No snapshot at {}, starting empty", path.display());
+            return HashMap::new();
+        }
+        Err(e) => {
+            tracing::error!("Failed to read snapshot {}: {}", path.display(), e);
+            return HashMap::new();
+        }
+    };
+
+    let snapshot: Snapshot = match serde_json::from_slice(&data) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            tracing::error!(
+                "Snapshot {} is corrupt or incomplete, starting empty: {}",
+                path.display(),
+                e
+            );
+            set_aside(path);
+            return HashMap::new();
+        }
+    };
+
+    if snapshot.version == 0 || snapshot.version > SNAPSHOT_FORMAT_VERSION {
+        tracing::error!(
+            "Snapshot {} has unsupported format version {}, starting empty",
+            path.display(),
+            snapshot.version
+        );
+        set_aside(path);
+        return HashMap::new();
+    }
+
+    let now = Instant::now();
+    let mut map = HashMap::with_capacity(snapshot.entries.len());
+
+    for item in snapshot.entries {
+        match item.entry.decode() {
+            // Expired while the server was down
+            Ok(entry) if entry.is_expired(now) => {}
+            Ok(entry) => {
+                map.insert(item.key, entry);
+            }
+            Err(e) => tracing::error!("Skipping snapshot entry {:?}: {}", item.key, e),
+        }
+    }
+
+    tracing::info!("Loaded {} keys from {}", map.len(), path.display());
+    map
+}
+
+// Serialize the store to `path`. Entries are cloned under the read lock, which
+// copies keys but only bumps the refcounts of values and their histories, and
+// are encoded once the lock is released, then written to a temp file and
+// atomically renamed into place so a crash mid-write never leaves a partial
+// snapshot behind. If a write-ahead log is given, it is rotated at the same
+// point the store is captured and the segments covered by the snapshot are
+// removed afterwards. Returns the number of keys and bytes written.
+pub fn write_snapshot(store: &Store, path: &Path, wal: Option<&Wal>) -> io::Result<(usize, u64)> {
+    let now = Instant::now();
+
+    let read = store.with_read(|view| {
+        // Writers log while holding the write lock, so everything logged so far
+        // is reflected in the view we're about to copy
+        let rotated = wal.map(|wal| wal.rotate());
+        let mut entries = Vec::with_capacity(view.len());
+        view.for_each(&mut |key, entry| {
+            if !entry.is_expired(now) {
+                entries.push((key.to_string(), entry.clone()));
+            }
+        });
+        (entries, rotated)
+    });
+    // This runs on a blocking thread, which can wait for the locks in place
+    let (entries, rotated) = tokio::runtime::Handle::current()
+        .block_on(read)
+        .map_err(io::Error::other)?;
+
+    let count = entries.len();
+    let entries = entries
+        .into_iter()
+        .map(|(key, entry)| SnapshotEntry {
+            key,
+            entry: StoredEntry::encode(&entry),
+        })
+        .collect();
+    let snapshot = Snapshot {
+        version: SNAPSHOT_FORMAT_VERSION,
+        entries,
+    };
+    let data = serde_json::to_vec(&snapshot).map_err(io::Error::other)?;
+
+    let tmp_path = temp_path(path);
+    {
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+
+    if let (Some(wal), Some(rotated)) = (wal, rotated) {
+        let closed = rotated
+            .blocking_recv()
+            .map_err(|_| io::Error::other("write-ahead log writer has stopped"))??;
+        let removed = wal.remove_segments_through(closed)?;
+        tracing::debug!("Removed {} write-ahead log segments", removed);
+    }
+
+    Ok((count, data.len() as u64))
+}
+
+// Run `write_snapshot` on the blocking thread pool
+pub async fn snapshot_blocking(
+    store: Store,
+    path: PathBuf,
+    wal: Option<Wal>,
+) -> io::Result<(usize, u64)> {
+    tokio::task::spawn_blocking(move || write_snapshot(&store, &path, wal.as_ref()))
+        .await
+        .map_err(io::Error::other)?
+}
+
+// Move an unusable snapshot out of the way so the next periodic write doesn't
+// overwrite it before someone has had a chance to inspect it
+fn set_aside(path: &Path) {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".corrupt");
+    let target = path.with_file_name(name);
+    match fs::rename(path, &target) {
+        Ok(()) => tracing::warn!("Moved unusable snapshot to {}", target.display()),
+        Err(e) => tracing::error!("Failed to move unusable snapshot aside: {}", e),
+    }
+}
+
+// Sibling temp file used while a snapshot is being written
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
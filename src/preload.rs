@@ -0,0 +1,99 @@
+// Seeding the store at startup from a file of newline-delimited JSON records,
+// in the format GET /admin/export writes
+use crate::ndjson::{self, Mode, Outcome, Record};
+use crate::store::Entry;
+use crate::AppState;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::time::Instant;
+
+// Records inserted per write transaction
+const BATCH: usize = 1000;
+
+// Records between progress messages
+const PROGRESS_EVERY: usize = 100_000;
+
+// The entry a line holds, or why it doesn't hold one
+fn parse(state: &AppState, line: &str) -> Result<(String, Entry), String> {
+    let record: Record = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    record.decode(state)
+}
+
+// Counts of a load in progress
+struct Progress {
+    loaded: usize,
+    skipped: usize,
+}
+
+impl Progress {
+    // Insert `batch`, logging progress every so many records
+    async fn flush(
+        &mut self,
+        state: &AppState,
+        path: &Path,
+        strict: bool,
+        batch: Vec<(usize, String, Entry)>,
+    ) -> Result<(), String> {
+        let loaded = ndjson::load(state, batch, Mode::Upsert, false)
+            .await
+            .map_err(|e| e.to_string())?;
+        let before = self.loaded;
+        for (line, outcome) in loaded.outcomes {
+            if !matches!(outcome, Outcome::NoRoom(_)) {
+                self.loaded += 1;
+            } else if strict {
+                return Err(format!("{} line {}: no room for it", path.display(), line));
+            } else {
+                tracing::warn!("Skipping {} line {}: no room for it", path.display(), line);
+                self.skipped += 1;
+            }
+        }
+        if self.loaded / PROGRESS_EVERY > before / PROGRESS_EVERY {
+            tracing::info!("Preloaded {} records from {}", self.loaded, path.display());
+        }
+        Ok(())
+    }
+}
+
+// Load every record of `path` into the store, later lines for a key replacing
+// earlier ones. Malformed lines and records that don't fit are logged and
+// skipped, or fail the load when `strict`. Returns the number of records loaded.
+pub(crate) async fn load(state: &AppState, path: &Path, strict: bool) -> Result<usize, String> {
+    let file = File::open(path)
+        .map_err(|e| format!("failed to open preload file {}: {}", path.display(), e))?;
+    let started = Instant::now();
+    let mut progress = Progress {
+        loaded: 0,
+        skipped: 0,
+    };
+    let mut batch = Vec::with_capacity(BATCH);
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line =
+            line.map_err(|e| format!("failed to read preload file {}: {}", path.display(), e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse(state, &line) {
+            Ok((key, entry)) => batch.push((n + 1, key, entry)),
+            Err(e) if strict => return Err(format!("{} line {}: {}", path.display(), n + 1, e)),
+            Err(e) => {
+                tracing::warn!("Skipping {} line {}: {}", path.display(), n + 1, e);
+                progress.skipped += 1;
+            }
+        }
+        if batch.len() == BATCH {
+            let full = std::mem::replace(&mut batch, Vec::with_capacity(BATCH));
+            progress.flush(state, path, strict, full).await?;
+        }
+    }
+    progress.flush(state, path, strict, batch).await?;
+    tracing::info!(
+        "Preloaded {} records from {} in {:?}, skipping {}",
+        progress.loaded,
+        path.display(),
+        started.elapsed(),
+        progress.skipped
+    );
+    Ok(progress.loaded)
+}
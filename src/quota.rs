@@ -0,0 +1,89 @@
+use crate::store::Usage;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{PoisonError, RwLock};
+use utoipa::ToSchema;
+
+// Limits on what a single tenant may store. Unset fields are unlimited.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct Quota {
+    pub max_keys: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+// Which part of a quota a write would exceed, with the limit
+pub enum Exceeded {
+    Keys(u64),
+    Bytes(u64),
+}
+
+impl Quota {
+    fn is_unlimited(&self) -> bool {
+        self.max_keys.is_none() && self.max_bytes.is_none()
+    }
+
+    // Check storing `size` bytes under a key currently taking `previous` bytes
+    // (None if the key is new) against a tenant's usage
+    pub fn check(&self, usage: Usage, previous: Option<u64>, size: u64) -> Result<(), Exceeded> {
+        let keys = usage.keys + previous.is_none() as u64;
+        let bytes = (usage.bytes - previous.unwrap_or(0).min(usage.bytes)) + size;
+        if let Some(max) = self.max_keys.filter(|&max| keys > max) {
+            return Err(Exceeded::Keys(max));
+        }
+        if let Some(max) = self.max_bytes.filter(|&max| bytes > max) {
+            return Err(Exceeded::Bytes(max));
+        }
+        Ok(())
+    }
+}
+
+// The quota applying to each tenant: a default from the command line, with
+// overrides set at runtime through the admin API. Overrides are not persisted.
+pub struct Quotas {
+    default: Quota,
+    overrides: RwLock<HashMap<String, Quota>>,
+}
+
+impl Quotas {
+    pub fn new(default: Quota) -> Self {
+        Self {
+            default,
+            overrides: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // The quota for `tenant`, or None if it is unlimited
+    pub fn get(&self, tenant: &str) -> Option<Quota> {
+        let quota = self.effective(tenant).0;
+        (!quota.is_unlimited()).then_some(quota)
+    }
+
+    // The quota for `tenant`, and whether it is an override
+    pub fn effective(&self, tenant: &str) -> (Quota, bool) {
+        match self
+            .overrides
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(tenant)
+        {
+            Some(quota) => (*quota, true),
+            None => (self.default, false),
+        }
+    }
+
+    pub fn set(&self, tenant: &str, quota: Quota) {
+        self.overrides
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(tenant.to_string(), quota);
+    }
+
+    // Drop an override, returning whether there was one
+    pub fn clear(&self, tenant: &str) -> bool {
+        self.overrides
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(tenant)
+            .is_some()
+    }
+}
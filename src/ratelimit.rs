@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+// A token bucket: refills at the limiter's rate up to its burst size, and
+// each request takes one token
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Token-bucket rate limits, one bucket per client. Buckets left idle long
+// enough to have refilled are indistinguishable from new ones, so `purge`
+// drops them to keep clients that went away from piling up.
+pub struct RateLimiter<K> {
+    // Tokens added per second
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: f64::from(burst),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Take a token for `client`, or if its bucket is empty, return how long
+    // until the next one
+    pub fn check(&self, client: K, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    // Drop the buckets that have refilled completely, returning how many were dropped
+    pub fn purge(&self, now: Instant) -> usize {
+        let refill = Duration::from_secs_f64(self.burst / self.rate);
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let before = buckets.len();
+        buckets.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < refill);
+        before - buckets.len()
+    }
+}
@@ -0,0 +1,570 @@
+// Asynchronous push replication to a warm standby. A primary started with
+// --replica-url tails its write-ahead log and posts the records, in batches,
+// to the follower's POST /replication/apply, which applies them in order.
+// Records carry the resulting state of their key, so applying one twice is
+// harmless: the position the follower last acknowledged is saved beside the
+// log, and after either side goes away replication resends from there.
+
+use crate::error::ApiError;
+use crate::store::{Entry, Store, WriteView};
+use crate::wal::{self, Wal, WalRecord};
+use crate::AppState;
+use axum::extract::State;
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+// Where a follower applies the writes its primary pushes
+pub(crate) const APPLY_ROUTE: &str = "/replication/apply";
+
+// Most records sent at once, and about the most bytes of them; a record
+// larger than that goes alone
+const BATCH_RECORDS: usize = 1000;
+const BATCH_BYTES: usize = 1024 * 1024;
+
+// Longest wait for new records before looking again, should a wakeup be missed
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Waits between attempts to send a batch the follower didn't take, doubling
+// from the first to the last
+const FIRST_RETRY: Duration = Duration::from_millis(100);
+const LAST_RETRY: Duration = Duration::from_secs(30);
+
+// Longest the follower may take to answer
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How finely the age of the writes not yet replicated is told
+const MARK_INTERVAL: Duration = Duration::from_secs(1);
+
+// How far the follower at `url` has acknowledged: `offset` bytes into
+// write-ahead log segment `segment`
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    url: String,
+    segment: u64,
+    offset: u64,
+}
+
+// The writes the follower has yet to acknowledge, for the lag in /metrics
+#[derive(Default)]
+pub(crate) struct Backlog {
+    // Records logged, and those from before a restart still to be sent
+    logged: AtomicU64,
+    // How many of those the follower has, or got in a full copy
+    sent: AtomicU64,
+    // Records of a full copy being sent
+    copying: AtomicU64,
+    // When the full copy was taken
+    copied_at: Mutex<Option<Instant>>,
+    // The count of records logged before each, with when it was logged, at
+    // most one per MARK_INTERVAL. Kept from the one before the oldest record
+    // not yet sent, so its age is known to within the interval.
+    marks: Mutex<VecDeque<(u64, Instant)>>,
+}
+
+impl Backlog {
+    // With `records` from before a restart still to be sent
+    fn behind(records: u64) -> Self {
+        let backlog = Self::default();
+        if records > 0 {
+            backlog.logged.store(records, Ordering::SeqCst);
+            backlog
+                .marks
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push_back((0, Instant::now()));
+        }
+        backlog
+    }
+
+    // Count that a record was logged; call as it's logged
+    pub(crate) fn logged(&self) {
+        let index = self.logged.fetch_add(1, Ordering::SeqCst);
+        let now = Instant::now();
+        let mut marks = self.marks.lock().unwrap_or_else(PoisonError::into_inner);
+        if marks
+            .back()
+            .is_none_or(|&(_, at)| now.duration_since(at) >= MARK_INTERVAL)
+        {
+            marks.push_back((index, now));
+        }
+    }
+
+    // Count `records` more of the logged records as sent
+    fn sent(&self, records: u64) {
+        let sent = self.sent.fetch_add(records, Ordering::SeqCst) + records;
+        let mut marks = self.marks.lock().unwrap_or_else(PoisonError::into_inner);
+        while marks.get(1).is_some_and(|&(index, _)| index <= sent) {
+            marks.pop_front();
+        }
+    }
+
+    // How many records the follower has yet to acknowledge, and how long ago
+    // the oldest of them was written
+    pub(crate) fn lag(&self) -> (u64, Duration) {
+        let logged = self.logged.load(Ordering::SeqCst);
+        let sent = self.sent.load(Ordering::SeqCst);
+        let copying = self.copying.load(Ordering::SeqCst);
+        let mut oldest = None;
+        if logged > sent {
+            let marks = self.marks.lock().unwrap_or_else(PoisonError::into_inner);
+            oldest = marks.front().map(|&(_, at)| at);
+        }
+        if copying > 0 {
+            let copied_at = *self
+                .copied_at
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            oldest = match (oldest, copied_at) {
+                (Some(logged), Some(copied)) => Some(logged.min(copied)),
+                (logged, copied) => logged.or(copied),
+            };
+        }
+        let age = oldest.map_or(Duration::ZERO, |at| at.elapsed());
+        (logged.saturating_sub(sent) + copying, age)
+    }
+}
+
+// A primary's replication to its follower, run by `run`
+#[derive(Clone)]
+pub(crate) struct Primary {
+    // The follower, as given
+    url: String,
+    secret: String,
+    wal: Wal,
+    client: reqwest::Client,
+    pub(crate) backlog: Arc<Backlog>,
+    // Where to go on from, or None to send a full copy first
+    start: Option<(u64, u64)>,
+}
+
+impl Primary {
+    // Replicate the write-ahead log `wal` to the follower at `url`, going on
+    // from the saved cursor if it's for the same follower and its segment is
+    // still there
+    pub(crate) fn new(url: &str, secret: &str, wal: Option<&Wal>) -> Result<Self, String> {
+        let wal = wal.ok_or(
+            "--replica-url sends the write-ahead log, which only the memory backend keeps",
+        )?;
+        let path = wal.path();
+        let start = match read_cursor(&cursor_path(path)) {
+            Ok(Some(cursor)) if cursor.url == url => {
+                let segment = wal::segment_path(path, cursor.segment);
+                let found = fs::metadata(&segment).map(|metadata| metadata.len());
+                (cursor.segment < wal.first_segment()
+                    && found.is_ok_and(|len| cursor.offset <= len))
+                .then_some((cursor.segment, cursor.offset))
+            }
+            Ok(_) => None,
+            Err(e) => {
+                tracing::warn!("Ignoring the unreadable replication cursor: {}", e);
+                None
+            }
+        };
+        let behind = match start {
+            Some(start) => {
+                let behind = count_records(path, start, wal.first_segment())
+                    .map_err(|e| format!("failed to read write-ahead log: {}", e))?;
+                wal.retain_from(start.0);
+                tracing::info!("Replicating to {}, {} records behind", url, behind);
+                behind
+            }
+            None => {
+                wal.retain_from(wal.first_segment());
+                tracing::info!("Replicating to {}, starting with a full copy", url);
+                0
+            }
+        };
+        let client = reqwest::Client::builder()
+            .timeout(SEND_TIMEOUT)
+            .build()
+            .map_err(|e| format!("failed to build the replication client: {}", e))?;
+        Ok(Self {
+            url: url.to_string(),
+            secret: secret.to_string(),
+            wal: wal.clone(),
+            client,
+            backlog: Arc::new(Backlog::behind(behind)),
+            start,
+        })
+    }
+
+    // Push writes to the follower as they're logged, until `shutdown` is set
+    pub(crate) async fn run(self, store: Store, mut shutdown: watch::Receiver<bool>) {
+        let mut cursor = match self.start {
+            Some(start) => start,
+            None => match self.copy(&store, &mut shutdown).await {
+                Some(start) => start,
+                None => return,
+            },
+        };
+        loop {
+            let path = self.wal.path().to_path_buf();
+            let read = tokio::task::spawn_blocking(move || read_batch(&path, cursor)).await;
+            let (body, records, end) = match read.map_err(io::Error::other).and_then(|read| read) {
+                Ok(batch) => batch,
+                Err(e) => {
+                    tracing::error!("Failed to read the write-ahead log to replicate: {}", e);
+                    (Vec::new(), 0, cursor)
+                }
+            };
+            if records > 0 && !self.send(body.into(), &mut shutdown).await {
+                return;
+            }
+            if end != cursor && self.save(end).await {
+                // Only once saved, so a restart finds the segment it names
+                self.wal.retain_from(end.0);
+            }
+            cursor = end;
+            self.backlog.sent(records);
+            if records == 0 {
+                tokio::select! {
+                    _ = self.wal.committed() => {}
+                    _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                    _ = shutdown.changed() => return,
+                }
+            }
+        }
+    }
+
+    // Replace what the follower has with a copy of the store, returning where
+    // the log goes on from it, or None if shutdown came first
+    async fn copy(
+        &self,
+        store: &Store,
+        shutdown: &mut watch::Receiver<bool>,
+    ) -> Option<(u64, u64)> {
+        let mut retry = FIRST_RETRY;
+        loop {
+            match self.take_copy(store).await {
+                Ok((batches, closed)) => {
+                    let path = self.wal.path().to_path_buf();
+                    // This process's records the copy covers
+                    let from = (self.wal.first_segment(), 0);
+                    let counted =
+                        tokio::task::spawn_blocking(move || count_records(&path, from, closed + 1))
+                            .await
+                            .map_err(io::Error::other)
+                            .and_then(|counted| counted);
+                    match counted {
+                        Ok(records) => self.backlog.sent(records),
+                        Err(e) => tracing::error!("Failed to count replicated records: {}", e),
+                    }
+                    self.wal.retain_from(closed + 1);
+                    for (body, records) in batches {
+                        if !self.send(body, shutdown).await {
+                            return None;
+                        }
+                        self.backlog.copying.fetch_sub(records, Ordering::SeqCst);
+                    }
+                    let start = (closed + 1, 0);
+                    self.save(start).await;
+                    tracing::info!("Sent {} a full copy of the store", self.url);
+                    return Some(start);
+                }
+                Err(e) => tracing::error!("Failed to copy the store to replicate: {}", e),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(retry) => {}
+                _ = shutdown.changed() => return None,
+            }
+            retry = (retry * 2).min(LAST_RETRY);
+        }
+    }
+
+    // The store as batches of records clearing the follower and putting every
+    // live key, with the write-ahead log segment closed as it was read, which
+    // the copy covers
+    async fn take_copy(&self, store: &Store) -> io::Result<(Vec<(Bytes, u64)>, u64)> {
+        let now = Instant::now();
+        let read = store.with_read(|view| {
+            // Writers log while holding the write lock, so everything logged
+            // so far is in the view
+            let rotated = self.wal.rotate();
+            let mut entries = Vec::with_capacity(view.len());
+            view.for_each(&mut |key, entry| {
+                if !entry.is_expired(now) {
+                    entries.push((key.to_string(), entry.clone()));
+                }
+            });
+            (entries, rotated)
+        });
+        let (entries, rotated) = read.await.map_err(io::Error::other)?;
+        let closed = rotated
+            .await
+            .map_err(|_| io::Error::other("write-ahead log writer has stopped"))??;
+
+        let records = entries.len() as u64 + 1;
+        let mut batches = Vec::new();
+        let (mut body, mut count) = (Vec::new(), 0u64);
+        let all = std::iter::once(WalRecord::Clear).chain(
+            entries
+                .iter()
+                .map(|(key, entry)| WalRecord::put(key, entry)),
+        );
+        for record in all {
+            serde_json::to_writer(&mut body, &record).map_err(io::Error::other)?;
+            body.push(b'\n');
+            count += 1;
+            if count == BATCH_RECORDS as u64 || body.len() >= BATCH_BYTES {
+                batches.push((std::mem::take(&mut body).into(), count));
+                count = 0;
+            }
+        }
+        if count > 0 {
+            batches.push((body.into(), count));
+        }
+        *self
+            .backlog
+            .copied_at
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(now);
+        self.backlog.copying.store(records, Ordering::SeqCst);
+        Ok((batches, closed))
+    }
+
+    // Send `body` until the follower takes it, backing off between attempts.
+    // False if shutdown came first.
+    async fn send(&self, body: Bytes, shutdown: &mut watch::Receiver<bool>) -> bool {
+        let url = format!("{}{}", self.url, APPLY_ROUTE);
+        let mut retry = FIRST_RETRY;
+        loop {
+            let request = self
+                .client
+                .post(&url)
+                .bearer_auth(&self.secret)
+                .header(header::CONTENT_TYPE, "application/x-ndjson")
+                .body(body.clone())
+                .send();
+            let failure = tokio::select! {
+                response = request => match response {
+                    Ok(response) if response.status().is_success() => return true,
+                    Ok(response) => format!("it answered {}", response.status()),
+                    Err(e) => e.to_string(),
+                },
+                _ = shutdown.changed() => return false,
+            };
+            tracing::warn!(
+                "Failed to replicate to {}: {}; retrying in {:?}",
+                self.url,
+                failure,
+                retry
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(retry) => {}
+                _ = shutdown.changed() => return false,
+            }
+            retry = (retry * 2).min(LAST_RETRY);
+        }
+    }
+
+    // Save that the follower has the log up to `position`, returning whether
+    // that worked. A cursor left behind only means records are sent again.
+    async fn save(&self, (segment, offset): (u64, u64)) -> bool {
+        let path = cursor_path(self.wal.path());
+        let cursor = Cursor {
+            url: self.url.clone(),
+            segment,
+            offset,
+        };
+        let saved = tokio::task::spawn_blocking(move || write_cursor(&path, &cursor))
+            .await
+            .map_err(io::Error::other)
+            .and_then(|saved| saved);
+        if let Err(e) = &saved {
+            tracing::error!("Failed to save the replication cursor: {}", e);
+        }
+        saved.is_ok()
+    }
+}
+
+// The file the cursor is saved in, beside the write-ahead log segments
+fn cursor_path(wal_path: &Path) -> PathBuf {
+    let mut name = wal_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".replica");
+    wal_path.with_file_name(name)
+}
+
+fn read_cursor(path: &Path) -> io::Result<Option<Cursor>> {
+    match fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data)
+            .map(Some)
+            .map_err(io::Error::other),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Replace the cursor file whole, so a crash leaves the old one or the new one
+fn write_cursor(path: &Path, cursor: &Cursor) -> io::Result<()> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let tmp_path = path.with_file_name(name);
+    fs::write(
+        &tmp_path,
+        serde_json::to_vec(cursor).map_err(io::Error::other)?,
+    )?;
+    fs::rename(&tmp_path, path)
+}
+
+// Read up to a batch of the complete records in the segments of the log at
+// `path` from `(segment, offset)`, returning them, how many there are and
+// where they end. Once a later segment has been started, the earlier one is
+// complete, and a record left torn at its end by a crash is passed over.
+fn read_batch(
+    path: &Path,
+    (mut segment, mut offset): (u64, u64),
+) -> io::Result<(Vec<u8>, u64, (u64, u64))> {
+    loop {
+        // Looked for first, so a segment that's followed is read to its end
+        let later = wal::list_segments(path)?
+            .into_iter()
+            .map(|(n, _)| n)
+            .find(|&n| n > segment);
+        let mut file = File::open(wal::segment_path(path, segment))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut reader = BufReader::new(file);
+        let (mut body, mut records) = (Vec::new(), 0);
+        loop {
+            let start = body.len();
+            let read = reader.read_until(b'\n', &mut body)?;
+            if read == 0 || body.last() != Some(&b'\n') {
+                // A record still being written is left for the next batch
+                body.truncate(start);
+                break;
+            }
+            records += 1;
+            offset += read as u64;
+            if records == BATCH_RECORDS as u64 || body.len() >= BATCH_BYTES {
+                break;
+            }
+        }
+        match later {
+            Some(later) if records == 0 => (segment, offset) = (later, 0),
+            _ => return Ok((body, records, (segment, offset))),
+        }
+    }
+}
+
+// How many complete records the log at `path` holds from `(segment, offset)`
+// up to segment `before`
+fn count_records(path: &Path, (first, offset): (u64, u64), before: u64) -> io::Result<u64> {
+    let mut records = 0;
+    for (n, segment) in wal::list_segments(path)? {
+        if n < first || n >= before {
+            continue;
+        }
+        let mut file = File::open(segment)?;
+        if n == first {
+            file.seek(SeekFrom::Start(offset))?;
+        }
+        let mut reader = BufReader::new(file);
+        let mut line = Vec::new();
+        while reader.read_until(b'\n', &mut line)? > 0 {
+            records += u64::from(line.last() == Some(&b'\n'));
+            line.clear();
+        }
+    }
+    Ok(records)
+}
+
+// One change a primary pushed
+enum Change {
+    Put(String, Entry),
+    Delete(String),
+    Clear,
+}
+
+// Remove `key` as a replicated delete, announcing it; false if it wasn't there
+fn remove(state: &AppState, view: &mut dyn WriteView, key: &str, now: Instant) -> bool {
+    match view.remove(key) {
+        Some(entry) if entry.is_expired(now) => state.record_expiry(key, &entry),
+        Some(_) => state.record_event(key, None),
+        None => return false,
+    }
+    true
+}
+
+// POST /replication/apply - Apply write-ahead log records a primary pushed,
+// one JSON record per line, in order and in one transaction. The follower
+// logs and announces them as its own writes. Records that can't be read are
+// logged and skipped, as on replay. Only served with --follower, to requests
+// bearing the --replication-secret.
+#[utoipa::path(
+    post, path = "/replication/apply", tag = "replication", operation_id = "replication_apply",
+    summary = "Apply writes pushed by the primary",
+    security(("replication_secret" = [])),
+    request_body(content = String, description = "Write-ahead log records, one per line", content_type = "application/x-ndjson"),
+    responses(
+        (status = 200, description = "`applied`: how many records were applied", content_type = "application/json"),
+        (status = 401, description = "The replication secret is missing or wrong"),
+        (status = 403, description = "The server isn't a follower"),
+    )
+)]
+pub(crate) async fn apply_handler(
+    State(state): State<AppState>,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let mut changes = Vec::new();
+    for (n, line) in body.split(|&byte| byte == b'\n').enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let change = serde_json::from_slice(line)
+            .map_err(|e| e.to_string())
+            .and_then(|record| match record {
+                WalRecord::Put { key, entry } => Ok(Change::Put(key, entry.decode()?)),
+                WalRecord::Delete { key } => Ok(Change::Delete(key)),
+                WalRecord::Clear => Ok(Change::Clear),
+            });
+        match change {
+            Ok(change) => changes.push(change),
+            Err(e) => tracing::warn!("Skipping unreadable replicated record {}: {}", n + 1, e),
+        }
+    }
+
+    let applied = changes.len();
+    let acks = state
+        .store
+        .with_write(|view| {
+            let now = Instant::now();
+            let mut acks = Vec::new();
+            for change in changes {
+                match change {
+                    Change::Put(key, entry) => {
+                        acks.extend(state.log(|| WalRecord::put(&key, &entry)));
+                        state.record_event(&key, Some(&entry));
+                        view.insert(key, entry);
+                    }
+                    Change::Delete(key) => {
+                        if remove(&state, view, &key, now) {
+                            acks.extend(state.log(|| WalRecord::delete(&key)));
+                        }
+                    }
+                    Change::Clear => {
+                        for key in view.keys_with_prefix("") {
+                            remove(&state, view, &key, now);
+                        }
+                        acks.extend(state.log(|| WalRecord::Clear));
+                    }
+                }
+            }
+            acks
+        })
+        .await?;
+    if let Err(e) = wal::wait_all(acks).await {
+        tracing::error!("Failed to log replicated records: {}", e);
+        return Err(ApiError::Internal);
+    }
+    Ok(Json(serde_json::json!({ "applied": applied })).into_response())
+}
@@ -0,0 +1,914 @@
+// A Redis protocol (RESP2) listener over the same store as the HTTP routes,
+// so `redis-cli` and Redis client libraries can read and write the keys of
+// the default namespace. Only the commands below are supported; anything else
+// gets an error reply and the connection stays open.
+
+use crate::auth::{Access, Permission};
+use crate::keyspace::{self, Namespace};
+use crate::middleware::Authenticator;
+use crate::store::Entry;
+use crate::{handlers, wal, AppState, NoRoom};
+use bytes::{Bytes, BytesMut};
+use std::ops::Range;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+// Longest inline command, or array or bulk string header, accepted
+const MAX_LINE: usize = 64 * 1024;
+
+// Most arguments a single command may have
+const MAX_ARGS: i64 = 1024 * 1024;
+
+// Keys returned per SCAN call when COUNT isn't given
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+// A malformed request. The connection is closed after replying, since there's
+// no telling where the next command starts.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ProtocolError(&'static str);
+
+// The arguments of a parsed command, as ranges of the buffer it came from,
+// and how many bytes of the buffer it took up
+type Parsed = (Vec<Range<usize>>, usize);
+
+// Parse the command at the start of `buf`: an array of bulk strings, as
+// clients send, or an inline command of space-separated words, as typed into
+// telnet. Returns None until the whole command has arrived. An empty array or
+// line parses as a command with no arguments, which is skipped.
+pub(crate) fn parse(buf: &[u8], max_bulk: usize) -> Result<Option<Parsed>, ProtocolError> {
+    match buf.first() {
+        None => Ok(None),
+        Some(b'*') => parse_array(buf, max_bulk),
+        Some(_) => parse_inline(buf),
+    }
+}
+
+fn parse_array(buf: &[u8], max_bulk: usize) -> Result<Option<Parsed>, ProtocolError> {
+    let Some((count, mut at)) = read_number(buf, 1, "invalid multibulk length")? else {
+        return Ok(None);
+    };
+    if count > MAX_ARGS {
+        return Err(ProtocolError("invalid multibulk length"));
+    }
+    let mut args = Vec::with_capacity(count.clamp(0, 64) as usize);
+    for _ in 0..count {
+        match buf.get(at) {
+            None => return Ok(None),
+            Some(b'$') => {}
+            Some(_) => return Err(ProtocolError("expected '$'")),
+        }
+        let Some((len, start)) = read_number(buf, at + 1, "invalid bulk length")? else {
+            return Ok(None);
+        };
+        if len < 0 || len as usize > max_bulk {
+            return Err(ProtocolError("invalid bulk length"));
+        }
+        let end = start + len as usize;
+        if buf.len() < end + 2 {
+            return Ok(None);
+        }
+        if &buf[end..end + 2] != b"\r\n" {
+            return Err(ProtocolError("expected CRLF after bulk string"));
+        }
+        args.push(start..end);
+        at = end + 2;
+    }
+    Ok(Some((args, at)))
+}
+
+// The decimal number from `start` up to the next CRLF, and where the line ends
+fn read_number(
+    buf: &[u8],
+    start: usize,
+    invalid: &'static str,
+) -> Result<Option<(i64, usize)>, ProtocolError> {
+    let rest = buf.get(start..).unwrap_or_default();
+    let Some(len) = rest.windows(2).position(|pair| pair == b"\r\n") else {
+        if rest.len() > MAX_LINE {
+            return Err(ProtocolError(invalid));
+        }
+        return Ok(None);
+    };
+    let number = std::str::from_utf8(&rest[..len])
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or(ProtocolError(invalid))?;
+    Ok(Some((number, start + len + 2)))
+}
+
+// Quoting isn't supported, so inline arguments can't contain spaces
+fn parse_inline(buf: &[u8]) -> Result<Option<Parsed>, ProtocolError> {
+    let Some(end) = buf.iter().position(|&byte| byte == b'\n') else {
+        if buf.len() > MAX_LINE {
+            return Err(ProtocolError("too big inline request"));
+        }
+        return Ok(None);
+    };
+    let mut args = Vec::new();
+    let mut word: Option<usize> = None;
+    for (at, byte) in buf[..end].iter().enumerate() {
+        match (byte.is_ascii_whitespace(), word) {
+            (false, None) => word = Some(at),
+            (true, Some(start)) => {
+                args.push(start..at);
+                word = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(start) = word {
+        args.push(start..end);
+    }
+    Ok(Some((args, end + 1)))
+}
+
+// A reply to a command
+#[derive(Debug, PartialEq)]
+pub(crate) enum Reply {
+    Simple(&'static str),
+    // The message starts with the error code, like "ERR" or "NOAUTH"
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Nil,
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn error(message: impl Into<String>) -> Self {
+        Reply::Error(message.into())
+    }
+
+    pub(crate) fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(text) => {
+                out.push(b'+');
+                out.extend_from_slice(text.as_bytes());
+            }
+            Reply::Error(message) => {
+                out.push(b'-');
+                // A line break would end the reply early
+                out.extend(message.bytes().map(|byte| match byte {
+                    b'\r' | b'\n' => b' ',
+                    byte => byte,
+                }));
+            }
+            Reply::Integer(n) => out.extend_from_slice(format!(":{}", n).as_bytes()),
+            Reply::Bulk(value) => {
+                out.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+                out.extend_from_slice(value);
+            }
+            Reply::Nil => out.extend_from_slice(b"$-1"),
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}", items.len()).as_bytes());
+                out.extend_from_slice(b"\r\n");
+                for item in items {
+                    item.encode(out);
+                }
+                return;
+            }
+        }
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+fn wrong_arity(command: &[u8]) -> Reply {
+    Reply::error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        String::from_utf8_lossy(command).to_lowercase()
+    ))
+}
+
+fn syntax_error() -> Reply {
+    Reply::error("ERR syntax error")
+}
+
+fn not_an_integer() -> Reply {
+    Reply::error("ERR value is not an integer or out of range")
+}
+
+fn storage_failure(e: impl std::fmt::Display) -> Reply {
+    tracing::error!("{}", e);
+    Reply::error("ERR storage failure")
+}
+
+fn log_failure(e: impl std::fmt::Display) -> Reply {
+    tracing::error!("Failed to log a Redis protocol write: {}", e);
+    Reply::error("ERR failed to log the write")
+}
+
+fn no_room(state: &AppState, reason: NoRoom) -> Reply {
+    Reply::error(match reason {
+        NoRoom::Budget => format!(
+            "OOM write would exceed the storage budget of {} bytes",
+            state.store.limits().max_bytes.unwrap_or_default()
+        ),
+        NoRoom::Quota(_) => "OOM write would exceed the tenant quota".to_string(),
+    })
+}
+
+// Whether `text` matches a glob-style pattern as KEYS and SCAN take it: `*`
+// for any run of bytes, `?` for any one byte, `[abc]`, `[a-z]` and `[^abc]`
+// for sets, and `\` to escape any of these
+pub(crate) fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| glob_match(rest, &text[skip..])),
+        Some((b'?', rest)) => !text.is_empty() && glob_match(rest, &text[1..]),
+        Some((b'[', rest)) => {
+            let Some((&byte, text_rest)) = text.split_first() else {
+                return false;
+            };
+            let (negated, set) = match rest.split_first() {
+                Some((b'^', set)) => (true, set),
+                _ => (false, rest),
+            };
+            let Some(close) = set.iter().skip(1).position(|&b| b == b']').map(|at| at + 1) else {
+                // An unclosed set matches `[` literally
+                return byte == b'[' && glob_match(rest, text_rest);
+            };
+            let mut matched = false;
+            let mut at = 0;
+            while at < close {
+                if at + 2 < close && set[at + 1] == b'-' {
+                    let (low, high) = (set[at].min(set[at + 2]), set[at].max(set[at + 2]));
+                    matched |= (low..=high).contains(&byte);
+                    at += 3;
+                } else {
+                    matched |= set[at] == byte;
+                    at += 1;
+                }
+            }
+            matched != negated && glob_match(&set[close + 1..], text_rest)
+        }
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            text.first() == Some(&rest[0]) && glob_match(&rest[1..], &text[1..])
+        }
+        Some((&literal, rest)) => text.first() == Some(&literal) && glob_match(rest, &text[1..]),
+    }
+}
+
+// The part of a pattern before its first special character, which every
+// matching key starts with
+fn literal_prefix(pattern: &[u8]) -> &[u8] {
+    let end = pattern
+        .iter()
+        .position(|byte| b"*?[\\".contains(byte))
+        .unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+// What a connection may do: nothing until it authenticates, if
+// authentication is enabled, and then what its token allows
+struct Session {
+    access: Option<Access>,
+}
+
+// Everything a connection needs, shared by all of them
+#[derive(Clone)]
+pub(crate) struct Resp {
+    pub(crate) state: AppState,
+    pub(crate) authenticator: Authenticator,
+}
+
+impl Resp {
+    fn auth_required(&self) -> bool {
+        self.authenticator.api_keys.is_enabled() || self.authenticator.acl.is_enabled()
+    }
+
+    // Longest bulk string accepted, enough for any key or value that fits
+    fn max_bulk(&self) -> usize {
+        self.state.max_value_bytes.max(self.state.max_key_bytes.0)
+    }
+
+    // Accept connections until `shutdown` is set. Connections also close then,
+    // once the command they are running, if any, has been answered.
+    pub(crate) async fn serve(self, listener: TcpListener, mut shutdown: watch::Receiver<bool>) {
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        tracing::warn!("Failed to accept a Redis protocol connection: {}", e);
+                        continue;
+                    }
+                },
+                _ = shutdown.wait_for(|&stop| stop) => break,
+            };
+            let resp = self.clone();
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                if let Err(e) = resp.connection(stream, shutdown).await {
+                    tracing::debug!("Redis protocol connection failed: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn connection(
+        &self,
+        mut stream: TcpStream,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> std::io::Result<()> {
+        let mut session = Session {
+            access: (!self.auth_required()).then_some(Access::Full),
+        };
+        let mut buf = BytesMut::with_capacity(4096);
+        let mut out = Vec::new();
+        loop {
+            // Answer every command that has fully arrived, then send the
+            // replies together, so pipelined commands take one write
+            loop {
+                let (ranges, used) = match parse(&buf, self.max_bulk()) {
+                    Ok(Some(parsed)) => parsed,
+                    Ok(None) => break,
+                    Err(ProtocolError(message)) => {
+                        Reply::error(format!("ERR Protocol error: {}", message)).encode(&mut out);
+                        return stream.write_all(&out).await;
+                    }
+                };
+                let frame = buf.split_to(used).freeze();
+                let args: Vec<Bytes> = ranges.into_iter().map(|range| frame.slice(range)).collect();
+                if args.is_empty() {
+                    continue;
+                }
+                if args[0].eq_ignore_ascii_case(b"QUIT") {
+                    Reply::Simple("OK").encode(&mut out);
+                    return stream.write_all(&out).await;
+                }
+                self.execute(&mut session, &args).await.encode(&mut out);
+            }
+            if !out.is_empty() {
+                stream.write_all(&out).await?;
+                out.clear();
+            }
+            tokio::select! {
+                read = stream.read_buf(&mut buf) => {
+                    if read? == 0 {
+                        return Ok(());
+                    }
+                }
+                _ = shutdown.wait_for(|&stop| stop) => return Ok(()),
+            }
+        }
+    }
+
+    async fn execute(&self, session: &mut Session, args: &[Bytes]) -> Reply {
+        let command = args[0].to_ascii_uppercase();
+        let args = &args[1..];
+        if command == b"AUTH" {
+            return self.auth(session, args);
+        }
+        let Some(access) = &session.access else {
+            return Reply::error("NOAUTH Authentication required.");
+        };
+        match (command.as_slice(), args.len()) {
+            (b"PING", 0) => Reply::Simple("PONG"),
+            (b"PING", 1) => Reply::Bulk(args[0].clone()),
+            (b"GET", 1) => self.get(access, &args[0]).await,
+            (b"SET", 2..) => self.set(access, args).await,
+            (b"DEL", 1..) => self.del(access, args).await,
+            (b"EXISTS", 1..) => self.exists(access, args).await,
+            (b"KEYS", 1) => self.keys(access, &args[0]).await,
+            (b"SCAN", 1..) => self.scan(access, args).await,
+            (b"INCR", 1) => self.incr(access, &args[0], Some(1)).await,
+            (b"DECR", 1) => self.incr(access, &args[0], Some(-1)).await,
+            (b"INCRBY" | b"DECRBY", 2) => {
+                let Some(by) = std::str::from_utf8(&args[1])
+                    .ok()
+                    .and_then(|by| by.parse::<i64>().ok())
+                else {
+                    return not_an_integer();
+                };
+                let by = if command == b"DECRBY" {
+                    by.checked_neg()
+                } else {
+                    Some(by)
+                };
+                self.incr(access, &args[0], by).await
+            }
+            (
+                b"PING" | b"GET" | b"SET" | b"DEL" | b"EXISTS" | b"KEYS" | b"SCAN" | b"INCR"
+                | b"DECR" | b"INCRBY" | b"DECRBY",
+                _,
+            ) => wrong_arity(&command),
+            _ => Reply::error(format!(
+                "ERR unknown command '{}'",
+                String::from_utf8_lossy(&command).to_lowercase()
+            )),
+        }
+    }
+
+    // AUTH <token> or AUTH <username> <token>; the username is ignored
+    fn auth(&self, session: &mut Session, args: &[Bytes]) -> Reply {
+        let token = match args {
+            [token] | [_, token] => String::from_utf8_lossy(token),
+            _ => return wrong_arity(b"AUTH"),
+        };
+        if !self.auth_required() {
+            return Reply::error("ERR AUTH called without any API key or ACL token configured");
+        }
+        let token = token.trim();
+        if self.authenticator.api_keys.verify(token) {
+            session.access = Some(Access::Full);
+        } else if let Some(grants) = self.authenticator.acl.lookup(token) {
+            session.access = Some(Access::Scoped(grants));
+        } else {
+            return Reply::error("WRONGPASS invalid API key or ACL token");
+        }
+        Reply::Simple("OK")
+    }
+
+    // The stored key named by a command argument, if it is valid and the
+    // client may use it as `permission` asks
+    fn key(&self, access: &Access, raw: &[u8], permission: Permission) -> Result<String, Reply> {
+        let key = std::str::from_utf8(raw).map_err(|_| Reply::error("ERR keys must be UTF-8"))?;
+        keyspace::validate_key(key, self.state.max_key_bytes)
+            .map_err(|msg| Reply::error(format!("ERR {}", msg)))?;
+        let key = Namespace::default().storage_key(key);
+        if !access.allows(&key, permission) {
+            return Err(Reply::error("NOPERM this token can't access that key"));
+        }
+        Ok(key)
+    }
+
+    fn read_only(&self) -> Option<Reply> {
+        if self.state.follower.is_some() {
+            return Some(Reply::error("READONLY You can't write against a follower"));
+        }
+        self.state
+            .read_only
+            .load(Ordering::Relaxed)
+            .then(|| Reply::error("READONLY The server is in read-only mode"))
+    }
+
+    async fn get(&self, access: &Access, key: &[u8]) -> Reply {
+        let key = match self.key(access, key, Permission::Read) {
+            Ok(key) => key,
+            Err(reply) => return reply,
+        };
+        let result = self
+            .state
+            .store
+            .with_key_read(&key, |view| {
+                let now = Instant::now();
+                view.get(&key)
+                    .map(|entry| (!entry.is_expired(now)).then_some(entry))
+            })
+            .await;
+        match result {
+            Ok(Some(Some(entry))) => {
+                self.state.ops.get(None, true);
+                match entry.data() {
+                    Ok(value) => Reply::Bulk(value),
+                    Err(e) => storage_failure(e),
+                }
+            }
+            Ok(Some(None)) => {
+                self.state.ops.get(None, false);
+                if let Err(e) = handlers::remove_if_expired(&self.state, &key).await {
+                    return storage_failure(e);
+                }
+                Reply::Nil
+            }
+            Ok(None) => {
+                self.state.ops.get(None, false);
+                Reply::Nil
+            }
+            Err(e) => storage_failure(e),
+        }
+    }
+
+    // SET key value [EX seconds | PX milliseconds] [NX | XX]
+    async fn set(&self, access: &Access, args: &[Bytes]) -> Reply {
+        let key = match self.key(access, &args[0], Permission::Write) {
+            Ok(key) => key,
+            Err(reply) => return reply,
+        };
+        if let Some(reply) = self.read_only() {
+            return reply;
+        }
+        let value = args[1].clone();
+        if value.len() > self.state.max_value_bytes {
+            return Reply::error(format!(
+                "ERR values are limited to {} bytes",
+                self.state.max_value_bytes
+            ));
+        }
+
+        let mut ttl = None;
+        let mut only_if: Option<bool> = None;
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            let option = option.to_ascii_uppercase();
+            match option.as_slice() {
+                b"EX" | b"PX" if ttl.is_none() => {
+                    let Some(amount) = options
+                        .next()
+                        .and_then(|amount| std::str::from_utf8(amount).ok()?.parse::<u64>().ok())
+                    else {
+                        return not_an_integer();
+                    };
+                    if amount == 0 {
+                        return Reply::error("ERR invalid expire time in 'set' command");
+                    }
+                    ttl = Some(if option == b"EX" {
+                        Duration::from_secs(amount)
+                    } else {
+                        Duration::from_millis(amount)
+                    });
+                }
+                b"NX" if only_if.is_none() => only_if = Some(false),
+                b"XX" if only_if.is_none() => only_if = Some(true),
+                _ => return syntax_error(),
+            }
+        }
+
+        let mut entry = Entry {
+            expires_at: ttl.map(|ttl| Instant::now() + ttl),
+            ..Entry::new(value)
+        };
+        let state = &self.state;
+        let result = state
+            .store
+            .with_key_write(&key, |view| {
+                let now = Instant::now();
+                let current = view.get(&key).filter(|current| !current.is_expired(now));
+                if only_if.is_some_and(|exists| exists != current.is_some()) {
+                    return Ok(None);
+                }
+                if let Some(current) = &current {
+                    entry.replaces(current, state.history_depth);
+                }
+                state.check_room(view, &key, &entry)?;
+                entry.version = view.next_version();
+                let ack = state.log(|| wal::WalRecord::put(&key, &entry));
+                state.record_event(&key, Some(&entry));
+                view.insert(key.clone(), entry);
+                Ok(Some(ack))
+            })
+            .await;
+        let ack = match result {
+            Ok(Ok(Some(ack))) => ack,
+            Ok(Ok(None)) => return Reply::Nil,
+            Ok(Err(reason)) => return no_room(state, reason),
+            Err(e) => return storage_failure(e),
+        };
+        if let Err(e) = wal::wait(ack).await {
+            return log_failure(e);
+        }
+        state.ops.put(1);
+        Reply::Simple("OK")
+    }
+
+    async fn del(&self, access: &Access, args: &[Bytes]) -> Reply {
+        let keys = match args
+            .iter()
+            .map(|key| self.key(access, key, Permission::Write))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(keys) => keys,
+            Err(reply) => return reply,
+        };
+        if let Some(reply) = self.read_only() {
+            return reply;
+        }
+        let state = &self.state;
+        let result = state
+            .store
+            .with_write(|view| {
+                let now = Instant::now();
+                let mut acks = Vec::new();
+                let mut removed = 0;
+                for key in keys {
+                    // An expired entry is removed either way, but not counted
+                    match view.remove(&key) {
+                        Some(entry) if !entry.is_expired(now) => {
+                            acks.extend(state.log(|| wal::WalRecord::delete(&key)));
+                            state.record_event(&key, None);
+                            if let Some(tombstones) = &state.tombstones {
+                                tombstones.bury(key, entry, now);
+                            }
+                            removed += 1;
+                        }
+                        Some(entry) => state.record_expiry(&key, &entry),
+                        None => {}
+                    }
+                }
+                (removed, acks)
+            })
+            .await;
+        let (removed, acks) = match result {
+            Ok(outcome) => outcome,
+            Err(e) => return storage_failure(e),
+        };
+        if let Err(e) = wal::wait_all(acks).await {
+            return log_failure(e);
+        }
+        for _ in 0..removed {
+            state.ops.delete();
+        }
+        Reply::Integer(removed)
+    }
+
+    // Repeated keys are counted each time, as Redis does
+    async fn exists(&self, access: &Access, args: &[Bytes]) -> Reply {
+        let keys = match args
+            .iter()
+            .map(|key| self.key(access, key, Permission::Read))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(keys) => keys,
+            Err(reply) => return reply,
+        };
+        let result = self
+            .state
+            .store
+            .with_read(|view| {
+                let now = Instant::now();
+                keys.iter()
+                    .filter(|key| view.get(key).is_some_and(|entry| !entry.is_expired(now)))
+                    .count()
+            })
+            .await;
+        match result {
+            Ok(count) => Reply::Integer(count as i64),
+            Err(e) => storage_failure(e),
+        }
+    }
+
+    // Whether a stored key is in the default namespace, matches `pattern` and
+    // may be read by the client
+    fn listed(access: &Access, stored: &str, pattern: &[u8]) -> bool {
+        Namespace::default()
+            .client_key(stored)
+            .is_some_and(|key| glob_match(pattern, key.as_bytes()))
+            && access.allows(stored, Permission::Read)
+    }
+
+    async fn keys(&self, access: &Access, pattern: &[u8]) -> Reply {
+        let prefix = String::from_utf8_lossy(literal_prefix(pattern));
+        let result = self
+            .state
+            .store
+            .with_read(|view| {
+                let now = Instant::now();
+                let mut keys = Vec::new();
+                view.for_each(&mut |key, entry| {
+                    if key.starts_with(prefix.as_ref())
+                        && !entry.is_expired(now)
+                        && Self::listed(access, key, pattern)
+                    {
+                        keys.push(Reply::Bulk(Bytes::copy_from_slice(key.as_bytes())));
+                    }
+                });
+                keys
+            })
+            .await;
+        match result {
+            Ok(keys) => Reply::Array(keys),
+            Err(e) => storage_failure(e),
+        }
+    }
+
+    // SCAN cursor [MATCH pattern] [COUNT count]. The cursor is how many keys
+    // earlier calls have covered, in key order, so keys written between calls
+    // can shift a later page and be returned twice or skipped, which Redis
+    // clients already allow for. As in Redis, MATCH filters each page after
+    // paging, so pages can come back short or empty before the scan ends.
+    async fn scan(&self, access: &Access, args: &[Bytes]) -> Reply {
+        let Some(cursor) = std::str::from_utf8(&args[0])
+            .ok()
+            .and_then(|cursor| cursor.parse::<usize>().ok())
+        else {
+            return Reply::error("ERR invalid cursor");
+        };
+        let mut pattern: &[u8] = b"*";
+        let mut count = DEFAULT_SCAN_COUNT;
+        let mut options = args[1..].iter();
+        while let Some(option) = options.next() {
+            let value = options.next();
+            match (option.to_ascii_uppercase().as_slice(), value) {
+                (b"MATCH", Some(value)) => pattern = value,
+                (b"COUNT", Some(value)) => {
+                    match std::str::from_utf8(value).ok().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => count = n,
+                        _ => return not_an_integer(),
+                    }
+                }
+                _ => return syntax_error(),
+            }
+        }
+
+        let prefix = String::from_utf8_lossy(literal_prefix(pattern));
+        let limit = cursor.saturating_add(count);
+        let result = self
+            .state
+            .store
+            .with_read(|view| view.scan(&prefix, None, limit, Instant::now()))
+            .await;
+        let scanned = match result {
+            Ok(scanned) => scanned,
+            Err(e) => return storage_failure(e),
+        };
+        // Other namespaces sort after the default one, so once a page reaches
+        // one of their keys, the scan is over
+        let more = scanned.len() == limit
+            && scanned
+                .last()
+                .is_some_and(|(key, _)| Namespace::default().client_key(key).is_some());
+        let next = if more { limit } else { 0 };
+        let keys = scanned
+            .into_iter()
+            .skip(cursor)
+            .filter(|(key, _)| Self::listed(access, key, pattern))
+            .map(|(key, _)| Reply::Bulk(Bytes::from(key)))
+            .collect();
+        Reply::Array(vec![
+            Reply::Bulk(Bytes::from(next.to_string())),
+            Reply::Array(keys),
+        ])
+    }
+
+    // INCR, DECR, INCRBY and DECRBY, with a missing key counting as 0 and any
+    // expiry the key has kept. `by` is None when negating it overflowed.
+    async fn incr(&self, access: &Access, key: &[u8], by: Option<i64>) -> Reply {
+        let key = match self.key(access, key, Permission::Write) {
+            Ok(key) => key,
+            Err(reply) => return reply,
+        };
+        if let Some(reply) = self.read_only() {
+            return reply;
+        }
+        let state = &self.state;
+        let result = state
+            .store
+            .with_key_write(&key, |view| {
+                let now = Instant::now();
+                let current = view.get(&key).filter(|current| !current.is_expired(now));
+                let mut entry = current
+                    .clone()
+                    .unwrap_or_else(|| Entry::new(Bytes::from_static(b"0")));
+                // Compressed values are all longer than any integer
+                let count: i64 = std::str::from_utf8(&entry.value)
+                    .ok()
+                    .filter(|_| !entry.compressed)
+                    .and_then(|text| text.parse().ok())
+                    .ok_or_else(not_an_integer)?;
+                let next = by
+                    .and_then(|by| count.checked_add(by))
+                    .ok_or_else(|| Reply::error("ERR increment or decrement would overflow"))?;
+
+                entry.value = Bytes::from(next.to_string());
+                entry.compressed = false;
+                if let Some(current) = &current {
+                    entry.replaces(current, state.history_depth);
+                }
+                state
+                    .check_room(view, &key, &entry)
+                    .map_err(|reason| no_room(state, reason))?;
+                entry.version = view.next_version();
+                let ack = state.log(|| wal::WalRecord::put(&key, &entry));
+                state.record_event(&key, Some(&entry));
+                view.insert(key.clone(), entry);
+                Ok((next, ack))
+            })
+            .await;
+        let (next, ack) = match result {
+            Ok(Ok(outcome)) => outcome,
+            Ok(Err(reply)) => return reply,
+            Err(e) => return storage_failure(e),
+        };
+        if let Err(e) = wal::wait(ack).await {
+            return log_failure(e);
+        }
+        Reply::Integer(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The arguments of the first command in `buf`, and the bytes it took up
+    fn parsed(buf: &[u8]) -> Option<(Vec<&[u8]>, usize)> {
+        let (ranges, used) = parse(buf, 1024).unwrap()?;
+        Some((ranges.into_iter().map(|range| &buf[range]).collect(), used))
+    }
+
+    #[test]
+    fn parses_arrays_of_bulk_strings() {
+        let buf = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$5\r\nv\r\nal\r\n";
+        let (args, used) = parsed(buf).unwrap();
+        assert_eq!(args, [&b"SET"[..], b"k", b"v\r\nal"]);
+        assert_eq!(used, buf.len());
+    }
+
+    #[test]
+    fn parses_one_command_at_a_time() {
+        let buf = b"*1\r\n$4\r\nPING\r\n*1\r\n$4\r\nPI";
+        let (args, used) = parsed(buf).unwrap();
+        assert_eq!(args, [b"PING"]);
+        assert_eq!(used, 14);
+        assert_eq!(parsed(&buf[used..]), None);
+    }
+
+    #[test]
+    fn waits_for_incomplete_commands() {
+        let buf = b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n";
+        for end in 0..buf.len() {
+            assert_eq!(parsed(&buf[..end]), None, "parsed {} bytes", end);
+        }
+        assert!(parsed(buf).is_some());
+    }
+
+    #[test]
+    fn parses_empty_and_zero_length_arguments() {
+        assert_eq!(parsed(b"*0\r\n"), Some((vec![], 4)));
+        let (args, _) = parsed(b"*2\r\n$3\r\nGET\r\n$0\r\n\r\n").unwrap();
+        assert_eq!(args, [&b"GET"[..], b""]);
+    }
+
+    #[test]
+    fn parses_inline_commands() {
+        let (args, used) = parsed(b"SET  k   v\r\nGET k\r\n").unwrap();
+        assert_eq!(args, [&b"SET"[..], b"k", b"v"]);
+        assert_eq!(used, 12);
+        assert_eq!(parsed(b"PING\n"), Some((vec![&b"PING"[..]], 5)));
+        assert_eq!(parsed(b"\r\n"), Some((vec![], 2)));
+        assert_eq!(parsed(b"PING"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_commands() {
+        let error = |buf: &[u8]| parse(buf, 1024).unwrap_err();
+        assert_eq!(error(b"*x\r\n"), ProtocolError("invalid multibulk length"));
+        assert_eq!(error(b"*1\r\n:1\r\n"), ProtocolError("expected '$'"));
+        assert_eq!(
+            error(b"*1\r\n$-1\r\n"),
+            ProtocolError("invalid bulk length")
+        );
+        assert_eq!(
+            error(b"*1\r\n$2000\r\n"),
+            ProtocolError("invalid bulk length")
+        );
+        assert_eq!(
+            error(b"*1\r\n$1\r\nab\r\n"),
+            ProtocolError("expected CRLF after bulk string")
+        );
+        assert_eq!(
+            error(&vec![b'x'; MAX_LINE + 1]),
+            ProtocolError("too big inline request")
+        );
+    }
+
+    fn encoded(reply: Reply) -> Vec<u8> {
+        let mut out = Vec::new();
+        reply.encode(&mut out);
+        out
+    }
+
+    #[test]
+    fn encodes_replies() {
+        assert_eq!(encoded(Reply::Simple("OK")), b"+OK\r\n");
+        assert_eq!(
+            encoded(Reply::error("ERR bad\r\nthing")),
+            b"-ERR bad  thing\r\n"
+        );
+        assert_eq!(encoded(Reply::Integer(-3)), b":-3\r\n");
+        assert_eq!(
+            encoded(Reply::Bulk(Bytes::from("a\r\nb"))),
+            b"$4\r\na\r\nb\r\n"
+        );
+        assert_eq!(encoded(Reply::Nil), b"$-1\r\n");
+        assert_eq!(
+            encoded(Reply::Array(vec![
+                Reply::Bulk(Bytes::from("0")),
+                Reply::Array(vec![])
+            ])),
+            b"*2\r\n$1\r\n0\r\n*0\r\n"
+        );
+    }
+
+    #[test]
+    fn matches_glob_patterns() {
+        let matches = |pattern: &str, text: &str| glob_match(pattern.as_bytes(), text.as_bytes());
+        assert!(matches("*", ""));
+        assert!(matches("user:*", "user:42"));
+        assert!(!matches("user:*", "users"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("k[0-9]", "k7"));
+        assert!(matches("a\\*b", "a*b"));
+        assert!(!matches("a\\*b", "axb"));
+        assert!(matches("*:*:end", "a:b:c:end"));
+        assert!(matches("[", "["));
+        assert_eq!(literal_prefix(b"user:*:x"), b"user:");
+        assert_eq!(literal_prefix(b"plain"), b"plain");
+    }
+}
@@ -0,0 +1,698 @@
+use crate::config::{Backend, Config};
+use crate::keyspace;
+use crate::persistence;
+use crate::wal::{self, Wal};
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io::{Read, Write};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Instant, SystemTime};
+
+mod memory;
+mod sled;
+
+pub use self::memory::MemoryStorage;
+pub use self::sled::SledStorage;
+
+/// A stored value together with its metadata
+#[derive(Clone)]
+pub struct Entry {
+    pub value: Bytes,
+    /// When the value stops being served, if it was written with a TTL
+    pub expires_at: Option<Instant>,
+    /// Content-Type supplied when the value was written
+    pub content_type: Option<String>,
+    /// Store-wide write sequence number of the current value, served as the ETag.
+    /// Zero for entries persisted before versions were tracked.
+    pub version: u64,
+    /// Values this one replaced, newest first. Shared so clones stay cheap.
+    pub history: Arc<[PastVersion]>,
+    /// When the key was first written, kept across overwrites
+    pub created_at: SystemTime,
+    /// When the current value was written
+    pub updated_at: SystemTime,
+    /// Opaque flags a memcached client stored along with the value, and gets
+    /// back with it. Zero for values written any other way.
+    pub flags: u32,
+    /// Whether `value` holds the gzip encoding of what was written. Read the
+    /// written value with [`Entry::data`].
+    pub compressed: bool,
+}
+
+/// A replaced value kept in a key's history
+#[derive(Clone)]
+pub struct PastVersion {
+    pub value: Bytes,
+    pub content_type: Option<String>,
+    pub version: u64,
+    pub replaced_at: SystemTime,
+    pub compressed: bool,
+}
+
+impl PastVersion {
+    /// The value as written, decompressed if it is stored compressed
+    pub fn data(&self) -> Result<Bytes, StorageError> {
+        stored_data(&self.value, self.compressed)
+    }
+
+    /// Length of the value as written, without decompressing it
+    pub fn data_len(&self) -> usize {
+        stored_len(&self.value, self.compressed)
+    }
+}
+
+/// The gzip encoding of `value`, if that is any smaller
+pub fn compress(value: &[u8]) -> Option<Bytes> {
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(value).ok()?;
+    let compressed = encoder.finish().ok()?;
+    (compressed.len() < value.len()).then(|| Bytes::from(compressed))
+}
+
+// A gzip stream ends with the length of its contents, modulo 2^32, which is
+// more than any value can be
+fn stored_len(value: &Bytes, compressed: bool) -> usize {
+    match value.len().checked_sub(4) {
+        Some(trailer) if compressed => {
+            let length: [u8; 4] = value[trailer..].try_into().unwrap();
+            u32::from_le_bytes(length) as usize
+        }
+        _ => value.len(),
+    }
+}
+
+pub(crate) fn stored_data(value: &Bytes, compressed: bool) -> Result<Bytes, StorageError> {
+    if !compressed {
+        return Ok(value.clone());
+    }
+    let mut data = Vec::new();
+    GzDecoder::new(&value[..])
+        .read_to_end(&mut data)
+        .map_err(|e| StorageError::new(format!("corrupt compressed value: {}", e)))?;
+    Ok(Bytes::from(data))
+}
+
+impl Entry {
+    /// A value written now with no expiry, Content-Type or history, not yet
+    /// given a version
+    pub fn new(value: Bytes) -> Self {
+        let now = SystemTime::now();
+        Self {
+            value,
+            expires_at: None,
+            content_type: None,
+            version: 0,
+            history: Arc::new([]),
+            created_at: now,
+            updated_at: now,
+            flags: 0,
+            compressed: false,
+        }
+    }
+
+    /// The value as written, decompressed if it is stored compressed
+    pub fn data(&self) -> Result<Bytes, StorageError> {
+        stored_data(&self.value, self.compressed)
+    }
+
+    /// Length of the value as written, without decompressing it
+    pub fn data_len(&self) -> usize {
+        stored_len(&self.value, self.compressed)
+    }
+
+    /// Whether the value's TTL has run out by `now`
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|deadline| deadline <= now)
+    }
+
+    /// Take over `previous`'s history with `previous` itself added in front,
+    /// keeping at most `depth` replaced versions, and its creation time. The
+    /// entry counts as updated now, even when it started as a copy of
+    /// `previous`
+    pub fn replaces(&mut self, previous: &Entry, depth: usize) {
+        let now = SystemTime::now();
+        self.created_at = previous.created_at;
+        self.updated_at = now;
+        let replaced = PastVersion {
+            value: previous.value.clone(),
+            content_type: previous.content_type.clone(),
+            version: previous.version,
+            replaced_at: now,
+            compressed: previous.compressed,
+        };
+        self.history = std::iter::once(replaced)
+            .chain(previous.history.iter().cloned())
+            .take(depth)
+            .collect();
+    }
+}
+
+// Bytes an entry counts against the memory budget, including its history
+pub(crate) fn entry_size(key: &str, entry: &Entry) -> u64 {
+    let history: usize = entry.history.iter().map(|past| past.value.len()).sum();
+    (key.len() + entry.value.len() + history) as u64
+}
+
+/// Capacity limits enforced by a backend
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    /// Maximum key count, evicting least-recently-used keys to stay under it
+    pub max_keys: Option<usize>,
+    /// Maximum total bytes of keys and values
+    pub max_bytes: Option<u64>,
+}
+
+/// What compacting a backend reclaimed
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Compaction {
+    /// Estimated bytes held for entries, including spare table capacity, before
+    pub bytes_before: u64,
+    /// The same estimate once compacted
+    pub bytes_after: u64,
+    /// Expired entries removed along the way
+    pub expired: usize,
+}
+
+/// Keys and bytes held by one tenant
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Usage {
+    pub keys: u64,
+    pub bytes: u64,
+}
+
+// Running usage of every tenant other than the default one, which is only
+// bounded by the store-wide limits. Backends update it wherever they update
+// their own byte count.
+#[derive(Default)]
+pub(crate) struct TenantUsage(Mutex<HashMap<String, Usage>>);
+
+impl TenantUsage {
+    // Account for `keys` keys of `bytes` bytes being added (positive) or removed
+    // (negative) under the tenant of `key`
+    fn adjust(&self, key: &str, keys: i64, bytes: i64) {
+        let Some(tenant) = keyspace::tenant_of(key) else {
+            return;
+        };
+        let mut map = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let usage = map.entry(tenant.to_string()).or_default();
+        usage.keys = usage.keys.saturating_add_signed(keys);
+        usage.bytes = usage.bytes.saturating_add_signed(bytes);
+        if usage.keys == 0 {
+            map.remove(tenant);
+        }
+    }
+
+    fn add(&self, key: &str, entry: &Entry) {
+        self.adjust(key, 1, entry_size(key, entry) as i64);
+    }
+
+    fn sub(&self, key: &str, entry: &Entry) {
+        self.adjust(key, -1, -(entry_size(key, entry) as i64));
+    }
+
+    fn get(&self, tenant: &str) -> Usage {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(tenant)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+// Upper bounds of the value size buckets, in bytes, and the names of every
+// bucket including the last, unbounded one
+const VALUE_SIZE_BOUNDS: [u64; 4] = [1024, 10 * 1024, 100 * 1024, 1024 * 1024];
+/// Names of the value size buckets counted by [`Storage::value_sizes`]
+pub const VALUE_SIZE_LABELS: [&str; 5] = ["<=1KiB", "<=10KiB", "<=100KiB", "<=1MiB", ">1MiB"];
+
+// How many stored values fall in each size bucket. Like `TenantUsage`, backends
+// update it wherever they update their own byte count, so it tracks the current
+// contents rather than past writes. Only current values count, not history.
+#[derive(Default)]
+pub(crate) struct ValueSizes([AtomicU64; VALUE_SIZE_LABELS.len()]);
+
+impl ValueSizes {
+    fn bucket(&self, len: usize) -> &AtomicU64 {
+        let bucket = VALUE_SIZE_BOUNDS
+            .iter()
+            .position(|&bound| len as u64 <= bound)
+            .unwrap_or(VALUE_SIZE_BOUNDS.len());
+        &self.0[bucket]
+    }
+
+    fn add(&self, len: usize) {
+        self.bucket(len).fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn sub(&self, len: usize) {
+        let _ = self
+            .bucket(len)
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                Some(count.saturating_sub(1))
+            });
+    }
+
+    pub fn counts(&self) -> [u64; VALUE_SIZE_LABELS.len()] {
+        std::array::from_fn(|bucket| self.0[bucket].load(Ordering::Relaxed))
+    }
+}
+
+/// Failure reported by a storage backend
+#[derive(Debug)]
+pub struct StorageError(String);
+
+impl StorageError {
+    /// An error described by `message`
+    pub fn new(message: impl fmt::Display) -> Self {
+        Self(message.to_string())
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "storage error: {}", self.0)
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+/// A consistent read-only view of the store
+pub trait ReadView {
+    /// The entry stored under `key`, even if it has expired
+    fn get(&self, key: &str) -> Option<Entry>;
+
+    /// Visit every entry, including expired ones, in no particular order
+    fn for_each(&self, f: &mut dyn FnMut(&str, &Entry));
+
+    /// Up to `limit` unexpired entries whose keys start with `prefix` and sort
+    /// after `after`, in key order. Ordered backends can seek straight to the
+    /// prefix; unordered ones have to scan everything.
+    fn scan(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+        now: Instant,
+    ) -> Vec<(String, Entry)>;
+
+    /// Number of entries, including expired ones
+    fn len(&self) -> usize;
+
+    /// Whether there are no entries at all
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Exclusive access to the store. Changes made through one view become
+/// visible to other readers all at once when the view is released.
+pub trait WriteView {
+    /// The entry stored under `key`, even if it has expired
+    fn get(&self, key: &str) -> Option<Entry>;
+
+    /// Insert or replace an entry, returning the previous one
+    fn insert(&mut self, key: String, entry: Entry) -> Option<Entry>;
+
+    /// Remove an entry, returning it if it was present
+    fn remove(&mut self, key: &str) -> Option<Entry>;
+
+    /// Allocate a version for a value about to be written. Versions only ever
+    /// increase, so a key that is deleted and recreated never reuses one.
+    fn next_version(&mut self) -> u64;
+
+    /// All keys starting with `prefix`, including expired ones, in no particular order
+    fn keys_with_prefix(&self, prefix: &str) -> Vec<String>;
+
+    /// Whether storing `size` bytes under `key` fits the byte budget, either
+    /// outright or by evicting other keys if the backend evicts
+    fn has_room(&self, key: &str, size: u64) -> bool;
+
+    /// Current usage of a tenant, including changes made through this view
+    fn tenant_usage(&self, tenant: &str) -> Usage;
+}
+
+/// A storage operation in progress, done once its closure has run
+pub type Pending<'a> = Pin<Box<dyn Future<Output = Result<(), StorageError>> + Send + 'a>>;
+
+/// A compaction in progress
+pub type Compacting<'a> =
+    Pin<Box<dyn Future<Output = Result<Compaction, StorageError>> + Send + 'a>>;
+
+/// A page of entries being read from a part of the store
+pub type Scanning<'a> =
+    Pin<Box<dyn Future<Output = Result<Vec<(String, Entry)>, StorageError>> + Send + 'a>>;
+
+/// A key-value storage backend. Handlers only ever talk to the store through
+/// `read` and `write`, so every backend gets identical HTTP semantics.
+///
+/// Each operation waits for its view without blocking the thread, so a task
+/// waiting on a lock lets the runtime get on with other requests. The closure
+/// itself runs synchronously once the view is held, and should be brief.
+pub trait Storage: Send + Sync {
+    /// Run `f` against a read-only view
+    fn read<'a>(&'a self, f: &'a mut (dyn FnMut(&dyn ReadView) + Send)) -> Pending<'a>;
+
+    /// Run `f` with exclusive write access. If the backend fails part-way,
+    /// none of the changes are applied and an error is returned.
+    fn write<'a>(&'a self, f: &'a mut (dyn FnMut(&mut dyn WriteView) + Send)) -> Pending<'a>;
+
+    /// Like `read`, for a view that only consults `key`. Backends that partition
+    /// their contents can lock just the part holding it.
+    fn read_key<'a>(
+        &'a self,
+        key: &'a str,
+        f: &'a mut (dyn FnMut(&dyn ReadView) + Send),
+    ) -> Pending<'a> {
+        let _ = key;
+        self.read(f)
+    }
+
+    /// Like `write`, for a view that only touches `key`
+    fn write_key<'a>(
+        &'a self,
+        key: &'a str,
+        f: &'a mut (dyn FnMut(&mut dyn WriteView) + Send),
+    ) -> Pending<'a> {
+        let _ = key;
+        self.write(f)
+    }
+
+    /// Make all applied writes durable, for backends that buffer them
+    fn flush(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Remove expired entries now, calling `removed` with each, and give back
+    /// memory left spare by removed ones, a part of the store at a time so
+    /// other requests keep being served. Backends that manage their own
+    /// memory do nothing.
+    fn compact<'a>(
+        &'a self,
+        now: Instant,
+        removed: &'a (dyn Fn(&str, &Entry) + Sync),
+    ) -> Compacting<'a> {
+        let _ = (now, removed);
+        let bytes = self.bytes();
+        Box::pin(async move {
+            Ok(Compaction {
+                bytes_before: bytes,
+                bytes_after: bytes,
+                expired: 0,
+            })
+        })
+    }
+
+    /// Number of parts `scan_part` reads the store in
+    fn parts(&self) -> usize {
+        1
+    }
+
+    /// Unexpired entries of part `part` whose keys sort after `after`, in key
+    /// order, all read in one consistent view of that part. Returns at least
+    /// `limit` entries while that many are left, so a shorter page ends the
+    /// part. Backends that would scan the whole part for every page return all
+    /// of it at once.
+    fn scan_part<'a>(
+        &'a self,
+        part: usize,
+        after: Option<&'a str>,
+        limit: usize,
+        now: Instant,
+    ) -> Scanning<'a> {
+        let _ = part;
+        Box::pin(async move {
+            let mut page = Vec::new();
+            self.read(&mut |view| page = view.scan("", after, limit, now))
+                .await?;
+            Ok(page)
+        })
+    }
+
+    /// Number of keys evicted to stay within a capacity limit
+    fn evictions(&self) -> u64 {
+        0
+    }
+
+    /// Total bytes of keys and values currently stored
+    fn bytes(&self) -> u64;
+
+    /// Configured capacity limits
+    fn limits(&self) -> Limits;
+
+    /// Keys and bytes currently held by a tenant
+    fn tenant_usage(&self, tenant: &str) -> Usage;
+
+    /// Number of stored values in each of the VALUE_SIZE_LABELS buckets
+    fn value_sizes(&self) -> [u64; VALUE_SIZE_LABELS.len()];
+}
+
+/// The store handlers share: any backend behind the `Storage` trait, with
+/// closure-returning wrappers over its object-safe primitives. Cloning shares
+/// the backend.
+#[derive(Clone)]
+pub struct Store(Arc<dyn Storage>);
+
+impl Store {
+    /// A store served by `storage`
+    pub fn new(storage: impl Storage + 'static) -> Self {
+        Self(Arc::new(storage))
+    }
+
+    /// Run `f` against a read-only view of the whole store, returning what it returns
+    pub async fn with_read<R: Send>(
+        &self,
+        f: impl FnOnce(&dyn ReadView) -> R + Send,
+    ) -> Result<R, StorageError> {
+        let mut f = Some(f);
+        let mut out = None;
+        self.0
+            .read(&mut |view| {
+                if let Some(f) = f.take() {
+                    out = Some(f(view));
+                }
+            })
+            .await?;
+        out.ok_or_else(|| StorageError::new("read transaction did not run"))
+    }
+
+    /// Run `f` with exclusive write access to the whole store, returning what
+    /// it returns
+    pub async fn with_write<R: Send>(
+        &self,
+        f: impl FnOnce(&mut dyn WriteView) -> R + Send,
+    ) -> Result<R, StorageError> {
+        let mut f = Some(f);
+        let mut out = None;
+        self.0
+            .write(&mut |view| {
+                if let Some(f) = f.take() {
+                    out = Some(f(view));
+                }
+            })
+            .await?;
+        out.ok_or_else(|| StorageError::new("write transaction did not run"))
+    }
+
+    /// Like [`Store::with_read`], for `f` that only reads `key`, so reads and
+    /// writes of other keys can proceed alongside it. Consulting any other key
+    /// through the view may panic.
+    pub async fn with_key_read<R: Send>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&dyn ReadView) -> R + Send,
+    ) -> Result<R, StorageError> {
+        let mut f = Some(f);
+        let mut out = None;
+        self.0
+            .read_key(key, &mut |view| {
+                if let Some(f) = f.take() {
+                    out = Some(f(view));
+                }
+            })
+            .await?;
+        out.ok_or_else(|| StorageError::new("read transaction did not run"))
+    }
+
+    /// Like [`Store::with_write`], for `f` that only touches `key`, so reads and
+    /// writes of other keys can proceed alongside it. Touching any other key
+    /// through the view may panic.
+    pub async fn with_key_write<R: Send>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&mut dyn WriteView) -> R + Send,
+    ) -> Result<R, StorageError> {
+        let mut f = Some(f);
+        let mut out = None;
+        self.0
+            .write_key(key, &mut |view| {
+                if let Some(f) = f.take() {
+                    out = Some(f(view));
+                }
+            })
+            .await?;
+        out.ok_or_else(|| StorageError::new("write transaction did not run"))
+    }
+
+    /// Make all applied writes durable, for backends that buffer them
+    pub fn flush(&self) -> Result<(), StorageError> {
+        self.0.flush()
+    }
+
+    /// Remove entries expired at `now`, calling `removed` with each, and
+    /// release spare memory
+    pub async fn compact(
+        &self,
+        now: Instant,
+        removed: &(dyn Fn(&str, &Entry) + Sync),
+    ) -> Result<Compaction, StorageError> {
+        self.0.compact(now, removed).await
+    }
+
+    /// Number of parts the store is read in by `scan_part`
+    pub fn parts(&self) -> usize {
+        self.0.parts()
+    }
+
+    /// A page of the unexpired entries of part `part` after `after`, in key
+    /// order; see [`Storage::scan_part`]
+    pub async fn scan_part(
+        &self,
+        part: usize,
+        after: Option<&str>,
+        limit: usize,
+        now: Instant,
+    ) -> Result<Vec<(String, Entry)>, StorageError> {
+        self.0.scan_part(part, after, limit, now).await
+    }
+
+    /// Number of keys evicted to stay within a capacity limit
+    pub fn evictions(&self) -> u64 {
+        self.0.evictions()
+    }
+
+    /// Total bytes of keys and values currently stored
+    pub fn bytes(&self) -> u64 {
+        self.0.bytes()
+    }
+
+    /// Configured capacity limits
+    pub fn limits(&self) -> Limits {
+        self.0.limits()
+    }
+
+    /// Keys and bytes currently held by a tenant
+    pub fn tenant_usage(&self, tenant: &str) -> Usage {
+        self.0.tenant_usage(tenant)
+    }
+
+    /// Number of stored values in each of the VALUE_SIZE_LABELS buckets
+    pub fn value_sizes(&self) -> [u64; VALUE_SIZE_LABELS.len()] {
+        self.0.value_sizes()
+    }
+}
+
+// Maximum number of expired keys removed per write-lock acquisition
+const SWEEP_BATCH_SIZE: usize = 1000;
+
+// Remove expired entries, taking the write lock for at most one batch at a time
+// and calling `removed` with each key and entry under it
+pub(crate) async fn sweep_expired(
+    store: &Store,
+    removed: &(dyn Fn(&str, &Entry) + Sync),
+) -> Result<usize, StorageError> {
+    let now = Instant::now();
+
+    // Find candidates under the read lock so readers aren't blocked during the scan
+    let expired: Vec<String> = store
+        .with_read(|view| {
+            let mut expired = Vec::new();
+            view.for_each(&mut |key, entry| {
+                if entry.is_expired(now) {
+                    expired.push(key.to_string());
+                }
+            });
+            expired
+        })
+        .await?;
+
+    let mut evicted = 0;
+    for batch in expired.chunks(SWEEP_BATCH_SIZE) {
+        evicted += store
+            .with_write(|view| {
+                let mut count = 0;
+                for key in batch {
+                    // The key may have been rewritten since the scan, so check again
+                    if let Some(entry) = view.get(key).filter(|entry| entry.is_expired(now)) {
+                        view.remove(key);
+                        removed(key, &entry);
+                        count += 1;
+                    }
+                }
+                count
+            })
+            .await?;
+    }
+
+    Ok(evicted)
+}
+
+// Build the storage backend `config` selects. For the memory backend this
+// restores the last snapshot and replays the write-ahead log, which is
+// returned for logging further writes. The sled backend ignores the snapshot,
+// write-ahead log, key limit and shard options.
+pub(crate) fn open(config: &Config) -> Result<(Store, Option<Wal>), String> {
+    if config.backend == Backend::Sled {
+        let storage = SledStorage::open(&config.data_dir, config.max_bytes).map_err(|e| {
+            format!(
+                "failed to open sled database in {}: {}",
+                config.data_dir.display(),
+                e
+            )
+        })?;
+        tracing::info!("Using sled backend in {}", config.data_dir.display());
+        return Ok((Store::new(storage), None));
+    }
+
+    // Restore the last snapshot if persistence is enabled
+    let mut initial = match &config.snapshot_path {
+        Some(path) => persistence::load_snapshot(path),
+        None => HashMap::new(),
+    };
+
+    // Replay the write-ahead log on top of the snapshot, then start a fresh segment
+    let wal = match &config.wal_path {
+        Some(path) => {
+            let applied = wal::replay(path, &mut initial).map_err(|e| {
+                format!("failed to replay write-ahead log {}: {}", path.display(), e)
+            })?;
+            tracing::info!("Replayed {} write-ahead log records", applied);
+            if config.snapshot_path.is_none() {
+                tracing::warn!(
+                    "Write-ahead log enabled without snapshots; it will never be truncated"
+                );
+            }
+            let wal = Wal::open(path.clone(), config.wal_fsync)
+                .map_err(|e| format!("failed to open write-ahead log {}: {}", path.display(), e))?;
+            Some(wal)
+        }
+        None => None,
+    };
+
+    let limits = Limits {
+        max_keys: config.max_keys.map(|n| n as usize),
+        max_bytes: config.max_bytes,
+    };
+    let shards = config.shards.map_or_else(
+        || std::thread::available_parallelism().map_or(1, |cpus| cpus.get()),
+        |shards| shards as usize,
+    );
+    let storage = MemoryStorage::new(initial, limits, shards);
+    Ok((Store::new(storage), wal))
+}
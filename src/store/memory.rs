@@ -0,0 +1,640 @@
+use super::{
+    entry_size, Compacting, Compaction, Entry, Limits, Pending, ReadView, Scanning, Storage,
+    TenantUsage, Usage, ValueSizes, WriteView, VALUE_SIZE_LABELS,
+};
+use crate::keyspace;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// The in-memory backend: `HashMap`s split across independently locked shards,
+/// optionally capped at a maximum key count with least-recently-used eviction.
+/// When a key cap is set, the byte budget is enforced by evicting too; otherwise
+/// writes that would exceed it are refused.
+///
+/// Keys are assigned to shards by hash, except that every key of a tenant goes
+/// to the tenant's shard, so a single-key access only locks one shard and a
+/// tenant's quota can still be checked exactly. Whole-store views lock every
+/// shard, always in index order, so they never deadlock against each other or
+/// against single-key ones. The locks are asynchronous, so a request waiting
+/// for a shard yields its thread to other requests. The store-wide limits
+/// depend on all shards at once, so while either is configured, single-key
+/// writes lock every shard too.
+///
+/// Recency is tracked without taking a write lock on reads: every access stamps
+/// the entry's `last_used` tick atomically, while each shard's `queue` orders
+/// its keys by the tick they were last queued at. Eviction pops the oldest
+/// queued key of any shard and, if it has been used since, re-queues it at its
+/// current tick instead. The first key popped whose tick is still current is
+/// the true LRU entry.
+pub struct MemoryStorage {
+    shards: Box<[RwLock<Shard>]>,
+    hasher: RandomState,
+    limits: Limits,
+    clock: AtomicU64,
+    evictions: AtomicU64,
+    // Only modified under a shard's write lock; atomic so it can be read without one
+    bytes: AtomicU64,
+    // Last version handed out, continuing from the highest loaded one
+    version: AtomicU64,
+    usage: TenantUsage,
+    sizes: ValueSizes,
+}
+
+struct Slot {
+    entry: Entry,
+    last_used: AtomicU64,
+    // Tick under which this key currently sits in its shard's `queue`
+    queued_at: u64,
+}
+
+#[derive(Default)]
+struct Shard {
+    slots: HashMap<String, Slot>,
+    // Only maintained when a key cap is configured
+    queue: BTreeMap<u64, String>,
+}
+
+impl MemoryStorage {
+    /// Build a store of `shards` shards (at least one) from existing contents.
+    /// If they exceed the limits and eviction is enabled, the surplus is evicted
+    /// in arbitrary order since no access history exists yet.
+    pub fn new(map: HashMap<String, Entry>, limits: Limits, shards: usize) -> Self {
+        let version = map.values().map(|entry| entry.version).max();
+        let storage = Self {
+            shards: (0..shards.max(1)).map(|_| RwLock::default()).collect(),
+            hasher: RandomState::new(),
+            limits,
+            clock: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            version: AtomicU64::new(version.unwrap_or_default()),
+            usage: TenantUsage::default(),
+            sizes: ValueSizes::default(),
+        };
+
+        {
+            // Nothing else can hold the locks yet
+            let guards = storage.shards.iter().map(|shard| shard.try_write());
+            let mut view = WriteGuardView {
+                first: 0,
+                guards: guards.collect::<Result<_, _>>().unwrap(),
+                storage: &storage,
+            };
+            for (key, entry) in map {
+                view.insert(key, entry);
+            }
+        }
+
+        let evicted = storage.evictions.swap(0, Ordering::Relaxed);
+        if evicted > 0 {
+            tracing::warn!(
+                "Loaded data exceeded the configured limits; evicted {} keys at startup",
+                evicted
+            );
+        }
+        if let Some(max_bytes) = limits.max_bytes {
+            let bytes = storage.bytes.load(Ordering::Relaxed);
+            if bytes > max_bytes {
+                tracing::warn!(
+                    "Loaded data uses {} bytes, over the {} byte budget; writes will be refused",
+                    bytes,
+                    max_bytes
+                );
+            }
+        }
+
+        storage
+    }
+
+    // Whether recency is tracked and keys are evicted to respect the limits
+    fn evicts(&self) -> bool {
+        self.limits.max_keys.is_some()
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    // Index of the shard holding `key`
+    fn shard_of(&self, key: &str) -> usize {
+        let routed = keyspace::tenant_of(key).unwrap_or(key);
+        (self.hasher.hash_one(routed) % self.shards.len() as u64) as usize
+    }
+
+    // Position of `key`'s shard among `locked` guards taken from shard `first`
+    // on. Views only ever touch keys in the shards they locked.
+    fn locked_index(&self, first: usize, locked: usize, key: &str) -> usize {
+        self.shard_of(key)
+            .checked_sub(first)
+            .filter(|&index| index < locked)
+            .expect("key outside the locked shards")
+    }
+
+    async fn read_all(&self) -> ReadGuardView<'_> {
+        let mut guards = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            guards.push(shard.read().await);
+        }
+        ReadGuardView {
+            first: 0,
+            guards,
+            storage: self,
+        }
+    }
+
+    async fn read_one(&self, key: &str) -> ReadGuardView<'_> {
+        let first = self.shard_of(key);
+        ReadGuardView {
+            first,
+            guards: vec![self.shards[first].read().await],
+            storage: self,
+        }
+    }
+
+    async fn write_all(&self) -> WriteGuardView<'_> {
+        let mut guards = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            guards.push(shard.write().await);
+        }
+        WriteGuardView {
+            first: 0,
+            guards,
+            storage: self,
+        }
+    }
+
+    async fn write_one(&self, key: &str) -> WriteGuardView<'_> {
+        let first = self.shard_of(key);
+        WriteGuardView {
+            first,
+            guards: vec![self.shards[first].write().await],
+            storage: self,
+        }
+    }
+}
+
+impl Shard {
+    fn get(&self, storage: &MemoryStorage, key: &str) -> Option<Entry> {
+        let slot = self.slots.get(key)?;
+        if storage.evicts() {
+            slot.last_used.store(storage.tick(), Ordering::Relaxed);
+        }
+        Some(slot.entry.clone())
+    }
+
+    // Rough bytes held: the table at its current capacity plus every key and value
+    fn allocated(&self) -> u64 {
+        let table = self.slots.capacity() * std::mem::size_of::<(String, Slot)>()
+            + self.queue.len() * std::mem::size_of::<(u64, String)>();
+        let entries: u64 = self
+            .slots
+            .iter()
+            .map(|(key, slot)| entry_size(key, &slot.entry))
+            .sum();
+        table as u64 + entries
+    }
+}
+
+struct ReadGuardView<'a> {
+    first: usize,
+    guards: Vec<RwLockReadGuard<'a, Shard>>,
+    storage: &'a MemoryStorage,
+}
+
+impl ReadGuardView<'_> {
+    fn shard(&self, key: &str) -> &Shard {
+        &self.guards[self
+            .storage
+            .locked_index(self.first, self.guards.len(), key)]
+    }
+
+    // Every shard, for views over the whole store
+    fn shards(&self) -> impl Iterator<Item = &Shard> {
+        assert_eq!(self.guards.len(), self.storage.shards.len());
+        self.guards.iter().map(|guard| &**guard)
+    }
+}
+
+impl ReadView for ReadGuardView<'_> {
+    fn get(&self, key: &str) -> Option<Entry> {
+        self.shard(key).get(self.storage, key)
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&str, &Entry)) {
+        for (key, slot) in self.shards().flat_map(|shard| &shard.slots) {
+            f(key, &slot.entry);
+        }
+    }
+
+    fn scan(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+        now: Instant,
+    ) -> Vec<(String, Entry)> {
+        // The maps are unordered, so this is a full scan; select the first `limit`
+        // keys in linear time and only sort those
+        let mut page: Vec<(&String, &Slot)> = self
+            .shards()
+            .flat_map(|shard| &shard.slots)
+            .filter(|(key, slot)| {
+                key.starts_with(prefix)
+                    && after.is_none_or(|after| key.as_str() > after)
+                    && !slot.entry.is_expired(now)
+            })
+            .collect();
+        if page.len() > limit && limit > 0 {
+            page.select_nth_unstable_by(limit - 1, |a, b| a.0.cmp(b.0));
+        }
+        page.truncate(limit);
+        page.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+        page.into_iter()
+            .map(|(key, slot)| (key.clone(), slot.entry.clone()))
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.shards().map(|shard| shard.slots.len()).sum()
+    }
+}
+
+struct WriteGuardView<'a> {
+    first: usize,
+    guards: Vec<RwLockWriteGuard<'a, Shard>>,
+    storage: &'a MemoryStorage,
+}
+
+impl WriteGuardView<'_> {
+    fn shard(&self, key: &str) -> &Shard {
+        &self.guards[self
+            .storage
+            .locked_index(self.first, self.guards.len(), key)]
+    }
+
+    fn shard_mut(&mut self, key: &str) -> &mut Shard {
+        let index = self
+            .storage
+            .locked_index(self.first, self.guards.len(), key);
+        &mut self.guards[index]
+    }
+
+    // Every shard, for views over the whole store
+    fn shards(&self) -> impl Iterator<Item = &Shard> {
+        assert_eq!(self.guards.len(), self.storage.shards.len());
+        self.guards.iter().map(|guard| &**guard)
+    }
+
+    // Evict least-recently-used keys until one more entry of `size` bytes fits.
+    // Only called with every shard locked.
+    fn make_room(&mut self, size: u64) {
+        let limits = self.storage.limits;
+        loop {
+            let over_keys = limits.max_keys.is_some_and(|max| self.len() >= max);
+            let over_bytes = limits
+                .max_bytes
+                .is_some_and(|max| self.storage.bytes.load(Ordering::Relaxed) + size > max);
+            if !over_keys && !over_bytes {
+                break;
+            }
+
+            // The shard whose oldest queued key was queued first
+            let oldest = self
+                .shards()
+                .enumerate()
+                .filter_map(|(index, shard)| {
+                    shard
+                        .queue
+                        .first_key_value()
+                        .map(|(&tick, _)| (tick, index))
+                })
+                .min();
+            let Some((_, index)) = oldest else {
+                break;
+            };
+            let shard = &mut self.guards[index];
+            let (tick, key) = shard.queue.pop_first().unwrap();
+            let Some(slot) = shard.slots.get_mut(&key) else {
+                continue;
+            };
+
+            let last_used = slot.last_used.load(Ordering::Relaxed);
+            if last_used != tick {
+                // Used since it was queued; give it a place matching its real recency
+                slot.queued_at = last_used;
+                shard.queue.insert(last_used, key);
+                continue;
+            }
+
+            let slot = shard.slots.remove(&key).unwrap();
+            self.storage
+                .bytes
+                .fetch_sub(entry_size(&key, &slot.entry), Ordering::Relaxed);
+            self.storage.usage.sub(&key, &slot.entry);
+            self.storage.sizes.sub(slot.entry.value.len());
+            self.storage.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Remove a slot and its queue position, keeping the byte count in step
+    fn take(&mut self, key: &str) -> Option<Entry> {
+        let evicts = self.storage.evicts();
+        let shard = self.shard_mut(key);
+        let slot = shard.slots.remove(key)?;
+        if evicts {
+            shard.queue.remove(&slot.queued_at);
+        }
+        self.storage
+            .bytes
+            .fetch_sub(entry_size(key, &slot.entry), Ordering::Relaxed);
+        self.storage.usage.sub(key, &slot.entry);
+        self.storage.sizes.sub(slot.entry.value.len());
+        Some(slot.entry)
+    }
+
+    fn len(&self) -> usize {
+        self.shards().map(|shard| shard.slots.len()).sum()
+    }
+}
+
+impl WriteView for WriteGuardView<'_> {
+    fn get(&self, key: &str) -> Option<Entry> {
+        self.shard(key).get(self.storage, key)
+    }
+
+    fn insert(&mut self, key: String, entry: Entry) -> Option<Entry> {
+        let size = entry_size(&key, &entry);
+
+        // Take the old entry out first so eviction can never pick the key itself
+        let previous = self.take(&key);
+
+        let tick = if self.storage.evicts() {
+            self.make_room(size);
+            let tick = self.storage.tick();
+            self.shard_mut(&key).queue.insert(tick, key.clone());
+            tick
+        } else {
+            0
+        };
+
+        self.storage.usage.add(&key, &entry);
+        self.storage.sizes.add(entry.value.len());
+        let slot = Slot {
+            entry,
+            last_used: AtomicU64::new(tick),
+            queued_at: tick,
+        };
+        self.shard_mut(&key).slots.insert(key, slot);
+        self.storage.bytes.fetch_add(size, Ordering::Relaxed);
+        previous
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        self.take(key)
+    }
+
+    fn next_version(&mut self) -> u64 {
+        self.storage.version.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn keys_with_prefix(&self, prefix: &str) -> Vec<String> {
+        self.shards()
+            .flat_map(|shard| shard.slots.keys())
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    fn has_room(&self, key: &str, size: u64) -> bool {
+        let Some(max_bytes) = self.storage.limits.max_bytes else {
+            return true;
+        };
+        if self.storage.evicts() {
+            return size <= max_bytes;
+        }
+        let current = self
+            .shard(key)
+            .slots
+            .get(key)
+            .map_or(0, |slot| entry_size(key, &slot.entry));
+        self.storage.bytes.load(Ordering::Relaxed) - current + size <= max_bytes
+    }
+
+    fn tenant_usage(&self, tenant: &str) -> Usage {
+        self.storage.usage.get(tenant)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read<'a>(&'a self, f: &'a mut (dyn FnMut(&dyn ReadView) + Send)) -> Pending<'a> {
+        Box::pin(async move {
+            f(&self.read_all().await);
+            Ok(())
+        })
+    }
+
+    fn write<'a>(&'a self, f: &'a mut (dyn FnMut(&mut dyn WriteView) + Send)) -> Pending<'a> {
+        Box::pin(async move {
+            f(&mut self.write_all().await);
+            Ok(())
+        })
+    }
+
+    fn read_key<'a>(
+        &'a self,
+        key: &'a str,
+        f: &'a mut (dyn FnMut(&dyn ReadView) + Send),
+    ) -> Pending<'a> {
+        Box::pin(async move {
+            f(&self.read_one(key).await);
+            Ok(())
+        })
+    }
+
+    fn write_key<'a>(
+        &'a self,
+        key: &'a str,
+        f: &'a mut (dyn FnMut(&mut dyn WriteView) + Send),
+    ) -> Pending<'a> {
+        if self.limits.max_keys.is_some() || self.limits.max_bytes.is_some() {
+            return self.write(f);
+        }
+        Box::pin(async move {
+            f(&mut self.write_one(key).await);
+            Ok(())
+        })
+    }
+
+    // One shard at a time, so each only waits for the requests on its own keys
+    fn compact<'a>(
+        &'a self,
+        now: Instant,
+        removed: &'a (dyn Fn(&str, &Entry) + Sync),
+    ) -> Compacting<'a> {
+        Box::pin(async move {
+            let mut compaction = Compaction::default();
+            for index in 0..self.shards.len() {
+                let mut view = WriteGuardView {
+                    first: index,
+                    guards: vec![self.shards[index].write().await],
+                    storage: self,
+                };
+                compaction.bytes_before += view.guards[0].allocated();
+                let expired: Vec<String> = view.guards[0]
+                    .slots
+                    .iter()
+                    .filter(|(_, slot)| slot.entry.is_expired(now))
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in &expired {
+                    if let Some(entry) = view.take(key) {
+                        removed(key, &entry);
+                    }
+                }
+                compaction.expired += expired.len();
+                view.guards[0].slots.shrink_to_fit();
+                compaction.bytes_after += view.guards[0].allocated();
+                drop(view);
+                tokio::task::yield_now().await;
+            }
+            Ok(compaction)
+        })
+    }
+
+    // A shard per part, read whole: its map is unordered, so any page of it
+    // would take a pass over all of it anyway
+    fn parts(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn scan_part<'a>(
+        &'a self,
+        part: usize,
+        after: Option<&'a str>,
+        limit: usize,
+        now: Instant,
+    ) -> Scanning<'a> {
+        let _ = limit;
+        Box::pin(async move {
+            let shard = self.shards[part].read().await;
+            let mut page: Vec<(String, Entry)> = shard
+                .slots
+                .iter()
+                .filter(|(key, slot)| {
+                    after.is_none_or(|after| key.as_str() > after) && !slot.entry.is_expired(now)
+                })
+                .map(|(key, slot)| (key.clone(), slot.entry.clone()))
+                .collect();
+            drop(shard);
+            page.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+            Ok(page)
+        })
+    }
+
+    fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    fn tenant_usage(&self, tenant: &str) -> Usage {
+        self.usage.get(tenant)
+    }
+
+    fn value_sizes(&self) -> [u64; VALUE_SIZE_LABELS.len()] {
+        self.sizes.counts()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn entry(value: &'static str) -> Entry {
+        Entry::new(Bytes::from_static(value.as_bytes()))
+    }
+
+    #[test]
+    fn keys_of_a_tenant_share_a_shard() {
+        let storage = MemoryStorage::new(HashMap::new(), Limits::default(), 64);
+        let namespace = keyspace::Namespace::named(Some("acme"), None).unwrap();
+        let tenant = |key: &str| namespace.storage_key(key);
+        let shard = storage.shard_of(&tenant("a"));
+        assert!((0..100).all(|n| storage.shard_of(&tenant(&n.to_string())) == shard));
+        let shards: std::collections::HashSet<_> =
+            (0..100).map(|n| storage.shard_of(&n.to_string())).collect();
+        assert!(shards.len() > 1);
+    }
+
+    #[tokio::test]
+    async fn evicts_the_oldest_key_of_any_shard() {
+        let limits = Limits {
+            max_keys: Some(3),
+            max_bytes: None,
+        };
+        let storage = MemoryStorage::new(HashMap::new(), limits, 8);
+        for key in ["a", "b", "c"] {
+            storage
+                .write_all()
+                .await
+                .insert(key.to_string(), entry("1"));
+        }
+        // Reading "a" makes "b" the least recently used
+        storage.read_one("a").await.get("a");
+        storage
+            .write_all()
+            .await
+            .insert("d".to_string(), entry("1"));
+
+        let view = storage.read_all().await;
+        let mut keys = Vec::new();
+        view.for_each(&mut |key, _| keys.push(key.to_string()));
+        keys.sort();
+        assert_eq!(keys, ["a", "c", "d"]);
+        assert_eq!(storage.evictions(), 1);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "key outside the locked shards")]
+    async fn single_key_views_only_reach_their_shard() {
+        let storage = MemoryStorage::new(HashMap::new(), Limits::default(), 64);
+        let other = (0..)
+            .map(|n| n.to_string())
+            .find(|key| storage.shard_of(key) != storage.shard_of("a"))
+            .unwrap();
+        storage.read_one("a").await.get(&other);
+    }
+
+    #[tokio::test]
+    async fn compaction_reports_each_expired_key() {
+        let storage = MemoryStorage::new(HashMap::new(), Limits::default(), 8);
+        let now = Instant::now();
+        for (key, expires_at) in [("gone", Some(now)), ("kept", None)] {
+            let entry = Entry {
+                expires_at,
+                ..entry("1")
+            };
+            storage.write_all().await.insert(key.to_string(), entry);
+        }
+
+        let removed = std::sync::Mutex::new(Vec::new());
+        let compaction = storage
+            .compact(now, &|key, _| removed.lock().unwrap().push(key.to_string()))
+            .await
+            .unwrap();
+        assert_eq!(compaction.expired, 1);
+        assert_eq!(removed.into_inner().unwrap(), ["gone"]);
+        assert!(storage.read_all().await.get("kept").is_some());
+    }
+}
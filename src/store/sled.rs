@@ -0,0 +1,370 @@
+use super::{
+    entry_size, Entry, Limits, Pending, ReadView, Storage, StorageError, TenantUsage, Usage,
+    ValueSizes, WriteView, VALUE_SIZE_LABELS,
+};
+use crate::keyspace;
+use crate::persistence::StoredEntry;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use tokio::sync::Mutex;
+
+/// A disk-backed backend on top of the sled embedded database.
+/// Writers are serialized by a mutex and each write transaction is applied as a
+/// single sled batch, so readers never observe a partially applied write.
+/// Only the byte budget applies here; writes that would exceed it are refused.
+pub struct SledStorage {
+    db: sled::Db,
+    write_lock: Mutex<()>,
+    max_bytes: Option<u64>,
+    // Only modified while `write_lock` is held
+    bytes: AtomicU64,
+    usage: TenantUsage,
+    sizes: ValueSizes,
+}
+
+impl SledStorage {
+    /// Open, or create, the database in `dir`, refusing writes past
+    /// `max_bytes` of keys and values if set
+    pub fn open(dir: &Path, max_bytes: Option<u64>) -> Result<Self, StorageError> {
+        let db = sled::open(dir).map_err(StorageError::new)?;
+
+        // Usage isn't stored on disk, so recount it from the contents
+        let mut bytes = 0;
+        let usage = TenantUsage::default();
+        let sizes = ValueSizes::default();
+        for item in db.iter() {
+            let (key, value) = item.map_err(StorageError::new)?;
+            let (key, entry) = (decode_key(&key)?, decode(&value)?);
+            bytes += entry_size(&key, &entry);
+            usage.add(&key, &entry);
+            sizes.add(entry.value.len());
+        }
+
+        Ok(Self {
+            db,
+            write_lock: Mutex::new(()),
+            max_bytes,
+            bytes: AtomicU64::new(bytes),
+            usage,
+            sizes,
+        })
+    }
+}
+
+fn encode(entry: &Entry) -> Result<Vec<u8>, StorageError> {
+    serde_json::to_vec(&StoredEntry::encode(entry)).map_err(StorageError::new)
+}
+
+fn decode(bytes: &[u8]) -> Result<Entry, StorageError> {
+    let stored: StoredEntry = serde_json::from_slice(bytes).map_err(StorageError::new)?;
+    stored.decode().map_err(StorageError::new)
+}
+
+fn decode_key(bytes: &[u8]) -> Result<String, StorageError> {
+    String::from_utf8(bytes.to_vec()).map_err(StorageError::new)
+}
+
+// Views can't return errors through the trait, so the first failure is kept
+// here and reported once the transaction closure returns
+struct SledRead<'a> {
+    db: &'a sled::Db,
+    error: RefCell<Option<StorageError>>,
+}
+
+impl SledRead<'_> {
+    fn fail(&self, error: StorageError) {
+        self.error.borrow_mut().get_or_insert(error);
+    }
+}
+
+impl ReadView for SledRead<'_> {
+    fn get(&self, key: &str) -> Option<Entry> {
+        match self.db.get(key) {
+            Ok(Some(bytes)) => decode(&bytes).map_err(|e| self.fail(e)).ok(),
+            Ok(None) => None,
+            Err(e) => {
+                self.fail(StorageError::new(e));
+                None
+            }
+        }
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&str, &Entry)) {
+        for item in self.db.iter() {
+            let decoded = item
+                .map_err(StorageError::new)
+                .and_then(|(key, value)| Ok((decode_key(&key)?, decode(&value)?)));
+            match decoded {
+                Ok((key, entry)) => f(&key, &entry),
+                Err(e) => {
+                    self.fail(e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn scan(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+        now: Instant,
+    ) -> Vec<(String, Entry)> {
+        use std::ops::Bound;
+
+        // Seek to whichever comes later: the prefix or the cursor
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after.as_bytes()),
+            _ => Bound::Included(prefix.as_bytes()),
+        };
+        let mut page = Vec::new();
+        for item in self.db.range::<&[u8], _>((start, Bound::Unbounded)) {
+            if page.len() >= limit {
+                break;
+            }
+            if let Ok((key, _)) = &item {
+                if !key.starts_with(prefix.as_bytes()) {
+                    break;
+                }
+            }
+            let decoded = item
+                .map_err(StorageError::new)
+                .and_then(|(key, value)| Ok((decode_key(&key)?, decode(&value)?)));
+            match decoded {
+                Ok((_, entry)) if entry.is_expired(now) => {}
+                Ok(item) => page.push(item),
+                Err(e) => {
+                    self.fail(e);
+                    break;
+                }
+            }
+        }
+        page
+    }
+
+    fn len(&self) -> usize {
+        self.db.len()
+    }
+}
+
+struct SledWrite<'a> {
+    read: SledRead<'a>,
+    // Pending changes: Some(entry) to insert, None to remove
+    pending: HashMap<String, Option<Entry>>,
+    // Size and value length of each changed key before this transaction, None
+    // if it was absent
+    original: HashMap<String, Option<(u64, usize)>>,
+    // Change in stored bytes once the pending changes are applied
+    delta: i64,
+    bytes: u64,
+    max_bytes: Option<u64>,
+    usage: &'a TenantUsage,
+}
+
+impl SledWrite<'_> {
+    // Remember what a key looked like before its first change
+    fn touch(&mut self, key: &str, previous: Option<&Entry>) {
+        if !self.original.contains_key(key) {
+            let size = previous.map(|entry| (entry_size(key, entry), entry.value.len()));
+            self.original.insert(key.to_string(), size);
+        }
+    }
+
+    // Key and byte deltas per changed key once the pending changes are applied
+    fn changes(&self) -> impl Iterator<Item = (&str, i64, i64)> {
+        self.pending.iter().map(|(key, change)| {
+            let before = self
+                .original
+                .get(key)
+                .copied()
+                .flatten()
+                .map(|(size, _)| size);
+            let after = change.as_ref().map(|entry| entry_size(key, entry));
+            let keys = after.is_some() as i64 - before.is_some() as i64;
+            let bytes = after.unwrap_or(0) as i64 - before.unwrap_or(0) as i64;
+            (key.as_str(), keys, bytes)
+        })
+    }
+}
+
+impl WriteView for SledWrite<'_> {
+    fn get(&self, key: &str) -> Option<Entry> {
+        match self.pending.get(key) {
+            Some(pending) => pending.clone(),
+            None => self.read.get(key),
+        }
+    }
+
+    fn insert(&mut self, key: String, entry: Entry) -> Option<Entry> {
+        let previous = WriteView::get(self, &key);
+        self.touch(&key, previous.as_ref());
+        if let Some(previous) = &previous {
+            self.delta -= entry_size(&key, previous) as i64;
+        }
+        self.delta += entry_size(&key, &entry) as i64;
+        self.pending.insert(key, Some(entry));
+        previous
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let previous = WriteView::get(self, key);
+        self.touch(key, previous.as_ref());
+        if let Some(previous) = &previous {
+            self.delta -= entry_size(key, previous) as i64;
+            self.pending.insert(key.to_string(), None);
+        }
+        previous
+    }
+
+    fn next_version(&mut self) -> u64 {
+        // sled's id generator is monotonic across restarts
+        match self.read.db.generate_id() {
+            Ok(id) => id + 1,
+            Err(e) => {
+                self.read.fail(StorageError::new(e));
+                0
+            }
+        }
+    }
+
+    fn keys_with_prefix(&self, prefix: &str) -> Vec<String> {
+        let mut keys = Vec::new();
+        for item in self.read.db.scan_prefix(prefix) {
+            match item
+                .map_err(StorageError::new)
+                .and_then(|(key, _)| decode_key(&key))
+            {
+                Ok(key) if !self.pending.contains_key(&key) => keys.push(key),
+                Ok(_) => {}
+                Err(e) => {
+                    self.read.fail(e);
+                    return keys;
+                }
+            }
+        }
+
+        // Overlay this transaction's own pending inserts
+        for (key, change) in &self.pending {
+            if change.is_some() && key.starts_with(prefix) {
+                keys.push(key.clone());
+            }
+        }
+        keys
+    }
+
+    fn has_room(&self, key: &str, size: u64) -> bool {
+        let Some(max_bytes) = self.max_bytes else {
+            return true;
+        };
+        let current = WriteView::get(self, key).map_or(0, |entry| entry_size(key, &entry));
+        (self.bytes as i64 + self.delta - current as i64 + size as i64) <= max_bytes as i64
+    }
+
+    fn tenant_usage(&self, tenant: &str) -> Usage {
+        let mut usage = self.usage.get(tenant);
+        for (key, keys, bytes) in self.changes() {
+            if keyspace::tenant_of(key) == Some(tenant) {
+                usage.keys = usage.keys.saturating_add_signed(keys);
+                usage.bytes = usage.bytes.saturating_add_signed(bytes);
+            }
+        }
+        usage
+    }
+}
+
+impl SledStorage {
+    // Run a write transaction and apply it as one batch; call with `write_lock` held
+    fn apply(&self, f: &mut dyn FnMut(&mut dyn WriteView)) -> Result<(), StorageError> {
+        let mut view = SledWrite {
+            read: SledRead {
+                db: &self.db,
+                error: RefCell::new(None),
+            },
+            pending: HashMap::new(),
+            original: HashMap::new(),
+            delta: 0,
+            bytes: self.bytes.load(Ordering::Relaxed),
+            max_bytes: self.max_bytes,
+            usage: &self.usage,
+        };
+        f(&mut view);
+
+        if let Some(e) = view.read.error.take() {
+            return Err(e);
+        }
+
+        let mut batch = sled::Batch::default();
+        for (key, change) in &view.pending {
+            match change {
+                Some(entry) => batch.insert(key.as_bytes(), encode(entry)?),
+                None => batch.remove(key.as_bytes()),
+            }
+        }
+        self.db.apply_batch(batch).map_err(StorageError::new)?;
+
+        let bytes = (view.bytes as i64 + view.delta).max(0) as u64;
+        self.bytes.store(bytes, Ordering::Relaxed);
+        for (key, keys, bytes) in view.changes() {
+            self.usage.adjust(key, keys, bytes);
+        }
+        for (key, change) in &view.pending {
+            if let Some((_, len)) = view.original.get(key).copied().flatten() {
+                self.sizes.sub(len);
+            }
+            if let Some(entry) = change {
+                self.sizes.add(entry.value.len());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storage for SledStorage {
+    fn read<'a>(&'a self, f: &'a mut (dyn FnMut(&dyn ReadView) + Send)) -> Pending<'a> {
+        Box::pin(async move {
+            let view = SledRead {
+                db: &self.db,
+                error: RefCell::new(None),
+            };
+            f(&view);
+            match view.error.into_inner() {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        })
+    }
+
+    fn write<'a>(&'a self, f: &'a mut (dyn FnMut(&mut dyn WriteView) + Send)) -> Pending<'a> {
+        Box::pin(async move {
+            let _guard = self.write_lock.lock().await;
+            self.apply(f)
+        })
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.db.flush().map(|_| ()).map_err(StorageError::new)
+    }
+
+    fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    fn limits(&self) -> Limits {
+        Limits {
+            max_keys: None,
+            max_bytes: self.max_bytes,
+        }
+    }
+
+    fn tenant_usage(&self, tenant: &str) -> Usage {
+        self.usage.get(tenant)
+    }
+
+    fn value_sizes(&self) -> [u64; VALUE_SIZE_LABELS.len()] {
+        self.sizes.counts()
+    }
+}
@@ -0,0 +1,84 @@
+use axum::http::HeaderMap;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+// Set once spans are being exported
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Whether `init` has set up span export
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Span export over OTLP, alongside the usual log output. Keep it until
+/// shutdown, then call `shutdown` so spans still batched in memory are sent.
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+}
+
+/// Install the log subscriber with an extra layer exporting spans to an OTLP/HTTP
+/// collector at `endpoint`, like http://localhost:4318/v1/traces
+pub fn init(endpoint: &str) -> Result<Telemetry, String> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            opentelemetry_sdk::Resource::builder()
+                .with_service_name(env!("CARGO_PKG_NAME"))
+                .build(),
+        )
+        .build();
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()
+        .map_err(|e| e.to_string())?;
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(Telemetry { provider })
+}
+
+impl Telemetry {
+    /// Flush the remaining spans and stop exporting
+    pub fn shutdown(self) {
+        if let Err(e) = self.provider.shutdown() {
+            tracing::error!("Failed to flush trace spans: {}", e);
+        }
+    }
+}
+
+// Make `span` a child of the caller's span from a W3C traceparent header, if
+// the request has one
+pub(crate) fn set_parent(span: &tracing::Span, headers: &HeaderMap) {
+    let context = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(headers))
+    });
+    let _ = span.set_parent(context);
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
@@ -0,0 +1,226 @@
+//! A throwaway server for end-to-end tests, listening on an ephemeral port
+//! with its background tasks running. Enabled by the `test-util` feature.
+
+use crate::store::WriteView;
+use crate::{Config, Server, Store};
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// A server serving `config` on 127.0.0.1 at a port picked by the OS, with the
+/// sweeper, snapshot task and the other background tasks running as they do
+/// in the binary. With `--resp-port`, `--memcache-port` or `--grpc-port` set,
+/// the Redis or memcached protocol or the gRPC service is served on another
+/// ephemeral port too. TLS and Unix socket settings are ignored.
+///
+/// Dropping it stops the server without waiting for it; call
+/// [`shutdown`](Self::shutdown) to wait for the final snapshot too.
+///
+/// ```
+/// use clap::Parser;
+/// use rust_kv::store::Entry;
+/// use rust_kv::test_util::TestServer;
+/// use rust_kv::Config;
+///
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let config = Config::parse_from(["rust-kv"]);
+/// let server = TestServer::spawn_with(config, |view| {
+///     let mut entry = Entry::new("hello".into());
+///     entry.version = view.next_version();
+///     view.insert("greeting".to_string(), entry);
+/// })
+/// .await;
+///
+/// let body = reqwest::get(server.url("/greeting")).await.unwrap().text().await.unwrap();
+/// assert_eq!(body, "hello");
+/// server.shutdown().await;
+/// # });
+/// ```
+pub struct TestServer {
+    address: SocketAddr,
+    resp_address: Option<SocketAddr>,
+    memcache_address: Option<SocketAddr>,
+    #[cfg(feature = "grpc")]
+    grpc_address: Option<SocketAddr>,
+    server: Server,
+    stop: ShutdownHandle,
+    // Serves until stopped, then stops the background tasks and waits for them
+    running: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    /// Start a server for `config` with the store as `config` opens it.
+    ///
+    /// # Panics
+    ///
+    /// If the server can't be built, like [`crate::app`], its --preload file
+    /// can't be loaded, or no port can be bound.
+    pub async fn spawn(config: Config) -> Self {
+        Self::spawn_with(config, |_| {}).await
+    }
+
+    /// Start a server for `config` once `fixture` has filled in its store.
+    /// The fixture runs in one write transaction before the server accepts
+    /// connections or its background tasks start. Its writes bypass the
+    /// write-ahead log, so they aren't recovered on a restart unless a
+    /// snapshot saves them.
+    ///
+    /// # Panics
+    ///
+    /// As [`spawn`](Self::spawn), or if the fixture's transaction fails.
+    pub async fn spawn_with(
+        mut config: Config,
+        fixture: impl FnOnce(&mut dyn WriteView) + Send,
+    ) -> Self {
+        config.bind = Ipv4Addr::LOCALHOST.into();
+        config.port = 0;
+        let server = Server::new(config).unwrap_or_else(|e| panic!("{}", e));
+        server.preload().await.unwrap_or_else(|e| panic!("{}", e));
+        server
+            .store()
+            .with_write(fixture)
+            .await
+            .expect("the fixture's transaction failed");
+
+        let listener = bind_ephemeral().await;
+        let address = listener.local_addr().expect("the listener has an address");
+
+        let (stop_tx, mut stop) = watch::channel(false);
+        let (tasks_tx, tasks_rx) = watch::channel(false);
+        let mut tasks = server.spawn_tasks(tasks_rx);
+        let mut resp_address = None;
+        if server.config().resp_port.is_some() {
+            let listener = bind_ephemeral().await;
+            resp_address = Some(listener.local_addr().expect("the listener has an address"));
+            let resp_server = server.clone();
+            let stop = stop.clone();
+            tasks.push(tokio::spawn(async move {
+                resp_server.serve_resp(listener, stop).await
+            }));
+        }
+        let mut memcache_address = None;
+        if server.config().memcache_port.is_some() {
+            let listener = bind_ephemeral().await;
+            memcache_address = Some(listener.local_addr().expect("the listener has an address"));
+            let memcache_server = server.clone();
+            let stop = stop.clone();
+            tasks.push(tokio::spawn(async move {
+                memcache_server.serve_memcache(listener, stop).await
+            }));
+        }
+        #[cfg(feature = "grpc")]
+        let mut grpc_address = None;
+        #[cfg(feature = "grpc")]
+        if server.config().grpc_port.is_some() {
+            let listener = bind_ephemeral().await;
+            grpc_address = Some(listener.local_addr().expect("the listener has an address"));
+            let grpc_server = server.clone();
+            let stop = stop.clone();
+            tasks.push(tokio::spawn(async move {
+                grpc_server.serve_grpc(listener, stop).await
+            }));
+        }
+        let app = server
+            .router()
+            .into_make_service_with_connect_info::<SocketAddr>();
+        server.mark_ready();
+        let draining = server.clone();
+        let running = tokio::spawn(async move {
+            let result = axum::serve(listener, app)
+                .with_graceful_shutdown(async move {
+                    let _ = stop.wait_for(|&stop| stop).await;
+                    draining.mark_draining();
+                })
+                .await;
+            if let Err(e) = result {
+                tracing::error!("Test server failed: {}", e);
+            }
+            let _ = tasks_tx.send(true);
+            for task in tasks {
+                let _ = task.await;
+            }
+        });
+
+        Self {
+            address,
+            resp_address,
+            memcache_address,
+            #[cfg(feature = "grpc")]
+            grpc_address,
+            server,
+            stop: ShutdownHandle(stop_tx),
+            running: Some(running),
+        }
+    }
+
+    /// The address the server listens on
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The address the Redis protocol is served on, if `--resp-port` is set
+    pub fn resp_address(&self) -> Option<SocketAddr> {
+        self.resp_address
+    }
+
+    /// The address the memcached protocol is served on, if `--memcache-port`
+    /// is set
+    pub fn memcache_address(&self) -> Option<SocketAddr> {
+        self.memcache_address
+    }
+
+    /// The `http://` URL the gRPC service is served on, if `--grpc-port` is
+    /// set, for [`KvClient::connect`](crate::grpc::proto::kv_client::KvClient::connect)
+    #[cfg(feature = "grpc")]
+    pub fn grpc_url(&self) -> Option<String> {
+        self.grpc_address
+            .map(|address| format!("http://{}", address))
+    }
+
+    /// The URL of `path` on the server, like `http://127.0.0.1:41234/key`
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.address, path)
+    }
+
+    /// The store the server serves, shared with it
+    pub fn store(&self) -> &Store {
+        self.server.store()
+    }
+
+    /// A handle that stops the server from elsewhere, like another task
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.stop.clone()
+    }
+
+    /// Stop accepting connections, let in-flight requests finish, then stop
+    /// the background tasks and wait for them, final snapshot included
+    pub async fn shutdown(mut self) {
+        self.stop.shutdown();
+        if let Some(running) = self.running.take() {
+            let _ = running.await;
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.stop.shutdown();
+    }
+}
+
+async fn bind_ephemeral() -> tokio::net::TcpListener {
+    tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .expect("failed to bind an ephemeral port")
+}
+
+/// Stops a [`TestServer`] as [`TestServer::shutdown`] does, without waiting
+#[derive(Clone)]
+pub struct ShutdownHandle(watch::Sender<bool>);
+
+impl ShutdownHandle {
+    /// Begin shutting down; later calls do nothing
+    pub fn shutdown(&self) {
+        self.0.send_replace(true);
+    }
+}
@@ -0,0 +1,85 @@
+use crate::store::{entry_size, Entry};
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+// Soft-deleted entries, restorable until their retention window passes. Held in
+// memory only, so they don't survive a restart. When taken together with the
+// store's write lock, the store lock is always taken first.
+pub struct Tombstones {
+    window: Duration,
+    entries: Mutex<HashMap<String, Tombstone>>,
+}
+
+struct Tombstone {
+    entry: Entry,
+    deleted_at: Instant,
+}
+
+impl Tombstones {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Keep an entry deleted at `deleted_at`, replacing any older tombstone of the same key
+    pub fn bury(&self, key: String, entry: Entry, deleted_at: Instant) {
+        let tombstone = Tombstone { entry, deleted_at };
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key, tombstone);
+    }
+
+    // Remove a key's tombstone, returning the entry and when it was deleted if
+    // it is still within the window
+    pub fn take(&self, key: &str, now: Instant) -> Option<(Entry, Instant)> {
+        let tombstone = self
+            .entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(key)?;
+        (now < tombstone.deleted_at + self.window)
+            .then_some((tombstone.entry, tombstone.deleted_at))
+    }
+
+    // Drop every tombstone, as a flush deletes everything for good
+    pub fn clear(&self) {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    // Drop tombstones past the window, returning how many were dropped
+    pub fn purge(&self, now: Instant) -> usize {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let before = entries.len();
+        entries.retain(|_, tombstone| now < tombstone.deleted_at + self.window);
+        before - entries.len()
+    }
+
+    // Purge, then release the table's spare capacity. Returns how many were
+    // dropped and the rough bytes held before and after.
+    pub fn compact(&self, now: Instant) -> (usize, u64, u64) {
+        let before = self.allocated();
+        let purged = self.purge(now);
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .shrink_to_fit();
+        (purged, before, self.allocated())
+    }
+
+    fn allocated(&self) -> u64 {
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let table = entries.capacity() * std::mem::size_of::<(String, Tombstone)>();
+        let buried: u64 = entries
+            .iter()
+            .map(|(key, tombstone)| entry_size(key, &tombstone.entry))
+            .sum();
+        table as u64 + buried
+    }
+}
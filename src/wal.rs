@@ -0,0 +1,344 @@
+use crate::persistence::StoredEntry;
+use crate::store::Entry;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot, Notify};
+
+// A logged mutation. Records always carry the resulting state of the key rather
+// than a delta, so replaying a record that a snapshot already covers is harmless.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum WalRecord {
+    Put {
+        key: String,
+        #[serde(flatten)]
+        entry: StoredEntry,
+    },
+    Delete {
+        key: String,
+    },
+    // Every key was removed
+    Clear,
+}
+
+impl WalRecord {
+    pub fn put(key: &str, entry: &Entry) -> Self {
+        WalRecord::Put {
+            key: key.to_string(),
+            entry: StoredEntry::encode(entry),
+        }
+    }
+
+    pub fn delete(key: &str) -> Self {
+        WalRecord::Delete {
+            key: key.to_string(),
+        }
+    }
+}
+
+enum WalMessage {
+    Append(WalRecord, oneshot::Sender<io::Result<()>>),
+    // Close the current segment and start a new one, replying with the
+    // number of the segment that was closed
+    Rotate(oneshot::Sender<io::Result<u64>>),
+}
+
+// Pending acknowledgement for an appended record
+pub type WalAck = oneshot::Receiver<io::Result<()>>;
+
+// Handle to the write-ahead log. The log is a sequence of numbered segment files
+// (`<path>.1`, `<path>.2`, ...) written by a single background writer.
+#[derive(Clone)]
+pub struct Wal {
+    tx: mpsc::UnboundedSender<WalMessage>,
+    path: PathBuf,
+    // The segment this process started writing
+    first: u64,
+    // Segments from this one on are kept after snapshots, for replication
+    retain: Arc<AtomicU64>,
+    // Notified each time appended records have been written out
+    committed: Arc<Notify>,
+}
+
+impl Wal {
+    // Start the writer on a fresh segment after any that already exist.
+    // Must be called after `replay` so existing segments are not appended to.
+    pub fn open(path: PathBuf, fsync: bool) -> io::Result<Self> {
+        let next = list_segments(&path)?.last().map_or(1, |(n, _)| n + 1);
+        let file = open_segment(&path, next)?;
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let writer_path = path.clone();
+        let committed = Arc::new(Notify::new());
+        let writer_committed = committed.clone();
+        tokio::task::spawn_blocking(move || {
+            run_writer(rx, writer_path, next, file, fsync, &writer_committed)
+        });
+
+        Ok(Self {
+            tx,
+            path,
+            first: next,
+            retain: Arc::new(AtomicU64::new(u64::MAX)),
+            committed,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // The segment this process started writing; earlier ones are from before
+    // the restart
+    pub fn first_segment(&self) -> u64 {
+        self.first
+    }
+
+    // Keep segment `first` and later ones when snapshots remove the rest
+    pub fn retain_from(&self, first: u64) {
+        self.retain.store(first, Ordering::SeqCst);
+    }
+
+    // Resolves once records appended since last time have been written out,
+    // at once if they already have
+    pub async fn committed(&self) {
+        self.committed.notified().await
+    }
+
+    // Queue a record for writing. Call this while still holding the store lock
+    // so records hit the log in the same order they were applied, then await the
+    // returned ack after releasing the lock.
+    pub fn append(&self, record: WalRecord) -> WalAck {
+        let (ack_tx, ack_rx) = oneshot::channel();
+        if let Err(mpsc::error::SendError(WalMessage::Append(_, ack_tx))) =
+            self.tx.send(WalMessage::Append(record, ack_tx))
+        {
+            let _ = ack_tx.send(Err(writer_gone()));
+        }
+        ack_rx
+    }
+
+    // Ask the writer to start a new segment. Everything appended before this call
+    // ends up in the closed segment or earlier ones.
+    pub fn rotate(&self) -> oneshot::Receiver<io::Result<u64>> {
+        let (tx, rx) = oneshot::channel();
+        if let Err(mpsc::error::SendError(WalMessage::Rotate(tx))) =
+            self.tx.send(WalMessage::Rotate(tx))
+        {
+            let _ = tx.send(Err(writer_gone()));
+        }
+        rx
+    }
+
+    // Delete segments that a successful snapshot has made redundant, short of
+    // those still to be replicated
+    pub fn remove_segments_through(&self, last: u64) -> io::Result<usize> {
+        let retain = self.retain.load(Ordering::SeqCst);
+        let mut removed = 0;
+        for (n, segment) in list_segments(&self.path)? {
+            if n <= last && n < retain {
+                fs::remove_file(segment)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+// Wait for an appended record to be written, if the log is enabled
+pub async fn wait(ack: Option<WalAck>) -> io::Result<()> {
+    match ack {
+        Some(ack) => ack.await.unwrap_or_else(|_| Err(writer_gone())),
+        None => Ok(()),
+    }
+}
+
+// Wait for every record appended by a multi-key operation
+pub async fn wait_all(acks: Vec<WalAck>) -> io::Result<()> {
+    for ack in acks {
+        wait(Some(ack)).await?;
+    }
+    Ok(())
+}
+
+fn writer_gone() -> io::Error {
+    io::Error::other("write-ahead log writer has stopped")
+}
+
+fn run_writer(
+    mut rx: mpsc::UnboundedReceiver<WalMessage>,
+    path: PathBuf,
+    mut segment: u64,
+    file: File,
+    fsync: bool,
+    committed: &Notify,
+) {
+    let mut out = BufWriter::new(file);
+    let mut pending: Vec<oneshot::Sender<io::Result<()>>> = Vec::new();
+
+    while let Some(first) = rx.blocking_recv() {
+        // Group-commit whatever else is already queued behind the first message
+        let mut next = Some(first);
+        while let Some(message) = next {
+            match message {
+                WalMessage::Append(record, ack) => {
+                    let result = serde_json::to_writer(&mut out, &record)
+                        .map_err(io::Error::other)
+                        .and_then(|_| out.write_all(b"\n"));
+                    match result {
+                        Ok(()) => pending.push(ack),
+                        Err(e) => {
+                            let _ = ack.send(Err(e));
+                        }
+                    }
+                }
+                WalMessage::Rotate(reply) => {
+                    let result = commit(&mut out, fsync, &mut pending).and_then(|_| {
+                        let file = open_segment(&path, segment + 1)?;
+                        out = BufWriter::new(file);
+                        segment += 1;
+                        Ok(segment - 1)
+                    });
+                    let _ = reply.send(result);
+                }
+            }
+            next = rx.try_recv().ok();
+        }
+
+        if let Err(e) = commit(&mut out, fsync, &mut pending) {
+            tracing::error!("Failed to write to write-ahead log: {}", e);
+        }
+        committed.notify_one();
+    }
+}
+
+// Flush buffered records (and fsync if requested), then acknowledge them
+fn commit(
+    out: &mut BufWriter<File>,
+    fsync: bool,
+    pending: &mut Vec<oneshot::Sender<io::Result<()>>>,
+) -> io::Result<()> {
+    let mut result = out.flush();
+    if result.is_ok() && fsync {
+        result = out.get_ref().sync_data();
+    }
+
+    for ack in pending.drain(..) {
+        let _ = ack.send(match &result {
+            Ok(()) => Ok(()),
+            Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+        });
+    }
+
+    result
+}
+
+pub fn segment_path(path: &Path, n: u64) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", n));
+    path.with_file_name(name)
+}
+
+fn open_segment(path: &Path, n: u64) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(path, n))
+}
+
+// Existing segments for `path`, sorted by segment number
+pub fn list_segments(path: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let prefix = format!(
+        "{}.",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    );
+
+    let mut segments = Vec::new();
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(segments),
+        Err(e) => return Err(e),
+    };
+    for dir_entry in entries {
+        let dir_entry = dir_entry?;
+        let name = dir_entry.file_name();
+        let Some(n) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|suffix| suffix.parse::<u64>().ok())
+        else {
+            continue;
+        };
+        segments.push((n, dir_entry.path()));
+    }
+
+    segments.sort_by_key(|(n, _)| *n);
+    Ok(segments)
+}
+
+// Apply every logged record on top of `map`, oldest segment first. A torn
+// record at the end of a segment (from a crash mid-write) is logged and skipped.
+pub fn replay(path: &Path, map: &mut HashMap<String, Entry>) -> io::Result<usize> {
+    let now = Instant::now();
+    let mut applied = 0;
+
+    for (_, segment) in list_segments(path)? {
+        let reader = BufReader::new(File::open(&segment)?);
+        for (line_no, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let record: WalRecord = match serde_json::from_str(&line) {
+                Ok(record) => record,
+                Err(e) => {
+                    tracing::warn!(
+                        "Skipping unreadable record at {}:{}: {}",
+                        segment.display(),
+                        line_no + 1,
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            match record {
+                WalRecord::Put { key, entry } => match entry.decode() {
+                    // Expired while the server was down
+                    Ok(entry) if entry.is_expired(now) => {
+                        map.remove(&key);
+                    }
+                    Ok(entry) => {
+                        map.insert(key, entry);
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            "Skipping unreadable record at {}:{}: {}",
+                            segment.display(),
+                            line_no + 1,
+                            e
+                        );
+                        continue;
+                    }
+                },
+                WalRecord::Delete { key } => {
+                    map.remove(&key);
+                }
+                WalRecord::Clear => map.clear(),
+            }
+            applied += 1;
+        }
+    }
+
+    Ok(applied)
+}
@@ -0,0 +1,358 @@
+// WebSocket sessions at GET /ws, over the same store as the HTTP routes. The
+// client sends commands as JSON text messages, like
+// `{"id": 1, "op": "put", "key": "a", "value": "text"}`, and each is answered
+// on the socket under the `id` it gave. Subscriptions push the writes they
+// pick out, under the id of the command that made them, between the replies.
+
+use crate::error::ApiError;
+use crate::events::{Event, Watched};
+use crate::keyspace::{self, Namespace};
+use crate::persistence::StoredValue;
+use crate::store::StorageError;
+use crate::{handlers, ndjson, wal, AppState};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::Response;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+
+// Most subscriptions one session may hold
+const MAX_SUBSCRIPTIONS: usize = 64;
+
+// Room in a message for the fields of a command besides its value, which may
+// take up to twice the value size limit once base64-encoded or escaped
+const MESSAGE_OVERHEAD: usize = 64 * 1024;
+
+// What a command asks for; its `id` is read separately
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Command {
+    Get {
+        key: String,
+    },
+    // With `value`, or `value_b64` for binary values, expiring in `ttl` seconds
+    Put {
+        key: String,
+        #[serde(flatten)]
+        value: StoredValue,
+        ttl: Option<u64>,
+    },
+    Delete {
+        key: String,
+    },
+    // To one `key`, or the keys starting with `prefix`
+    Subscribe {
+        key: Option<String>,
+        prefix: Option<String>,
+    },
+    // The subscription made under the same id
+    Unsubscribe,
+}
+
+// The reply to the command with `id`: the fields of `result`, an object, or
+// the error's message and code
+fn reply(id: Value, result: Result<Value, ApiError>) -> Value {
+    let mut reply = match result {
+        Ok(body) => body,
+        Err(e) => json!({ "error": e.to_string(), "code": e.code() }),
+    };
+    reply["id"] = id;
+    reply
+}
+
+fn log_failure(e: std::io::Error) -> ApiError {
+    tracing::error!("Failed to log a WebSocket write: {}", e);
+    ApiError::Internal
+}
+
+// One client's connection, with the namespace named on the upgrade request
+struct Session {
+    state: AppState,
+    namespace: Namespace,
+    // By the id of the command that made them
+    subscriptions: Vec<(Value, Watched)>,
+}
+
+impl Session {
+    // The stored form of a key the client named
+    fn key(&self, key: &str) -> Result<String, ApiError> {
+        keyspace::validate_key(key, self.state.max_key_bytes).map_err(ApiError::BadRequest)?;
+        Ok(self.namespace.storage_key(key))
+    }
+
+    fn writable(&self) -> Result<(), ApiError> {
+        if self.state.follower.is_some() {
+            return Err(ApiError::Forbidden(
+                "This server is a follower; write to its primary",
+            ));
+        }
+        match self.state.read_only.load(Ordering::Relaxed) {
+            true => Err(ApiError::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    // Run the command in the message `text` and reply to it
+    async fn command(&mut self, text: &str) -> Value {
+        let mut command: Value = match serde_json::from_str(text) {
+            Ok(command) => command,
+            Err(e) => {
+                let e = ApiError::BadRequest(format!("Invalid JSON: {}", e));
+                return reply(Value::Null, Err(e));
+            }
+        };
+        let id = command.get_mut("id").map(Value::take).unwrap_or_default();
+        let result = match serde_json::from_value(command) {
+            Ok(command) => self.run(&id, command).await,
+            Err(e) => Err(ApiError::BadRequest(format!("Invalid command: {}", e))),
+        };
+        reply(id, result)
+    }
+
+    async fn run(&mut self, id: &Value, command: Command) -> Result<Value, ApiError> {
+        match command {
+            Command::Get { key } => self.get(key).await,
+            Command::Put { key, value, ttl } => self.put(key, value, ttl).await,
+            Command::Delete { key } => self.delete(key).await,
+            Command::Subscribe { key, prefix } => {
+                let watched = match (key, prefix) {
+                    (Some(key), None) => Watched::Key(self.key(&key)?),
+                    (None, Some(prefix)) => {
+                        keyspace::validate(&prefix)
+                            .map_err(|msg| ApiError::BadRequest(msg.into()))?;
+                        Watched::Prefix(self.namespace.clone(), prefix)
+                    }
+                    _ => {
+                        return Err(ApiError::BadRequest(
+                            "Subscribe to one of `key` or `prefix`".to_string(),
+                        ))
+                    }
+                };
+                self.subscriptions.retain(|(existing, _)| existing != id);
+                if self.subscriptions.len() == MAX_SUBSCRIPTIONS {
+                    return Err(ApiError::BadRequest(format!(
+                        "A session may hold at most {} subscriptions",
+                        MAX_SUBSCRIPTIONS
+                    )));
+                }
+                self.subscriptions.push((id.clone(), watched));
+                Ok(json!({ "subscribed": true }))
+            }
+            Command::Unsubscribe => {
+                let before = self.subscriptions.len();
+                self.subscriptions.retain(|(existing, _)| existing != id);
+                Ok(json!({ "unsubscribed": self.subscriptions.len() < before }))
+            }
+        }
+    }
+
+    async fn get(&self, key: String) -> Result<Value, ApiError> {
+        let key = self.key(&key)?;
+        let state = &self.state;
+        let found = state
+            .store
+            .with_key_read(&key, |view| {
+                let now = Instant::now();
+                view.get(&key)
+                    .map(|entry| (!entry.is_expired(now)).then_some(entry))
+            })
+            .await?;
+        let tenant = self.namespace.tenant();
+        match found {
+            Some(Some(entry)) => {
+                state.ops.get(tenant, true);
+                let mut body = json!(StoredValue::encode(&entry.data()?));
+                body["version"] = entry.version.into();
+                Ok(body)
+            }
+            Some(None) => {
+                state.ops.get(tenant, false);
+                handlers::remove_if_expired(state, &key).await?;
+                Err(ApiError::KeyNotFound(key))
+            }
+            None => {
+                state.ops.get(tenant, false);
+                Err(ApiError::KeyNotFound(key))
+            }
+        }
+    }
+
+    async fn put(
+        &self,
+        key: String,
+        value: StoredValue,
+        ttl: Option<u64>,
+    ) -> Result<Value, ApiError> {
+        self.writable()?;
+        let value = value.decode().map_err(ApiError::BadRequest)?;
+        let mut entry =
+            ndjson::entry(&self.state, &key, value, ttl).map_err(ApiError::BadRequest)?;
+        let key = self.namespace.storage_key(&key);
+        let state = &self.state;
+        let result = state
+            .store
+            .with_key_write(&key, |view| {
+                let now = Instant::now();
+                let current = view.get(&key).filter(|current| !current.is_expired(now));
+                if let Some(current) = &current {
+                    entry.replaces(current, state.history_depth);
+                }
+                state.check_room(view, &key, &entry)?;
+                entry.version = view.next_version();
+                let version = entry.version;
+                let ack = state.log(|| wal::WalRecord::put(&key, &entry));
+                state.record_event(&key, Some(&entry));
+                view.insert(key.clone(), entry);
+                Ok((current.is_none(), version, ack))
+            })
+            .await?;
+        let (created, version, ack) =
+            result.map_err(|reason| handlers::insufficient_storage(&state.store, reason))?;
+        wal::wait(ack).await.map_err(log_failure)?;
+        state.ops.put(1);
+        Ok(json!({ "created": created, "version": version }))
+    }
+
+    async fn delete(&self, key: String) -> Result<Value, ApiError> {
+        self.writable()?;
+        let key = self.key(&key)?;
+        let state = &self.state;
+        let ack = state
+            .store
+            .with_key_write(&key, |view| {
+                let now = Instant::now();
+                // An expired entry is removed either way, but isn't found
+                match view.remove(&key) {
+                    Some(entry) if !entry.is_expired(now) => {
+                        let ack = state.log(|| wal::WalRecord::delete(&key));
+                        state.record_event(&key, None);
+                        if let Some(tombstones) = &state.tombstones {
+                            tombstones.bury(key.clone(), entry, now);
+                        }
+                        Some(ack)
+                    }
+                    Some(entry) => {
+                        state.record_expiry(&key, &entry);
+                        None
+                    }
+                    None => None,
+                }
+            })
+            .await?;
+        let Some(ack) = ack else {
+            return Err(ApiError::KeyNotFound(key));
+        };
+        wal::wait(ack).await.map_err(log_failure)?;
+        state.ops.delete();
+        Ok(json!({ "deleted": true }))
+    }
+
+    // A notification for each subscription `event` matches
+    fn notifications(&self, event: &Event) -> Result<Vec<Value>, StorageError> {
+        let ids: Vec<_> = self
+            .subscriptions
+            .iter()
+            .filter(|(_, watched)| watched.matches(event))
+            .map(|(id, _)| id)
+            .collect();
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let record = json!(event.record()?);
+        Ok(ids
+            .into_iter()
+            .map(|id| json!({ "id": id, "event": record }))
+            .collect())
+    }
+}
+
+// The next write, once there are subscriptions to receive it
+async fn next_event(events: &mut Option<Receiver<Event>>) -> Result<Event, RecvError> {
+    match events {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Serve commands until the client goes away or the server shuts down. Replies
+// and notifications are sent one at a time, so a client that stops reading
+// stops being served. Writes go on meanwhile; once its subscriptions have
+// fallen too far behind, the ones it missed are dropped and it gets
+// `{"lagged": <how many>}` in their place.
+async fn serve(mut socket: WebSocket, mut session: Session) {
+    let closed = session.state.events.closed();
+    tokio::pin!(closed);
+    let mut events = None;
+    loop {
+        let outgoing = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => vec![session.command(&text).await],
+                Some(Ok(Message::Binary(_))) => {
+                    let e = ApiError::BadRequest("Commands are sent as text".to_string());
+                    vec![reply(Value::Null, Err(e))]
+                }
+                // Pings are answered as they're read
+                Some(Ok(_)) => continue,
+                Some(Err(_)) | None => return,
+            },
+            event = next_event(&mut events) => match event {
+                Ok(event) => session.notifications(&event).unwrap_or_else(|e| {
+                    tracing::error!("Failed to notify a WebSocket subscriber: {}", e);
+                    Vec::new()
+                }),
+                Err(RecvError::Lagged(missed)) => vec![json!({ "lagged": missed })],
+                Err(RecvError::Closed) => break,
+            },
+            () = &mut closed => break,
+        };
+        // Subscribed before the reply is sent, so no write after it is missed
+        match (session.subscriptions.is_empty(), events.is_some()) {
+            (false, false) => events = Some(session.state.events.watch()),
+            (true, true) => events = None,
+            _ => {}
+        }
+        for message in outgoing {
+            let message = Message::Text(message.to_string().into());
+            if socket.send(message).await.is_err() {
+                return;
+            }
+        }
+    }
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+// GET /ws - Upgrade to a WebSocket session of JSON commands: `get`, `put`
+// and `delete` a `key`, and `subscribe` to a `key` or `prefix`, within the
+// tenant named on the upgrade request
+#[utoipa::path(
+    get, path = "/ws", tag = "keys", operation_id = "websocket",
+    summary = "Open a WebSocket session",
+    params(handlers::TenantHeader),
+    responses(
+        (status = 101, description = "Commands are text messages like `{\"id\": 1, \"op\": \"get\", \"key\": \"a\"}`, with `value` or `value_b64` and an optional `ttl` for `put`, and `key` or `prefix` for `subscribe`; `unsubscribe` cancels the subscription made under its `id`. Replies carry the command's `id` and its result, or an `error` and `code`. Subscriptions send `{\"id\", \"event\"}` with the record GET /events would list, and `{\"lagged\": n}` when the client read too slowly to be sent `n` events"),
+        (status = 400, description = "Not a WebSocket upgrade, or an invalid X-Tenant"),
+    )
+)]
+pub(crate) async fn ws_handler(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let max_message = 2 * state.max_value_bytes + MESSAGE_OVERHEAD;
+    upgrade
+        .max_message_size(max_message)
+        .on_upgrade(move |socket| {
+            serve(
+                socket,
+                Session {
+                    state,
+                    namespace,
+                    subscriptions: Vec::new(),
+                },
+            )
+        })
+}
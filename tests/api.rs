@@ -0,0 +1,1874 @@
+// Drive the router in-process, without binding a port. The cases taking a
+// `Backend` run against both the memory and the sled backend, which must
+// answer every request alike.
+use axum::body::Body;
+use axum::http::{header, Method, Request, StatusCode};
+use axum::response::Response;
+use axum::Router;
+use clap::Parser;
+use http_body_util::BodyExt;
+use rust_kv::test_util::TestServer;
+use rust_kv::Config;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tower::ServiceExt;
+
+#[derive(Clone, Copy)]
+enum Backend {
+    Memory,
+    Sled,
+}
+
+// Run each case, a function taking the `Backend` to use, as one test per
+// backend: `memory::case` and `sled::case`. They run on several threads, so
+// the concurrent ones contend for real.
+macro_rules! on_each_backend {
+    ($($case:ident),* $(,)?) => {
+        mod memory {
+            $(
+                #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+                async fn $case() {
+                    super::$case(super::Backend::Memory).await
+                }
+            )*
+        }
+        mod sled {
+            $(
+                #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+                async fn $case() {
+                    super::$case(super::Backend::Sled).await
+                }
+            )*
+        }
+    };
+}
+
+on_each_backend!(
+    put_get_delete,
+    missing_key,
+    touch_extends_a_ttl,
+    binary_values_round_trip,
+    batch_get_maps_every_key,
+    conditional_gets_answer_304_for_the_current_etag,
+    metrics_count_requests,
+    openapi_describes_the_routes,
+    read_only_mode_refuses_writes,
+    admin_routes_need_an_admin_token,
+    unsupported_methods_list_the_allowed_ones,
+    compressible_values_are_stored_compressed,
+    incompressible_values_are_stored_raw,
+    oversized_uploads_are_refused_as_they_arrive,
+    large_uploads_round_trip,
+    ranges_serve_part_of_a_value,
+    no_range_of_an_empty_value_is_satisfiable,
+    imports_report_each_line,
+    import_modes_decide_over_existing_keys,
+    csv_imports_upsert_rows,
+    the_change_feed_lists_writes_in_order,
+    the_change_feed_is_off_by_default,
+    touches_are_announced_with_their_deadline,
+    writes_refresh_updated_at,
+    conflicting_transactions_commit_one,
+    parallel_increments_all_land,
+    concurrent_appends_keep_every_fragment,
+    one_of_many_takes_gets_the_value,
+    one_put_if_absent_claims_the_key,
+    history_keeps_replaced_versions,
+    buckets_are_separate_keyspaces,
+    tenants_never_see_each_other,
+    overwrites_growing_past_the_quota_are_refused,
+    renames_and_copies_move_values,
+    one_of_many_renames_moves_the_key,
+    flushed_keys_cannot_be_restored,
+    puts_answer_201_with_a_location_only_when_creating,
+    keys_may_nest_under_slashes,
+    stats_follow_a_known_sequence,
+    responses_are_counted_by_status,
+    scrapes_and_probes_are_left_out_of_latency,
+    value_sizes_follow_overwrites,
+    slow_requests_are_kept_in_the_slow_log,
+    cors_headers_go_only_to_allowed_origins,
+);
+
+fn config(args: &[&str]) -> Config {
+    Config::parse_from(["rust-kv"].iter().chain(args))
+}
+
+// A fresh store, and the directory sled keeps it in, removed once dropped
+struct DataDir(Option<PathBuf>);
+
+impl Drop for DataDir {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.0 {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+impl Backend {
+    // `config(args)`, set to use this backend
+    fn config(self, args: &[&str]) -> (Config, DataDir) {
+        let mut config = config(args);
+        let Backend::Sled = self else {
+            return (config, DataDir(None));
+        };
+        static DIRS: AtomicUsize = AtomicUsize::new(0);
+        let n = DIRS.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("rust-kv-api-{}-{}", std::process::id(), n));
+        config.backend = rust_kv::Backend::Sled;
+        config.data_dir = dir.clone();
+        (config, DataDir(Some(dir)))
+    }
+
+    fn router(self, args: &[&str]) -> App {
+        let (config, data_dir) = self.config(args);
+        App {
+            router: rust_kv::app(config),
+            _data_dir: data_dir,
+        }
+    }
+
+    async fn server(self, args: &[&str]) -> Served {
+        let (config, data_dir) = self.config(args);
+        Served {
+            server: TestServer::spawn(config).await,
+            _data_dir: data_dir,
+        }
+    }
+}
+
+// A server for one backend, for cases that go over HTTP
+struct Served {
+    server: TestServer,
+    _data_dir: DataDir,
+}
+
+impl Deref for Served {
+    type Target = TestServer;
+
+    fn deref(&self) -> &TestServer {
+        &self.server
+    }
+}
+
+// A router over the store of one backend
+struct App {
+    router: Router,
+    _data_dir: DataDir,
+}
+
+impl Deref for App {
+    type Target = Router;
+
+    fn deref(&self) -> &Router {
+        &self.router
+    }
+}
+
+fn router(args: &[&str]) -> Router {
+    rust_kv::app(config(args))
+}
+
+async fn send(app: &Router, method: Method, uri: &str, body: &str) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn text(response: Response) -> String {
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    String::from_utf8(bytes.to_vec()).unwrap()
+}
+
+async fn json(response: Response) -> serde_json::Value {
+    serde_json::from_str(&text(response).await).unwrap()
+}
+
+async fn put_get_delete(backend: Backend) {
+    let app = backend.router(&[]);
+
+    let response = send(&app, Method::PUT, "/greeting", "hello").await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = send(&app, Method::GET, "/greeting", "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().contains_key(header::ETAG));
+    assert_eq!(text(response).await, "hello");
+
+    let response = send(&app, Method::PUT, "/greeting", "hi").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        text(send(&app, Method::GET, "/greeting", "").await).await,
+        "hi"
+    );
+
+    let response = send(&app, Method::DELETE, "/greeting", "").await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = send(&app, Method::GET, "/greeting", "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+async fn missing_key(backend: Backend) {
+    let app = backend.router(&[]);
+    assert_eq!(
+        send(&app, Method::GET, "/nothing", "").await.status(),
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        send(&app, Method::DELETE, "/nothing", "").await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+async fn binary_values_round_trip(backend: Backend) {
+    let app = backend.router(&[]);
+    // NUL bytes, a lone continuation byte and a truncated sequence
+    let value = b"\x00bin\x00ary\x80\xc3\xff\xfe\x00";
+    let request = Request::put("/blob").body(Body::from(&value[..])).unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = send(&app, Method::GET, "/blob", "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[header::CONTENT_TYPE],
+        "application/octet-stream"
+    );
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], value);
+}
+
+async fn post_json(app: &Router, uri: &str, body: serde_json::Value) -> Response {
+    let request = Request::post(uri)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn batch_get_maps_every_key(backend: Backend) {
+    let app = backend.router(&[]);
+    send(&app, Method::PUT, "/a", "1").await;
+    let request = Request::put("/bin").body(Body::from(&b"\x00\xff"[..]));
+    app.clone().oneshot(request.unwrap()).await.unwrap();
+
+    let keys = serde_json::json!(["a", "missing", "a", "bin"]);
+    let response = post_json(&app, "/batch/get", keys).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        json(response).await,
+        serde_json::json!({
+            "a": {"value": "1"},
+            "missing": null,
+            "bin": {"value_b64": "AP8="},
+        })
+    );
+
+    let keys: Vec<_> = (0..1001).map(|n| n.to_string()).collect();
+    let response = post_json(&app, "/batch/get", serde_json::json!(keys)).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+// A request with one header set
+async fn send_with(
+    app: &Router,
+    method: Method,
+    uri: &str,
+    (name, value): (&str, &str),
+    body: &str,
+) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(name, value)
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn touch_extends_a_ttl(backend: Backend) {
+    let app = backend.router(&[]);
+    let ttl = |seconds| ("x-ttl-seconds", seconds);
+    let response = send_with(&app, Method::PUT, "/session", ttl("1"), "state").await;
+    let etag = response.headers()[header::ETAG].clone();
+
+    // Just before it would have expired
+    tokio::time::sleep(Duration::from_millis(800)).await;
+    let response = send_with(&app, Method::POST, "/session/touch", ttl("2"), "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let response = send(&app, Method::GET, "/session", "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::ETAG], etag);
+    assert_eq!(text(response).await, "state");
+
+    let response = send_with(&app, Method::POST, "/nothing/touch", ttl("2"), "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    send_with(&app, Method::PUT, "/brief", ttl("1"), "v").await;
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let response = send_with(&app, Method::POST, "/brief/touch", ttl("2"), "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+async fn conditional_gets_answer_304_for_the_current_etag(backend: Backend) {
+    let app = backend.router(&[]);
+    let response = send(&app, Method::PUT, "/doc", "body").await;
+    let etag = response.headers()[header::ETAG]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let get = |uri, tags: &str| {
+        let request = Request::get(uri)
+            .header(header::IF_NONE_MATCH, tags)
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(request)
+    };
+
+    for tags in [
+        etag.clone(),
+        format!("W/{etag}"),
+        format!("\"999\", {etag}"),
+        "*".to_string(),
+    ] {
+        let response = get("/doc", &tags).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{}", tags);
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+        assert_eq!(text(response).await, "");
+    }
+
+    let response = get("/doc", "\"999\", W/\"998\"").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::ETAG], etag.as_str());
+    assert_eq!(text(response).await, "body");
+
+    let response = get("/nothing", &etag).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+async fn metrics_count_requests(backend: Backend) {
+    let app = backend.router(&[]);
+    send(&app, Method::PUT, "/a", "1").await;
+    send(&app, Method::GET, "/a", "").await;
+    send(&app, Method::GET, "/b", "").await;
+
+    let response = send(&app, Method::GET, "/metrics?format=json", "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let metrics = json(response).await;
+    assert_eq!(metrics["lookups"]["hits"], 1);
+    assert_eq!(metrics["lookups"]["misses"], 1);
+    assert_eq!(metrics["per_route"]["GET /{*key}"]["count"], 2);
+    assert_eq!(metrics["per_route"]["PUT /{*key}"]["count"], 1);
+
+    // The Prometheus text tells the same counts
+    let response = send(&app, Method::GET, "/metrics", "").await;
+    let exposition = text(response).await;
+    // Summed over the series of `name` carrying all of `labels`
+    let sample = |name: &str, labels: &[&str]| -> u64 {
+        exposition
+            .lines()
+            .filter_map(|line| line.strip_prefix(name)?.strip_prefix('{'))
+            .filter_map(|line| line.split_once("} "))
+            .filter(|(series, _)| {
+                labels
+                    .iter()
+                    .all(|label| series.split(',').any(|l| l == *label))
+            })
+            .map(|(_, value)| value.parse::<u64>().unwrap())
+            .sum()
+    };
+    for (result, field) in [("hit", "hits"), ("miss", "misses")] {
+        let label = format!("result=\"{result}\"");
+        let count = sample("kv_lookups_total", &[&label]);
+        assert_eq!(metrics["lookups"][field], count, "{result}");
+    }
+    for method in ["GET", "PUT"] {
+        let label = format!("method=\"{method}\"");
+        let count = sample("kv_http_requests_total", &[&label, "route=\"/{*key}\""]);
+        assert_eq!(
+            metrics["per_route"][format!("{method} /{{*key}}")]["count"],
+            count
+        );
+    }
+
+    let stats = json(send(&app, Method::GET, "/stats", "").await).await;
+    assert_eq!(stats["keys"], 1);
+    assert_eq!(stats["read_only"], false);
+}
+
+async fn openapi_describes_the_routes(backend: Backend) {
+    let app = backend.router(&[]);
+    let response = send(&app, Method::GET, "/openapi.json", "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let document = json(response).await;
+    let paths = document["paths"].as_object().unwrap();
+    for path in [
+        "/{key}",
+        "/b/{bucket}/{key}",
+        "/keys",
+        "/batch/get",
+        "/admin/flush",
+    ] {
+        assert!(paths.contains_key(path), "{} is missing", path);
+    }
+    assert!(document["components"]["securitySchemes"]["bearer"].is_object());
+
+    let response = send(&app, Method::GET, "/docs", "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+async fn read_only_mode_refuses_writes(backend: Backend) {
+    let app = backend.router(&["--admin-token", "secret"]);
+    send(&app, Method::PUT, "/kept", "value").await;
+    // Enough keys that an export streams many pages
+    for batch in 0..8 {
+        let values: serde_json::Map<_, _> = (0..1000)
+            .map(|n| (format!("filler-{batch}-{n}"), serde_json::json!("v")))
+            .collect();
+        let body = serde_json::Value::Object(values).to_string();
+        let response = send(&app, Method::POST, "/batch/put", &body).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    // An export is under way, its first page read, when writes are turned off
+    let request = Request::get("/admin/export")
+        .header(header::AUTHORIZATION, "Bearer secret")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut export = response.into_body();
+    let first = export.frame().await.unwrap().unwrap().into_data().unwrap();
+
+    let request = Request::post("/admin/readonly")
+        .header(header::AUTHORIZATION, "Bearer secret")
+        .body(Body::from("true"))
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = send(&app, Method::PUT, "/kept", "changed").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    // It runs to the end regardless
+    let rest = export.collect().await.unwrap().to_bytes();
+    let lines = first.iter().chain(&rest).filter(|&&b| b == b'\n').count();
+    assert_eq!(lines, 8001);
+
+    let response = send(&app, Method::PUT, "/kept", "changed").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let response = send(&app, Method::POST, "/kept/copy", "other").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(
+        text(send(&app, Method::GET, "/kept", "").await).await,
+        "value"
+    );
+
+    let request = Request::post("/admin/readonly")
+        .header(header::AUTHORIZATION, "Bearer secret")
+        .body(Body::from("false"))
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap();
+    let response = send(&app, Method::PUT, "/kept", "changed").await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+async fn admin_routes_need_an_admin_token(backend: Backend) {
+    let app = backend.router(&[]);
+    let response = send(&app, Method::POST, "/admin/flush", "").await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let app = backend.router(&["--admin-token", "secret", "--api-key", "data"]);
+    let request = Request::post("/admin/snapshot")
+        .header(header::AUTHORIZATION, "Bearer data")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+async fn unsupported_methods_list_the_allowed_ones(backend: Backend) {
+    let app = backend.router(&[]);
+    for method in [Method::POST, Method::OPTIONS] {
+        let response = send(&app, method, "/greeting", "").await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(
+            response.headers()[header::ALLOW],
+            "GET,HEAD,PUT,PATCH,DELETE"
+        );
+    }
+    let response = send(&app, Method::PUT, "/greeting/ttl", "").await;
+    assert_eq!(response.headers()[header::ALLOW], "GET");
+    let response = send(&app, Method::GET, "/greeting/incr", "").await;
+    assert_eq!(response.headers()[header::ALLOW], "POST");
+
+    for method in [Method::POST, Method::PUT, Method::DELETE] {
+        let response = send(&app, method, "/metrics", "").await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "GET,HEAD");
+    }
+
+    // Paths no route matches are still unknown
+    let response = send(&app, Method::POST, "/", "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(!response.headers().contains_key(header::ALLOW));
+
+    let metrics = json(send(&app, Method::GET, "/metrics?format=json", "").await).await;
+    assert_eq!(metrics["status_codes"]["405"], 4);
+}
+
+// Only the memory backend caps its key count
+#[tokio::test]
+async fn hot_keys_survive_eviction() {
+    let app = router(&["--max-keys", "3"]);
+    for key in ["hot", "warm", "cold"] {
+        send(&app, Method::PUT, &format!("/{key}"), key).await;
+    }
+    // Read in the reverse of the order they were written
+    send(&app, Method::GET, "/warm", "").await;
+    send(&app, Method::GET, "/hot", "").await;
+
+    send(&app, Method::PUT, "/new", "1").await;
+    let response = send(&app, Method::GET, "/cold", "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    send(&app, Method::PUT, "/newer", "2").await;
+    let response = send(&app, Method::GET, "/warm", "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    for key in ["hot", "new", "newer"] {
+        let response = send(&app, Method::GET, &format!("/{key}"), "").await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", key);
+    }
+
+    let metrics = json(send(&app, Method::GET, "/metrics?format=json", "").await).await;
+    assert_eq!(metrics["evictions"], 2);
+    let metrics = text(send(&app, Method::GET, "/metrics", "").await).await;
+    assert!(metrics.contains("\nkv_evictions_total 2\n"), "{}", metrics);
+}
+
+// A value the memory backend stores is one allocation from the PUT body to
+// every GET response
+#[tokio::test]
+async fn values_are_served_without_copying() {
+    let app = router(&[]);
+    let value = bytes::Bytes::from(vec![7u8; 1 << 20]);
+
+    let request = Request::put("/big")
+        .body(Body::from(value.clone()))
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    for _ in 0..2 {
+        let response = send(&app, Method::GET, "/big", "").await;
+        let served = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(served.len(), value.len());
+        assert_eq!(served.as_ptr(), value.as_ptr());
+    }
+}
+
+// GET throughput for 1 MB values as served, against the same requests paying
+// for the per-response copy values used to need. Run with
+// `cargo test --release --test api -- --ignored --nocapture`.
+#[tokio::test]
+#[ignore]
+async fn large_value_get_throughput() {
+    const GETS: u32 = 2_000;
+
+    let app = router(&[]);
+    let value = bytes::Bytes::from(vec![7u8; 1 << 20]);
+    let request = Request::put("/big").body(Body::from(value)).unwrap();
+    app.clone().oneshot(request).await.unwrap();
+
+    let run = |copy: bool| {
+        let app = app.clone();
+        async move {
+            let start = std::time::Instant::now();
+            for _ in 0..GETS {
+                let response = send(&app, Method::GET, "/big", "").await;
+                let served = response.into_body().collect().await.unwrap().to_bytes();
+                if copy {
+                    std::hint::black_box(served.to_vec());
+                }
+            }
+            f64::from(GETS) / start.elapsed().as_secs_f64()
+        }
+    };
+
+    let copied = run(true).await;
+    let shared = run(false).await;
+    println!("copying: {copied:.0} GETs/s, shared: {shared:.0} GETs/s");
+    assert!(shared > copied);
+}
+
+async fn get_with(app: &Router, uri: &str, accept_encoding: &str) -> Response {
+    let request = Request::get(uri)
+        .header(header::ACCEPT_ENCODING, accept_encoding)
+        .body(Body::empty())
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn compressible_values_are_stored_compressed(backend: Backend) {
+    let app = backend.router(&["--compress-min-bytes", "1024"]);
+    let value = r#"{"name":"widget","tags":["a","b"]},"#.repeat(4000);
+    let response = send(&app, Method::PUT, "/blob", &value).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let meta = json(send(&app, Method::GET, "/blob/meta", "").await).await;
+    assert_eq!(meta["size"], value.len());
+    let stored = meta["stored_size"].as_u64().unwrap();
+    assert!(stored < value.len() as u64 / 10, "{}", meta);
+    let stats = json(send(&app, Method::GET, "/stats", "").await).await;
+    assert_eq!(stats["bytes"], stored + "blob".len() as u64);
+
+    // Decompressed for clients that don't accept gzip
+    let response = send(&app, Method::GET, "/blob", "").await;
+    assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+    assert_eq!(text(response).await, value);
+    let response = get_with(&app, "/blob", "gzip;q=0, br").await;
+    assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+    assert_eq!(text(response).await, value);
+
+    // Served as stored to those that do
+    let response = get_with(&app, "/blob", "br, gzip").await;
+    assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body.len() as u64, stored);
+    let mut decoded = String::new();
+    std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&body[..]), &mut decoded)
+        .unwrap();
+    assert_eq!(decoded, value);
+
+    let request = Request::head("/blob").body(Body::empty()).unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(
+        response.headers()[header::CONTENT_LENGTH],
+        value.len().to_string()
+    );
+
+    // Appending leaves the value readable, now stored uncompressed
+    let response = send(&app, Method::PATCH, "/blob", "tail").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = get_with(&app, "/blob", "gzip").await;
+    assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+    assert_eq!(text(response).await, value + "tail");
+}
+
+async fn incompressible_values_are_stored_raw(backend: Backend) {
+    let app = backend.router(&["--compress-min-bytes", "1024"]);
+    // xorshift noise, which gzip can't shrink
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let value: Vec<u8> = (0..64 * 1024)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    let request = Request::put("/noise")
+        .body(Body::from(value.clone()))
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let meta = json(send(&app, Method::GET, "/noise/meta", "").await).await;
+    assert_eq!(meta["size"], meta["stored_size"]);
+    let response = get_with(&app, "/noise", "gzip").await;
+    assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, value);
+}
+
+// A body of `chunks` chunks of `chunk` bytes each, sent without a
+// Content-Length, counting how many chunks were read
+struct Chunked {
+    chunk: bytes::Bytes,
+    left: usize,
+    read: Arc<AtomicUsize>,
+}
+
+impl http_body::Body for Chunked {
+    type Data = bytes::Bytes;
+    type Error = std::convert::Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Option<Result<http_body::Frame<Self::Data>, Self::Error>>> {
+        if self.left == 0 {
+            return Poll::Ready(None);
+        }
+        self.left -= 1;
+        self.read.fetch_add(1, Ordering::Relaxed);
+        Poll::Ready(Some(Ok(http_body::Frame::data(self.chunk.clone()))))
+    }
+}
+
+fn chunked(chunk: bytes::Bytes, chunks: usize) -> (Body, Arc<AtomicUsize>) {
+    let read = Arc::default();
+    let body = Chunked {
+        chunk,
+        left: chunks,
+        read: Arc::clone(&read),
+    };
+    (Body::new(body), read)
+}
+
+async fn oversized_uploads_are_refused_as_they_arrive(backend: Backend) {
+    let app = backend.router(&["--max-value-bytes", "1048576"]);
+    // 300 MB in 64 KB chunks
+    let (body, read) = chunked(bytes::Bytes::from(vec![b'x'; 64 * 1024]), 4800);
+    let response = app
+        .clone()
+        .oneshot(Request::put("/huge").body(body).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    // Reading stopped with the chunk that crossed the limit
+    assert_eq!(read.load(Ordering::Relaxed), 17);
+    let response = send(&app, Method::GET, "/huge", "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+async fn large_uploads_round_trip(backend: Backend) {
+    let app = backend.router(&["--max-value-bytes", "134217728"]);
+    // 128 MB in 1 MB chunks
+    let (body, _) = chunked(bytes::Bytes::from(vec![b'y'; 1 << 20]), 128);
+    let response = app
+        .clone()
+        .oneshot(Request::put("/large").body(body).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = send(&app, Method::GET, "/large", "").await;
+    let served = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(served.len(), 128 << 20);
+    assert!(served.iter().all(|&byte| byte == b'y'));
+}
+
+async fn get_range(app: &Router, uri: &str, range: &str) -> Response {
+    let request = Request::get(uri)
+        .header(header::RANGE, range)
+        .body(Body::empty())
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn ranges_serve_part_of_a_value(backend: Backend) {
+    let app = backend.router(&[]);
+    send(&app, Method::PUT, "/digits", "0123456789").await;
+
+    for (range, part, content_range) in [
+        ("bytes=2-4", "234", "bytes 2-4/10"),
+        ("bytes=7-", "789", "bytes 7-9/10"),
+        ("bytes=5-100", "56789", "bytes 5-9/10"),
+        ("bytes=-3", "789", "bytes 7-9/10"),
+        ("bytes=-20", "0123456789", "bytes 0-9/10"),
+    ] {
+        let response = get_range(&app, "/digits", range).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT, "{}", range);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], content_range);
+        assert_eq!(text(response).await, part);
+    }
+
+    for range in ["bytes=10-", "bytes=0-1,4-5", "bytes=-0"] {
+        let response = get_range(&app, "/digits", range).await;
+        assert_eq!(
+            response.status(),
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            "{}",
+            range
+        );
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */10");
+    }
+
+    // Other units and malformed ranges are ignored
+    for range in ["items=0-1", "bytes=4-2", "bytes=x-"] {
+        let response = get_range(&app, "/digits", range).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", range);
+        assert_eq!(response.headers()[header::ACCEPT_RANGES], "bytes");
+        assert_eq!(text(response).await, "0123456789");
+    }
+
+    // A stale If-Range gets the whole value
+    let request = Request::get("/digits")
+        .header(header::RANGE, "bytes=0-0")
+        .header(header::IF_RANGE, "\"12345\"")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+async fn no_range_of_an_empty_value_is_satisfiable(backend: Backend) {
+    let app = backend.router(&[]);
+    send(&app, Method::PUT, "/empty", "").await;
+    for range in ["bytes=0-", "bytes=0-0", "bytes=-5"] {
+        let response = get_range(&app, "/empty", range).await;
+        assert_eq!(
+            response.status(),
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            "{}",
+            range
+        );
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */0");
+        assert_eq!(json(response).await["code"], "range_not_satisfiable");
+    }
+}
+
+#[tokio::test]
+async fn ranges_share_the_stored_value() {
+    let app = router(&[]);
+    let value = bytes::Bytes::from(vec![3u8; 1 << 20]);
+    let request = Request::put("/big")
+        .body(Body::from(value.clone()))
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap();
+
+    let response = get_range(&app, "/big", "bytes=1000-1999").await;
+    let served = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(served.len(), 1000);
+    assert_eq!(served.as_ptr(), value[1000..].as_ptr());
+}
+
+// A body sent as the given chunks, however they split its lines
+struct Pieces(std::collections::VecDeque<&'static str>);
+
+impl http_body::Body for Pieces {
+    type Data = bytes::Bytes;
+    type Error = std::convert::Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Option<Result<http_body::Frame<Self::Data>, Self::Error>>> {
+        let piece = self.0.pop_front();
+        Poll::Ready(piece.map(|piece| Ok(http_body::Frame::data(piece.into()))))
+    }
+}
+
+async fn import(app: &Router, query: &str, pieces: &[&'static str]) -> Response {
+    let request = Request::post(format!("/admin/import?{query}"))
+        .header(header::AUTHORIZATION, "Bearer secret")
+        .body(Body::new(Pieces(pieces.iter().copied().collect())))
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn imports_report_each_line(backend: Backend) {
+    let app = backend.router(&["--admin-token", "secret"]);
+    send(&app, Method::PUT, "/a", "old").await;
+
+    let response = import(
+        &app,
+        "mode=upsert",
+        &[
+            "{\"key\": \"a\", \"val",
+            "ue\": \"new\"}\n{garbage\n",
+            "{\"key\": \"b\", \"value_b64\": \"AP8=\"}\n\n{\"key\": \"c\", \"value\": \"3\", \"ttl\": 0}\n",
+            "{\"key\": \"d\", \"value\": \"4\", \"bucket\": \"x\"}\n",
+        ],
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let report = json(response).await;
+    assert_eq!(report["processed"], 5);
+    assert_eq!(report["inserted"], 2);
+    assert_eq!(report["updated"], 1);
+    assert_eq!(report["skipped"], 0);
+    assert_eq!(report["errored"], 2);
+    let lines: Vec<_> = report["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|error| error["line"].as_u64().unwrap())
+        .collect();
+    assert_eq!(lines, [2, 5]);
+
+    assert_eq!(text(send(&app, Method::GET, "/a", "").await).await, "new");
+    let response = send(&app, Method::GET, "/b", "").await;
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], [0, 0xff]);
+    assert_eq!(text(send(&app, Method::GET, "/b/x/d", "").await).await, "4");
+    let response = send(&app, Method::GET, "/c", "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+async fn import_modes_decide_over_existing_keys(backend: Backend) {
+    let app = backend.router(&["--admin-token", "secret"]);
+    send(&app, Method::PUT, "/a", "old").await;
+    let lines = [
+        "{\"key\": \"new1\", \"value\": \"1\"}\n",
+        "{\"key\": \"a\", \"value\": \"new\"}\n",
+        "{\"key\": \"new2\", \"value\": \"2\"}",
+    ];
+
+    let report = json(import(&app, "mode=skip-existing", &lines).await).await;
+    assert_eq!(report["inserted"], 2);
+    assert_eq!(report["skipped"], 1);
+    assert_eq!(text(send(&app, Method::GET, "/a", "").await).await, "old");
+
+    send(&app, Method::DELETE, "/new1", "").await;
+    send(&app, Method::DELETE, "/new2", "").await;
+    let response = import(&app, "mode=fail-on-conflict", &lines).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let error = json(response).await;
+    assert_eq!(error["code"], "conflict");
+    assert!(error.to_string().contains("\"a\""), "{}", error);
+    // Lines before the conflict stay imported, the rest aren't
+    let response = send(&app, Method::GET, "/new1", "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = send(&app, Method::GET, "/new2", "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = import(&app, "mode=whatever", &lines).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+async fn csv_imports_upsert_rows(backend: Backend) {
+    let app = backend.router(&["--admin-token", "secret"]);
+    send(&app, Method::PUT, "/sku1", "old").await;
+
+    let response = import(
+        &app,
+        "format=csv&key_col=id&value_col=payload",
+        &[
+            "name,id,payload\r\nwidget,sku1,\"red, large\"\r\n",
+            "gadget,sku2,\"says \"\"hi\"\"\nover two lines\"\r",
+            "\nshort,row\r\nbad,/sku3,x\r\nlast,sku4,",
+        ],
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let report = json(response).await;
+    assert_eq!(report["processed"], 5, "{}", report);
+    assert_eq!(report["inserted"], 2);
+    assert_eq!(report["updated"], 1);
+    assert_eq!(report["errored"], 2);
+    let lines: Vec<_> = report["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|error| error["line"].as_u64().unwrap())
+        .collect();
+    assert_eq!(lines, [4, 5]);
+
+    assert_eq!(
+        text(send(&app, Method::GET, "/sku1", "").await).await,
+        "red, large"
+    );
+    let value = text(send(&app, Method::GET, "/sku2", "").await).await;
+    assert_eq!(value, "says \"hi\"\nover two lines");
+    assert_eq!(text(send(&app, Method::GET, "/sku4", "").await).await, "");
+
+    // Told apart by Content-Type, with the default columns
+    let request = Request::post("/admin/import")
+        .header(header::AUTHORIZATION, "Bearer secret")
+        .header(header::CONTENT_TYPE, "text/csv; charset=utf-8")
+        .body(Body::from("key,value\nsku5,5\n"))
+        .unwrap();
+    let report = json(app.clone().oneshot(request).await.unwrap()).await;
+    assert_eq!(report["inserted"], 1);
+    assert_eq!(text(send(&app, Method::GET, "/sku5", "").await).await, "5");
+
+    let response = import(&app, "format=csv&key_col=sku", &["key,value\n"]).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert!(text(response).await.contains("`sku`"));
+}
+
+async fn the_change_feed_lists_writes_in_order(backend: Backend) {
+    let app = backend.router(&["--event-log-capacity", "3", "--event-log-values"]);
+    send(&app, Method::PUT, "/a", "1").await;
+    send(&app, Method::PUT, "/b/photos/cat", "meow").await;
+    send(&app, Method::DELETE, "/a", "").await;
+    // Deleting a missing key changes nothing, so records nothing
+    send(&app, Method::DELETE, "/a", "").await;
+
+    let feed = json(send(&app, Method::GET, "/events", "").await).await;
+    assert_eq!(feed["latest"], 3);
+    let events = feed["events"].as_array().unwrap();
+    let ops: Vec<_> = events
+        .iter()
+        .map(|e| (e["seq"].as_u64().unwrap(), e["op"].as_str().unwrap()))
+        .collect();
+    assert_eq!(ops, [(1, "put"), (2, "put"), (3, "delete")]);
+    assert_eq!(events[0]["value"], "1");
+    assert_eq!(events[0]["version"], 1);
+    assert_eq!(events[1]["key"], "cat");
+    assert_eq!(events[1]["bucket"], "photos");
+    assert!(events[2].get("value").is_none());
+    assert!(events[2]["at"].is_string());
+
+    let feed = json(send(&app, Method::GET, "/events?since=1&limit=1", "").await).await;
+    assert_eq!(feed["events"][0]["seq"], 2);
+    assert_eq!(feed["events"].as_array().unwrap().len(), 1);
+
+    // Reading from the latest event answers at once, with nothing
+    let started = std::time::Instant::now();
+    let feed = json(send(&app, Method::GET, "/events?since=3", "").await).await;
+    assert_eq!(feed["events"], serde_json::json!([]));
+    assert_eq!(feed["latest"], 3);
+    assert!(started.elapsed() < std::time::Duration::from_secs(1));
+
+    // Two more writes push out the first two events
+    send(&app, Method::PUT, "/c", "2").await;
+    send(&app, Method::PUT, "/d", "3").await;
+    let feed = json(send(&app, Method::GET, "/events?since=2", "").await).await;
+    let seqs: Vec<_> = feed["events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["seq"].as_u64().unwrap())
+        .collect();
+    assert_eq!(seqs, [3, 4, 5]);
+    let response = send(&app, Method::GET, "/events?since=1", "").await;
+    assert_eq!(response.status(), StatusCode::GONE);
+    assert_eq!(json(response).await["code"], "gone");
+    let response = send(&app, Method::GET, "/events?since=6", "").await;
+    assert_eq!(response.status(), StatusCode::GONE);
+}
+
+async fn the_change_feed_is_off_by_default(backend: Backend) {
+    let app = backend.router(&[]);
+    let response = send(&app, Method::GET, "/events", "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+// `requests` run at once, each built by `request(n)` for its index, and the
+// status and body of each response in order
+async fn race(
+    requests: usize,
+    request: impl Fn(&reqwest::Client, usize) -> reqwest::RequestBuilder,
+) -> Vec<(reqwest::StatusCode, String)> {
+    let client = reqwest::Client::new();
+    let barrier = Arc::new(tokio::sync::Barrier::new(requests));
+    let tasks: Vec<_> = (0..requests)
+        .map(|n| {
+            let request = request(&client, n);
+            let barrier = Arc::clone(&barrier);
+            tokio::spawn(async move {
+                barrier.wait().await;
+                let response = request.send().await.unwrap();
+                (response.status(), response.text().await.unwrap())
+            })
+        })
+        .collect();
+    let mut responses = Vec::new();
+    for task in tasks {
+        responses.push(task.await.unwrap());
+    }
+    responses
+}
+
+async fn conflicting_transactions_commit_one(backend: Backend) {
+    let server = backend.server(&[]).await;
+    let client = reqwest::Client::new();
+    for round in 0..20 {
+        let response = client.put(server.url("/lock")).body("free").send().await;
+        assert!(response.unwrap().status().is_success());
+
+        // Both take the lock only if it's still free
+        let responses = race(2, |client, n| {
+            let txn = serde_json::json!({
+                "conditions": [{"check": "equals", "key": "lock", "value": "free"}],
+                "operations": [{"op": "put", "key": "lock", "value": format!("owner{n}")}],
+            });
+            client
+                .post(server.url("/txn"))
+                .header("content-type", "application/json")
+                .body(txn.to_string())
+        })
+        .await;
+        let mut winners = Vec::new();
+        for (n, (status, body)) in responses.into_iter().enumerate() {
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            match status {
+                reqwest::StatusCode::OK => {
+                    assert_eq!(body, serde_json::json!({"succeeded": true}));
+                    winners.push(format!("owner{n}"));
+                }
+                status => {
+                    assert_eq!(status, reqwest::StatusCode::CONFLICT);
+                    assert_eq!(body["failed"]["index"], 0);
+                }
+            }
+        }
+        assert_eq!(winners.len(), 1, "round {}", round);
+        let response = client.get(server.url("/lock")).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), winners[0]);
+    }
+}
+
+async fn parallel_increments_all_land(backend: Backend) {
+    let server = backend.server(&[]).await;
+    let responses = race(100, |client, _| client.post(server.url("/hits/incr"))).await;
+    assert!(responses.iter().all(|(status, _)| status.is_success()));
+    // Each saw a value of its own
+    let mut values: Vec<i64> = responses
+        .iter()
+        .map(|(_, body)| body.trim().parse().unwrap())
+        .collect();
+    values.sort();
+    assert_eq!(values, (1..=100).collect::<Vec<_>>());
+    let response = reqwest::get(server.url("/hits")).await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "100");
+}
+
+async fn concurrent_appends_keep_every_fragment(backend: Backend) {
+    let server = backend.server(&[]).await;
+    let responses = race(50, |client, n| {
+        client.patch(server.url("/log")).body(format!("<{n}>"))
+    })
+    .await;
+    assert!(responses.iter().all(|(status, _)| status.is_success()));
+
+    let response = reqwest::get(server.url("/log")).await.unwrap();
+    let log = response.text().await.unwrap();
+    let mut fragments: Vec<&str> = log
+        .strip_prefix('<')
+        .unwrap()
+        .strip_suffix('>')
+        .unwrap()
+        .split("><")
+        .collect();
+    fragments.sort_by_key(|n| n.parse::<usize>().unwrap());
+    let expected: Vec<String> = (0..50).map(|n| n.to_string()).collect();
+    assert_eq!(fragments, expected);
+}
+
+async fn one_of_many_takes_gets_the_value(backend: Backend) {
+    let server = backend.server(&[]).await;
+    let client = reqwest::Client::new();
+    for round in 0..10 {
+        let item = format!("job {round}");
+        let response = client.put(server.url("/queue")).body(item.clone()).send();
+        assert!(response.await.unwrap().status().is_success());
+
+        let responses = race(20, |client, _| {
+            client.delete(server.url("/queue?return=old"))
+        })
+        .await;
+        let taken: Vec<_> = responses
+            .iter()
+            .filter(|(status, _)| *status == reqwest::StatusCode::OK)
+            .map(|(_, body)| body.as_str())
+            .collect();
+        assert_eq!(taken, [item.as_str()]);
+        let missed = responses
+            .iter()
+            .filter(|(status, _)| *status == reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(missed.count(), 19);
+    }
+}
+
+async fn one_put_if_absent_claims_the_key(backend: Backend) {
+    let server = backend.server(&[]).await;
+    let responses = race(50, |client, n| {
+        client
+            .put(server.url("/leader"))
+            .header("if-none-match", "*")
+            .body(format!("candidate{n}"))
+    })
+    .await;
+    let created: Vec<_> = (0..50)
+        .filter(|&n| responses[n].0 == reqwest::StatusCode::CREATED)
+        .collect();
+    assert_eq!(created.len(), 1);
+    let refused = responses
+        .iter()
+        .filter(|(status, _)| *status == reqwest::StatusCode::PRECONDITION_FAILED);
+    assert_eq!(refused.count(), 49);
+
+    let response = reqwest::get(server.url("/leader")).await.unwrap();
+    let leader = format!("candidate{}", created[0]);
+    assert_eq!(response.text().await.unwrap(), leader);
+}
+
+// The version of a value, from the ETag its write answered with
+fn version_of(response: &Response) -> u64 {
+    let etag = response.headers()[header::ETAG].to_str().unwrap();
+    etag.trim_matches('"').parse().unwrap()
+}
+
+async fn history_keeps_replaced_versions(backend: Backend) {
+    let app = backend.router(&[]);
+    let mut versions = Vec::new();
+    for n in 0..7 {
+        let response = send(&app, Method::PUT, "/config", &format!("v{n}")).await;
+        versions.push(version_of(&response));
+    }
+
+    // The default depth keeps the 5 values before the current one
+    let response = send(&app, Method::GET, "/config/history", "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let history = json(response).await;
+    assert_eq!(history["current_version"], versions[6]);
+    let kept: Vec<_> = history["versions"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|past| (past["version"].as_u64().unwrap(), past["value"].clone()))
+        .collect();
+    let expected: Vec<_> = (1..6)
+        .rev()
+        .map(|n| (versions[n], serde_json::json!(format!("v{n}"))))
+        .collect();
+    assert_eq!(kept, expected);
+
+    let uri = format!("/config?version={}", versions[2]);
+    let response = send(&app, Method::GET, &uri, "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(version_of(&response), versions[2]);
+    assert_eq!(text(response).await, "v2");
+    // Named by its ETag, strong or weak
+    let v = versions[3];
+    for uri in [
+        format!("/config?version=%22{v}%22"),
+        format!("/config?version=W/%22{v}%22"),
+    ] {
+        let response = send(&app, Method::GET, &uri, "").await;
+        assert_eq!(text(response).await, "v3");
+    }
+    // Past the depth
+    let uri = format!("/config?version={}", versions[0]);
+    let response = send(&app, Method::GET, &uri, "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // Deleting a key clears its history
+    send(&app, Method::DELETE, "/config", "").await;
+    let response = send(&app, Method::GET, "/config/history", "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = send(&app, Method::PUT, "/config", "fresh").await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let history = json(send(&app, Method::GET, "/config/history", "").await).await;
+    assert_eq!(history["versions"], serde_json::json!([]));
+    let uri = format!("/config?version={}", versions[5]);
+    let response = send(&app, Method::GET, &uri, "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+async fn buckets_are_separate_keyspaces(backend: Backend) {
+    let app = backend.router(&[]);
+    for (uri, value) in [
+        ("/b/a/name", "in a"),
+        ("/b/a/other", "also in a"),
+        ("/b/b/name", "in b"),
+        ("/name", "unbucketed"),
+    ] {
+        let response = send(&app, Method::PUT, uri, value).await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{}", uri);
+    }
+    for (uri, value) in [
+        ("/b/a/name", "in a"),
+        ("/b/b/name", "in b"),
+        ("/name", "unbucketed"),
+    ] {
+        assert_eq!(text(send(&app, Method::GET, uri, "").await).await, value);
+    }
+
+    let keys = json(send(&app, Method::GET, "/b/a/keys", "").await).await;
+    assert_eq!(keys["keys"], serde_json::json!(["name", "other"]));
+    let keys = json(send(&app, Method::GET, "/b/b/keys", "").await).await;
+    assert_eq!(keys["keys"], serde_json::json!(["name"]));
+    let keys = json(send(&app, Method::GET, "/keys", "").await).await;
+    assert_eq!(keys["keys"], serde_json::json!(["name"]));
+
+    let response = send(&app, Method::DELETE, "/b/a", "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(text(response).await, "2");
+    let response = send(&app, Method::GET, "/b/a/name", "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let keys = json(send(&app, Method::GET, "/b/a/keys", "").await).await;
+    assert_eq!(keys["keys"], serde_json::json!([]));
+    assert_eq!(
+        text(send(&app, Method::GET, "/b/b/name", "").await).await,
+        "in b"
+    );
+    assert_eq!(
+        text(send(&app, Method::GET, "/name", "").await).await,
+        "unbucketed"
+    );
+}
+
+async fn tenants_never_see_each_other(backend: Backend) {
+    let app = backend.router(&[]);
+    let tenant = |name| ("x-tenant", name);
+    send_with(&app, Method::PUT, "/secret", tenant("alpha"), "alpha's").await;
+    send_with(&app, Method::PUT, "/secret", tenant("beta"), "beta's").await;
+    send_with(&app, Method::PUT, "/only-alpha", tenant("alpha"), "a").await;
+    send(&app, Method::PUT, "/secret", "default's").await;
+
+    for (name, value) in [("alpha", "alpha's"), ("beta", "beta's")] {
+        let response = send_with(&app, Method::GET, "/secret", tenant(name), "").await;
+        assert_eq!(text(response).await, value);
+    }
+    assert_eq!(
+        text(send(&app, Method::GET, "/secret", "").await).await,
+        "default's"
+    );
+    let response = send_with(&app, Method::GET, "/only-alpha", tenant("beta"), "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = send(&app, Method::GET, "/only-alpha", "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let list = |name| send_with(&app, Method::GET, "/keys", tenant(name), "");
+    let keys = json(list("alpha").await).await;
+    assert_eq!(keys["keys"], serde_json::json!(["only-alpha", "secret"]));
+    let keys = json(list("beta").await).await;
+    assert_eq!(keys["keys"], serde_json::json!(["secret"]));
+    let keys = json(send(&app, Method::GET, "/keys", "").await).await;
+    assert_eq!(keys["keys"], serde_json::json!(["secret"]));
+
+    // Deleting another tenant's key finds nothing to delete
+    let response = send_with(&app, Method::DELETE, "/only-alpha", tenant("beta"), "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = send_with(&app, Method::DELETE, "/secret", tenant("beta"), "").await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = send_with(&app, Method::GET, "/secret", tenant("alpha"), "").await;
+    assert_eq!(text(response).await, "alpha's");
+    assert_eq!(
+        text(send(&app, Method::GET, "/secret", "").await).await,
+        "default's"
+    );
+    let response = send_with(&app, Method::GET, "/secret", tenant("beta"), "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+async fn overwrites_growing_past_the_quota_are_refused(backend: Backend) {
+    let app = backend.router(&["--admin-token", "secret", "--history-depth", "0"]);
+    let tenant = ("x-tenant", "small");
+    let admin = |method, body: &str| {
+        let request = Request::builder()
+            .method(method)
+            .uri("/admin/quota/small")
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        app.clone().oneshot(request)
+    };
+    let value = |bytes| "x".repeat(bytes);
+    let response = send_with(&app, Method::PUT, "/doc", tenant, &value(10)).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let usage = json(admin(Method::GET, "").await.unwrap()).await;
+    assert_eq!(usage["keys"], 1);
+    let used = usage["bytes"].as_u64().unwrap();
+
+    // Room for the value to grow by 20 bytes, and no more
+    let quota = serde_json::json!({ "max_bytes": used + 20 }).to_string();
+    let response = admin(Method::PUT, &quota).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json(response).await["override"], true);
+
+    let response = send_with(&app, Method::PUT, "/doc", tenant, &value(31)).await;
+    assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+    let usage = json(admin(Method::GET, "").await.unwrap()).await;
+    assert_eq!(usage["bytes"], used);
+    let response = send_with(&app, Method::GET, "/doc", tenant, "").await;
+    assert_eq!(text(response).await, value(10));
+
+    let response = send_with(&app, Method::PUT, "/doc", tenant, &value(30)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let usage = json(admin(Method::GET, "").await.unwrap()).await;
+    assert_eq!(
+        (usage["keys"].clone(), usage["bytes"].clone()),
+        (1.into(), (used + 20).into())
+    );
+
+    // Shrinking it frees the difference, and deleting it everything
+    send_with(&app, Method::PUT, "/doc", tenant, &value(5)).await;
+    let usage = json(admin(Method::GET, "").await.unwrap()).await;
+    assert_eq!(usage["bytes"], used - 5);
+    send_with(&app, Method::DELETE, "/doc", tenant, "").await;
+    let usage = json(admin(Method::GET, "").await.unwrap()).await;
+    assert_eq!(
+        (usage["keys"].clone(), usage["bytes"].clone()),
+        (0.into(), 0.into())
+    );
+
+    // Other tenants aren't held to it
+    let other = ("x-tenant", "large");
+    let response = send_with(&app, Method::PUT, "/doc", other, &value(1000)).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+}
+
+async fn renames_and_copies_move_values(backend: Backend) {
+    let app = backend.router(&[]);
+    let request = Request::put("/a")
+        .header(header::CONTENT_TYPE, "text/markdown")
+        .body(Body::from("# notes"))
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap();
+
+    let response = send(&app, Method::POST, "/a/copy", "b").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    for key in ["/a", "/b"] {
+        let response = send(&app, Method::GET, key, "").await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/markdown");
+        assert_eq!(text(response).await, "# notes");
+    }
+
+    let response = send_with(&app, Method::POST, "/a/rename", ("x-destination", "c"), "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = send(&app, Method::GET, "/a", "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(
+        text(send(&app, Method::GET, "/c", "").await).await,
+        "# notes"
+    );
+    let response = send(&app, Method::POST, "/a/rename", "d").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    send(&app, Method::PUT, "/b", "taken").await;
+    for action in ["rename", "copy"] {
+        let uri = format!("/c/{action}?overwrite=false");
+        let response = send(&app, Method::POST, &uri, "b").await;
+        assert_eq!(response.status(), StatusCode::CONFLICT, "{}", action);
+    }
+    assert_eq!(text(send(&app, Method::GET, "/b", "").await).await, "taken");
+    assert_eq!(
+        text(send(&app, Method::GET, "/c", "").await).await,
+        "# notes"
+    );
+
+    // Onto itself changes nothing, whichever the action
+    let etag = send(&app, Method::GET, "/c", "").await.headers()[header::ETAG].clone();
+    for action in ["rename", "copy"] {
+        let uri = format!("/c/{action}?overwrite=false");
+        let response = send(&app, Method::POST, &uri, "c").await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", action);
+        assert_eq!(response.headers()[header::ETAG], etag);
+    }
+    let response = send(&app, Method::GET, "/c", "").await;
+    assert_eq!(response.headers()[header::ETAG], etag);
+    assert_eq!(text(response).await, "# notes");
+}
+
+async fn one_of_many_renames_moves_the_key(backend: Backend) {
+    let server = backend.server(&[]).await;
+    let client = reqwest::Client::new();
+    for round in 0..10 {
+        let response = client.put(server.url("/token")).body("t").send().await;
+        assert!(response.unwrap().status().is_success());
+
+        let responses = race(20, |client, n| {
+            let destination = format!("holder{round}-{n}");
+            client.post(server.url("/token/rename")).body(destination)
+        })
+        .await;
+        let moved: Vec<_> = (0..20)
+            .filter(|&n| responses[n].0 == reqwest::StatusCode::OK)
+            .collect();
+        assert_eq!(moved.len(), 1, "round {}", round);
+        let missed = responses
+            .iter()
+            .filter(|(status, _)| *status == reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(missed.count(), 19);
+
+        let prefix = format!("holder{round}-");
+        let uri = server.url(&format!("/keys?prefix={prefix}"));
+        let keys: serde_json::Value =
+            serde_json::from_str(&reqwest::get(uri).await.unwrap().text().await.unwrap()).unwrap();
+        assert_eq!(
+            keys["keys"],
+            serde_json::json!([format!("{prefix}{}", moved[0])])
+        );
+        let response = reqwest::get(server.url("/token")).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+}
+
+async fn flushed_keys_cannot_be_restored(backend: Backend) {
+    let app = backend.router(&["--soft-delete-secs", "60", "--admin-token", "secret"]);
+    send(&app, Method::PUT, "/gone", "before").await;
+    send(&app, Method::DELETE, "/gone", "").await;
+    send(&app, Method::PUT, "/live", "value").await;
+
+    let request = Request::post("/admin/flush")
+        .header(header::AUTHORIZATION, "Bearer secret")
+        .header("x-confirm", "DELETE-EVERYTHING")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    for key in ["/gone", "/live"] {
+        let response = send(&app, Method::POST, &format!("{key}/restore"), "").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", key);
+        let response = send(&app, Method::GET, key, "").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", key);
+    }
+
+    // Keys deleted after the flush are restorable as ever
+    send(&app, Method::PUT, "/gone", "after").await;
+    send(&app, Method::DELETE, "/gone", "").await;
+    let response = send(&app, Method::POST, "/gone/restore", "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        text(send(&app, Method::GET, "/gone", "").await).await,
+        "after"
+    );
+}
+
+async fn puts_answer_201_with_a_location_only_when_creating(backend: Backend) {
+    let app = backend.router(&[]);
+    for uri in ["/doc", "/nested/doc%20one", "/b/photos/cat"] {
+        let response = send(&app, Method::PUT, uri, "first").await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{}", uri);
+        assert_eq!(response.headers()[header::LOCATION], uri);
+
+        let response = send(&app, Method::PUT, uri, "second").await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+        assert!(!response.headers().contains_key(header::LOCATION));
+        assert_eq!(text(send(&app, Method::GET, uri, "").await).await, "second");
+    }
+
+    // Once deleted, the key is created anew
+    send(&app, Method::DELETE, "/doc", "").await;
+    let response = send(&app, Method::PUT, "/doc", "third").await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(response.headers()[header::LOCATION], "/doc");
+}
+
+async fn keys_may_nest_under_slashes(backend: Backend) {
+    let app = backend.router(&[]);
+    for (uri, value) in [
+        ("/config/prod/db/url", "postgres://db"),
+        ("/config/prod", "parent"),
+        ("/app/metrics", "not the metrics"),
+        ("/app/keys/healthz", "deep"),
+    ] {
+        let response = send(&app, Method::PUT, uri, value).await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{}", uri);
+        assert_eq!(text(send(&app, Method::GET, uri, "").await).await, value);
+    }
+    // Keys are decoded, so an escaped slash names the same key as a plain one
+    send(&app, Method::PUT, "/a%2Fb", "escaped").await;
+    assert_eq!(
+        text(send(&app, Method::GET, "/a/b", "").await).await,
+        "escaped"
+    );
+
+    let keys = json(send(&app, Method::GET, "/keys?prefix=config/", "").await).await;
+    assert_eq!(
+        keys["keys"],
+        serde_json::json!(["config/prod", "config/prod/db/url"])
+    );
+    // The reserved routes are still served at the top level
+    let response = send(&app, Method::GET, "/metrics", "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_ne!(text(response).await, "not the metrics");
+
+    for uri in [
+        "/config/",
+        "/config/prod/",
+        "/config//prod",
+        "/config/../prod",
+    ] {
+        let response = send(&app, Method::PUT, uri, "value").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        let response = send(&app, Method::GET, uri, "").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", uri);
+    }
+    let response = send(&app, Method::DELETE, "/config/prod", "").await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        text(send(&app, Method::GET, "/config/prod/db/url", "").await).await,
+        "postgres://db"
+    );
+}
+
+async fn stats_follow_a_known_sequence(backend: Backend) {
+    let app = backend.router(&["--history-depth", "0"]);
+    let stats = json(send(&app, Method::GET, "/stats", "").await).await;
+    assert_eq!(stats["keys"], 0);
+    assert_eq!(stats["bytes"], 0);
+    assert_eq!(stats["largest_key_bytes"], 0);
+    assert_eq!(stats["largest_value_bytes"], 0);
+    assert_eq!(
+        stats["operations"],
+        serde_json::json!({"puts": 0, "gets": 0, "deletes": 0, "hits": 0, "misses": 0})
+    );
+
+    send(&app, Method::PUT, "/a", "1").await;
+    send(&app, Method::PUT, "/long-key", "hello").await;
+    send(&app, Method::PUT, "/a", "1234567").await;
+    send(&app, Method::PUT, "/gone", "x").await;
+    send(&app, Method::GET, "/a", "").await;
+    send(&app, Method::GET, "/long-key", "").await;
+    send(&app, Method::GET, "/missing", "").await;
+    send(&app, Method::DELETE, "/gone", "").await;
+    // Deleting nothing isn't counted
+    send(&app, Method::DELETE, "/gone", "").await;
+
+    let stats = json(send(&app, Method::GET, "/stats", "").await).await;
+    assert_eq!(stats["keys"], 2);
+    // "a" and "1234567", "long-key" and "hello"
+    assert_eq!(stats["bytes"], 1 + 7 + 8 + 5);
+    assert_eq!(stats["largest_key_bytes"], 8);
+    assert_eq!(stats["largest_value_bytes"], 7);
+    assert_eq!(
+        stats["operations"],
+        serde_json::json!({"puts": 4, "gets": 3, "deletes": 1, "hits": 2, "misses": 1})
+    );
+}
+
+async fn responses_are_counted_by_status(backend: Backend) {
+    let app = backend.router(&[]);
+    send(&app, Method::PUT, "/a", "1").await;
+    send(&app, Method::PUT, "/a", "2").await;
+    send(&app, Method::GET, "/a", "").await;
+    send(&app, Method::GET, "/a", "").await;
+    send(&app, Method::GET, "/missing", "").await;
+    send(&app, Method::DELETE, "/missing", "").await;
+    let response = send(&app, Method::GET, "/a?version=latest", "").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let metrics = json(send(&app, Method::GET, "/metrics?format=json", "").await).await;
+    assert_eq!(
+        metrics["responses"],
+        serde_json::json!({"2xx": 4, "4xx": 3, "5xx": 0})
+    );
+    assert_eq!(
+        metrics["status_codes"],
+        serde_json::json!({"200": 3, "201": 1, "400": 1, "404": 2})
+    );
+    let metrics = text(send(&app, Method::GET, "/metrics", "").await).await;
+    for line in [
+        "kv_http_responses_total{class=\"2xx\"} 4\n",
+        "kv_http_responses_total{class=\"4xx\"} 3\n",
+        "kv_http_responses_total{class=\"5xx\"} 0\n",
+    ] {
+        assert!(metrics.contains(line), "{}", line);
+    }
+}
+
+async fn scrapes_and_probes_are_left_out_of_latency(backend: Backend) {
+    let app = backend.router(&["--metrics-exclude-route", "/stats"]);
+    send(&app, Method::GET, "/a", "").await;
+    let count = |metrics: &serde_json::Value| metrics["count"].as_u64().unwrap();
+    let metrics = json(send(&app, Method::GET, "/metrics?format=json", "").await).await;
+    assert_eq!(count(&metrics), 1);
+
+    for _ in 0..10 {
+        for uri in ["/metrics", "/metrics?format=json", "/healthz", "/stats"] {
+            let response = send(&app, Method::GET, uri, "").await;
+            assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+        }
+    }
+    let metrics = json(send(&app, Method::GET, "/metrics?format=json", "").await).await;
+    assert_eq!(count(&metrics), 1);
+    assert_eq!(metrics["status_codes"], serde_json::json!({"404": 1}));
+    let routes = metrics["per_route"].as_object().unwrap();
+    assert_eq!(routes.keys().collect::<Vec<_>>(), ["GET /{*key}"]);
+}
+
+async fn value_sizes_follow_overwrites(backend: Backend) {
+    let app = backend.router(&[]);
+    let sizes = |small, medium, large| {
+        serde_json::json!({
+            "<=1KiB": small, "<=10KiB": medium, "<=100KiB": 0, "<=1MiB": large, ">1MiB": 0,
+        })
+    };
+    send(&app, Method::PUT, "/fixed", &"f".repeat(10)).await;
+    send(&app, Method::PUT, "/growing", &"g".repeat(100)).await;
+    let stats = json(send(&app, Method::GET, "/stats", "").await).await;
+    assert_eq!(stats["value_sizes"], sizes(2, 0, 0));
+
+    send(&app, Method::PUT, "/growing", &"g".repeat(5 * 1024)).await;
+    let stats = json(send(&app, Method::GET, "/stats", "").await).await;
+    assert_eq!(stats["value_sizes"], sizes(1, 1, 0));
+    send(&app, Method::PUT, "/growing", &"g".repeat(200 * 1024)).await;
+    let metrics = json(send(&app, Method::GET, "/metrics?format=json", "").await).await;
+    assert_eq!(metrics["value_sizes"], sizes(1, 0, 1));
+    let metrics = text(send(&app, Method::GET, "/metrics", "").await).await;
+    assert!(metrics.contains("kv_values_by_size{size=\"<=1MiB\"} 1\n"));
+    assert!(metrics.contains("kv_values_by_size{size=\"<=10KiB\"} 0\n"));
+
+    // Shrinking moves it back down, and deleting takes it out
+    send(&app, Method::PUT, "/growing", "g").await;
+    let stats = json(send(&app, Method::GET, "/stats", "").await).await;
+    assert_eq!(stats["value_sizes"], sizes(2, 0, 0));
+    send(&app, Method::DELETE, "/growing", "").await;
+    let stats = json(send(&app, Method::GET, "/stats", "").await).await;
+    assert_eq!(stats["value_sizes"], sizes(1, 0, 0));
+}
+
+async fn slow_requests_are_kept_in_the_slow_log(backend: Backend) {
+    let app = backend.router(&[]);
+    send(&app, Method::PUT, "/a", "hello").await;
+    let slow = json(send(&app, Method::GET, "/metrics/slow", "").await).await;
+    assert_eq!(
+        slow,
+        serde_json::json!({"threshold_ms": null, "requests": []})
+    );
+
+    // Every request takes longer than no time at all
+    let app = backend.router(&["--slow-ms", "0"]);
+    send(&app, Method::PUT, "/a", "hello").await;
+    send(&app, Method::GET, "/missing", "").await;
+    let slow = json(send(&app, Method::GET, "/metrics/slow", "").await).await;
+    assert_eq!(slow["threshold_ms"], 0);
+    let requests = slow["requests"].as_array().unwrap();
+    let seen: Vec<_> = requests
+        .iter()
+        .map(|request| {
+            let fields = ["method", "path", "route", "status"];
+            fields.map(|name| request[name].clone())
+        })
+        .collect();
+    let expected = serde_json::json!([
+        ["GET", "/missing", "/{*key}", 404],
+        ["PUT", "/a", "/{*key}", 201],
+    ]);
+    assert_eq!(serde_json::json!(seen), expected);
+    assert!(requests[0]["duration_ms"].as_f64().unwrap() > 0.0);
+
+    // Only the latest 100 are kept
+    for _ in 0..120 {
+        send(&app, Method::GET, "/a", "").await;
+    }
+    let slow = json(send(&app, Method::GET, "/metrics/slow", "").await).await;
+    assert_eq!(slow["requests"].as_array().unwrap().len(), 100);
+}
+
+async fn cors_headers_go_only_to_allowed_origins(backend: Backend) {
+    let app = backend.router(&["--cors-origin", "https://dash.example.com"]);
+    send(&app, Method::PUT, "/a", "1").await;
+    let preflight = |origin: &str| {
+        let request = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/a")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "x-ttl-seconds")
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(request)
+    };
+    let get = |origin: &str| {
+        let request = Request::get("/a")
+            .header(header::ORIGIN, origin)
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(request)
+    };
+
+    let response = preflight("https://dash.example.com").await.unwrap();
+    assert!(response.status().is_success());
+    let headers = response.headers();
+    assert_eq!(
+        headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+        "https://dash.example.com"
+    );
+    let methods = headers[header::ACCESS_CONTROL_ALLOW_METHODS]
+        .to_str()
+        .unwrap();
+    assert!(methods.contains("PUT"), "{}", methods);
+    let allowed = headers[header::ACCESS_CONTROL_ALLOW_HEADERS]
+        .to_str()
+        .unwrap();
+    assert!(allowed.contains("x-ttl-seconds"), "{}", allowed);
+    let response = get("https://dash.example.com").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let headers = response.headers();
+    assert_eq!(
+        headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+        "https://dash.example.com"
+    );
+    let exposed = headers[header::ACCESS_CONTROL_EXPOSE_HEADERS]
+        .to_str()
+        .unwrap();
+    assert!(exposed.contains("etag"), "{}", exposed);
+
+    // Served as usual, but nothing tells the browser it may read the response
+    let cors = |response: &Response| {
+        let names = response.headers().keys();
+        let mut cors = names.filter(|name| name.as_str().starts_with("access-control-"));
+        cors.next().cloned()
+    };
+    let response = preflight("https://evil.example.com").await.unwrap();
+    assert_eq!(cors(&response), None);
+    let response = get("https://evil.example.com").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(cors(&response), None);
+    assert_eq!(text(response).await, "1");
+
+    // Without --cors-origin, not even the listed origin is allowed
+    let app = backend.router(&[]);
+    let request = Request::get("/a")
+        .header(header::ORIGIN, "https://dash.example.com")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(cors(&response), None);
+}
+
+async fn touches_are_announced_with_their_deadline(backend: Backend) {
+    let app = backend.router(&["--event-log-capacity", "10", "--event-log-values"]);
+    let ttl = |seconds| ("x-ttl-seconds", seconds);
+    let response = send_with(&app, Method::PUT, "/session", ttl("60"), "state").await;
+    let version = version_of(&response);
+
+    // A GET waiting for a new value isn't answered by a touch
+    let waiting = {
+        let app = app.clone();
+        let uri = format!("/session?wait=1&version={version}");
+        tokio::spawn(async move { send(&app, Method::GET, &uri, "").await.status() })
+    };
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let response = send_with(&app, Method::POST, "/session/touch", ttl("3600"), "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(waiting.await.unwrap(), StatusCode::NOT_MODIFIED);
+
+    let feed = json(send(&app, Method::GET, "/events", "").await).await;
+    let events = feed["events"].as_array().unwrap();
+    let ops: Vec<_> = events.iter().map(|e| e["op"].as_str().unwrap()).collect();
+    assert_eq!(ops, ["put", "touch"]);
+    let touch = &events[1];
+    assert_eq!(touch["key"], "session");
+    assert_eq!(touch["version"], version);
+    assert!(touch.get("value").is_none());
+    // Both RFC 3339 in UTC, so they compare as text
+    let (at, deadline) = (touch["at"].as_str(), touch["expires_at"].as_str());
+    assert!(deadline.unwrap() > at.unwrap());
+}
+
+async fn writes_refresh_updated_at(backend: Backend) {
+    let app = backend.router(&[]);
+    let times = |meta: serde_json::Value| {
+        let time = |field: &str| meta[field].as_str().unwrap().to_string();
+        (time("created_at"), time("updated_at"))
+    };
+    send(&app, Method::PUT, "/count", "1").await;
+    let (created, mut updated) =
+        times(json(send(&app, Method::GET, "/count/meta", "").await).await);
+    assert_eq!(created, updated);
+
+    // Appends and increments start from a copy of the entry they replace
+    for (method, uri, body) in [
+        (Method::PATCH, "/count", "0"),
+        (Method::POST, "/count/incr", ""),
+    ] {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let response = send(&app, method, uri, body).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let (created_now, updated_now) =
+            times(json(send(&app, Method::GET, "/count/meta", "").await).await);
+        assert_eq!(created_now, created);
+        // Both RFC 3339 in UTC, so they compare as text
+        assert!(updated_now > updated, "{updated_now} after {updated}");
+        updated = updated_now;
+    }
+    assert_eq!(
+        text(send(&app, Method::GET, "/count", "").await).await,
+        "11"
+    );
+}
@@ -0,0 +1,415 @@
+// Error responses: their status, and the JSON body or, asked for, plain text
+use axum::body::Body;
+use axum::http::{header, Method, Request, StatusCode};
+use axum::response::Response;
+use axum::Router;
+use clap::Parser;
+use http_body_util::BodyExt;
+use rust_kv::Config;
+use serde_json::Value;
+use tower::ServiceExt;
+
+fn router(args: &[&str]) -> Router {
+    rust_kv::app(Config::parse_from(["rust-kv"].iter().chain(args)))
+}
+
+async fn call(app: &Router, request: Request<Body>) -> Response {
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn send(app: &Router, method: Method, uri: &str, body: &str) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    call(app, request).await
+}
+
+async fn text(response: Response) -> String {
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    String::from_utf8(bytes.to_vec()).unwrap()
+}
+
+// Check an error response's status and JSON body, returning the body
+async fn assert_error(response: Response, status: StatusCode, code: &str) -> Value {
+    assert_eq!(response.status(), status);
+    let content_type = response.headers()[header::CONTENT_TYPE].clone();
+    assert_eq!(content_type, "application/json");
+    let body: Value = serde_json::from_str(&text(response).await).unwrap();
+    assert_eq!(body["code"], code, "{}", body);
+    assert!(
+        body["error"]
+            .as_str()
+            .is_some_and(|error| !error.is_empty()),
+        "{}",
+        body
+    );
+    body
+}
+
+#[tokio::test]
+async fn missing_keys_are_named() {
+    let app = router(&[]);
+    for method in [Method::GET, Method::DELETE] {
+        let response = send(&app, method, "/nothing", "").await;
+        let body = assert_error(response, StatusCode::NOT_FOUND, "key_not_found").await;
+        assert_eq!(body["key"], "nothing");
+    }
+    for uri in ["/a/b/meta", "/a/b/ttl", "/a/b/history"] {
+        let response = send(&app, Method::GET, uri, "").await;
+        let body = assert_error(response, StatusCode::NOT_FOUND, "key_not_found").await;
+        assert_eq!(body["key"], "a/b");
+    }
+
+    // As the client named it, without the namespace
+    let request = Request::get("/b/bucket/missing")
+        .header("x-tenant", "acme")
+        .body(Body::empty())
+        .unwrap();
+    let body = assert_error(
+        call(&app, request).await,
+        StatusCode::NOT_FOUND,
+        "key_not_found",
+    )
+    .await;
+    assert_eq!(body["key"], "missing");
+}
+
+#[tokio::test]
+async fn invalid_requests() {
+    let app = router(&[]);
+    let request = Request::put("/key")
+        .header("x-ttl-seconds", "0")
+        .body(Body::from("value"))
+        .unwrap();
+    let body = assert_error(
+        call(&app, request).await,
+        StatusCode::BAD_REQUEST,
+        "bad_request",
+    )
+    .await;
+    assert_eq!(body["error"], "X-Ttl-Seconds must be greater than zero");
+    assert!(body.get("key").is_none());
+
+    let response = send(&app, Method::GET, "/a//b", "").await;
+    assert_error(response, StatusCode::BAD_REQUEST, "bad_request").await;
+    let response = send(&app, Method::DELETE, "/keys", "").await;
+    assert_error(response, StatusCode::BAD_REQUEST, "bad_request").await;
+    let response = send(&app, Method::POST, "/batch/put", "[1, 2]").await;
+    assert_error(response, StatusCode::BAD_REQUEST, "bad_request").await;
+    let response = send(&app, Method::GET, "/metrics?format=xml", "").await;
+    assert_error(response, StatusCode::BAD_REQUEST, "bad_request").await;
+
+    // Rejected by an extractor before any handler runs
+    let response = send(&app, Method::GET, "/key?version=latest", "").await;
+    assert_error(response, StatusCode::BAD_REQUEST, "bad_request").await;
+    let response = send(&app, Method::POST, "/batch/get", "[\"a\"]").await;
+    assert_error(
+        response,
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        "unsupported_media_type",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn unknown_routes_and_methods() {
+    let app = router(&[]);
+    let response = send(&app, Method::GET, "/", "").await;
+    assert_error(response, StatusCode::NOT_FOUND, "not_found").await;
+    let response = send(&app, Method::POST, "/key", "").await;
+    assert_error(
+        response,
+        StatusCode::METHOD_NOT_ALLOWED,
+        "method_not_allowed",
+    )
+    .await;
+    let response = send(&app, Method::DELETE, "/metrics", "").await;
+    assert_error(
+        response,
+        StatusCode::METHOD_NOT_ALLOWED,
+        "method_not_allowed",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn conflicts_and_preconditions() {
+    let app = router(&[]);
+    send(&app, Method::PUT, "/word", "hello").await;
+
+    let body = assert_error(
+        send(&app, Method::POST, "/word/incr", "").await,
+        StatusCode::CONFLICT,
+        "conflict",
+    )
+    .await;
+    assert_eq!(body["key"], "word");
+
+    send(&app, Method::PUT, "/other", "x").await;
+    let body = assert_error(
+        send(&app, Method::POST, "/word/copy?overwrite=false", "other").await,
+        StatusCode::CONFLICT,
+        "conflict",
+    )
+    .await;
+    assert_eq!(body["key"], "other");
+
+    let request = Request::put("/word")
+        .header(header::IF_NONE_MATCH, "*")
+        .body(Body::from("again"))
+        .unwrap();
+    let body = assert_error(
+        call(&app, request).await,
+        StatusCode::PRECONDITION_FAILED,
+        "precondition_failed",
+    )
+    .await;
+    assert_eq!(body["key"], "word");
+    assert_eq!(body["error"], "Key already exists");
+
+    send(&app, Method::PUT, "/big", i64::MAX.to_string().as_str()).await;
+    let response = send(&app, Method::POST, "/big/incr", "").await;
+    assert_error(response, StatusCode::UNPROCESSABLE_ENTITY, "unprocessable").await;
+
+    // A failed transaction also says which condition failed
+    let txn = r#"{"conditions": [{"check": "absent", "key": "word"}], "operations": []}"#;
+    let body = assert_error(
+        send(&app, Method::POST, "/txn", txn).await,
+        StatusCode::CONFLICT,
+        "condition_failed",
+    )
+    .await;
+    assert_eq!(body["succeeded"], false);
+    assert_eq!(body["failed"]["index"], 0);
+}
+
+#[tokio::test]
+async fn size_limits() {
+    let app = router(&["--max-value-bytes", "4", "--max-bytes", "64"]);
+    let body = assert_error(
+        send(&app, Method::PUT, "/key", "too long").await,
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "payload_too_large",
+    )
+    .await;
+    assert_eq!(body["error"], "Request bodies are limited to 4 bytes");
+
+    send(&app, Method::PUT, "/key", "abc").await;
+    let body = assert_error(
+        send(&app, Method::PATCH, "/key", "de").await,
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "value_too_large",
+    )
+    .await;
+    assert_eq!(body["key"], "key");
+
+    for i in 0..16 {
+        let response = send(&app, Method::PUT, &format!("/filler-{}", i), "abcd").await;
+        if response.status() == StatusCode::INSUFFICIENT_STORAGE {
+            assert_error(
+                response,
+                StatusCode::INSUFFICIENT_STORAGE,
+                "insufficient_storage",
+            )
+            .await;
+            return;
+        }
+    }
+    panic!("the byte budget was never reached");
+}
+
+#[tokio::test]
+async fn authentication() {
+    let app = router(&["--api-key", "secret"]);
+    let response = send(&app, Method::GET, "/key", "").await;
+    assert_eq!(
+        response.headers()[header::WWW_AUTHENTICATE],
+        "Bearer realm=\"rust-kv\""
+    );
+    let body = assert_error(response, StatusCode::UNAUTHORIZED, "unauthorized").await;
+    assert_eq!(body["error"], "Missing API key");
+
+    let request = Request::get("/key")
+        .header(header::AUTHORIZATION, "Bearer wrong")
+        .body(Body::empty())
+        .unwrap();
+    let response = call(&app, request).await;
+    assert!(response.headers()[header::WWW_AUTHENTICATE]
+        .to_str()
+        .unwrap()
+        .contains("invalid_token"));
+    assert_error(response, StatusCode::UNAUTHORIZED, "unauthorized").await;
+
+    // The right key is taken in either header
+    for (name, value) in [
+        (header::AUTHORIZATION.as_str(), "Bearer secret"),
+        ("x-api-key", "secret"),
+    ] {
+        let request = Request::put("/key")
+            .header(name, value)
+            .body(Body::from("value"))
+            .unwrap();
+        assert_eq!(call(&app, request).await.status(), StatusCode::CREATED);
+        let request = Request::get("/key")
+            .header(name, value)
+            .body(Body::empty())
+            .unwrap();
+        let response = call(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(text(response).await, "value");
+        let request = Request::delete("/key")
+            .header(name, value)
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(call(&app, request).await.status(), StatusCode::NO_CONTENT);
+    }
+    // Health checks need none
+    let response = send(&app, Method::GET, "/healthz", "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = send(&app, Method::POST, "/admin/flush", "").await;
+    assert_error(response, StatusCode::FORBIDDEN, "forbidden").await;
+}
+
+#[tokio::test]
+async fn scoped_tokens_outside_their_grants() {
+    let acl = std::env::temp_dir().join(format!("rust-kv-errors-{}.acl", std::process::id()));
+    let grants = r#"{"tokens": {"reader": [{"prefix": "public/", "access": "read"}]}}"#;
+    std::fs::write(&acl, grants).unwrap();
+    let app = router(&["--acl-file", acl.to_str().unwrap()]);
+    std::fs::remove_file(&acl).unwrap();
+
+    let request = Request::put("/public/key")
+        .header(header::AUTHORIZATION, "Bearer reader")
+        .body(Body::from("value"))
+        .unwrap();
+    assert_error(
+        call(&app, request).await,
+        StatusCode::FORBIDDEN,
+        "forbidden",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn admin_errors() {
+    let app = router(&["--admin-token", "admin"]);
+    let admin = |uri: &str, headers: &[(&str, &str)]| {
+        let mut request = Request::post(uri).header(header::AUTHORIZATION, "Bearer admin");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(Body::empty()).unwrap()
+    };
+
+    let response = call(&app, admin("/admin/flush", &[])).await;
+    assert_error(
+        response,
+        StatusCode::PRECONDITION_REQUIRED,
+        "confirmation_required",
+    )
+    .await;
+    let response = call(&app, admin("/admin/snapshot", &[])).await;
+    let body = assert_error(response, StatusCode::NOT_FOUND, "not_found").await;
+    assert_eq!(body["error"], "No --snapshot-path is configured");
+    let response = call(&app, admin("/admin/acl/reload", &[])).await;
+    assert_error(response, StatusCode::NOT_FOUND, "not_found").await;
+    let response = call(&app, admin("/admin/readonly", &[])).await;
+    assert_error(response, StatusCode::BAD_REQUEST, "bad_request").await;
+
+    let request = Request::delete("/admin/quota/acme")
+        .header(header::AUTHORIZATION, "Bearer admin")
+        .body(Body::empty())
+        .unwrap();
+    assert_error(
+        call(&app, request).await,
+        StatusCode::NOT_FOUND,
+        "not_found",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn read_only_and_soft_delete() {
+    let app = router(&["--read-only"]);
+    let response = send(&app, Method::PUT, "/key", "value").await;
+    let body = assert_error(response, StatusCode::SERVICE_UNAVAILABLE, "read_only").await;
+    assert_eq!(body["error"], "The server is in read-only mode");
+
+    let app = router(&[]);
+    let response = send(&app, Method::POST, "/key/restore", "").await;
+    assert_error(response, StatusCode::NOT_FOUND, "not_found").await;
+    let app = router(&["--soft-delete-secs", "60"]);
+    let response = send(&app, Method::POST, "/key/restore", "").await;
+    assert_error(response, StatusCode::NOT_FOUND, "key_not_found").await;
+}
+
+#[tokio::test]
+async fn rate_limits() {
+    let app = router(&["--rate-limit", "5", "--rate-limit-burst", "5"]);
+    let mut limited = 0;
+    for _ in 0..20 {
+        let response = send(&app, Method::GET, "/key", "").await;
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+            assert_error(response, StatusCode::TOO_MANY_REQUESTS, "rate_limited").await;
+            limited += 1;
+        } else {
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
+    // Only the burst gets through, well before a token is earned back
+    assert_eq!(limited, 15);
+
+    // Monitoring goes on while the client is throttled
+    for _ in 0..20 {
+        for uri in ["/healthz", "/metrics"] {
+            let response = send(&app, Method::GET, uri, "").await;
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
+        }
+    }
+}
+
+#[tokio::test]
+async fn plain_text_on_request() {
+    let app = router(&[]);
+    let get = |uri: &str, accept: &str| {
+        Request::get(uri)
+            .header(header::ACCEPT, accept)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = call(&app, get("/missing", "text/plain")).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(
+        response.headers()[header::CONTENT_TYPE],
+        "text/plain; charset=utf-8"
+    );
+    assert_eq!(text(response).await, "Key not found");
+
+    // Errors made outside the handlers too
+    let response = call(&app, get("/", "text/plain")).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(text(response).await, "Not Found");
+
+    // JSON wins when both are acceptable
+    let response = call(&app, get("/missing", "text/plain, application/json")).await;
+    assert_error(response, StatusCode::NOT_FOUND, "key_not_found").await;
+}
+
+#[tokio::test]
+async fn openapi_describes_error_bodies() {
+    let app = router(&[]);
+    let document: Value =
+        serde_json::from_str(&text(send(&app, Method::GET, "/openapi.json", "").await).await)
+            .unwrap();
+    assert!(document["components"]["schemas"]["ErrorBody"].is_object());
+    let not_found = &document["paths"]["/{key}"]["get"]["responses"]["404"];
+    assert_eq!(
+        not_found["content"]["application/json"]["schema"]["$ref"],
+        "#/components/schemas/ErrorBody"
+    );
+}
@@ -0,0 +1,103 @@
+// The gRPC service, driven by the generated client
+#![cfg(feature = "grpc")]
+
+use clap::Parser;
+use rust_kv::grpc::proto::kv_client::KvClient;
+use rust_kv::grpc::proto::{BatchGetRequest, DeleteRequest, GetRequest, PutRequest};
+use rust_kv::test_util::TestServer;
+use rust_kv::Config;
+use tonic::transport::Channel;
+use tonic::Code;
+
+async fn spawn(args: &[&str]) -> (TestServer, KvClient<Channel>) {
+    let args = ["rust-kv", "--grpc-port", "0"]
+        .into_iter()
+        .chain(args.iter().copied());
+    let server = TestServer::spawn(Config::parse_from(args)).await;
+    let client = KvClient::connect(server.grpc_url().unwrap()).await.unwrap();
+    (server, client)
+}
+
+fn put(key: &str, value: &[u8]) -> PutRequest {
+    PutRequest {
+        key: key.to_string(),
+        value: value.to_vec().into(),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn put_get_delete() {
+    let (server, mut client) = spawn(&[]).await;
+
+    let response = client.put(put("greeting", b"\x00hi\xff")).await.unwrap();
+    assert!(response.get_ref().created);
+    let version = response.get_ref().version;
+    let response = client.put(put("greeting", b"hello")).await.unwrap();
+    assert!(!response.get_ref().created);
+    assert!(response.get_ref().version > version);
+
+    let request = GetRequest {
+        key: "greeting".to_string(),
+    };
+    let value = client.get(request.clone()).await.unwrap().into_inner();
+    assert_eq!(value.value.as_ref(), b"hello");
+
+    // Shared with the HTTP side
+    let body = reqwest::get(server.url("/greeting")).await.unwrap();
+    assert_eq!(body.text().await.unwrap(), "hello");
+
+    let delete = DeleteRequest {
+        key: "greeting".to_string(),
+    };
+    client.delete(delete.clone()).await.unwrap();
+    let status = client.get(request).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+    let status = client.delete(delete).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+
+    let metrics = reqwest::get(server.url("/metrics?format=json"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("POST /kv.v1.Kv/Get"), "{}", metrics);
+}
+
+#[tokio::test]
+async fn batch_get_leaves_out_missing_keys() {
+    let (_server, mut client) = spawn(&[]).await;
+    client.put(put("a", b"1")).await.unwrap();
+    client.put(put("b", b"2")).await.unwrap();
+
+    let request = BatchGetRequest {
+        keys: vec!["a".into(), "b".into(), "missing".into(), "a".into()],
+    };
+    let values = client.batch_get(request).await.unwrap().into_inner().values;
+    assert_eq!(values.len(), 2);
+    assert_eq!(values["a"].value.as_ref(), b"1");
+    assert_eq!(values["b"].value.as_ref(), b"2");
+}
+
+#[tokio::test]
+async fn rejects_invalid_requests() {
+    let (_server, mut client) = spawn(&["--max-value-bytes", "4"]).await;
+    let status = client.put(put("big", b"too long")).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    let status = client.put(put("", b"x")).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn requires_a_token_when_keys_are_configured() {
+    let (_server, mut client) = spawn(&["--api-key", "secret"]).await;
+    let status = client.put(put("key", b"value")).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+
+    let mut request = tonic::Request::new(put("key", b"value"));
+    request
+        .metadata_mut()
+        .insert("authorization", "Bearer secret".parse().unwrap());
+    client.put(request).await.unwrap();
+}
@@ -0,0 +1,132 @@
+// The memcached text protocol listener, driven over a raw TCP connection
+use clap::Parser;
+use rust_kv::test_util::TestServer;
+use rust_kv::Config;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn spawn() -> (TestServer, TcpStream) {
+    let config = Config::parse_from(["rust-kv", "--memcache-port", "0"]);
+    let server = TestServer::spawn(config).await;
+    let stream = TcpStream::connect(server.memcache_address().unwrap())
+        .await
+        .unwrap();
+    (server, stream)
+}
+
+// Send `request` and read until the reply ends with `until`
+async fn exchange(stream: &mut TcpStream, request: &[u8], until: &[u8]) -> String {
+    stream.write_all(request).await.unwrap();
+    let mut reply = Vec::new();
+    while !reply.ends_with(until) {
+        let mut chunk = [0; 4096];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut chunk))
+            .await
+            .expect("no reply")
+            .unwrap();
+        assert_ne!(
+            read,
+            0,
+            "closed after {:?}",
+            String::from_utf8_lossy(&reply)
+        );
+        reply.extend_from_slice(&chunk[..read]);
+    }
+    String::from_utf8(reply).unwrap()
+}
+
+#[tokio::test]
+async fn stores_and_retrieves_values() {
+    let (server, mut stream) = spawn().await;
+
+    let reply = exchange(&mut stream, b"set greeting 42 0 5\r\nhello\r\n", b"\r\n").await;
+    assert_eq!(reply, "STORED\r\n");
+    let reply = exchange(&mut stream, b"add greeting 0 0 1\r\nx\r\n", b"\r\n").await;
+    assert_eq!(reply, "NOT_STORED\r\n");
+    let reply = exchange(&mut stream, b"replace missing 0 0 1\r\nx\r\n", b"\r\n").await;
+    assert_eq!(reply, "NOT_STORED\r\n");
+    let reply = exchange(&mut stream, b"add other 7 0 6\r\nwo\r\nld\r\n", b"\r\n").await;
+    assert_eq!(reply, "STORED\r\n");
+
+    let reply = exchange(&mut stream, b"get greeting missing other\r\n", b"END\r\n").await;
+    assert_eq!(
+        reply,
+        "VALUE greeting 42 5\r\nhello\r\nVALUE other 7 6\r\nwo\r\nld\r\nEND\r\n"
+    );
+
+    // The same keys over HTTP, where writes reset the flags
+    let body = reqwest::get(server.url("/greeting")).await.unwrap();
+    assert_eq!(body.text().await.unwrap(), "hello");
+    reqwest::Client::new()
+        .put(server.url("/greeting"))
+        .body("hi")
+        .send()
+        .await
+        .unwrap();
+    let reply = exchange(&mut stream, b"get greeting\r\n", b"END\r\n").await;
+    assert_eq!(reply, "VALUE greeting 0 2\r\nhi\r\nEND\r\n");
+
+    let reply = exchange(&mut stream, b"delete greeting\r\n", b"\r\n").await;
+    assert_eq!(reply, "DELETED\r\n");
+    let reply = exchange(&mut stream, b"delete greeting\r\n", b"\r\n").await;
+    assert_eq!(reply, "NOT_FOUND\r\n");
+    let reply = exchange(&mut stream, b"flush_all\r\n", b"\r\n").await;
+    assert_eq!(reply, "OK\r\n");
+    let keys = server.store().with_read(|view| view.len()).await.unwrap();
+    assert_eq!(keys, 0);
+}
+
+#[tokio::test]
+async fn maps_exptime_onto_ttls() {
+    let (server, mut stream) = spawn().await;
+    exchange(&mut stream, b"set later 0 60 1\r\na\r\n", b"\r\n").await;
+    exchange(&mut stream, b"set gone 0 -1 1\r\nb\r\n", b"\r\n").await;
+
+    let expires_at = server
+        .store()
+        .with_read(|view| view.get("later").unwrap().expires_at)
+        .await
+        .unwrap();
+    assert!(expires_at.is_some());
+    let reply = exchange(&mut stream, b"get later gone\r\n", b"END\r\n").await;
+    assert_eq!(reply, "VALUE later 0 1\r\na\r\nEND\r\n");
+}
+
+#[tokio::test]
+async fn noreply_suppresses_replies() {
+    let (_server, mut stream) = spawn().await;
+    let reply = exchange(
+        &mut stream,
+        b"set a 0 0 1 noreply\r\n1\r\nadd a 0 0 1 noreply\r\n2\r\ndelete b noreply\r\nget a\r\n",
+        b"END\r\n",
+    )
+    .await;
+    assert_eq!(reply, "VALUE a 0 1\r\n1\r\nEND\r\n");
+}
+
+#[tokio::test]
+async fn answers_malformed_lines_and_carries_on() {
+    let (_server, mut stream) = spawn().await;
+    let reply = exchange(&mut stream, b"bogus\r\n", b"\r\n").await;
+    assert_eq!(reply, "ERROR\r\n");
+    let reply = exchange(&mut stream, b"set k zero 0 1\r\n", b"\r\n").await;
+    assert_eq!(reply, "CLIENT_ERROR bad command line format\r\n");
+    let reply = exchange(&mut stream, b"set k 0 0 1\r\ntoo long\r\n", b"\r\n").await;
+    assert_eq!(reply, "CLIENT_ERROR bad data chunk\r\n");
+    let reply = exchange(&mut stream, b"version\r\n", b"\r\n").await;
+    assert!(reply.starts_with("VERSION "), "{}", reply);
+
+    // A line that never ends closes the connection
+    let reply = exchange(&mut stream, &[b'x'; 4096], b"\r\n").await;
+    assert_eq!(reply, "CLIENT_ERROR line too long\r\n");
+    // Closing with the rest of the line unread can reset the connection
+    let mut rest = [0; 16];
+    assert!(matches!(stream.read(&mut rest).await, Ok(0) | Err(_)));
+}
+
+#[test]
+fn cant_be_combined_with_authentication() {
+    let result = Config::try_parse_from(["rust-kv", "--memcache-port", "0", "--api-key", "k"]);
+    assert!(result.is_err());
+}
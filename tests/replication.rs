@@ -0,0 +1,248 @@
+// A primary pushing its writes to a follower, both served in-process
+use clap::Parser;
+use reqwest::StatusCode;
+use rust_kv::store::Entry;
+use rust_kv::test_util::TestServer;
+use rust_kv::Config;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+const SECRET: &str = "shared-secret";
+
+fn config(args: &[&str]) -> Config {
+    Config::parse_from(["rust-kv"].iter().chain(args))
+}
+
+async fn follower() -> TestServer {
+    TestServer::spawn(config(&["--follower", "--replication-secret", SECRET])).await
+}
+
+// A primary logging to `wal` and replicating to `url`, with `fixture` in its
+// store from the start
+async fn spawn_primary(wal: &Path, url: &str, fixture: &[(&str, &str)]) -> TestServer {
+    let wal = wal.to_str().unwrap();
+    let args = ["--wal-path", wal, "--replica-url", url];
+    let config = config(&[&args[..], &["--replication-secret", SECRET]].concat());
+    TestServer::spawn_with(config, |view| {
+        for (key, value) in fixture {
+            let mut entry = Entry::new(value.to_string().into());
+            entry.version = view.next_version();
+            view.insert(key.to_string(), entry);
+        }
+    })
+    .await
+}
+
+// The value of `key` on `server`, or None if it has none
+async fn get(server: &TestServer, key: &str) -> Option<String> {
+    let response = reqwest::get(server.url(key)).await.unwrap();
+    match response.status() {
+        StatusCode::OK => Some(response.text().await.unwrap()),
+        StatusCode::NOT_FOUND => None,
+        status => panic!("GET {} answered {}", key, status),
+    }
+}
+
+// Poll `check` until it holds
+async fn eventually<F: Future<Output = bool>>(mut check: impl FnMut() -> F) {
+    let started = Instant::now();
+    while !check().await {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "gave up waiting"
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+// The primary's replication lag, in entries and seconds
+async fn lag(server: &TestServer) -> (u64, f64) {
+    let metrics = reqwest::get(server.url("/metrics?format=json"))
+        .await
+        .unwrap();
+    let metrics: serde_json::Value = serde_json::from_str(&metrics.text().await.unwrap()).unwrap();
+    let lag = &metrics["replication"];
+    (
+        lag["lag_entries"].as_u64().unwrap(),
+        lag["lag_seconds"].as_f64().unwrap(),
+    )
+}
+
+#[tokio::test]
+async fn writes_to_the_primary_are_read_from_the_follower() {
+    let dir = std::env::temp_dir().join(format!("rust-kv-replication-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let follower = follower().await;
+    // Keys the primary had before replicating reach the follower in a full copy
+    let primary = spawn_primary(&dir.join("wal"), &follower.url(""), &[("seeded", "s")]).await;
+    let client = reqwest::Client::new();
+
+    client
+        .put(primary.url("/a"))
+        .body("1")
+        .send()
+        .await
+        .unwrap();
+    client
+        .put(primary.url("/gone"))
+        .body("x")
+        .send()
+        .await
+        .unwrap();
+    client.delete(primary.url("/gone")).send().await.unwrap();
+    client
+        .put(primary.url("/t"))
+        .header("x-tenant", "acme")
+        .body("theirs")
+        .send()
+        .await
+        .unwrap();
+    client
+        .put(primary.url("/a"))
+        .body("2")
+        .send()
+        .await
+        .unwrap();
+    eventually(|| async { get(&follower, "/a").await.as_deref() == Some("2") }).await;
+    assert_eq!(get(&follower, "/seeded").await.as_deref(), Some("s"));
+    assert_eq!(get(&follower, "/gone").await, None);
+    let response = client
+        .get(follower.url("/t"))
+        .header("x-tenant", "acme")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.text().await.unwrap(), "theirs");
+    // Versions are the primary's, so ETags carry over
+    let etag = |response: &reqwest::Response| response.headers()["etag"].clone();
+    let on_primary = client.get(primary.url("/a")).send().await.unwrap();
+    let on_follower = client.get(follower.url("/a")).send().await.unwrap();
+    assert_eq!(etag(&on_primary), etag(&on_follower));
+    eventually(|| async { lag(&primary).await.0 == 0 }).await;
+    assert_eq!(lag(&primary).await.1, 0.0);
+
+    // Clients can't write to the follower, only its primary can
+    let response = client
+        .put(follower.url("/a"))
+        .body("3")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let apply = |server: &TestServer, secret: &str| {
+        client
+            .post(server.url("/replication/apply"))
+            .bearer_auth(secret)
+            .body("{\"op\":\"delete\",\"key\":\"a\"}\n")
+            .send()
+    };
+    let response = apply(&follower, "wrong").await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = apply(&primary, SECRET).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(get(&follower, "/a").await.as_deref(), Some("2"));
+
+    primary.shutdown().await;
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// Forwards connections to `target` while it's up, and cuts them off when it
+// goes down, to part a primary from its follower
+struct Proxy {
+    address: SocketAddr,
+    up: watch::Sender<bool>,
+}
+
+impl Proxy {
+    async fn start(target: SocketAddr) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (up, watching) = watch::channel(true);
+        tokio::spawn(async move {
+            loop {
+                let (mut inbound, _) = listener.accept().await.unwrap();
+                let mut up = watching.clone();
+                tokio::spawn(async move {
+                    if !*up.borrow() {
+                        return;
+                    }
+                    let mut outbound = TcpStream::connect(target).await.unwrap();
+                    // Cut off before forwarding anything more once it's down
+                    tokio::select! {
+                        biased;
+                        _ = up.wait_for(|&up| !up) => {}
+                        _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound) => {}
+                    }
+                });
+            }
+        });
+        Self { address, up }
+    }
+
+    fn set_up(&self, up: bool) {
+        self.up.send_replace(up);
+    }
+}
+
+#[tokio::test]
+async fn replication_catches_up_after_an_outage() {
+    let dir = std::env::temp_dir().join(format!("rust-kv-outage-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let wal = dir.join("wal");
+    let follower = follower().await;
+    let proxy = Proxy::start(follower.address()).await;
+    let url = format!("http://{}", proxy.address);
+    let primary = spawn_primary(&wal, &url, &[]).await;
+    let client = reqwest::Client::new();
+
+    client
+        .put(primary.url("/k"))
+        .body("1")
+        .send()
+        .await
+        .unwrap();
+    eventually(|| async { get(&follower, "/k").await.as_deref() == Some("1") }).await;
+    // Only a full copy would remove this
+    follower
+        .store()
+        .with_write(|view| view.insert("marker".to_string(), Entry::new("m".into())))
+        .await
+        .unwrap();
+
+    // Writes go on while the follower is away, and are counted as lag
+    proxy.set_up(false);
+    client
+        .put(primary.url("/k"))
+        .body("2")
+        .send()
+        .await
+        .unwrap();
+    client
+        .put(primary.url("/j"))
+        .body("1")
+        .send()
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let (entries, seconds) = lag(&primary).await;
+    assert_eq!(entries, 2);
+    assert!(seconds >= 1.0, "{}", seconds);
+    assert_eq!(get(&follower, "/k").await.as_deref(), Some("1"));
+
+    // A restarted primary goes on from what the follower acknowledged
+    primary.shutdown().await;
+    let primary = spawn_primary(&wal, &url, &[]).await;
+    assert_eq!(lag(&primary).await.0, 2);
+    proxy.set_up(true);
+    eventually(|| async { get(&follower, "/j").await.is_some() }).await;
+    assert_eq!(get(&follower, "/k").await.as_deref(), Some("2"));
+    assert_eq!(get(&follower, "/marker").await.as_deref(), Some("m"));
+    eventually(|| async { lag(&primary).await.0 == 0 }).await;
+
+    primary.shutdown().await;
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,139 @@
+// The Redis protocol listener, driven by a real Redis client
+use clap::Parser;
+use redis::AsyncCommands;
+use reqwest::StatusCode;
+use rust_kv::test_util::TestServer;
+use rust_kv::Config;
+
+async fn spawn(args: &[&str]) -> TestServer {
+    let args = ["rust-kv", "--resp-port", "0"]
+        .into_iter()
+        .chain(args.iter().copied());
+    TestServer::spawn(Config::parse_from(args)).await
+}
+
+async fn connect(server: &TestServer) -> redis::aio::MultiplexedConnection {
+    let address = server.resp_address().unwrap();
+    redis::Client::open(format!("redis://{}", address))
+        .unwrap()
+        .get_multiplexed_async_connection()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn shares_keys_with_http() {
+    let server = spawn(&[]).await;
+    let mut redis = connect(&server).await;
+    let http = reqwest::Client::new();
+
+    http.put(server.url("/from-http"))
+        .body("one")
+        .send()
+        .await
+        .unwrap();
+    let value: Option<String> = redis.get("from-http").await.unwrap();
+    assert_eq!(value.as_deref(), Some("one"));
+
+    let () = redis.set("from-redis", b"\x00binary\xff").await.unwrap();
+    let response = http.get(server.url("/from-redis")).send().await.unwrap();
+    assert_eq!(response.bytes().await.unwrap().as_ref(), b"\x00binary\xff");
+
+    let deleted: i64 = redis.del(&["from-http", "missing"]).await.unwrap();
+    assert_eq!(deleted, 1);
+    let response = http.get(server.url("/from-http")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn supports_the_basic_commands() {
+    let server = spawn(&[]).await;
+    let mut redis = connect(&server).await;
+
+    let pong: String = redis::cmd("PING").query_async(&mut redis).await.unwrap();
+    assert_eq!(pong, "PONG");
+
+    let () = redis::cmd("SET")
+        .arg("session")
+        .arg("x")
+        .arg("EX")
+        .arg(60)
+        .query_async(&mut redis)
+        .await
+        .unwrap();
+    let ttl = server
+        .store()
+        .with_read(|view| view.get("session").unwrap().expires_at)
+        .await
+        .unwrap();
+    assert!(ttl.is_some());
+
+    let set: Option<String> = redis::cmd("SET")
+        .arg("session")
+        .arg("y")
+        .arg("NX")
+        .query_async(&mut redis)
+        .await
+        .unwrap();
+    assert_eq!(set, None);
+
+    let _: () = redis.set("user:1", "a").await.unwrap();
+    let _: () = redis.set("user:2", "b").await.unwrap();
+    let exists: i64 = redis.exists(&["user:1", "user:2", "user:3"]).await.unwrap();
+    assert_eq!(exists, 2);
+
+    let mut keys: Vec<String> = redis.keys("user:*").await.unwrap();
+    keys.sort();
+    assert_eq!(keys, ["user:1", "user:2"]);
+
+    let mut scanned: Vec<String> = Vec::new();
+    let mut iter: redis::AsyncIter<String> = redis.scan_match("*").await.unwrap();
+    while let Some(key) = iter.next_item().await {
+        scanned.push(key.unwrap());
+    }
+    drop(iter);
+    scanned.sort();
+    assert_eq!(scanned, ["session", "user:1", "user:2"]);
+
+    let count: i64 = redis.incr("visits", 1).await.unwrap();
+    assert_eq!(count, 1);
+    let count: i64 = redis.incr("visits", 41).await.unwrap();
+    assert_eq!(count, 42);
+    let result: redis::RedisResult<i64> = redis.incr("user:1", 1).await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn unknown_commands_keep_the_connection() {
+    let server = spawn(&[]).await;
+    let mut redis = connect(&server).await;
+
+    let error = redis::cmd("FLUSHALL")
+        .query_async::<()>(&mut redis)
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("unknown command"), "{}", error);
+    let pong: String = redis::cmd("PING").query_async(&mut redis).await.unwrap();
+    assert_eq!(pong, "PONG");
+}
+
+#[tokio::test]
+async fn requires_auth_when_keys_are_configured() {
+    let server = spawn(&["--api-key", "secret"]).await;
+    let mut redis = connect(&server).await;
+
+    let result: redis::RedisResult<Option<String>> = redis.get("key").await;
+    assert_eq!(result.unwrap_err().code(), Some("NOAUTH"));
+    let result: redis::RedisResult<()> = redis::cmd("AUTH")
+        .arg("wrong")
+        .query_async(&mut redis)
+        .await;
+    assert!(result.is_err());
+
+    let () = redis::cmd("AUTH")
+        .arg("secret")
+        .query_async(&mut redis)
+        .await
+        .unwrap();
+    let () = redis.set("key", "value").await.unwrap();
+}
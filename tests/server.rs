@@ -0,0 +1,965 @@
+// End to end over HTTP against a throwaway server
+use clap::Parser;
+use reqwest::StatusCode;
+use rust_kv::store::Entry;
+use rust_kv::test_util::TestServer;
+use rust_kv::Config;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+fn config(args: &[&str]) -> Config {
+    Config::parse_from(["rust-kv"].iter().chain(args))
+}
+
+#[tokio::test]
+async fn serves_fixture_contents() {
+    let server = TestServer::spawn_with(config(&[]), |view| {
+        for (key, value) in [("a", "1"), ("b", "2")] {
+            let mut entry = Entry::new(value.into());
+            entry.version = view.next_version();
+            view.insert(key.to_string(), entry);
+        }
+    })
+    .await;
+    assert_ne!(server.address().port(), 0);
+
+    let client = reqwest::Client::new();
+    let response = client.get(server.url("/b")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await.unwrap(), "2");
+
+    let response = client.put(server.url("/c")).body("3").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let keys = server.store().with_read(|view| view.len()).await.unwrap();
+    assert_eq!(keys, 3);
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn sweeper_removes_expired_keys() {
+    // Enough keys that the sweeper takes the write lock batch by batch
+    let deadline = Instant::now() + Duration::from_millis(500);
+    let server = TestServer::spawn_with(config(&["--sweep-interval-ms", "10"]), |view| {
+        for n in 0..10_000 {
+            let mut entry = Entry::new("lived".into());
+            entry.expires_at = Some(deadline);
+            entry.version = view.next_version();
+            view.insert(format!("short-{n}"), entry);
+        }
+    })
+    .await;
+    let keys = server.store().with_read(|view| view.len()).await.unwrap();
+    assert_eq!(keys, 10_000);
+
+    // Nothing reads the keys, so only the sweeper can remove them
+    tokio::time::sleep_until((deadline + Duration::from_millis(50)).into()).await;
+    let give_up = Instant::now() + Duration::from_secs(5);
+    loop {
+        let keys = server.store().with_read(|view| view.len()).await.unwrap();
+        if keys == 0 {
+            break;
+        }
+        assert!(Instant::now() < give_up, "{keys} keys left unswept");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+// Keys with a 1 ms TTL, read and deleted as they expire: none is served past
+// its deadline, and one found expired is gone
+#[tokio::test]
+async fn reads_and_deletes_never_see_expired_keys() {
+    let server = TestServer::spawn(config(&["--sweep-interval-ms", "3600000"])).await;
+    let client = reqwest::Client::new();
+    for round in 0..50 {
+        let deadline = Instant::now() + Duration::from_millis(1);
+        server
+            .store()
+            .with_write(|view| {
+                for key in ["get", "delete"] {
+                    let mut entry = Entry::new("v".into());
+                    entry.expires_at = Some(deadline);
+                    view.insert(format!("{key}{round}"), entry);
+                }
+            })
+            .await
+            .unwrap();
+
+        let sent = Instant::now();
+        let response = client.get(server.url(&format!("/get{round}"))).send();
+        match response.await.unwrap().status() {
+            StatusCode::OK => assert!(sent < deadline),
+            status => assert_eq!(status, StatusCode::NOT_FOUND),
+        }
+        let sent = Instant::now();
+        let response = client.delete(server.url(&format!("/delete{round}"))).send();
+        match response.await.unwrap().status() {
+            StatusCode::NO_CONTENT => assert!(sent < deadline),
+            status => assert_eq!(status, StatusCode::NOT_FOUND),
+        }
+
+        // Past the deadline, both are missing, and no longer stored
+        tokio::time::sleep_until((deadline + Duration::from_millis(1)).into()).await;
+        let response = client.get(server.url(&format!("/get{round}"))).send();
+        assert_eq!(response.await.unwrap().status(), StatusCode::NOT_FOUND);
+        let response = client.delete(server.url(&format!("/delete{round}"))).send();
+        assert_eq!(response.await.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+    let keys = server.store().with_read(|view| view.len()).await.unwrap();
+    assert_eq!(keys, 0);
+}
+
+#[tokio::test]
+async fn shutdown_writes_a_final_snapshot() {
+    let dir = std::env::temp_dir().join(format!("rust-kv-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("snapshot.json");
+    let server = TestServer::spawn(config(&[
+        "--snapshot-path",
+        path.to_str().unwrap(),
+        "--snapshot-interval-secs",
+        "3600",
+    ]))
+    .await;
+    reqwest::Client::new()
+        .put(server.url("/saved"))
+        .body("value")
+        .send()
+        .await
+        .unwrap();
+    server.shutdown().await;
+
+    let snapshot = std::fs::read_to_string(&path).unwrap();
+    assert!(snapshot.contains("saved"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// The built binary, stopped by SIGTERM as an orchestrator would, leaves a
+// snapshot of every write it acknowledged
+#[cfg(unix)]
+#[tokio::test]
+async fn sigterm_writes_a_final_snapshot() {
+    let dir = std::env::temp_dir().join(format!("rust-kv-sigterm-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("snapshot.json");
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+        .to_string();
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_rust-kv"))
+        .args(["--bind", "127.0.0.1", "--port", &port])
+        .args(["--snapshot-path", path.to_str().unwrap()])
+        .args(["--snapshot-interval-secs", "3600"])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let url = |path: &str| format!("http://127.0.0.1:{port}{path}");
+    let client = reqwest::Client::new();
+    let give_up = Instant::now() + Duration::from_secs(30);
+    while !client
+        .get(url("/readyz"))
+        .send()
+        .await
+        .is_ok_and(|response| response.status() == StatusCode::OK)
+    {
+        assert!(Instant::now() < give_up, "the server never became ready");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let values: serde_json::Map<_, _> = (0..500)
+        .map(|n| (format!("key-{n}"), serde_json::json!(n.to_string())))
+        .collect();
+    let response = client
+        .post(url("/batch/put"))
+        .body(serde_json::Value::Object(values).to_string())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let killed = std::process::Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(killed.success());
+    let status = tokio::task::spawn_blocking(move || child.wait().unwrap())
+        .await
+        .unwrap();
+    assert!(status.success(), "{status}");
+
+    // The whole store, not a file cut short
+    let snapshot: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    let mut keys: Vec<_> = snapshot["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["key"].as_str().unwrap().to_string())
+        .collect();
+    keys.sort();
+    let mut expected: Vec<_> = (0..500).map(|n| format!("key-{n}")).collect();
+    expected.sort();
+    assert_eq!(keys, expected);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// The numbers of the write-ahead log segments `wal` is written to
+fn wal_segments(wal: &Path) -> Vec<u64> {
+    let prefix = format!("{}.", wal.file_name().unwrap().to_str().unwrap());
+    let mut segments: Vec<u64> = std::fs::read_dir(wal.parent().unwrap())
+        .unwrap()
+        .filter_map(|entry| {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            name.strip_prefix(&prefix)?.parse().ok()
+        })
+        .collect();
+    segments.sort();
+    segments
+}
+
+// Writers are cut off mid-stream by dropping a server that logs every write
+// but never snapshots. Each write acknowledged before that is there once a
+// server is started on the same log.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn acknowledged_writes_survive_a_restart() {
+    let dir = std::env::temp_dir().join(format!("rust-kv-wal-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let wal = dir.join("wal");
+    let args = ["--wal-path", wal.to_str().unwrap()];
+    let server = TestServer::spawn(config(&args)).await;
+
+    // What each key holds as last acknowledged, for keys no write is in flight to
+    let settled = Arc::new(Mutex::new(HashMap::new()));
+    let writers: Vec<_> = (0..8)
+        .map(|writer| {
+            let url = server.url("");
+            let settled = Arc::clone(&settled);
+            tokio::spawn(async move {
+                let client = reqwest::Client::new();
+                for n in 0.. {
+                    let key = format!("w{writer}-{n}");
+                    let value = format!("value {n}");
+                    let response = client.put(format!("{url}/{key}")).body(value.clone());
+                    // Until the server is gone, or turns writes away as it stops
+                    match response.send().await {
+                        Ok(response) if response.status().is_success() => {}
+                        _ => return,
+                    }
+                    settled.lock().unwrap().insert(key.clone(), Some(value));
+                    if n % 3 == 0 {
+                        settled.lock().unwrap().remove(&key);
+                        match client.delete(format!("{url}/{key}")).send().await {
+                            Ok(response) if response.status() == StatusCode::NO_CONTENT => {}
+                            _ => return,
+                        }
+                        settled.lock().unwrap().insert(key, None);
+                    }
+                }
+            })
+        })
+        .collect();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    drop(server);
+    for writer in writers {
+        writer.abort();
+        let _ = writer.await;
+    }
+    let settled = std::mem::take(&mut *settled.lock().unwrap());
+    assert!(settled.len() > 10, "{} writes", settled.len());
+
+    let server = TestServer::spawn(config(&args)).await;
+    let client = reqwest::Client::new();
+    for (key, value) in settled {
+        let response = client.get(server.url(&format!("/{key}"))).send().await;
+        let response = response.unwrap();
+        match value {
+            Some(value) => assert_eq!(response.text().await.unwrap(), value, "{}", key),
+            None => assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", key),
+        }
+    }
+    server.shutdown().await;
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn snapshots_truncate_the_write_ahead_log() {
+    let dir = std::env::temp_dir().join(format!("rust-kv-truncate-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (wal, snapshot) = (dir.join("wal"), dir.join("snapshot.json"));
+    let args = [
+        "--wal-path",
+        wal.to_str().unwrap(),
+        "--snapshot-path",
+        snapshot.to_str().unwrap(),
+        "--snapshot-interval-secs",
+        "3600",
+        "--admin-token",
+        "admin",
+    ];
+    let server = TestServer::spawn(config(&args)).await;
+    let client = reqwest::Client::new();
+    for n in 0..100 {
+        let response = client.put(server.url(&format!("/k{n}"))).body("v").send();
+        assert_eq!(response.await.unwrap().status(), StatusCode::CREATED);
+    }
+    let logged = wal_segments(&wal);
+    assert!(!logged.is_empty());
+
+    let response = client
+        .post(server.url("/admin/snapshot"))
+        .bearer_auth("admin")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    // The segments the snapshot covers go once it's written
+    let started = Instant::now();
+    while wal_segments(&wal).iter().any(|n| logged.contains(n)) {
+        assert!(started.elapsed() < Duration::from_secs(5));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(std::fs::read_to_string(&snapshot).unwrap().contains("k99"));
+
+    // Writes after it are logged anew, and both come back on a restart
+    let response = client.put(server.url("/after")).body("v").send().await;
+    assert_eq!(response.unwrap().status(), StatusCode::CREATED);
+    server.shutdown().await;
+    let server = TestServer::spawn(config(&args)).await;
+    let keys = server.store().with_read(|view| view.len()).await.unwrap();
+    assert_eq!(keys, 101);
+    server.shutdown().await;
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_handle_stops_the_server() {
+    let server = TestServer::spawn(config(&[])).await;
+    let url = server.url("/healthz");
+    assert!(reqwest::get(&url).await.is_ok());
+
+    server.shutdown_handle().shutdown();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(reqwest::get(&url).await.is_err());
+}
+
+#[tokio::test]
+async fn compaction_reclaims_deleted_and_expired_keys() {
+    // The sweeper runs once at startup, then not again during the test
+    let args = ["--admin-token", "admin", "--sweep-interval-ms", "3600000"];
+    let server = TestServer::spawn_with(config(&args), |view| {
+        for n in 0..2000 {
+            view.insert(format!("key{n}"), Entry::new("value".into()));
+        }
+        // Gone once the startup sweep is done
+        let mut probe = Entry::new("probe".into());
+        probe.expires_at = Some(Instant::now());
+        view.insert("probe".to_string(), probe);
+    })
+    .await;
+    let started = Instant::now();
+    while server
+        .store()
+        .with_key_read("probe", |view| view.get("probe"))
+        .await
+        .unwrap()
+        .is_some()
+    {
+        assert!(started.elapsed() < Duration::from_secs(5));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    server
+        .store()
+        .with_write(|view| {
+            for n in 0..1900 {
+                view.remove(&format!("key{n}"));
+            }
+            for n in 0..5 {
+                let mut entry = Entry::new("gone".into());
+                entry.expires_at = Some(Instant::now());
+                view.insert(format!("expired{n}"), entry);
+            }
+        })
+        .await
+        .unwrap();
+
+    let client = reqwest::Client::new();
+    let response = client
+        .post(server.url("/admin/compact"))
+        .bearer_auth("admin")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["expired"], 5);
+    let (before, after) = (body["bytes_before"].as_u64(), body["bytes_after"].as_u64());
+    assert!(after.unwrap() < before.unwrap() / 2, "{}", body);
+
+    let stats = client.get(server.url("/stats")).send().await.unwrap();
+    let stats: serde_json::Value = serde_json::from_str(&stats.text().await.unwrap()).unwrap();
+    assert_eq!(stats["keys"], 100);
+    assert!(stats["last_compaction"].is_string());
+}
+
+#[tokio::test]
+async fn preload_loads_the_last_line_for_each_key() {
+    let dir = std::env::temp_dir().join(format!("rust-kv-preload-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("seed.ndjson");
+    std::fs::write(
+        &path,
+        concat!(
+            "{\"key\": \"a\", \"value\": \"first\"}\n",
+            "{\"key\": \"b\", \"value_b64\": \"AP8=\", \"ttl\": 3600}\n",
+            "not json\n",
+            "\n",
+            "{\"key\": \"a\", \"value\": \"second\"}\n",
+            "{\"key\": \"/bad\", \"value\": \"x\"}\n",
+            "{\"key\": \"a\", \"value\": \"last\"}\n",
+        ),
+    )
+    .unwrap();
+    let path = path.to_str().unwrap();
+
+    let server = TestServer::spawn(config(&["--preload", path])).await;
+    let client = reqwest::Client::new();
+    let response = client.get(server.url("/a")).send().await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "last");
+    let response = client.get(server.url("/b")).send().await.unwrap();
+    assert_eq!(&response.bytes().await.unwrap()[..], [0, 0xff]);
+    let b = server.store().with_key_read("b", |view| view.get("b"));
+    assert!(b.await.unwrap().unwrap().expires_at.is_some());
+    let keys = server.store().with_read(|view| view.len()).await.unwrap();
+    assert_eq!(keys, 2);
+    server.shutdown().await;
+
+    // Strict loading refuses the malformed lines instead
+    let server = rust_kv::Server::new(config(&["--preload", path, "--preload-strict"])).unwrap();
+    let e = server.preload().await.unwrap_err();
+    assert!(e.contains("line 3"), "{}", e);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// The records of an export, without what a reload writes anew
+async fn export(server: &TestServer, query: &str) -> Vec<serde_json::Value> {
+    let response = reqwest::Client::new()
+        .get(server.url(&format!("/admin/export{query}")))
+        .bearer_auth("admin")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.text().await.unwrap();
+    let mut records: Vec<serde_json::Value> = body
+        .lines()
+        .map(|line| {
+            let mut record: serde_json::Value = serde_json::from_str(line).unwrap();
+            for field in ["version", "created_at", "updated_at"] {
+                assert!(record[field].is_string() || record[field].is_u64());
+                record.as_object_mut().unwrap().remove(field);
+            }
+            record
+        })
+        .collect();
+    records.sort_by_key(|record| record.to_string());
+    records
+}
+
+#[tokio::test]
+async fn exports_load_back_as_they_were() {
+    let dir = std::env::temp_dir().join(format!("rust-kv-export-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("export.ndjson");
+    let args = ["--admin-token", "admin", "--shards", "4"];
+    let server = TestServer::spawn(config(
+        &[&args[..], &["--compress-min-bytes", "64"]].concat(),
+    ))
+    .await;
+    let client = reqwest::Client::new();
+    for n in 0..1500 {
+        let response = client
+            .put(server.url(&format!("/k{n}")))
+            .body(format!("value {n}"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+    let writes = [
+        client.put(server.url("/binary")).body(vec![0u8, 0xff]),
+        client
+            .put(server.url("/text"))
+            .header("content-type", "text/plain")
+            .header("x-ttl-seconds", "3600")
+            .body("x".repeat(1000)),
+        client.put(server.url("/b/photos/cat")).body("meow"),
+        client
+            .put(server.url("/b/photos/k1"))
+            .header("x-tenant", "acme")
+            .body("tenant"),
+    ];
+    for write in writes {
+        assert_eq!(write.send().await.unwrap().status(), StatusCode::CREATED);
+    }
+
+    let exported = export(&server, "").await;
+    assert_eq!(exported.len(), 1504);
+    let text = exported
+        .iter()
+        .find(|record| record["key"] == "text")
+        .unwrap();
+    assert_eq!(text["value"], "x".repeat(1000));
+    assert_eq!(text["content_type"], "text/plain");
+    assert!((3599..=3600).contains(&text["ttl"].as_u64().unwrap()));
+    let cat = exported
+        .iter()
+        .find(|record| record["key"] == "cat")
+        .unwrap();
+    assert_eq!(cat["bucket"], "photos");
+    assert!(cat.get("tenant").is_none());
+    let binary = exported
+        .iter()
+        .find(|record| record["key"] == "binary")
+        .unwrap();
+    assert_eq!(binary["value_b64"], "AP8=");
+
+    // The prefix applies to keys within their namespace
+    let k1: Vec<_> = export(&server, "?prefix=k1").await;
+    assert_eq!(k1.len(), 1 + 10 + 100 + 500 + 1);
+    assert!(k1.iter().any(|record| record["tenant"] == "acme"));
+
+    let response = client
+        .get(server.url("/admin/export"))
+        .bearer_auth("admin")
+        .send()
+        .await
+        .unwrap();
+    std::fs::write(&path, response.bytes().await.unwrap()).unwrap();
+    server.shutdown().await;
+
+    let path = path.to_str().unwrap();
+    let reloaded = TestServer::spawn(config(&[&args[..], &["--preload", path]].concat())).await;
+    let mut reexported = export(&reloaded, "").await;
+    // Expiry counts down between the two
+    for record in &mut reexported {
+        if record["key"] == "text" {
+            record["ttl"] = text["ttl"].clone();
+        }
+    }
+    assert_eq!(reexported, exported);
+    reloaded.shutdown().await;
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// Sled is read a page of keys at a time rather than a shard at a time
+#[tokio::test]
+async fn sled_exports_page_through_every_key() {
+    let dir = std::env::temp_dir().join(format!("rust-kv-export-sled-{}", std::process::id()));
+    let data = dir.to_str().unwrap();
+    let args = [
+        "--admin-token",
+        "admin",
+        "--backend",
+        "sled",
+        "--data-dir",
+        data,
+    ];
+    let server = TestServer::spawn(config(&args)).await;
+    let client = reqwest::Client::new();
+    for n in 0..2500 {
+        let response = client
+            .put(server.url(&format!("/k{n}")))
+            .body("v")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+    let exported = export(&server, "").await;
+    assert_eq!(exported.len(), 2500);
+    assert_eq!(export(&server, "?prefix=k24").await.len(), 1 + 10 + 100);
+    server.shutdown().await;
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// export | curl | import between two instances
+#[tokio::test]
+async fn exports_import_into_another_server() {
+    let args = ["--admin-token", "admin", "--request-timeout-secs", "1"];
+    let source = TestServer::spawn(config(&args)).await;
+    let target = TestServer::spawn(config(&args)).await;
+    let client = reqwest::Client::new();
+    for n in 0..3000 {
+        let request = client
+            .put(source.url(&format!("/k{n}")))
+            .body("x".repeat(n));
+        assert_eq!(request.send().await.unwrap().status(), StatusCode::CREATED);
+    }
+    let request = client.put(target.url("/k0")).body("replaced");
+    assert_eq!(request.send().await.unwrap().status(), StatusCode::CREATED);
+
+    let response = client
+        .get(source.url("/admin/export"))
+        .bearer_auth("admin")
+        .send()
+        .await
+        .unwrap();
+    let exported = response.bytes().await.unwrap();
+    let response = client
+        .post(target.url("/admin/import"))
+        .bearer_auth("admin")
+        .body(exported)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let report: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(report["processed"], 3000);
+    assert_eq!(report["inserted"], 2999);
+    assert_eq!(report["updated"], 1);
+    assert_eq!(report["errored"], 0);
+
+    assert_eq!(export(&target, "").await, export(&source, "").await);
+    source.shutdown().await;
+    target.shutdown().await;
+}
+
+// The Server-Sent Events of a watch, as they arrive
+struct Watch {
+    response: reqwest::Response,
+    buffer: String,
+}
+
+impl Watch {
+    async fn open(server: &TestServer, path: &str) -> Self {
+        let response = reqwest::get(server.url(path)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let content_type = &response.headers()["content-type"];
+        assert_eq!(content_type, "text/event-stream");
+        Self {
+            response,
+            buffer: String::new(),
+        }
+    }
+
+    // The name and data of the next event
+    async fn next(&mut self) -> (String, serde_json::Value) {
+        loop {
+            if let Some(end) = self.buffer.find("\n\n") {
+                let block: String = self.buffer.drain(..end + 2).collect();
+                let (mut name, mut data) = (None, None);
+                for line in block.lines() {
+                    if let Some(value) = line.strip_prefix("event: ") {
+                        name = Some(value.to_string());
+                    } else if let Some(value) = line.strip_prefix("data: ") {
+                        data = Some(serde_json::from_str(value).unwrap());
+                    }
+                }
+                // Keep-alive comments have neither
+                if let (Some(name), Some(data)) = (name, data) {
+                    return (name, data);
+                }
+                continue;
+            }
+            let chunk = tokio::time::timeout(Duration::from_secs(5), self.response.chunk());
+            let chunk = chunk.await.unwrap().unwrap().unwrap();
+            self.buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+    }
+}
+
+#[tokio::test]
+async fn watchers_see_writes_in_order() {
+    let server = TestServer::spawn(config(&[])).await;
+    let mut key = Watch::open(&server, "/watch/a").await;
+    let mut prefix = Watch::open(&server, "/watch?prefix=a").await;
+
+    // Writes to `a` and `ab` interleave, and `b` is watched by neither
+    let writers: Vec<_> = ["a", "ab", "b"]
+        .into_iter()
+        .map(|key| {
+            let url = server.url(&format!("/{key}"));
+            tokio::spawn(async move {
+                let client = reqwest::Client::new();
+                for n in 0..20 {
+                    let response = client.put(&url).body(n.to_string()).send();
+                    assert!(response.await.unwrap().status().is_success());
+                }
+                let response = client.delete(&url).send().await.unwrap();
+                assert_eq!(response.status(), StatusCode::NO_CONTENT);
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.await.unwrap();
+    }
+
+    let mut seen = Vec::new();
+    for _ in 0..21 {
+        seen.push(key.next().await);
+    }
+    let mut of_prefix = Vec::new();
+    for _ in 0..42 {
+        of_prefix.push(prefix.next().await);
+    }
+
+    let values = |events: &[(String, serde_json::Value)], key: &str| -> Vec<String> {
+        events
+            .iter()
+            .filter(|(_, data)| data["key"] == key)
+            .map(|(name, data)| match name.as_str() {
+                "put" => data["value"].as_str().unwrap().to_string(),
+                _ => name.clone(),
+            })
+            .collect()
+    };
+    let mut written: Vec<String> = (0..20).map(|n| n.to_string()).collect();
+    written.push("delete".to_string());
+    assert_eq!(values(&seen, "a"), written);
+    assert_eq!(values(&of_prefix, "a"), written);
+    assert_eq!(values(&of_prefix, "ab"), written);
+
+    // Both number them alike, in the order they were applied
+    let seqs = |events: &[(String, serde_json::Value)]| -> Vec<u64> {
+        events
+            .iter()
+            .map(|(_, data)| data["seq"].as_u64().unwrap())
+            .collect()
+    };
+    let prefix_seqs = seqs(&of_prefix);
+    assert!(prefix_seqs.windows(2).all(|pair| pair[0] < pair[1]));
+    let of_a: Vec<_> = of_prefix
+        .iter()
+        .filter(|(_, data)| data["key"] == "a")
+        .cloned()
+        .collect();
+    assert_eq!(seqs(&seen), seqs(&of_a));
+
+    // Shutting down ends the streams rather than waiting on them
+    let shutdown = tokio::time::timeout(Duration::from_secs(5), server.shutdown());
+    shutdown.await.unwrap();
+    let end = tokio::time::timeout(Duration::from_secs(5), key.response.chunk());
+    assert!(end.await.unwrap().unwrap().is_none());
+}
+
+#[tokio::test]
+async fn a_watcher_that_stops_reading_is_told_what_it_missed() {
+    let server = TestServer::spawn(config(&[])).await;
+    let mut watch = Watch::open(&server, "/watch/big").await;
+
+    // Far more than the connection buffers, so the watcher falls behind;
+    // the writes go on regardless
+    let client = reqwest::Client::new();
+    let value = "v".repeat(16 * 1024);
+    let started = Instant::now();
+    for _ in 0..3000 {
+        let response = client.put(server.url("/big")).body(value.clone()).send();
+        assert!(response.await.unwrap().status().is_success());
+    }
+    assert!(started.elapsed() < Duration::from_secs(60));
+
+    let mut events = 0;
+    loop {
+        let (name, data) = watch.next().await;
+        if name == "lagged" {
+            assert!(data.as_u64().unwrap() > 0);
+            break;
+        }
+        assert_eq!(name, "put");
+        events += 1;
+        assert!(events < 3000);
+    }
+    // And it goes on with the writes after the ones it missed
+    let response = client.delete(server.url("/big")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    loop {
+        let (name, _) = watch.next().await;
+        if name == "delete" {
+            break;
+        }
+        assert_eq!(name, "put");
+    }
+}
+
+#[tokio::test]
+async fn expiry_is_announced_apart_from_deletes() {
+    let server = TestServer::spawn(config(&[
+        "--sweep-interval-ms",
+        "20",
+        "--event-log-capacity",
+        "10",
+    ]))
+    .await;
+    let client = reqwest::Client::new();
+    let mut watch = Watch::open(&server, "/watch?prefix=").await;
+
+    client
+        .put(server.url("/gone"))
+        .body("v")
+        .send()
+        .await
+        .unwrap();
+    client.delete(server.url("/gone")).send().await.unwrap();
+    let put = Instant::now();
+    let response = client
+        .put(server.url("/short"))
+        .header("x-ttl-seconds", "1")
+        .body("v")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    assert_eq!(watch.next().await.0, "put");
+    assert_eq!(watch.next().await.0, "delete");
+    assert_eq!(watch.next().await.0, "put");
+    let (name, data) = watch.next().await;
+    assert_eq!(name, "expire");
+    assert_eq!(data["key"], "short");
+    let elapsed = put.elapsed();
+    assert!(elapsed >= Duration::from_secs(1), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+    // Both are written alike, so they compare as text
+    let (deadline, at) = (data["expires_at"].as_str().unwrap(), data["at"].as_str());
+    assert!(deadline <= at.unwrap());
+
+    let response = client.get(server.url("/events")).send().await.unwrap();
+    let feed: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    let ops: Vec<_> = feed["events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|event| event["op"].as_str().unwrap())
+        .collect();
+    assert_eq!(ops, ["put", "delete", "put", "expire"]);
+
+    // A key found expired on reading is announced the same way
+    let server = TestServer::spawn(config(&["--sweep-interval-ms", "3600000"])).await;
+    let mut watch = Watch::open(&server, "/watch/lazy").await;
+    let response = client
+        .put(server.url("/lazy"))
+        .header("x-ttl-seconds", "1")
+        .body("v")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(watch.next().await.0, "put");
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let response = client.get(server.url("/lazy")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let (name, data) = watch.next().await;
+    assert_eq!(name, "expire");
+    assert!(data["expires_at"].is_string());
+}
+
+// The number of GETs parked waiting for a key to change
+async fn long_polls(server: &TestServer) -> u64 {
+    let metrics = reqwest::get(server.url("/metrics")).await.unwrap();
+    let metrics = metrics.text().await.unwrap();
+    let line = metrics
+        .lines()
+        .find_map(|line| line.strip_prefix("kv_long_polls "))
+        .unwrap();
+    line.parse().unwrap()
+}
+
+// Poll until `long_polls` reads `expected`
+async fn wait_for_long_polls(server: &TestServer, expected: u64) {
+    let started = Instant::now();
+    while long_polls(server).await != expected {
+        assert!(started.elapsed() < Duration::from_secs(5));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn long_polls_return_once_the_key_changes() {
+    let server = TestServer::spawn(config(&[])).await;
+    let client = reqwest::Client::new();
+    let response = client
+        .put(server.url("/k"))
+        .body("old")
+        .send()
+        .await
+        .unwrap();
+    let version = response.headers()["etag"].to_str().unwrap().to_string();
+
+    let readers: Vec<_> = (0..10)
+        .map(|_| {
+            let url = server.url(&format!("/k?wait=10&version={version}"));
+            tokio::spawn(async move {
+                let response = reqwest::get(url).await.unwrap();
+                let etag = response.headers()["etag"].to_str().unwrap().to_string();
+                (response.status(), etag, response.text().await.unwrap())
+            })
+        })
+        .collect();
+    wait_for_long_polls(&server, 10).await;
+
+    let written = Instant::now();
+    let response = client
+        .put(server.url("/k"))
+        .body("new")
+        .send()
+        .await
+        .unwrap();
+    let new_etag = response.headers()["etag"].to_str().unwrap().to_string();
+    for reader in readers {
+        let (status, etag, body) = reader.await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(etag, new_etag);
+        assert_eq!(body, "new");
+    }
+    assert!(written.elapsed() < Duration::from_secs(2));
+    assert_eq!(long_polls(&server).await, 0);
+
+    // A version that's already been replaced is answered at once
+    let response = client
+        .get(server.url(&format!("/k?wait=10&version={version}")))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.text().await.unwrap(), "new");
+    // As is a key that now has a value when none was known, or no longer has
+    // the known one
+    let response = client.get(server.url("/k?wait=10")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = client
+        .get(server.url("/deleted?wait=10&version=1"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn long_polls_time_out_and_unpark() {
+    // Waits outlast the request timeout rather than count against it
+    let server = TestServer::spawn(config(&["--request-timeout-secs", "1"])).await;
+    let client = reqwest::Client::new();
+    let response = client.put(server.url("/k")).body("v").send().await.unwrap();
+    let version = response.headers()["etag"].to_str().unwrap().to_string();
+
+    let started = Instant::now();
+    let response = client
+        .get(server.url(&format!("/k?wait=2&version={version}")))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers()["etag"], version.as_str());
+    assert!(started.elapsed() >= Duration::from_secs(2));
+    assert_eq!(long_polls(&server).await, 0);
+
+    // A client that gives up unparks its GET too
+    let url = server.url(&format!("/k?wait=30&version={version}"));
+    let abandoned = tokio::spawn(async move { reqwest::get(url).await });
+    wait_for_long_polls(&server, 1).await;
+    abandoned.abort();
+    wait_for_long_polls(&server, 0).await;
+}
@@ -0,0 +1,463 @@
+// The sharded memory backend under concurrent access
+use bytes::Bytes;
+use clap::Parser;
+use reqwest::StatusCode;
+use rust_kv::store::{Entry, Limits, MemoryStorage, Store};
+use rust_kv::test_util::TestServer;
+use rust_kv::Config;
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Barrier};
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+
+fn store(shards: usize) -> Store {
+    Store::new(MemoryStorage::new(
+        HashMap::new(),
+        Limits::default(),
+        shards,
+    ))
+}
+
+async fn put(store: &Store, key: &str, value: &str) {
+    store
+        .with_key_write(key, |view| {
+            let mut entry = Entry::new(Bytes::copy_from_slice(value.as_bytes()));
+            entry.version = view.next_version();
+            view.insert(key.to_string(), entry);
+        })
+        .await
+        .unwrap();
+}
+
+// Run `f` in a write transaction over the whole store from a blocking thread,
+// so it can hold the locks for as long as it likes without holding up the runtime
+fn hold_store(store: &Store, f: impl FnOnce() + Send + 'static) -> tokio::task::JoinHandle<()> {
+    let store = store.clone();
+    tokio::task::spawn_blocking(move || {
+        let write = store.with_write(|_| f());
+        Handle::current().block_on(write).unwrap();
+    })
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_writers_keep_totals_exact() {
+    let store = store(8);
+    let writers: Vec<_> = (0..8)
+        .map(|writer| {
+            let store = store.clone();
+            tokio::spawn(async move {
+                for n in 0..500 {
+                    put(&store, &format!("{writer}/{n}"), "value").await;
+                }
+                for n in 0..100 {
+                    let key = format!("{writer}/{n}");
+                    store
+                        .with_key_write(&key, |view| view.remove(&key))
+                        .await
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    // Whole-store readers run alongside and always see whole writes
+    let reader = {
+        let store = store.clone();
+        tokio::spawn(async move {
+            for _ in 0..50 {
+                let (keys, bytes) = store
+                    .with_read(|view| {
+                        let mut bytes = 0;
+                        view.for_each(&mut |key, entry| {
+                            bytes += key.len() + entry.value.len();
+                        });
+                        (view.len(), bytes)
+                    })
+                    .await
+                    .unwrap();
+                assert!(keys <= 8 * 500);
+                assert!(bytes >= keys * "0/0value".len());
+            }
+        })
+    };
+    for writer in writers {
+        writer.await.unwrap();
+    }
+    reader.await.unwrap();
+
+    assert_eq!(store.with_read(|view| view.len()).await.unwrap(), 8 * 400);
+    let bytes = store
+        .with_read(|view| {
+            let mut bytes = 0;
+            view.for_each(&mut |key, entry| bytes += (key.len() + entry.value.len()) as u64);
+            bytes
+        })
+        .await
+        .unwrap();
+    assert_eq!(store.bytes(), bytes);
+    let versions = store
+        .with_read(|view| {
+            let mut versions = Vec::new();
+            view.for_each(&mut |_, entry| versions.push(entry.version));
+            versions
+        })
+        .await
+        .unwrap();
+    let mut unique = versions.clone();
+    unique.sort_unstable();
+    unique.dedup();
+    assert_eq!(unique.len(), versions.len());
+}
+
+#[tokio::test]
+async fn whole_store_writes_reach_every_shard() {
+    let store = store(16);
+    for n in 0..100 {
+        put(&store, &format!("k{n}"), "v").await;
+    }
+    let keys = store
+        .with_write(|view| view.keys_with_prefix("k"))
+        .await
+        .unwrap();
+    assert_eq!(keys.len(), 100);
+    store
+        .with_write(|view| {
+            for key in &keys {
+                view.remove(key);
+            }
+        })
+        .await
+        .unwrap();
+    assert!(store.with_read(|view| view.is_empty()).await.unwrap());
+    assert_eq!(store.bytes(), 0);
+}
+
+#[tokio::test]
+async fn a_held_key_does_not_block_other_shards() {
+    let store = store(64);
+    put(&store, "held", "v").await;
+
+    let barrier = Arc::new(Barrier::new(2));
+    let (release, released) = mpsc::channel::<()>();
+    let holder = {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        tokio::task::spawn_blocking(move || {
+            let write = store.with_key_write("held", move |_| {
+                barrier.wait();
+                released.recv().unwrap();
+            });
+            Handle::current().block_on(write).unwrap();
+        })
+    };
+    tokio::task::spawn_blocking(move || barrier.wait())
+        .await
+        .unwrap();
+
+    // Some of these keys may share the held key's shard and wait for it; nearly
+    // all don't
+    let (done, mut finished) = tokio::sync::mpsc::unbounded_channel();
+    for n in 0..10 {
+        let store = store.clone();
+        let done = done.clone();
+        tokio::spawn(async move {
+            put(&store, &format!("other{n}"), "v").await;
+            let _ = done.send(n);
+        });
+    }
+    let first = tokio::time::timeout(Duration::from_secs(5), finished.recv()).await;
+    assert!(first.unwrap().is_some());
+
+    release.send(()).unwrap();
+    holder.await.unwrap();
+    for _ in 1..10 {
+        finished.recv().await.unwrap();
+    }
+}
+
+// The server and its clients share a single runtime thread here, so a request
+// that blocked the thread waiting for the store would stall every one of them
+#[tokio::test(flavor = "current_thread")]
+async fn a_long_write_leaves_other_requests_served() {
+    let config = Config::parse_from(["rust-kv", "--request-timeout-secs", "1"]);
+    let server = TestServer::spawn(config).await;
+    let client = reqwest::Client::new();
+    let response = client.put(server.url("/key")).body("value").send().await;
+    assert_eq!(response.unwrap().status(), StatusCode::CREATED);
+
+    let (held, is_held) = tokio::sync::oneshot::channel();
+    let holder = hold_store(server.store(), move || {
+        held.send(()).unwrap();
+        std::thread::sleep(Duration::from_millis(1500));
+    });
+    is_held.await.unwrap();
+
+    let started = Instant::now();
+    let read = tokio::spawn(client.get(server.url("/key")).send());
+    let health = client.get(server.url("/healthz")).send().await.unwrap();
+    assert_eq!(health.status(), StatusCode::OK);
+    assert!(started.elapsed() < Duration::from_millis(500));
+
+    // The read waits for the write, then gives up cleanly
+    let read = read.await.unwrap().unwrap();
+    assert_eq!(read.status(), StatusCode::SERVICE_UNAVAILABLE);
+    holder.await.unwrap();
+    let read = client.get(server.url("/key")).send().await.unwrap();
+    assert_eq!(read.text().await.unwrap(), "value");
+}
+
+#[tokio::test]
+async fn tenant_quotas_hold_under_concurrent_writes() {
+    let config = Config::parse_from(["rust-kv", "--shards", "8", "--tenant-max-keys", "10"]);
+    let server = TestServer::spawn(config).await;
+    let client = reqwest::Client::new();
+
+    let requests: Vec<_> = (0..50)
+        .map(|n| {
+            let request = client
+                .put(server.url(&format!("/key{n}")))
+                .header("x-tenant", "acme")
+                .body("value");
+            tokio::spawn(request.send())
+        })
+        .collect();
+    let mut statuses = Vec::new();
+    for request in requests {
+        statuses.push(request.await.unwrap().unwrap().status());
+    }
+    let created = statuses
+        .iter()
+        .filter(|&&s| s == StatusCode::CREATED)
+        .count();
+    assert_eq!(created, 10, "{:?}", statuses);
+    assert_eq!(server.store().tenant_usage("acme").keys, 10);
+}
+
+// Throughput of many tasks writing and reading with one shard against many.
+// Only a machine with several cores shows the difference. Run with
+// `cargo test --release --test store -- --ignored --nocapture`.
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn sharding_reduces_write_contention() {
+    const TASKS: usize = 16;
+    const WRITES: usize = 50_000;
+
+    let run = |shards: usize| async move {
+        let store = store(shards);
+        let start = Instant::now();
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|task| {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    for n in 0..WRITES {
+                        put(&store, &format!("{task}/{}", n % 1000), "value").await;
+                        let key = format!("{task}/{}", n % 997);
+                        store
+                            .with_key_read(&key, |view| view.get(&key))
+                            .await
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        start.elapsed()
+    };
+
+    let single = run(1).await;
+    let sharded = run(TASKS * 4).await;
+    let ops = (TASKS * WRITES * 2) as f64;
+    println!(
+        "1 shard: {:.0} ops/s, {} shards: {:.0} ops/s",
+        ops / single.as_secs_f64(),
+        TASKS * 4,
+        ops / sharded.as_secs_f64()
+    );
+}
+
+// The slowest of `count` PUTs, or of as many as it takes for `done` to hold
+async fn slowest_put(
+    client: &reqwest::Client,
+    server: &TestServer,
+    count: usize,
+    done: impl Fn() -> bool,
+) -> (Duration, usize) {
+    let (mut slowest, mut puts) = (Duration::ZERO, 0);
+    while puts < count || !done() {
+        let started = Instant::now();
+        let response = client
+            .put(server.url(&format!("/put{}", puts % 100)))
+            .body("value")
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        slowest = slowest.max(started.elapsed());
+        puts += 1;
+    }
+    (slowest, puts)
+}
+
+// PUT latency while a 1 GB store is snapshotted, against PUTs with no
+// snapshot running. Every key shares one value so the store itself stays small,
+// but the snapshot still encodes and writes all of it. Run with
+// `cargo test --release --test store -- --ignored --nocapture`.
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn snapshots_barely_delay_writes() {
+    const KEYS: usize = 16 * 1024;
+    const VALUE_BYTES: usize = 64 * 1024;
+
+    let dir = std::env::temp_dir().join(format!("rust-kv-store-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("snapshot.json");
+    let config = Config::parse_from([
+        "rust-kv",
+        "--snapshot-path",
+        path.to_str().unwrap(),
+        "--snapshot-interval-secs",
+        "3600",
+        "--admin-token",
+        "admin",
+    ]);
+    let value = Bytes::from("v".repeat(VALUE_BYTES));
+    let server = TestServer::spawn_with(config, |view| {
+        for n in 0..KEYS {
+            view.insert(format!("key{n}"), Entry::new(value.clone()));
+        }
+    })
+    .await;
+    let client = reqwest::Client::new();
+
+    let (before, _) = slowest_put(&client, &server, 500, || true).await;
+    let started = Instant::now();
+    let response = client
+        .post(server.url("/admin/snapshot"))
+        .bearer_auth("admin")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let (during, puts) = slowest_put(&client, &server, 1, || path.exists()).await;
+    println!(
+        "slowest PUT: {:?} alone, {:?} over {} PUTs during a {:?} snapshot",
+        before,
+        during,
+        puts,
+        started.elapsed()
+    );
+    // Copying the values out under the lock stalled writes for most of a
+    // second here. What's left, on a machine with few cores, is mostly the
+    // encoder competing with the server for CPU.
+    assert!(during < Duration::from_millis(100));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// A panic while a write holds the store leaves its locks usable
+#[tokio::test]
+async fn a_panicking_write_leaves_the_store_serving() {
+    let server = TestServer::spawn(Config::parse_from(["rust-kv"])).await;
+    put(server.store(), "kept", "value").await;
+
+    let store = server.store().clone();
+    let writer = tokio::spawn(async move {
+        store
+            .with_write(|view| {
+                view.remove("kept");
+                panic!("bug in a write");
+            })
+            .await
+    });
+    assert!(writer.await.unwrap_err().is_panic());
+
+    let client = reqwest::Client::new();
+    let response = client
+        .put(server.url("/after"))
+        .body("v")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = client.get(server.url("/after")).send().await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "v");
+    let stats = client.get(server.url("/stats")).send().await.unwrap();
+    assert_eq!(stats.status(), StatusCode::OK);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn healthz_answers_while_the_store_is_locked() {
+    let server = TestServer::spawn(Config::parse_from(["rust-kv"])).await;
+    let client = reqwest::Client::new();
+    let response = client
+        .put(server.url("/healthz/key"))
+        .body("v")
+        .send()
+        .await;
+    assert_eq!(response.unwrap().status(), StatusCode::CREATED);
+
+    let (held, is_held) = tokio::sync::oneshot::channel();
+    let (release, released) = mpsc::channel::<()>();
+    let holder = hold_store(server.store(), move || {
+        held.send(()).unwrap();
+        released.recv().unwrap();
+    });
+    is_held.await.unwrap();
+
+    for _ in 0..5 {
+        let started = Instant::now();
+        let health = client.get(server.url("/healthz")).send().await.unwrap();
+        assert_eq!(health.status(), StatusCode::OK);
+        assert_eq!(health.text().await.unwrap(), "ok");
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+    // A key under it waits for the store as usual
+    let read = tokio::spawn(client.get(server.url("/healthz/key")).send());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!read.is_finished());
+
+    release.send(()).unwrap();
+    holder.await.unwrap();
+    let read = read.await.unwrap().unwrap();
+    assert_eq!(read.text().await.unwrap(), "v");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn requests_past_the_timeout_answer_503() {
+    let config = Config::parse_from(["rust-kv", "--request-timeout-secs", "1"]);
+    let server = TestServer::spawn(config).await;
+    let client = reqwest::Client::new();
+
+    let (held, is_held) = tokio::sync::oneshot::channel();
+    let (release, released) = mpsc::channel::<()>();
+    let holder = hold_store(server.store(), move || {
+        held.send(()).unwrap();
+        released.recv().unwrap();
+    });
+    is_held.await.unwrap();
+
+    let started = Instant::now();
+    let write = client.put(server.url("/key")).body("value").send();
+    let read = client.get(server.url("/key")).send();
+    let (write, read) = tokio::join!(write, read);
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_secs(1), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(1800), "{:?}", elapsed);
+    for response in [write.unwrap(), read.unwrap()] {
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value =
+            serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        assert_eq!(body["code"], "timeout");
+        assert_eq!(body["error"], "Request timed out after 1 seconds");
+    }
+
+    // The write gave up waiting for the store, so it was never applied
+    release.send(()).unwrap();
+    holder.await.unwrap();
+    let response = client.get(server.url("/key")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let metrics = client.get(server.url("/metrics?format=json")).send().await;
+    let metrics = metrics.unwrap().text().await.unwrap();
+    let metrics: serde_json::Value = serde_json::from_str(&metrics).unwrap();
+    assert_eq!(metrics["status_codes"]["503"], 2);
+}
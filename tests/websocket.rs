@@ -0,0 +1,244 @@
+// WebSocket sessions at /ws, driven by a real WebSocket client
+use clap::Parser;
+use futures_util::{SinkExt, StreamExt};
+use reqwest::StatusCode;
+use rust_kv::test_util::TestServer;
+use rust_kv::Config;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+struct Session(WebSocketStream<MaybeTlsStream<TcpStream>>);
+
+impl Session {
+    async fn open(server: &TestServer, tenant: Option<&str>) -> Self {
+        let url = format!("ws://{}/ws", server.address());
+        let mut request = url.into_client_request().unwrap();
+        if let Some(tenant) = tenant {
+            request
+                .headers_mut()
+                .insert("x-tenant", tenant.parse().unwrap());
+        }
+        let (socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        Self(socket)
+    }
+
+    async fn send(&mut self, command: Value) {
+        let message = Message::Text(command.to_string().into());
+        self.0.send(message).await.unwrap();
+    }
+
+    // The next message, parsed
+    async fn receive(&mut self) -> Value {
+        let next = tokio::time::timeout(Duration::from_secs(5), self.0.next());
+        match next.await.unwrap().unwrap().unwrap() {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            other => panic!("expected a text message, got {:?}", other),
+        }
+    }
+
+    // Send a command and read the reply, which comes next when nothing is
+    // subscribed
+    async fn call(&mut self, command: Value) -> Value {
+        self.send(command).await;
+        self.receive().await
+    }
+}
+
+#[tokio::test]
+async fn commands_are_answered_by_id() {
+    let server = TestServer::spawn(Config::parse_from(["rust-kv"])).await;
+    let http = reqwest::Client::new();
+    let mut session = Session::open(&server, None).await;
+
+    let reply = session
+        .call(json!({"id": 1, "op": "put", "key": "a", "value": "one"}))
+        .await;
+    assert_eq!(reply["id"], 1);
+    assert_eq!(reply["created"], true);
+    let version = reply["version"].as_u64().unwrap();
+
+    let response = http.get(server.url("/a")).send().await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "one");
+
+    let reply = session
+        .call(json!({"id": "two", "op": "get", "key": "a"}))
+        .await;
+    assert_eq!(
+        reply,
+        json!({"id": "two", "value": "one", "version": version})
+    );
+
+    http.put(server.url("/bin"))
+        .body(&b"\x00\xff"[..])
+        .send()
+        .await
+        .unwrap();
+    let reply = session.call(json!({"op": "get", "key": "bin"})).await;
+    assert_eq!(reply["id"], Value::Null);
+    assert_eq!(reply["value_b64"], "AP8=");
+
+    let reply = session
+        .call(json!({"id": 3, "op": "delete", "key": "a"}))
+        .await;
+    assert_eq!(reply, json!({"id": 3, "deleted": true}));
+    let reply = session
+        .call(json!({"id": 4, "op": "get", "key": "a"}))
+        .await;
+    assert_eq!(reply["id"], 4);
+    assert_eq!(reply["code"], "key_not_found");
+    let reply = session
+        .call(json!({"id": 5, "op": "delete", "key": "a"}))
+        .await;
+    assert_eq!(reply["code"], "key_not_found");
+
+    // Mistakes are answered, and the session goes on
+    let reply = session.call(json!({"id": 6, "op": "rename"})).await;
+    assert_eq!(reply["id"], 6);
+    assert_eq!(reply["code"], "bad_request");
+    session.0.send(Message::Text("{".into())).await.unwrap();
+    assert_eq!(session.receive().await["code"], "bad_request");
+    let reply = session
+        .call(json!({"id": 7, "op": "put", "key": "ttl", "value": "v", "ttl": 0}))
+        .await;
+    assert_eq!(reply["code"], "bad_request");
+
+    // A session keeps to the tenant named when it was opened
+    let mut tenant = Session::open(&server, Some("acme")).await;
+    let reply = tenant
+        .call(json!({"id": 1, "op": "put", "key": "bin", "value": "theirs"}))
+        .await;
+    assert_eq!(reply["created"], true);
+    let response = http
+        .get(server.url("/bin"))
+        .header("x-tenant", "acme")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.text().await.unwrap(), "theirs");
+    let response = http.get(server.url("/bin")).send().await.unwrap();
+    assert_eq!(response.bytes().await.unwrap().as_ref(), b"\x00\xff");
+}
+
+#[tokio::test]
+async fn subscriptions_notify_under_their_id() {
+    let server = TestServer::spawn(Config::parse_from(["rust-kv"])).await;
+    let http = reqwest::Client::new();
+    let mut session = Session::open(&server, None).await;
+
+    let reply = session
+        .call(json!({"id": "key", "op": "subscribe", "key": "a"}))
+        .await;
+    assert_eq!(reply, json!({"id": "key", "subscribed": true}));
+    let reply = session
+        .call(json!({"id": "prefix", "op": "subscribe", "prefix": "a"}))
+        .await;
+    assert_eq!(reply["subscribed"], true);
+    let reply = session
+        .call(json!({"id": 1, "op": "subscribe", "key": "a", "prefix": "a"}))
+        .await;
+    assert_eq!(reply["code"], "bad_request");
+
+    for key in ["a", "ab", "b"] {
+        http.put(server.url(&format!("/{key}")))
+            .body(key)
+            .send()
+            .await
+            .unwrap();
+    }
+    http.delete(server.url("/a")).send().await.unwrap();
+
+    let mut notifications = Vec::new();
+    for _ in 0..5 {
+        let notification = session.receive().await;
+        let event = &notification["event"];
+        notifications.push(format!(
+            "{} {} {} {}",
+            notification["id"].as_str().unwrap(),
+            event["op"].as_str().unwrap(),
+            event["key"].as_str().unwrap(),
+            event["value"].as_str().unwrap_or("-")
+        ));
+    }
+    assert_eq!(
+        notifications,
+        [
+            "key put a a",
+            "prefix put a a",
+            "prefix put ab ab",
+            "key delete a -",
+            "prefix delete a -",
+        ]
+    );
+
+    // Writes from the session itself are seen too, after the reply
+    let reply = session.call(json!({"id": 2, "op": "unsubscribe"})).await;
+    assert_eq!(reply, json!({"id": 2, "unsubscribed": false}));
+    let reply = session
+        .call(json!({"id": "key", "op": "unsubscribe"}))
+        .await;
+    assert_eq!(reply["unsubscribed"], true);
+    let reply = session
+        .call(json!({"id": 3, "op": "put", "key": "a", "value": "again"}))
+        .await;
+    assert_eq!(reply["id"], 3);
+    let notification = session.receive().await;
+    assert_eq!(notification["id"], "prefix");
+    assert_eq!(notification["event"]["value"], "again");
+
+    // Shutting down ends the session rather than waiting on it
+    let shutdown = tokio::time::timeout(Duration::from_secs(5), server.shutdown());
+    shutdown.await.unwrap();
+    let next = tokio::time::timeout(Duration::from_secs(5), session.0.next());
+    assert!(matches!(
+        next.await.unwrap(),
+        Some(Ok(Message::Close(_))) | None | Some(Err(_))
+    ));
+}
+
+#[tokio::test]
+async fn a_client_that_stops_reading_is_told_what_it_missed() {
+    let server = TestServer::spawn(Config::parse_from(["rust-kv"])).await;
+    let http = reqwest::Client::new();
+    let mut session = Session::open(&server, None).await;
+    let reply = session
+        .call(json!({"id": "big", "op": "subscribe", "key": "big"}))
+        .await;
+    assert_eq!(reply["subscribed"], true);
+
+    // Far more than the connection buffers; the writes go on regardless
+    let value = "v".repeat(16 * 1024);
+    for _ in 0..3000 {
+        let response = http.put(server.url("/big")).body(value.clone()).send();
+        assert!(response.await.unwrap().status().is_success());
+    }
+
+    let mut notifications = 0;
+    loop {
+        let message = session.receive().await;
+        if let Some(missed) = message.get("lagged") {
+            assert!(missed.as_u64().unwrap() > 0);
+            break;
+        }
+        assert_eq!(message["id"], "big");
+        notifications += 1;
+        assert!(notifications < 3000);
+    }
+
+    // And it's served again once it reads
+    let response = http.delete(server.url("/big")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    loop {
+        let message = session.receive().await;
+        if message["event"]["op"] == "delete" {
+            break;
+        }
+    }
+    let reply = session
+        .call(json!({"id": 1, "op": "get", "key": "big"}))
+        .await;
+    assert_eq!(reply["code"], "key_not_found");
+}